@@ -0,0 +1,312 @@
+//! How CPUs are taken away from the workload and given back.
+//!
+//! By default the CPU manager hotplugs the CPUs through their sysfs `online` file (`Hotplug`).
+//! With `--backend cpuset`, it narrows and widens the `cpuset.cpus` of a cgroup instead, e.g.
+//! `system.slice` (`CpusetActuation`). The CPUs taken away stay online but run nothing from the
+//! cgroup, so they reach deep idle states without the latency of a hotplug, and the daemon only
+//! needs write access to the cgroup, e.g. one delegated to it by systemd.
+//!
+//! On s390x, a CPU also has a `configure` file: a deconfigured CPU is in standby, handed back to
+//! the hypervisor, and must be configured before it can be onlined. The hotplug backend configures
+//! standby CPUs before onlining them and, with `--deconfigure`, deconfigures the CPUs it offlines.
+use crate::cpu_list::CpuList;
+use crate::sysfs::SysfsBackend;
+use async_trait::async_trait;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::warn;
+
+/// The cgroup narrowed by the cpuset backend unless set with `--backend-cgroup`.
+pub static DEFAULT_CGROUP: &str = "system.slice";
+
+/// The backends selectable with `--backend`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendName {
+    /// Offline and online the CPUs through sysfs.
+    #[default]
+    Hotplug,
+    /// Remove the CPUs from and add them to the `cpuset.cpus` of a cgroup.
+    Cpuset,
+    /// Cap the maximum frequency of the CPUs instead of taking any away, see `FrequencyCap`.
+    Cpufreq,
+    /// Cap the maximum frequency first, then allow deeper idle states, then offline CPUs through
+    /// sysfs, see `TierStage`.
+    Tiered,
+}
+
+impl BackendName {
+    /// Returns whether the CPU manager caps the frequency with this backend.
+    pub fn caps_frequency(self) -> bool {
+        matches!(self, BackendName::Cpufreq | BackendName::Tiered)
+    }
+
+    /// Creates the backend, acting on the CPUs of `sysfs` and, for the cpuset backend, on the
+    /// cgroup directory `cgroup_dir`. The cpufreq and tiered backends hotplug the CPUs they bring
+    /// online or take offline; the frequency cap is left to the CPU manager. With `deconfigure`,
+    /// the backends that hotplug also deconfigure the CPUs they offline, see `Hotplug`.
+    ///
+    /// # Returns
+    /// * `io::Result<Arc<dyn ActuationBackend>>` - The backend, or an error if the cgroup has no
+    ///   `cpuset.cpus`, e.g. because the cpuset controller is not enabled for it.
+    pub fn build(
+        self,
+        sysfs: Arc<dyn SysfsBackend>,
+        cgroup_dir: PathBuf,
+        deconfigure: bool,
+    ) -> io::Result<Arc<dyn ActuationBackend>> {
+        Ok(match self {
+            BackendName::Hotplug | BackendName::Cpufreq | BackendName::Tiered => {
+                Arc::new(Hotplug::new(sysfs).deconfigure(deconfigure))
+            }
+            BackendName::Cpuset => Arc::new(CpusetActuation::new(sysfs, cgroup_dir)?),
+        })
+    }
+}
+
+/// Takes CPUs away from the workload and gives them back.
+#[async_trait]
+pub trait ActuationBackend: Send + Sync {
+    /// Returns the name of the backend, for logs.
+    fn name(&self) -> &'static str;
+
+    /// Returns whether CPU `id` can be taken away and given back, e.g. whether it has an `online`
+    /// file for the hotplug backend.
+    fn controls(&self, id: usize) -> bool;
+
+    /// Returns whether CPU `id` is currently available to the workload.
+    async fn is_online(&self, id: usize) -> io::Result<bool>;
+
+    /// Gives CPU `id` to the workload, or takes it away. May block for as long as the kernel
+    /// takes, e.g. to complete a hotplug operation.
+    async fn set_online(&self, id: usize, online: bool) -> io::Result<()>;
+}
+
+/// Offlines and onlines CPUs by writing their sysfs `online` file, configuring standby CPUs
+/// through their `configure` file first where there is one, as on s390x.
+pub struct Hotplug {
+    sysfs: Arc<dyn SysfsBackend>,
+    deconfigure: bool,
+}
+
+impl Hotplug {
+    pub fn new(sysfs: Arc<dyn SysfsBackend>) -> Self {
+        Hotplug {
+            sysfs,
+            deconfigure: false,
+        }
+    }
+
+    /// Deconfigures the CPUs with a `configure` file once they are offline, putting them in
+    /// standby, so the hypervisor can give them to other guests.
+    pub fn deconfigure(mut self, deconfigure: bool) -> Self {
+        self.deconfigure = deconfigure;
+        self
+    }
+}
+
+/// Reads whether CPU `id` is configured, from its `configure` file.
+///
+/// # Returns
+/// * `io::Result<Option<bool>>` - Whether the CPU is configured, or `None` if it has no `configure`
+///   file, i.e. on anything but s390x.
+pub async fn is_configured(sysfs: &dyn SysfsBackend, id: usize) -> io::Result<Option<bool>> {
+    if !sysfs.exists(id, "configure") {
+        return Ok(None);
+    }
+    Ok(Some(sysfs.read(id, "configure").await?.trim() == "1"))
+}
+
+#[async_trait]
+impl ActuationBackend for Hotplug {
+    fn name(&self) -> &'static str {
+        "hotplug"
+    }
+
+    fn controls(&self, id: usize) -> bool {
+        self.sysfs.exists(id, "online")
+    }
+
+    async fn is_online(&self, id: usize) -> io::Result<bool> {
+        if !self.sysfs.exists(id, "online") {
+            return Ok(true); // CPU is always online if the 'online' file doesn't exist
+        }
+        Ok(self.sysfs.read(id, "online").await?.trim() == "1")
+    }
+
+    /// Configures a standby CPU before onlining it. With `deconfigure`, deconfigures the CPU after
+    /// offlining it; if that fails, the CPU is left offline but configured, and only a warning is
+    /// logged, since the offline itself succeeded.
+    async fn set_online(&self, id: usize, online: bool) -> io::Result<()> {
+        if online && is_configured(&*self.sysfs, id).await? == Some(false) {
+            self.sysfs.write(id, "configure", "1").await?;
+        }
+        self.sysfs
+            .write(id, "online", if online { "1" } else { "0" })
+            .await?;
+        if !online && self.deconfigure && self.sysfs.exists(id, "configure") {
+            if let Err(e) = self.sysfs.write(id, "configure", "0").await {
+                warn!(
+                    "Cannot deconfigure CPU {}, leaving it configured: {}",
+                    id, e
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Narrows and widens the `cpuset.cpus` of a cgroup. A CPU counts as online while it is in the
+/// cpuset and online in sysfs, so CPUs offlined by someone else are not mistaken for available.
+pub struct CpusetActuation {
+    sysfs: Arc<dyn SysfsBackend>,
+    cgroup_dir: PathBuf,
+}
+
+impl CpusetActuation {
+    /// Creates a backend for the cgroup directory `cgroup_dir`.
+    ///
+    /// # Returns
+    /// * `io::Result<CpusetActuation>` - The backend, or an error if the cgroup has no `cpuset.cpus`.
+    pub fn new(sysfs: Arc<dyn SysfsBackend>, cgroup_dir: PathBuf) -> io::Result<Self> {
+        if !cgroup_dir.join("cpuset.cpus").exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "{:?} has no cpuset.cpus; is the cpuset controller enabled for it?",
+                    cgroup_dir
+                ),
+            ));
+        }
+        Ok(CpusetActuation { sysfs, cgroup_dir })
+    }
+
+    /// Reads the CPUs of the cpuset. An empty `cpuset.cpus` inherits the parent's CPUs, which are
+    /// read from `cpuset.cpus.effective` instead.
+    async fn cpus(&self) -> io::Result<BTreeSet<usize>> {
+        for file in ["cpuset.cpus", "cpuset.cpus.effective"] {
+            let content = tokio::fs::read_to_string(self.cgroup_dir.join(file)).await?;
+            let cpus = content
+                .trim()
+                .parse::<CpuList>()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if !cpus.is_empty() {
+                return Ok(cpus.0);
+            }
+        }
+        Ok(BTreeSet::new())
+    }
+}
+
+#[async_trait]
+impl ActuationBackend for CpusetActuation {
+    fn name(&self) -> &'static str {
+        "cpuset"
+    }
+
+    fn controls(&self, _id: usize) -> bool {
+        true
+    }
+
+    async fn is_online(&self, id: usize) -> io::Result<bool> {
+        let online = Hotplug::new(Arc::clone(&self.sysfs)).is_online(id).await?;
+        Ok(online && self.cpus().await?.contains(&id))
+    }
+
+    async fn set_online(&self, id: usize, online: bool) -> io::Result<()> {
+        let mut cpus = self.cpus().await?;
+        if online {
+            cpus.insert(id);
+        } else {
+            cpus.remove(&id);
+        }
+        if cpus.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cannot remove the last CPU {} from the cpuset", id),
+            ));
+        }
+        tokio::fs::write(
+            self.cgroup_dir.join("cpuset.cpus"),
+            CpuList(cpus).to_string(),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sysfs::MockSysfs;
+    use std::fs;
+
+    #[tokio::test]
+    async fn narrows_and_widens_the_cpuset() {
+        let root = std::env::temp_dir().join(format!("cpu-on-off-cpuset-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("cpuset.cpus"), "\n").unwrap();
+        fs::write(root.join("cpuset.cpus.effective"), "0-3\n").unwrap();
+        let sysfs = Arc::new(
+            MockSysfs::new()
+                .with_cpu(0, 0, 0, "0")
+                .with_cpu(1, 1, 0, "1")
+                .with_cpu(2, 2, 0, "2")
+                .with_cpu(3, 3, 0, "3"),
+        );
+        let backend = BackendName::Cpuset
+            .build(sysfs.clone(), root.clone(), false)
+            .unwrap();
+        assert!(backend.is_online(3).await.unwrap());
+
+        backend.set_online(3, false).await.unwrap();
+        backend.set_online(2, false).await.unwrap();
+        assert_eq!(fs::read_to_string(root.join("cpuset.cpus")).unwrap(), "0,1");
+        assert!(!backend.is_online(3).await.unwrap());
+        backend.set_online(3, true).await.unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("cpuset.cpus")).unwrap(),
+            "0,1,3"
+        );
+
+        // A CPU hotplugged away by someone else is not online, whatever the cpuset says.
+        sysfs.set(3, "online", "0");
+        assert!(!backend.is_online(3).await.unwrap());
+        assert!(BackendName::Cpuset
+            .build(sysfs, root.join("missing"), false)
+            .is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn configures_standby_cpus_before_onlining_them() {
+        let sysfs = Arc::new(MockSysfs::new().with_cpu(1, 1, 0, "1"));
+        sysfs.set(1, "online", "0");
+        sysfs.set(1, "configure", "0");
+        let backend = Hotplug::new(sysfs.clone());
+        backend.set_online(1, true).await.unwrap();
+        assert_eq!(sysfs.get(1, "configure").as_deref(), Some("1"));
+        assert_eq!(sysfs.get(1, "online").as_deref(), Some("1"));
+
+        // Offlining leaves the CPU configured unless asked to deconfigure it.
+        backend.set_online(1, false).await.unwrap();
+        assert_eq!(sysfs.get(1, "configure").as_deref(), Some("1"));
+        let backend = Hotplug::new(sysfs.clone()).deconfigure(true);
+        backend.set_online(1, true).await.unwrap();
+        backend.set_online(1, false).await.unwrap();
+        assert_eq!(sysfs.get(1, "online").as_deref(), Some("0"));
+        assert_eq!(sysfs.get(1, "configure").as_deref(), Some("0"));
+        assert_eq!(is_configured(&*sysfs, 1).await.unwrap(), Some(false));
+
+        // A failure to deconfigure does not fail the offline.
+        backend.set_online(1, true).await.unwrap();
+        sysfs.fail_writes(1, "configure", libc::EBUSY, 1);
+        backend.set_online(1, false).await.unwrap();
+        assert_eq!(sysfs.get(1, "online").as_deref(), Some("0"));
+        assert_eq!(sysfs.get(1, "configure").as_deref(), Some("1"));
+    }
+}
@@ -0,0 +1,214 @@
+//! Tasks pinned to the CPUs about to be offlined, found from `/proc`.
+//!
+//! When the last CPU of a task's affinity mask goes offline, the kernel breaks the affinity and
+//! lets the task run anywhere, silently undoing the pinning of e.g. a real-time or packet
+//! processing thread. Before every offline, the CPU manager looks for user threads whose
+//! `Cpus_allowed_list` in `/proc/<pid>/task/<tid>/status` lies entirely within the CPUs to be
+//! offlined, and `--pinned-tasks` decides whether to warn about them, keep the CPUs online, or
+//! re-pin the threads to the CPUs that stay online. Per-CPU kernel threads are parked by the
+//! kernel itself and never count.
+use crate::cpu_list::CpuList;
+use crate::stickiness::PROC_DIR;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// What to do when CPUs about to be offlined are the only ones some tasks may run on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PinnedTaskPolicy {
+    /// Offline the CPUs anyway and log every stranded task.
+    #[default]
+    Warn,
+    /// Keep the CPUs online.
+    Skip,
+    /// Re-pin the tasks to the CPUs that stay online, then offline the CPUs.
+    Repin,
+}
+
+/// A thread whose affinity lies entirely within the CPUs about to be offlined.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PinnedTask {
+    pub pid: u32,
+    pub tid: u32,
+    /// The thread's command name.
+    pub name: String,
+    /// The CPUs the thread may run on.
+    pub allowed: CpuList,
+}
+
+/// The fields of a thread's `status` file used to find pinned tasks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TaskStatus {
+    pub name: String,
+    pub allowed: CpuList,
+    /// Whether the thread is a kernel thread, which has no address space.
+    pub kernel: bool,
+}
+
+/// Parses a `/proc/<pid>/task/<tid>/status` file. Kernel threads are told apart by their
+/// `Kthread` field on recent kernels, and by the missing `VmSize` field on older ones.
+pub fn parse_task_status(content: &str) -> Option<TaskStatus> {
+    let mut name = None;
+    let mut allowed = None;
+    let mut kthread = None;
+    let mut has_vm = false;
+    for line in content.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match key {
+            "Name" => name = Some(value.to_string()),
+            "Cpus_allowed_list" => allowed = value.parse().ok(),
+            "Kthread" => kthread = Some(value == "1"),
+            "VmSize" => has_vm = true,
+            _ => {}
+        }
+    }
+    Some(TaskStatus {
+        name: name?,
+        allowed: allowed?,
+        kernel: kthread.unwrap_or(!has_vm),
+    })
+}
+
+/// Returns the user threads that may only run on CPUs in `cpus`.
+pub async fn pinned_tasks(cpus: &[usize]) -> io::Result<Vec<PinnedTask>> {
+    let cpus: BTreeSet<usize> = cpus.iter().copied().collect();
+    tokio::task::spawn_blocking(move || find_pinned(Path::new(PROC_DIR), &cpus))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Scans the `status` file of every thread below `proc_dir` for user threads that may only run
+/// on CPUs in `cpus`. Processes and threads that exit during the scan are skipped.
+fn find_pinned(proc_dir: &Path, cpus: &BTreeSet<usize>) -> io::Result<Vec<PinnedTask>> {
+    let mut pinned = Vec::new();
+    for process in fs::read_dir(proc_dir)?.flatten() {
+        let Some(pid) = process
+            .file_name()
+            .to_str()
+            .and_then(|pid| pid.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        let Ok(threads) = fs::read_dir(process.path().join("task")) else {
+            continue;
+        };
+        for thread in threads.flatten() {
+            let Some(tid) = thread.file_name().to_str().and_then(|tid| tid.parse().ok()) else {
+                continue;
+            };
+            let Some(status) = fs::read_to_string(thread.path().join("status"))
+                .ok()
+                .as_deref()
+                .and_then(parse_task_status)
+            else {
+                continue;
+            };
+            if !status.kernel && !status.allowed.is_empty() && status.allowed.0.is_subset(cpus) {
+                pinned.push(PinnedTask {
+                    pid,
+                    tid,
+                    name: status.name,
+                    allowed: status.allowed,
+                });
+            }
+        }
+    }
+    pinned.sort_by_key(|task| task.tid);
+    Ok(pinned)
+}
+
+/// Sets the affinity of thread `tid` to `cpus`.
+pub fn repin(tid: u32, cpus: &BTreeSet<usize>) -> io::Result<()> {
+    // Safety: `cpu_set_t` is a plain bit mask, for which all zeroes is the empty set.
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &cpu in cpus.iter().filter(|&&cpu| cpu < libc::CPU_SETSIZE as usize) {
+        // Safety: `cpu` is within the bounds of the set.
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    // Safety: `set` is a valid CPU set of the size passed along with it.
+    let result = unsafe {
+        libc::sched_setaffinity(
+            tid as libc::pid_t,
+            std::mem::size_of::<libc::cpu_set_t>(),
+            &set,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER_STATUS: &str = "Name:\tdpdk-worker\nUmask:\t0022\nState:\tR (running)\n\
+                               Tgid:\t4242\nPid:\t4243\nVmSize:\t  123456 kB\n\
+                               Cpus_allowed:\tc000\nCpus_allowed_list:\t14-15\n";
+
+    #[test]
+    fn parses_thread_status_files() {
+        assert_eq!(
+            parse_task_status(USER_STATUS),
+            Some(TaskStatus {
+                name: "dpdk-worker".to_string(),
+                allowed: "14-15".parse().unwrap(),
+                kernel: false,
+            })
+        );
+        let kthread = "Name:\tksoftirqd/14\nKthread:\t1\nCpus_allowed_list:\t14\n";
+        assert!(parse_task_status(kthread).unwrap().kernel);
+        let old_kthread = "Name:\tksoftirqd/14\nCpus_allowed_list:\t14\n";
+        assert!(parse_task_status(old_kthread).unwrap().kernel);
+    }
+
+    #[test]
+    fn finds_user_threads_pinned_to_the_offlined_cpus() {
+        let root = std::env::temp_dir().join(format!("cpu-on-off-proc-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let threads = [
+            (4242, 4243, USER_STATUS.to_string()),
+            (
+                4242,
+                4244,
+                USER_STATUS
+                    .replace("14-15", "0-15")
+                    .replace("dpdk-worker", "main"),
+            ),
+            (
+                7,
+                7,
+                "Name:\tksoftirqd/14\nCpus_allowed_list:\t14\n".to_string(),
+            ),
+        ];
+        for (pid, tid, status) in &threads {
+            let dir = root.join(format!("{}/task/{}", pid, tid));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("status"), status).unwrap();
+        }
+        fs::create_dir_all(root.join("self")).unwrap();
+
+        let pinned = find_pinned(&root, &BTreeSet::from([12, 13, 14, 15])).unwrap();
+        assert_eq!(
+            pinned,
+            [PinnedTask {
+                pid: 4242,
+                tid: 4243,
+                name: "dpdk-worker".to_string(),
+                allowed: "14-15".parse().unwrap(),
+            }]
+        );
+        assert!(find_pinned(&root, &BTreeSet::from([15]))
+            .unwrap()
+            .is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
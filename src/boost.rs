@@ -0,0 +1,161 @@
+//! Turbo coordination, selected with `--boost-policy`.
+//!
+//! Turbo, or boost, lets a few busy cores run above their base clock while the package has power
+//! and thermal headroom. The more cores are online and busy, the less of it each one gets, so some
+//! machines do more work with fewer cores at turbo and others with more cores at base clock. The
+//! CPU manager treats turbo as one more step next to the hotplug steps: with `cores-first`, every
+//! core is onlined before turbo is enabled, and with `turbo-first`, turbo is enabled before any core
+//! is onlined. As the load drops, the steps are undone in the reverse order.
+//!
+//! Turbo is switched for the whole system through `intel_pstate/no_turbo`, or
+//! `cpufreq/boost` with the other cpufreq drivers, e.g. `acpi-cpufreq` and `amd-pstate`.
+use crate::metrics::{SysfsAccess, METRICS};
+use crate::topology::SystemTopology;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::io;
+use tracing::info;
+
+/// How turbo is coordinated with hotplug, selected with `--boost-policy`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BoostPolicy {
+    /// Leave turbo alone.
+    #[default]
+    Leave,
+    /// More cores at base clock: online every core before enabling turbo, and disable turbo before
+    /// offlining any core.
+    CoresFirst,
+    /// Fewer cores at turbo: enable turbo before onlining any core, and disable it only once the
+    /// cores are offlined down to the floor.
+    TurboFirst,
+}
+
+/// The system-wide attribute that switches turbo.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Knob {
+    /// `intel_pstate/no_turbo`, 1 while turbo is disabled.
+    NoTurbo,
+    /// `cpufreq/boost`, 1 while turbo is enabled.
+    Boost,
+}
+
+impl Knob {
+    fn attr(self) -> &'static str {
+        match self {
+            Knob::NoTurbo => "intel_pstate/no_turbo",
+            Knob::Boost => "cpufreq/boost",
+        }
+    }
+
+    fn value(self, enabled: bool) -> &'static str {
+        match (self, enabled) {
+            (Knob::NoTurbo, true) | (Knob::Boost, false) => "0",
+            (Knob::NoTurbo, false) | (Knob::Boost, true) => "1",
+        }
+    }
+}
+
+/// The turbo state of the system.
+#[derive(Default)]
+pub struct Boost {
+    /// The attribute in use and whether turbo is enabled; `None` until read, or without either
+    /// attribute.
+    state: Option<(Knob, bool)>,
+    /// Whether turbo was enabled at startup, restored on exit.
+    initial: Option<bool>,
+    /// Whether the attributes were looked up.
+    probed: bool,
+}
+
+impl Boost {
+    /// Returns whether turbo is enabled, if known.
+    pub fn enabled(&self) -> Option<bool> {
+        self.state.map(|(_, enabled)| enabled)
+    }
+
+    /// Enables or disables turbo.
+    ///
+    /// # Returns
+    /// * `io::Result<bool>` - Whether turbo was switched, `false` if it already was in that state
+    ///   or cannot be switched on this system, or an error if the write failed.
+    pub async fn set(&mut self, topology: &SystemTopology, enabled: bool) -> io::Result<bool> {
+        let Some((knob, current)) = self.current(topology).await else {
+            return Ok(false);
+        };
+        if current == enabled {
+            return Ok(false);
+        }
+        if let Err(e) = topology
+            .sysfs
+            .write_global(knob.attr(), knob.value(enabled))
+            .await
+        {
+            METRICS.sysfs_error(SysfsAccess::CpufreqWrite, &e);
+            return Err(e);
+        }
+        info!("Turbo {}", if enabled { "enabled" } else { "disabled" });
+        self.state = Some((knob, enabled));
+        Ok(true)
+    }
+
+    /// Returns turbo to its state at startup.
+    pub async fn restore(&mut self, topology: &SystemTopology) -> io::Result<()> {
+        match self.initial {
+            Some(initial) => self.set(topology, initial).await.map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the attribute in use and whether turbo is enabled, reading them the first time.
+    async fn current(&mut self, topology: &SystemTopology) -> Option<(Knob, bool)> {
+        if !self.probed {
+            self.probed = true;
+            for knob in [Knob::NoTurbo, Knob::Boost] {
+                let Ok(value) = topology.sysfs.read_global(knob.attr()).await else {
+                    continue;
+                };
+                let enabled = value.trim() != knob.value(false);
+                self.state = Some((knob, enabled));
+                self.initial = Some(enabled);
+                break;
+            }
+            if self.state.is_none() {
+                info!("Turbo cannot be switched on this system, --boost-policy has no effect");
+            }
+        }
+        self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sysfs::{MockSysfs, SysfsBackend};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn switches_and_restores_intel_pstate_turbo() {
+        let sysfs = Arc::new(MockSysfs::new().with_cpu(0, 0, 0, "0"));
+        sysfs.set_global("intel_pstate/no_turbo", "0");
+        sysfs.set_global("cpufreq/boost", "1");
+        let topology = SystemTopology::builder()
+            .backend(sysfs.clone())
+            .build()
+            .await
+            .unwrap();
+        let mut boost = Boost::default();
+        assert!(!boost.set(&topology, true).await.unwrap());
+        assert_eq!(boost.enabled(), Some(true));
+        assert!(boost.set(&topology, false).await.unwrap());
+        assert_eq!(
+            sysfs.read_global("intel_pstate/no_turbo").await.unwrap(),
+            "1\n"
+        );
+        boost.restore(&topology).await.unwrap();
+        assert_eq!(
+            sysfs.read_global("intel_pstate/no_turbo").await.unwrap(),
+            "0\n"
+        );
+    }
+}
@@ -0,0 +1,52 @@
+//! Burst handling: onlining every managed CPU at once when the load spikes past a panic threshold.
+//!
+//! The regular policies ramp up by `step` cores per cooldown, which is right for a load that grows
+//! gradually but leaves a sudden burst starved for many cycles. With `--panic-threshold 95`, a load
+//! above 95% onlines every offline CPU in a single cycle, regardless of hysteresis and cooldown,
+//! and the regular policy takes over again from there.
+use crate::topology::SystemTopology;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// Which load is compared with the panic threshold.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PanicTrigger {
+    /// The load that drives the regular policy, averaged over the online CPUs.
+    #[default]
+    Average,
+    /// The load of the busiest managed online CPU, or the average if it is higher, e.g. with a
+    /// system-wide load metric.
+    Max,
+}
+
+impl PanicTrigger {
+    /// Returns the load `trigger` compares with the panic threshold, given the cycle's `load`.
+    pub fn load(self, load: f64, topology: &SystemTopology) -> f64 {
+        match self {
+            PanicTrigger::Average => load,
+            PanicTrigger::Max => topology
+                .cpus
+                .values()
+                .filter(|cpu| cpu.online && cpu.managed)
+                .map(|cpu| cpu.c0_percentage)
+                .fold(load, f64::max),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_trigger_follows_the_busiest_managed_online_cpu() {
+        let mut topology = SystemTopology::single_thread_cores(&[true, true, false, true]);
+        for (id, c0_percentage) in [20.0, 98.0, 99.0, 100.0].into_iter().enumerate() {
+            topology.cpus.get_mut(&id).unwrap().c0_percentage = c0_percentage;
+        }
+        topology.cpus.get_mut(&3).unwrap().managed = false;
+        assert_eq!(PanicTrigger::Average.load(59.0, &topology), 59.0);
+        assert_eq!(PanicTrigger::Max.load(59.0, &topology), 98.0);
+    }
+}
@@ -0,0 +1,1137 @@
+//! Command-line arguments, the optional TOML configuration file, and the settings resolved from both.
+//!
+//! Every option can be given on the command line or in the file; command-line flags take
+//! precedence so that a deployed file can still be overridden for a single run.
+use crate::actuation::{self, BackendName};
+use crate::affinity::PinnedTaskPolicy;
+use crate::boost::BoostPolicy;
+use crate::burst::PanicTrigger;
+use crate::control;
+use crate::cpu_list::CpuList;
+use crate::cpuidle::{IdlePolicy, SHALLOWEST_STATE};
+use crate::decision_log;
+use crate::http;
+use crate::influx::InfluxUrl;
+use crate::journal;
+use crate::kubelet;
+use crate::load::{MetricKind, WindowAggregation};
+use crate::logging::{LogFormat, LogLevel};
+use crate::profile::{Profile, ProfileName, ProfileOverrides};
+use crate::pstate;
+use crate::remote_config;
+use crate::sink::SinkConfig;
+use crate::strategy::StrategyName;
+use crate::target::{self, ScalingMode};
+use crate::topology::{self, CapacityClass, ExitPolicy, Granularity, OfflineOrder, StartFrom};
+use crate::workload::{self, WorkloadClass, WorkloadMode};
+use clap::{ArgAction, Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::info;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Path of a TOML configuration file
+    #[arg(short = 'c', long)]
+    pub config: Option<PathBuf>,
+
+    /// HTTPS URL of a signed TOML configuration file used instead of the local one
+    #[arg(long)]
+    pub config_url: Option<String>,
+
+    /// Ed25519 public key, in hex, that the configuration at --config-url must be signed with
+    #[arg(long)]
+    pub config_key: Option<String>,
+
+    /// Tuning profile (default: balanced)
+    #[arg(short = 'p', long, value_enum)]
+    pub profile: Option<ProfileName>,
+
+    /// Classify the workload to pick the profile automatically (auto), or pin a workload class
+    #[arg(short = 'w', long, value_enum)]
+    pub workload: Option<WorkloadMode>,
+
+    /// Upper load threshold percentage (default: from the profile)
+    #[arg(short = 'u', long)]
+    pub upper_threshold: Option<u8>,
+
+    /// Lower load threshold percentage (default: from the profile)
+    #[arg(short = 'l', long)]
+    pub lower_threshold: Option<u8>,
+
+    /// Consecutive samples beyond a threshold required before acting (default: from the profile)
+    #[arg(long)]
+    pub hysteresis: Option<u32>,
+
+    /// Minimum time in seconds between two hotplug actions (default: from the profile)
+    #[arg(long)]
+    pub cooldown_secs: Option<f64>,
+
+    /// Ignore load spikes above the upper threshold shorter than this many seconds when onlining (default: 0, disabled)
+    #[arg(long)]
+    pub spike_filter_secs: Option<f64>,
+
+    /// Scale by crossing the profile's thresholds (threshold), towards a target utilization (target), or with a PID controller (pid) (default: threshold)
+    #[arg(long, value_enum)]
+    pub mode: Option<ScalingMode>,
+
+    /// Utilization percentage that --mode target and --mode pid size the online CPUs for (default: 70)
+    #[arg(long)]
+    pub target_util: Option<u8>,
+
+    /// Proportional gain of --mode pid (default: 0.5)
+    #[arg(long)]
+    pub kp: Option<f64>,
+
+    /// Integral gain of --mode pid, per second (default: 0.1)
+    #[arg(long)]
+    pub ki: Option<f64>,
+
+    /// Derivative gain of --mode pid, in seconds (default: 0)
+    #[arg(long)]
+    pub kd: Option<f64>,
+
+    /// Online every managed CPU at once when the load exceeds this percentage (default: disabled)
+    #[arg(long)]
+    pub panic_threshold: Option<u8>,
+
+    /// Compare the average load (average) or the busiest CPU's load (max) with --panic-threshold (default: average)
+    #[arg(long, value_enum)]
+    pub panic_on: Option<PanicTrigger>,
+
+    /// Most CPUs to online or offline within any minute, in both directions together (default: unlimited)
+    #[arg(long)]
+    pub max_transitions_per_minute: Option<u32>,
+
+    /// Failed hotplug writes in a row after which a CPU is left alone for --blacklist-secs, 0 to never blacklist (default: 3)
+    #[arg(long)]
+    pub blacklist_after: Option<u32>,
+
+    /// Seconds a CPU stays blacklisted before it is tried again (default: 300)
+    #[arg(long)]
+    pub blacklist_secs: Option<f64>,
+
+    /// Seconds between two scans for hot-added or removed CPUs, 0 to never rescan (default: 60)
+    #[arg(long)]
+    pub rescan_secs: Option<f64>,
+
+    /// React to the kernel's CPU uevents right away instead of at the next cycle or rescan
+    #[arg(long)]
+    pub uevents: bool,
+
+    /// Polling interval in seconds (default: 1)
+    #[arg(short = 'i', long)]
+    pub interval: Option<f64>,
+
+    /// Load metric driving the decisions (default: cpuidle)
+    #[arg(short = 'm', long, value_enum)]
+    pub metric: Option<MetricKind>,
+
+    /// Drive the decisions by the CPU usage of this cgroup, e.g. batch.slice, instead of the whole system
+    #[arg(long, value_name = "CGROUP")]
+    pub watch_cgroup: Option<String>,
+
+    /// Number of samples each CPU's load is smoothed over (default: 1, no smoothing)
+    #[arg(long)]
+    pub window: Option<usize>,
+
+    /// How the samples of the window are combined (default: mean)
+    #[arg(long, value_enum)]
+    pub window_aggregation: Option<WindowAggregation>,
+
+    /// Address to serve Prometheus metrics on, e.g. 127.0.0.1:9101
+    #[arg(long)]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Path of the control socket (default: /run/cpu-on-off.sock)
+    #[arg(long)]
+    pub control_socket: Option<PathBuf>,
+
+    /// InfluxDB endpoint to export metrics to, e.g. udp://localhost:8089 or http://localhost:8086/write?db=telegraf
+    #[arg(long)]
+    pub influx_url: Option<String>,
+
+    /// Host-side companion asked to hot-unplug the offline vCPUs and plug more, e.g. http://192.168.122.1:8700/vcpus
+    #[arg(long)]
+    pub vcpu_companion: Option<String>,
+
+    /// Kubernetes API server, e.g. http://127.0.0.1:8001 of kubectl proxy, to publish the online CPUs on the node object
+    #[arg(long)]
+    pub kube_api: Option<String>,
+
+    /// Name of the Kubernetes node (default: the host name)
+    #[arg(long)]
+    pub kube_node: Option<String>,
+
+    /// Never leave a core with only some of its thread siblings online
+    #[arg(long)]
+    pub full_cores_only: bool,
+
+    /// CPU that always stays online; any other value lets the boot CPU be offlined if the kernel supports it (default: the boot CPU, usually 0)
+    #[arg(long)]
+    pub housekeeping_cpu: Option<usize>,
+
+    /// Deconfigure the CPUs offlined on s390x, putting them in standby
+    #[arg(long)]
+    pub deconfigure: bool,
+
+    /// Prefer offlining cores whose recently active tasks have already migrated away
+    #[arg(long)]
+    pub stickiness: bool,
+
+    /// Which CPUs to offline first: highest-id, or drain the remote-socket or least-loaded-socket first (default: highest-id)
+    #[arg(long, value_enum)]
+    pub offline_order: Option<OfflineOrder>,
+
+    /// What to do when the CPUs to offline are the only ones some tasks may run on: warn, skip, or repin (default: warn)
+    #[arg(long, value_enum)]
+    pub pinned_tasks: Option<PinnedTaskPolicy>,
+
+    /// Checkpoint of the kubelet's CPU manager whose exclusively assigned CPUs are never offlined (default: /var/lib/kubelet/cpu_manager_state)
+    #[arg(long, value_name = "PATH")]
+    pub kubelet_state: Option<PathBuf>,
+
+    /// Before offlining CPUs, retarget the IRQs that may only be delivered to them to the CPUs that stay online
+    #[arg(long)]
+    pub migrate_irqs: bool,
+
+    /// Restore the original affinity of the IRQs moved by --migrate-irqs once their CPUs are back online
+    #[arg(long)]
+    pub restore_irqs: bool,
+
+    /// Manage the CPUs isolated with isolcpus or nohz_full like any other, instead of leaving them alone
+    #[arg(long)]
+    pub manage_isolated: bool,
+
+    /// Manage the CPUs outside the cpuset of the daemon's own cgroup too, e.g. inside a container
+    #[arg(long)]
+    pub ignore_cpuset: bool,
+
+    /// Also restrict management to the CPUs allowed by the cpuset of this cgroup, e.g. system.slice
+    #[arg(long, value_name = "CGROUP")]
+    pub restrict_to_cgroup: Option<String>,
+
+    /// How CPUs are taken away: hotplug them, remove them from the cpuset of --backend-cgroup, cap their frequency with cpufreq, or tiered: cap first, then allow deeper idle states, then hotplug (default: hotplug)
+    #[arg(long, value_enum)]
+    pub backend: Option<BackendName>,
+
+    /// The cgroup whose cpuset.cpus the cpuset backend narrows and widens (default: system.slice)
+    #[arg(long, value_name = "CGROUP")]
+    pub backend_cgroup: Option<String>,
+
+    /// Deepest idle state the managed CPUs may enter, e.g. 2 for state2; deeper ones are disabled
+    #[arg(long, value_name = "STATE")]
+    pub max_cstate: Option<usize>,
+
+    /// Whether to leave the idle states alone (static) or allow deeper ones on low load and disable them on high load (load) (default: static)
+    #[arg(long, value_enum)]
+    pub idle_policy: Option<IdlePolicy>,
+
+    /// Offline no CPUs and disable the idle states slower to wake up from than this while the CPU latency target in /dev/cpu_dma_latency is at most this many microseconds
+    #[arg(long, value_name = "US")]
+    pub latency_us: Option<u32>,
+
+    /// Offline cores and online none while the hottest CPU package read from hwmon is above this many degrees Celsius
+    #[arg(long, value_name = "CELSIUS")]
+    pub thermal_limit: Option<u32>,
+
+    /// Offline cores while the package power read from RAPL is above this many watts, and online more only with headroom below it
+    #[arg(long, value_name = "WATTS")]
+    pub power_budget_watts: Option<f64>,
+
+    /// Steal time in percent above which no CPUs are onlined for load, since more vCPUs would not help (disabled by default)
+    #[arg(long, value_name = "PERCENT")]
+    pub steal_limit: Option<f64>,
+
+    /// Online CPUs for load whenever woken tasks wait longer than this many microseconds on average before running, even below the upper threshold; needs --metric ebpf (disabled by default)
+    #[arg(long, value_name = "USEC")]
+    pub wakeup_latency_us: Option<u32>,
+
+    /// How to coordinate turbo with hotplug: leave it alone, online every core before enabling turbo (cores-first), or enable turbo before onlining any core (turbo-first) (default: leave)
+    #[arg(long, value_enum)]
+    pub boost_policy: Option<BoostPolicy>,
+
+    /// Set the energy performance preference of the online CPUs to power and lower the intel_pstate performance limit on low load, and bias them towards performance again on high load
+    #[arg(long)]
+    pub tune_pstate: bool,
+
+    /// The intel_pstate max_perf_pct set on low load with --tune-pstate (default: 60)
+    #[arg(long, value_name = "PCT")]
+    pub power_max_perf_pct: Option<u32>,
+
+    /// Which CPU to hotplug next: highest-id, least-loaded, most-loaded-first-online, topology-packing, or round-robin (default: the profile's)
+    #[arg(long, value_enum)]
+    pub strategy: Option<StrategyName>,
+
+    /// Whether each decision covers a single thread, a whole core, or a whole socket (default: core)
+    #[arg(long, value_enum)]
+    pub granularity: Option<Granularity>,
+
+    /// When scaling down, offline the second thread of every core before removing whole cores
+    #[arg(long)]
+    pub prefer_no_smt: bool,
+
+    /// Never online a second thread of a core, even on high load, if the CPU reports SMT as vulnerable to L1TF, MDS, TAA, or MMIO stale data
+    #[arg(long)]
+    pub no_smt_if_vulnerable: bool,
+
+    /// Which class of CPUs to vacate first on big.LITTLE and other heterogeneous systems: big or little (default: none)
+    #[arg(long, value_enum)]
+    pub prefer_offline: Option<CapacityClass>,
+
+    /// Scale each socket against its own average load instead of the system-wide load
+    #[arg(long)]
+    pub per_socket: bool,
+
+    /// Move cores from an idle socket to a saturated one, even when the system-wide load needs no change
+    #[arg(long)]
+    pub rebalance_sockets: bool,
+
+    /// Use the power-saver profile while logind reports all sessions as idle
+    #[arg(long)]
+    pub logind_idle: bool,
+
+    /// Number of samples taken at startup to calibrate the noise floor (default: 0, disabled)
+    #[arg(long)]
+    pub calibration_samples: Option<usize>,
+
+    /// Minimum number of CPUs to keep online (default: 0, i.e. only the built-in minimum)
+    #[arg(long)]
+    pub min_cpus: Option<usize>,
+
+    /// Offline cores down to the minimum as soon as the lid closes or all displays blank
+    #[arg(long)]
+    pub consolidate_on_lid: bool,
+
+    /// CPUs to never online, offline, or count toward the load, e.g. 2,3,8-11
+    #[arg(long)]
+    pub exclude_cpus: Option<CpuList>,
+
+    /// Where sysfs is mounted, e.g. the host's sysfs in a container or a fake tree in tests (default: /sys)
+    #[arg(long)]
+    pub sysfs_root: Option<PathBuf>,
+
+    /// File the decision log is dumped to on panic or SIGABRT (default: /var/tmp/cpu-on-off-decisions.jsonl)
+    #[arg(long)]
+    pub crash_dump: Option<PathBuf>,
+
+    /// File each hotplug write is recorded in until verified, reconciled at startup (default: /run/cpu-on-off-hotplug.json)
+    #[arg(long)]
+    pub hotplug_journal: Option<PathBuf>,
+
+    /// Report readiness to systemd only once the first full control cycle has completed
+    #[arg(long)]
+    pub ready_after_first_cycle: bool,
+
+    /// File created once the first full control cycle has completed and removed on exit
+    #[arg(long)]
+    pub ready_file: Option<PathBuf>,
+
+    /// What to do with the CPUs on exit (default: restore)
+    #[arg(long, value_enum)]
+    pub on_exit: Option<ExitPolicy>,
+
+    /// Start with all CPUs online, or from the CPUs currently online (default: all)
+    #[arg(long, value_enum)]
+    pub start_from: Option<StartFrom>,
+
+    /// Log more detail; repeat for even more (-v: debug, -vv: trace)
+    #[arg(short = 'v', long, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Log less; repeat for even less (-q: warnings and errors, -qq: errors only)
+    #[arg(short = 'q', long, action = ArgAction::Count)]
+    pub quiet: u8,
+
+    /// Log output format (default: text)
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+
+    /// Log every cycle how far the cpuidle load of each CPU is from its /proc/stat utilization
+    #[arg(long)]
+    pub metric_validation: bool,
+
+    /// Leave the CPU time of niced tasks, e.g. background builds, out of the load
+    #[arg(long)]
+    pub exclude_nice: bool,
+
+    /// Tag each cycle's log lines with a trace ID and attach it to the decision metrics as an OpenMetrics exemplar
+    #[arg(long)]
+    pub exemplars: bool,
+}
+
+/// Client subcommands talking to a running daemon over the control socket.
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Print the state of the running daemon
+    Status {
+        /// Print the status document on a single line, for scripts
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the package energy of the running daemon and the estimated savings of the offlining
+    Energy,
+    /// Stop making decisions and online all CPUs until resumed
+    Pause,
+    /// Resume making decisions
+    Resume,
+    /// Change the thresholds, hysteresis, or cooldown of the running daemon
+    Set {
+        /// Upper load threshold percentage
+        #[arg(long)]
+        upper: Option<u8>,
+        /// Lower load threshold percentage
+        #[arg(long)]
+        lower: Option<u8>,
+        /// Consecutive samples beyond a threshold required before acting
+        #[arg(long)]
+        hysteresis: Option<u32>,
+        /// Minimum time in seconds between two hotplug actions
+        #[arg(long)]
+        cooldown_secs: Option<f64>,
+    },
+    /// Print the log filter of the running daemon, or replace it
+    LogFilter {
+        /// New filter, e.g. `debug` or `info,cpu_on_off_rust::topology=trace`
+        filter: Option<String>,
+    },
+    /// Print the daemon's recent events as JSON lines
+    Events {
+        /// Keep streaming new events until interrupted
+        #[arg(short = 'f', long)]
+        follow: bool,
+    },
+    /// Run a command while the daemon's CPU configuration is frozen, like systemd-inhibit
+    Inhibit {
+        /// Name of the holder reported by the daemon
+        #[arg(long, default_value = "cpu-on-off-rust inhibit")]
+        who: String,
+        /// Reason reported by the daemon
+        #[arg(long, default_value = "unknown")]
+        why: String,
+        /// The command to run, followed by its arguments
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+}
+
+/// Contents of the configuration file. All keys are optional.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub config_url: Option<String>,
+    pub config_key: Option<String>,
+    pub profile: Option<ProfileName>,
+    pub workload: Option<WorkloadMode>,
+    pub upper_threshold: Option<u8>,
+    pub lower_threshold: Option<u8>,
+    pub hysteresis: Option<u32>,
+    pub cooldown_secs: Option<f64>,
+    pub spike_filter_secs: Option<f64>,
+    pub mode: Option<ScalingMode>,
+    pub target_util: Option<u8>,
+    pub kp: Option<f64>,
+    pub ki: Option<f64>,
+    pub kd: Option<f64>,
+    pub panic_threshold: Option<u8>,
+    pub panic_on: Option<PanicTrigger>,
+    pub max_transitions_per_minute: Option<u32>,
+    pub blacklist_after: Option<u32>,
+    pub blacklist_secs: Option<f64>,
+    pub rescan_secs: Option<f64>,
+    pub uevents: Option<bool>,
+    pub interval: Option<f64>,
+    pub metric: Option<MetricKind>,
+    pub watch_cgroup: Option<String>,
+    pub window: Option<usize>,
+    pub window_aggregation: Option<WindowAggregation>,
+    pub metric_validation: Option<bool>,
+    pub exclude_nice: Option<bool>,
+    pub metrics_addr: Option<SocketAddr>,
+    pub control_socket: Option<PathBuf>,
+    pub influx_url: Option<String>,
+    pub vcpu_companion: Option<String>,
+    pub kube_api: Option<String>,
+    pub kube_node: Option<String>,
+    /// Outputs for the per-cycle records and events, one `[[sinks]]` table each.
+    pub sinks: Option<Vec<SinkConfig>>,
+    pub full_cores_only: Option<bool>,
+    pub housekeeping_cpu: Option<usize>,
+    pub deconfigure: Option<bool>,
+    pub stickiness: Option<bool>,
+    pub offline_order: Option<OfflineOrder>,
+    pub strategy: Option<StrategyName>,
+    pub pinned_tasks: Option<PinnedTaskPolicy>,
+    pub kubelet_state: Option<PathBuf>,
+    pub migrate_irqs: Option<bool>,
+    pub restore_irqs: Option<bool>,
+    pub manage_isolated: Option<bool>,
+    pub ignore_cpuset: Option<bool>,
+    pub restrict_to_cgroup: Option<String>,
+    pub backend: Option<BackendName>,
+    pub backend_cgroup: Option<String>,
+    pub max_cstate: Option<usize>,
+    pub idle_policy: Option<IdlePolicy>,
+    pub latency_us: Option<u32>,
+    pub thermal_limit: Option<u32>,
+    pub power_budget_watts: Option<f64>,
+    pub steal_limit: Option<f64>,
+    pub wakeup_latency_us: Option<u32>,
+    pub boost_policy: Option<BoostPolicy>,
+    pub tune_pstate: Option<bool>,
+    pub power_max_perf_pct: Option<u32>,
+    pub granularity: Option<Granularity>,
+    pub prefer_no_smt: Option<bool>,
+    pub no_smt_if_vulnerable: Option<bool>,
+    pub prefer_offline: Option<CapacityClass>,
+    pub per_socket: Option<bool>,
+    pub rebalance_sockets: Option<bool>,
+    pub logind_idle: Option<bool>,
+    pub calibration_samples: Option<usize>,
+    pub min_cpus: Option<usize>,
+    pub consolidate_on_lid: Option<bool>,
+    pub exclude_cpus: Option<CpuList>,
+    pub sysfs_root: Option<PathBuf>,
+    pub crash_dump: Option<PathBuf>,
+    pub hotplug_journal: Option<PathBuf>,
+    pub ready_after_first_cycle: Option<bool>,
+    pub ready_file: Option<PathBuf>,
+    pub on_exit: Option<ExitPolicy>,
+    pub start_from: Option<StartFrom>,
+    pub log_level: Option<LogLevel>,
+    pub log_format: Option<LogFormat>,
+    pub exemplars: Option<bool>,
+}
+
+impl FileConfig {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        Self::parse(&content, &path.display().to_string())
+    }
+
+    /// Parses the TOML `content` of the configuration file found at `source`.
+    pub fn parse(content: &str, source: &str) -> io::Result<Self> {
+        toml::from_str(content)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", source, e)))
+    }
+}
+
+/// Settings in effect after merging the command line over the configuration file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Settings {
+    pub profile: Profile,
+    /// Overrides to reapply when the profile is switched at runtime.
+    pub overrides: ProfileOverrides,
+    pub workload: Option<WorkloadMode>,
+    /// Shortest time the load must stay above the upper threshold before CPUs are onlined.
+    pub spike_filter: Duration,
+    pub mode: ScalingMode,
+    /// Utilization percentage the online CPUs are sized for in target mode.
+    pub target_util: u8,
+    /// Proportional, integral, and derivative gains in PID mode.
+    pub kp: f64,
+    pub ki: f64,
+    pub kd: f64,
+    /// Load percentage above which every managed CPU is onlined at once; `None` disables it.
+    pub panic_threshold: Option<u8>,
+    pub panic_on: PanicTrigger,
+    /// Most CPU transitions, online and offline together, within any minute; `None` is unlimited.
+    pub max_transitions_per_minute: Option<u32>,
+    /// Failed hotplug writes in a row after which a CPU is blacklisted; 0 never blacklists.
+    pub blacklist_after: u32,
+    /// Time a CPU stays blacklisted.
+    pub blacklist_for: Duration,
+    /// Time between two scans for hot-added or removed CPUs; `None` never rescans.
+    pub rescan_interval: Option<Duration>,
+    /// Whether the kernel's CPU uevents are handled as they arrive.
+    pub uevents: bool,
+    pub interval: Duration,
+    pub metric: MetricKind,
+    /// The cgroup, relative to the cgroup root, whose CPU usage is the load with the cgroup metric.
+    pub watch_cgroup: Option<String>,
+    /// Number of samples the load is smoothed over; 1 disables smoothing.
+    pub window: usize,
+    pub window_aggregation: WindowAggregation,
+    pub metric_validation: bool,
+    /// Whether the time of niced tasks is taken out of each CPU's load, see
+    /// `LoadSample::exclude_nice`.
+    pub exclude_nice: bool,
+    pub metrics_addr: Option<SocketAddr>,
+    pub control_socket: PathBuf,
+    pub influx_url: Option<InfluxUrl>,
+    /// The host-side companion asked to hot-unplug and plug vCPUs, see `vcpu::VcpuCompanion`.
+    pub vcpu_companion: Option<String>,
+    /// The Kubernetes API server the node object is patched through, see `kube::KubeNode`.
+    pub kube_api: Option<String>,
+    /// The name of the Kubernetes node; `None` uses the host name.
+    pub kube_node: Option<String>,
+    pub sinks: Vec<SinkConfig>,
+    pub full_cores_only: bool,
+    /// The CPU that always stays online; `None` keeps the boot CPU, see `topology::boot_cpu`.
+    pub housekeeping_cpu: Option<usize>,
+    /// Whether the CPUs offlined are also deconfigured where they have a `configure` file.
+    pub deconfigure: bool,
+    pub stickiness: bool,
+    pub offline_order: OfflineOrder,
+    pub pinned_tasks: PinnedTaskPolicy,
+    /// The kubelet's CPU manager checkpoint, see `kubelet::exclusive_cpus`.
+    pub kubelet_state: PathBuf,
+    /// Retargets the IRQs of the CPUs to offline beforehand, see `IrqMigrator`.
+    pub migrate_irqs: bool,
+    /// Restores the affinity of the IRQs moved away once their CPUs are back online.
+    pub restore_irqs: bool,
+    /// Manages the CPUs listed in `isolated` and `nohz_full` instead of excluding them.
+    pub manage_isolated: bool,
+    /// Manages the CPUs outside the daemon's cgroup cpuset, see `cpuset::allowed_cpus`.
+    pub ignore_cpuset: bool,
+    /// The cgroup whose cpuset further restricts the managed CPUs, relative to the cgroup root.
+    pub restrict_to_cgroup: Option<String>,
+    /// Takes the CPUs away through hotplug or a cgroup cpuset, see `ActuationBackend`.
+    pub backend: BackendName,
+    /// The cgroup narrowed by the cpuset backend, relative to the cgroup root.
+    pub backend_cgroup: String,
+    /// The deepest idle state the managed CPUs may enter, see `IdleLimit`.
+    pub max_cstate: Option<usize>,
+    /// Whether the idle states follow the load; always with the tiered backend.
+    pub idle_policy: IdlePolicy,
+    /// The CPU latency target at or below which latency-sensitive mode is active, see `LatencyGuard`.
+    pub latency_us: Option<u32>,
+    /// The package temperature in degrees Celsius above which cores are offlined, see `ThermalGuard`.
+    pub thermal_limit: Option<u32>,
+    /// The package power in watts above which cores are offlined, see `EnergyMeter`.
+    pub power_budget_watts: Option<f64>,
+    /// The steal time in percent above which no CPUs are onlined for load.
+    pub steal_limit: Option<f64>,
+    /// The average wakeup latency in microseconds above which the load counts as high.
+    pub wakeup_latency_us: Option<u32>,
+    /// How turbo is coordinated with hotplug, see `Boost`.
+    pub boost_policy: BoostPolicy,
+    /// Tunes the EPP and performance limit of the online CPUs with the load, see `PstateTuning`.
+    pub tune_pstate: bool,
+    /// The `max_perf_pct` of the power regime of `tune_pstate`.
+    pub power_max_perf_pct: u32,
+    pub granularity: Granularity,
+    pub prefer_no_smt: bool,
+    /// Keeps the SMT siblings offline if the CPU reports SMT as vulnerable, see
+    /// `SystemTopology::no_smt`.
+    pub no_smt_if_vulnerable: bool,
+    pub prefer_offline: Option<CapacityClass>,
+    pub per_socket: bool,
+    pub rebalance_sockets: bool,
+    pub logind_idle: bool,
+    pub calibration_samples: usize,
+    pub min_cpus: usize,
+    pub consolidate_on_lid: bool,
+    pub exclude_cpus: CpuList,
+    pub sysfs_root: PathBuf,
+    pub crash_dump: PathBuf,
+    pub hotplug_journal: PathBuf,
+    pub ready_after_first_cycle: bool,
+    pub ready_file: Option<PathBuf>,
+    pub on_exit: ExitPolicy,
+    pub start_from: StartFrom,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    pub exemplars: bool,
+}
+
+impl Settings {
+    /// Resolves the settings from the command-line arguments and the configuration file they point to.
+    ///
+    /// This function performs the following steps:
+    /// 1. Loads the configuration file given by `--config`, if any.
+    /// 2. Picks each option from the command line, falling back to the file and then to the default.
+    ///    The log level from the file is made more or less verbose by `-v` and `-q`.
+    /// 3. Selects the profile, either by name or from a pinned workload class, and applies any
+    ///    threshold, hysteresis, cooldown, and strategy overrides on top of it.
+    /// 4. Validates the resulting thresholds, hysteresis, cooldown, spike filter, interval, panic threshold, transition limit, blacklist time, rescan interval, IRQ options, backend, idle state limit, and InfluxDB URL. In
+    ///    automatic workload mode, the thresholds are validated against every profile it may select.
+    ///
+    /// # Returns
+    /// * `io::Result<Settings>` - The resolved settings, or an error if the file cannot be read or
+    ///   parsed, or the values are inconsistent.
+    pub fn resolve(args: &Args) -> io::Result<Self> {
+        Self::merge(args, Self::local_file(args)?)
+    }
+
+    /// Resolves the settings from the command-line arguments and the signed configuration file at
+    /// `--config-url`, if one is given on the command line or in the local configuration file.
+    ///
+    /// This function performs the following steps:
+    /// 1. Loads the local configuration file to find the URL and public key, unless given as flags.
+    /// 2. Downloads the remote file and its signature, and verifies the signature with the key.
+    /// 3. Resolves the settings like `resolve`, with the remote file in place of the local one.
+    ///
+    /// # Returns
+    /// * `io::Result<Option<Settings>>` - The resolved settings, `None` if no URL is configured, or
+    ///   an error if the file cannot be fetched, is not correctly signed, or is invalid.
+    pub async fn fetch(args: &Args) -> io::Result<Option<Self>> {
+        let local = Self::local_file(args)?;
+        let Some(url) = args.config_url.clone().or(local.config_url) else {
+            return Ok(None);
+        };
+        let key = args
+            .config_key
+            .clone()
+            .or(local.config_key)
+            .ok_or_else(|| invalid("config_url requires config_key".to_string()))?;
+        let key = remote_config::parse_public_key(&key).map_err(invalid)?;
+        let content = remote_config::fetch(&url, &key).await?;
+        let remote = FileConfig::parse(&content, &url)?;
+        if remote.config_url.is_some() || remote.config_key.is_some() {
+            return Err(invalid(format!(
+                "{}: config_url and config_key can only be set locally",
+                url
+            )));
+        }
+        info!("Fetched the configuration from {}", url);
+        Self::merge(args, remote).map(Some)
+    }
+
+    fn local_file(args: &Args) -> io::Result<FileConfig> {
+        match &args.config {
+            Some(path) => FileConfig::load(path),
+            None => Ok(FileConfig::default()),
+        }
+    }
+
+    /// Picks each setting from the command line, falling back to `file` and then to the default.
+    fn merge(args: &Args, file: FileConfig) -> io::Result<Self> {
+        let overrides = ProfileOverrides {
+            strategy: args.strategy.or(file.strategy),
+            ..ProfileOverrides::new(
+                args.upper_threshold.or(file.upper_threshold),
+                args.lower_threshold.or(file.lower_threshold),
+                args.hysteresis.or(file.hysteresis),
+                args.cooldown_secs.or(file.cooldown_secs),
+            )
+            .map_err(invalid)?
+        };
+
+        let profile_name = args.profile.or(file.profile);
+        let workload = args.workload.or(file.workload);
+        let profile_name = match (profile_name, workload) {
+            (Some(_), Some(_)) => {
+                return Err(invalid(
+                    "profile and workload are mutually exclusive".to_string(),
+                ))
+            }
+            (_, Some(mode)) => mode
+                .pinned()
+                .map_or(ProfileName::Balanced, WorkloadClass::profile_name),
+            (name, None) => name.unwrap_or(ProfileName::Balanced),
+        };
+        // In automatic mode, the overrides must fit every profile that may be selected.
+        overrides
+            .validate(&workload::candidate_profiles(workload, profile_name))
+            .map_err(invalid)?;
+        let profile = overrides.apply(profile_name);
+
+        let interval = args.interval.or(file.interval).unwrap_or(1.0);
+
+        if !(interval > 0.0 && interval.is_finite()) {
+            return Err(invalid(format!(
+                "interval must be a positive number of seconds, got {}",
+                interval
+            )));
+        }
+
+        let spike_filter = args
+            .spike_filter_secs
+            .or(file.spike_filter_secs)
+            .unwrap_or(0.0);
+        if !(spike_filter >= 0.0 && spike_filter.is_finite()) {
+            return Err(invalid(format!(
+                "spike_filter_secs must be a non-negative number of seconds, got {}",
+                spike_filter
+            )));
+        }
+
+        let migrate_irqs = args.migrate_irqs || file.migrate_irqs.unwrap_or(false);
+        let restore_irqs = args.restore_irqs || file.restore_irqs.unwrap_or(false);
+        if restore_irqs && !migrate_irqs {
+            return Err(invalid("restore_irqs requires migrate_irqs".to_string()));
+        }
+
+        let window = args.window.or(file.window).unwrap_or(1);
+        if window == 0 {
+            return Err(invalid("window must be at least 1 sample".to_string()));
+        }
+
+        let full_cores_only = args.full_cores_only || file.full_cores_only.unwrap_or(false);
+        let granularity = args.granularity.or(file.granularity).unwrap_or_default();
+        if full_cores_only && granularity == Granularity::Thread {
+            return Err(invalid(
+                "full_cores_only cannot be combined with thread granularity".to_string(),
+            ));
+        }
+        let prefer_no_smt = args.prefer_no_smt || file.prefer_no_smt.unwrap_or(false);
+        if prefer_no_smt && full_cores_only {
+            return Err(invalid(
+                "prefer_no_smt cannot be combined with full_cores_only".to_string(),
+            ));
+        }
+        if prefer_no_smt && granularity == Granularity::Socket {
+            return Err(invalid(
+                "prefer_no_smt cannot be combined with socket granularity".to_string(),
+            ));
+        }
+        let no_smt_if_vulnerable =
+            args.no_smt_if_vulnerable || file.no_smt_if_vulnerable.unwrap_or(false);
+        if no_smt_if_vulnerable && full_cores_only {
+            return Err(invalid(
+                "no_smt_if_vulnerable cannot be combined with full_cores_only".to_string(),
+            ));
+        }
+        if no_smt_if_vulnerable && granularity == Granularity::Socket {
+            return Err(invalid(
+                "no_smt_if_vulnerable cannot be combined with socket granularity".to_string(),
+            ));
+        }
+        let per_socket = args.per_socket || file.per_socket.unwrap_or(false);
+        let rebalance_sockets = args.rebalance_sockets || file.rebalance_sockets.unwrap_or(false);
+        if rebalance_sockets && per_socket {
+            return Err(invalid(
+                "rebalance_sockets cannot be combined with per_socket".to_string(),
+            ));
+        }
+        let backend = args.backend.or(file.backend).unwrap_or_default();
+        if backend == BackendName::Cpufreq && (per_socket || rebalance_sockets) {
+            return Err(invalid(
+                "per_socket and rebalance_sockets cannot be combined with the cpufreq backend"
+                    .to_string(),
+            ));
+        }
+        if backend == BackendName::Tiered && (per_socket || rebalance_sockets) {
+            return Err(invalid(
+                "per_socket and rebalance_sockets cannot be combined with the tiered backend"
+                    .to_string(),
+            ));
+        }
+        let idle_policy = args.idle_policy.or(file.idle_policy).unwrap_or_default();
+        if idle_policy == IdlePolicy::Load && (per_socket || rebalance_sockets) {
+            return Err(invalid(
+                "per_socket and rebalance_sockets cannot be combined with the load idle policy"
+                    .to_string(),
+            ));
+        }
+        let boost_policy = args.boost_policy.or(file.boost_policy).unwrap_or_default();
+        if backend == BackendName::Cpufreq && boost_policy != BoostPolicy::Leave {
+            return Err(invalid(
+                "boost_policy cannot be combined with the cpufreq backend".to_string(),
+            ));
+        }
+        if boost_policy != BoostPolicy::Leave && (per_socket || rebalance_sockets) {
+            return Err(invalid(
+                "boost_policy cannot be combined with per_socket or rebalance_sockets".to_string(),
+            ));
+        }
+        let max_cstate = args.max_cstate.or(file.max_cstate);
+        if max_cstate.is_some_and(|state| state < SHALLOWEST_STATE) {
+            return Err(invalid(format!(
+                "max_cstate must be at least {}",
+                SHALLOWEST_STATE
+            )));
+        }
+        let thermal_limit = args.thermal_limit.or(file.thermal_limit);
+        if let Some(thermal_limit) = thermal_limit {
+            if !(1..=150).contains(&thermal_limit) {
+                return Err(invalid(format!(
+                    "thermal_limit must be between 1 and 150 degrees Celsius, got {}",
+                    thermal_limit
+                )));
+            }
+        }
+        let power_budget_watts = args.power_budget_watts.or(file.power_budget_watts);
+        if let Some(budget) = power_budget_watts {
+            if !(budget > 0.0 && budget.is_finite()) {
+                return Err(invalid(format!(
+                    "power_budget_watts must be a positive number of watts, got {}",
+                    budget
+                )));
+            }
+        }
+        let steal_limit = args.steal_limit.or(file.steal_limit);
+        if let Some(limit) = steal_limit {
+            if !(0.0..=100.0).contains(&limit) {
+                return Err(invalid(format!(
+                    "steal_limit must be between 0 and 100 percent, got {}",
+                    limit
+                )));
+            }
+        }
+        let wakeup_latency_us = args.wakeup_latency_us.or(file.wakeup_latency_us);
+        if wakeup_latency_us == Some(0) {
+            return Err(invalid("wakeup_latency_us must be at least 1".to_string()));
+        }
+        let tune_pstate = args.tune_pstate || file.tune_pstate.unwrap_or(false);
+        if tune_pstate && (per_socket || rebalance_sockets) {
+            return Err(invalid(
+                "tune_pstate cannot be combined with per_socket or rebalance_sockets".to_string(),
+            ));
+        }
+        let power_max_perf_pct = args
+            .power_max_perf_pct
+            .or(file.power_max_perf_pct)
+            .unwrap_or(pstate::DEFAULT_POWER_MAX_PERF_PCT);
+        if !(1..=100).contains(&power_max_perf_pct) {
+            return Err(invalid(format!(
+                "power_max_perf_pct must be between 1 and 100, got {}",
+                power_max_perf_pct
+            )));
+        }
+        let mode = args.mode.or(file.mode).unwrap_or_default();
+        let target_util = args
+            .target_util
+            .or(file.target_util)
+            .unwrap_or(target::DEFAULT_TARGET_UTIL);
+        if !(1..=100).contains(&target_util) {
+            return Err(invalid(format!(
+                "target_util must be between 1 and 100, got {}",
+                target_util
+            )));
+        }
+        if mode != ScalingMode::Threshold && per_socket {
+            let name = if mode == ScalingMode::Target {
+                "target"
+            } else {
+                "pid"
+            };
+            return Err(invalid(format!(
+                "{} mode cannot be combined with per_socket",
+                name
+            )));
+        }
+        let kp = args.kp.or(file.kp).unwrap_or(target::DEFAULT_KP);
+        let ki = args.ki.or(file.ki).unwrap_or(target::DEFAULT_KI);
+        let kd = args.kd.or(file.kd).unwrap_or(target::DEFAULT_KD);
+        for (name, gain) in [("kp", kp), ("ki", ki), ("kd", kd)] {
+            if !(gain >= 0.0 && gain.is_finite()) {
+                return Err(invalid(format!(
+                    "{} must be a non-negative number, got {}",
+                    name, gain
+                )));
+            }
+        }
+
+        let panic_threshold = args.panic_threshold.or(file.panic_threshold);
+        if let Some(panic_threshold) = panic_threshold {
+            if !(1..=100).contains(&panic_threshold) {
+                return Err(invalid(format!(
+                    "panic_threshold must be between 1 and 100, got {}",
+                    panic_threshold
+                )));
+            }
+        }
+
+        let max_transitions_per_minute = args
+            .max_transitions_per_minute
+            .or(file.max_transitions_per_minute);
+        if max_transitions_per_minute == Some(0) {
+            return Err(invalid(
+                "max_transitions_per_minute must be at least 1".to_string(),
+            ));
+        }
+
+        let blacklist_secs = args
+            .blacklist_secs
+            .or(file.blacklist_secs)
+            .unwrap_or(topology::DEFAULT_BLACKLIST_FOR.as_secs_f64());
+        if !(blacklist_secs > 0.0 && blacklist_secs.is_finite()) {
+            return Err(invalid(format!(
+                "blacklist_secs must be a positive number of seconds, got {}",
+                blacklist_secs
+            )));
+        }
+
+        let rescan_secs = args.rescan_secs.or(file.rescan_secs).unwrap_or(60.0);
+        if !(rescan_secs >= 0.0 && rescan_secs.is_finite()) {
+            return Err(invalid(format!(
+                "rescan_secs must be a non-negative number of seconds, got {}",
+                rescan_secs
+            )));
+        }
+
+        let influx_url = match args.influx_url.clone().or(file.influx_url) {
+            Some(url) => Some(url.parse().map_err(invalid)?),
+            None => None,
+        };
+        let vcpu_companion = args.vcpu_companion.clone().or(file.vcpu_companion);
+        if let Some(url) = &vcpu_companion {
+            http::split_url(url).map_err(|e| invalid(format!("vcpu_companion: {}", e)))?;
+        }
+        let kube_api = args.kube_api.clone().or(file.kube_api);
+        if let Some(url) = &kube_api {
+            http::split_url(url).map_err(|e| invalid(format!("kube_api: {}", e)))?;
+        }
+        let sinks = file.sinks.unwrap_or_default();
+        for sink in &sinks {
+            sink.validate()
+                .map_err(|e| invalid(format!("{} sink: {}", sink.name(), e)))?;
+        }
+        let watch_cgroup = args.watch_cgroup.clone().or(file.watch_cgroup);
+        let metric = args.metric.or(file.metric).unwrap_or(match watch_cgroup {
+            Some(_) => MetricKind::Cgroup,
+            None => MetricKind::Cpuidle,
+        });
+        match (metric, &watch_cgroup) {
+            (MetricKind::Cgroup, None) => {
+                return Err(invalid(
+                    "the cgroup metric requires watch_cgroup".to_string(),
+                ));
+            }
+            (MetricKind::Cgroup, Some(_)) | (_, None) => {}
+            (metric, Some(_)) => {
+                return Err(invalid(format!(
+                    "watch_cgroup requires the cgroup metric, got {:?}",
+                    metric
+                )));
+            }
+        }
+        let exclude_nice = args.exclude_nice || file.exclude_nice.unwrap_or(false);
+        if exclude_nice && !matches!(metric, MetricKind::Cpuidle | MetricKind::ProcStat) {
+            return Err(invalid(format!(
+                "exclude_nice requires the cpuidle or proc-stat metric, got {:?}",
+                metric
+            )));
+        }
+
+        Ok(Settings {
+            profile,
+            overrides,
+            workload,
+            spike_filter: Duration::from_secs_f64(spike_filter),
+            mode,
+            target_util,
+            kp,
+            ki,
+            kd,
+            panic_threshold,
+            panic_on: args.panic_on.or(file.panic_on).unwrap_or_default(),
+            max_transitions_per_minute,
+            blacklist_after: args
+                .blacklist_after
+                .or(file.blacklist_after)
+                .unwrap_or(topology::DEFAULT_BLACKLIST_AFTER),
+            blacklist_for: Duration::from_secs_f64(blacklist_secs),
+            rescan_interval: (rescan_secs > 0.0).then(|| Duration::from_secs_f64(rescan_secs)),
+            uevents: args.uevents || file.uevents.unwrap_or(false),
+            interval: Duration::from_secs_f64(interval),
+            metric,
+            watch_cgroup,
+            window,
+            window_aggregation: args
+                .window_aggregation
+                .or(file.window_aggregation)
+                .unwrap_or_default(),
+            metric_validation: args.metric_validation || file.metric_validation.unwrap_or(false),
+            exclude_nice,
+            metrics_addr: args.metrics_addr.or(file.metrics_addr),
+            control_socket: args
+                .control_socket
+                .clone()
+                .or(file.control_socket)
+                .unwrap_or_else(|| PathBuf::from(control::DEFAULT_SOCKET)),
+            influx_url,
+            vcpu_companion,
+            kube_api,
+            kube_node: args.kube_node.clone().or(file.kube_node),
+            sinks,
+            full_cores_only,
+            housekeeping_cpu: args.housekeeping_cpu.or(file.housekeeping_cpu),
+            deconfigure: args.deconfigure || file.deconfigure.unwrap_or(false),
+            stickiness: args.stickiness || file.stickiness.unwrap_or(false),
+            offline_order: args
+                .offline_order
+                .or(file.offline_order)
+                .unwrap_or_default(),
+            pinned_tasks: args.pinned_tasks.or(file.pinned_tasks).unwrap_or_default(),
+            kubelet_state: args
+                .kubelet_state
+                .clone()
+                .or(file.kubelet_state)
+                .unwrap_or_else(|| PathBuf::from(kubelet::CPU_MANAGER_STATE)),
+            migrate_irqs,
+            restore_irqs,
+            manage_isolated: args.manage_isolated || file.manage_isolated.unwrap_or(false),
+            ignore_cpuset: args.ignore_cpuset || file.ignore_cpuset.unwrap_or(false),
+            restrict_to_cgroup: args.restrict_to_cgroup.clone().or(file.restrict_to_cgroup),
+            backend,
+            backend_cgroup: args
+                .backend_cgroup
+                .clone()
+                .or(file.backend_cgroup)
+                .unwrap_or_else(|| actuation::DEFAULT_CGROUP.to_string()),
+            max_cstate,
+            idle_policy,
+            latency_us: args.latency_us.or(file.latency_us),
+            thermal_limit,
+            power_budget_watts,
+            steal_limit,
+            wakeup_latency_us,
+            boost_policy,
+            tune_pstate,
+            power_max_perf_pct,
+            granularity,
+            prefer_no_smt,
+            no_smt_if_vulnerable,
+            prefer_offline: args.prefer_offline.or(file.prefer_offline),
+            per_socket,
+            rebalance_sockets,
+            logind_idle: args.logind_idle || file.logind_idle.unwrap_or(false),
+            calibration_samples: args
+                .calibration_samples
+                .or(file.calibration_samples)
+                .unwrap_or(0),
+            min_cpus: args.min_cpus.or(file.min_cpus).unwrap_or(0),
+            consolidate_on_lid: args.consolidate_on_lid || file.consolidate_on_lid.unwrap_or(false),
+            exclude_cpus: args
+                .exclude_cpus
+                .clone()
+                .or(file.exclude_cpus)
+                .unwrap_or_default(),
+            sysfs_root: args
+                .sysfs_root
+                .clone()
+                .or(file.sysfs_root)
+                .unwrap_or_else(|| PathBuf::from(topology::SYSFS_ROOT)),
+            crash_dump: args
+                .crash_dump
+                .clone()
+                .or(file.crash_dump)
+                .unwrap_or_else(|| PathBuf::from(decision_log::DEFAULT_CRASH_DUMP)),
+            hotplug_journal: args
+                .hotplug_journal
+                .clone()
+                .or(file.hotplug_journal)
+                .unwrap_or_else(|| PathBuf::from(journal::DEFAULT_HOTPLUG_JOURNAL)),
+            ready_after_first_cycle: args.ready_after_first_cycle
+                || file.ready_after_first_cycle.unwrap_or(false),
+            ready_file: args.ready_file.clone().or(file.ready_file),
+            on_exit: args.on_exit.or(file.on_exit).unwrap_or_default(),
+            start_from: args.start_from.or(file.start_from).unwrap_or_default(),
+            log_level: file
+                .log_level
+                .unwrap_or_default()
+                .adjust(args.verbose, args.quiet),
+            log_format: args.log_format.or(file.log_format).unwrap_or_default(),
+            exemplars: args.exemplars || file.exemplars.unwrap_or(false),
+        })
+    }
+
+    /// Returns the selection strategy of the profile, which is `--strategy` if given.
+    pub fn strategy(&self) -> StrategyName {
+        self.profile.strategy.unwrap_or_default()
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
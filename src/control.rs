@@ -0,0 +1,623 @@
+//! Unix-domain control socket.
+//!
+//! Clients send one JSON request per line and receive one JSON response per line, e.g.
+//! `{"command":"set_schedule","plan":[{"from":1760536800,"min_cpus":8}]}`.
+//!
+//! The `events` command turns the connection into a stream of `Event` lines instead. Inhibitors
+//! taken with the `inhibit` command are held until the connection closes.
+use crate::config::Settings;
+use crate::decision_log::DECISION_LOG;
+use crate::events::{Event, EventBus, EventKind};
+use crate::inhibit::InhibitorTable;
+use crate::lease::LeaseTable;
+use crate::logging;
+use crate::metrics::{Direction, METRICS};
+use crate::profile::ProfileOverrides;
+use crate::schedule::{PlanEntry, Schedule};
+use crate::status::{self, Constraints, DaemonStatus, ManagerStatus};
+use crate::workload::WorkloadStatus;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{broadcast, oneshot, watch, Notify};
+use tracing::{info, warn};
+
+pub static DEFAULT_SOCKET: &str = "/run/cpu-on-off.sock";
+
+/// A command carried out by the CPU manager on behalf of a control client.
+pub enum ManagerCommand {
+    /// Onlines or offlines a CPU by hand.
+    Hotplug { direction: Direction, cpu: usize },
+    /// Changes the thresholds, hysteresis, or cooldown of the running profile.
+    Tune(ProfileOverrides),
+    /// Applies settings re-read from the configuration file and re-scans the topology.
+    Reload(Box<Settings>),
+    /// Logs the topology, the per-CPU load, and the most recent decisions.
+    DumpStatus,
+    /// Reports the package energy and the estimated savings, see `EnergyReport`.
+    EnergyReport,
+}
+
+/// A `ManagerCommand` waiting for the CPU manager.
+pub struct ManagerRequest {
+    pub command: ManagerCommand,
+    /// Receives the response data, or the reason the command was refused.
+    pub reply: oneshot::Sender<Result<Option<Value>, String>>,
+}
+
+/// State shared between the control socket and the CPU manager.
+pub struct ControlState {
+    pub schedule: watch::Sender<Schedule>,
+    pub leases: Mutex<LeaseTable>,
+    /// While any is held, the CPU manager neither onlines nor offlines CPUs.
+    pub inhibitors: Mutex<InhibitorTable>,
+    pub events: EventBus,
+    /// Published by the CPU manager when workload classification is enabled.
+    pub workload: Mutex<Option<WorkloadStatus>>,
+    /// Published by the CPU manager after every cycle and command, for the `status` command.
+    pub manager: Mutex<Option<ManagerStatus>>,
+    /// While set, the CPU manager keeps all CPUs online and makes no decisions.
+    pub paused: watch::Sender<bool>,
+    /// Commands waiting for the CPU manager, which is woken by `requests_ready`.
+    pub requests: Mutex<Vec<ManagerRequest>>,
+    pub requests_ready: Notify,
+}
+
+impl Default for ControlState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ControlState {
+    pub fn new() -> Self {
+        ControlState {
+            schedule: watch::Sender::new(Schedule::default()),
+            leases: Mutex::new(LeaseTable::default()),
+            inhibitors: Mutex::new(InhibitorTable::default()),
+            events: EventBus::new(),
+            workload: Mutex::new(None),
+            manager: Mutex::new(None),
+            paused: watch::Sender::new(false),
+            requests: Mutex::new(Vec::new()),
+            requests_ready: Notify::new(),
+        }
+    }
+
+    /// Pauses or resumes the CPU manager. Returns whether the state changed.
+    pub fn set_paused(&self, paused: bool) -> bool {
+        let changed = self
+            .paused
+            .send_if_modified(|current| std::mem::replace(current, paused) != paused);
+        if changed {
+            info!("{}", if paused { "Pausing" } else { "Resuming" });
+        }
+        changed
+    }
+
+    /// Takes the commands waiting for the CPU manager.
+    pub fn take_requests(&self) -> Vec<ManagerRequest> {
+        std::mem::take(&mut *self.requests.lock().unwrap())
+    }
+
+    /// Queues a command for the CPU manager and waits for its outcome.
+    ///
+    /// # Returns
+    /// * `Result<Option<Value>, String>` - The response data, or the reason the command was refused.
+    pub async fn submit(&self, command: ManagerCommand) -> Result<Option<Value>, String> {
+        let (reply, outcome) = oneshot::channel();
+        self.requests
+            .lock()
+            .unwrap()
+            .push(ManagerRequest { command, reply });
+        self.requests_ready.notify_one();
+        outcome
+            .await
+            .unwrap_or_else(|_| Err("the CPU manager is not running".to_string()))
+    }
+
+    /// Submits a command for a control client and turns its outcome into a response.
+    async fn request(&self, command: ManagerCommand) -> Response {
+        match self.submit(command).await {
+            Ok(data) => Response::ok(data),
+            Err(message) => Response::error(message),
+        }
+    }
+
+    /// Expires stale leases and publishes an event for each of them.
+    pub fn expire_leases(&self, now: Instant) {
+        for lease in self.leases.lock().unwrap().expire(now) {
+            info!(
+                "Lease {} of {} for {} CPUs expired",
+                lease.id, lease.client, lease.min_cpus
+            );
+            self.events.emit(EventKind::LeaseExpired {
+                id: lease.id,
+                client: lease.client,
+            });
+        }
+    }
+
+    /// Takes an inhibitor for `who` and publishes an event. Returns its ID.
+    pub fn inhibit(&self, who: &str, why: &str) -> u64 {
+        let id = self.inhibitors.lock().unwrap().take(who, why);
+        info!("{} inhibits CPU changes: {}", who, why);
+        self.events.emit(EventKind::InhibitorTaken {
+            id,
+            who: who.to_string(),
+            why: why.to_string(),
+        });
+        id
+    }
+
+    /// Releases an inhibitor and publishes an event, if it is still held.
+    pub fn uninhibit(&self, id: u64) {
+        let Some(inhibitor) = self.inhibitors.lock().unwrap().release(id) else {
+            return;
+        };
+        info!("{} no longer inhibits CPU changes", inhibitor.who);
+        self.events.emit(EventKind::InhibitorReleased {
+            id,
+            who: inhibitor.who,
+        });
+    }
+
+    /// Returns the holders of the inhibitors, or `None` if CPU changes are not inhibited.
+    pub fn inhibited_by(&self) -> Option<String> {
+        let inhibitors = self.inhibitors.lock().unwrap();
+        (!inhibitors.is_empty()).then(|| inhibitors.holders())
+    }
+
+    /// Returns the minimum number of CPUs that must stay online: the larger of the
+    /// scheduled floor and the CPUs reserved by active leases.
+    pub fn floor(&self) -> usize {
+        let scheduled = self
+            .schedule
+            .borrow()
+            .floor_at(SystemTime::now())
+            .unwrap_or(0);
+        let leased = self.leases.lock().unwrap().floor();
+        scheduled.max(leased)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Request {
+    /// Replaces the current plan of online CPU floors; an empty plan removes the floor.
+    SetSchedule {
+        plan: Vec<PlanEntry>,
+    },
+    GetSchedule,
+    /// Reserves `min_cpus` online CPUs for `client` until the lease expires after `ttl_secs`.
+    AcquireLease {
+        client: String,
+        min_cpus: usize,
+        ttl_secs: u64,
+    },
+    RenewLease {
+        id: u64,
+        ttl_secs: u64,
+    },
+    ReleaseLease {
+        id: u64,
+    },
+    /// Freezes the CPU configuration until this connection closes.
+    Inhibit {
+        who: String,
+        why: String,
+    },
+    Status,
+    /// Returns the package energy and the estimated savings of the offlining.
+    Energy,
+    /// Stops making decisions and onlines all CPUs until `resume`.
+    Pause,
+    Resume,
+    /// Onlines `cpu`, or its whole core in full-cores-only mode.
+    Online {
+        cpu: usize,
+    },
+    /// Offlines `cpu`, or its whole core in full-cores-only mode.
+    Offline {
+        cpu: usize,
+    },
+    /// Changes the running profile; omitted values are left as they are.
+    Set {
+        upper_threshold: Option<u8>,
+        lower_threshold: Option<u8>,
+        hysteresis: Option<u32>,
+        cooldown_secs: Option<f64>,
+    },
+    /// Replaces the log filter with `filter`, e.g. `debug` or `info,cpu_on_off_rust::topology=trace`,
+    /// and returns the filter in effect.
+    LogFilter {
+        filter: Option<String>,
+    },
+    /// Streams the recent events, and with `follow` all further events until the client disconnects.
+    Events {
+        #[serde(default)]
+        follow: bool,
+    },
+}
+
+#[derive(Serialize)]
+struct Response {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+impl Response {
+    fn ok(data: Option<Value>) -> Self {
+        Response {
+            ok: true,
+            error: None,
+            data,
+        }
+    }
+
+    fn error(message: impl Into<String>) -> Self {
+        Response {
+            ok: false,
+            error: Some(message.into()),
+            data: None,
+        }
+    }
+}
+
+/// Accepts control connections on `path` until an accept error occurs.
+pub async fn serve(path: &Path, state: Arc<ControlState>) -> io::Result<()> {
+    serve_listener(bind(path)?, state).await
+}
+
+/// Binds the control socket at `path`.
+///
+/// A stale socket file left behind by a previous run is removed before binding, and the
+/// socket is restricted to its owner since commands change the CPU configuration.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    info!("Listening for control commands on {:?}", path);
+    Ok(listener)
+}
+
+/// Accepts control connections on an already bound `listener` until an accept error occurs,
+/// e.g. one handed over by the previous daemon on an upgrade.
+pub async fn serve_listener(listener: UnixListener, state: Arc<ControlState>) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &state).await {
+                warn!("Control connection error: {}", e);
+            }
+        });
+    }
+}
+
+/// Inhibitors taken on a control connection, released when the connection is dropped, however
+/// it ends.
+struct HeldInhibitors<'a> {
+    state: &'a ControlState,
+    ids: Vec<u64>,
+}
+
+impl Drop for HeldInhibitors<'_> {
+    fn drop(&mut self) {
+        for id in self.ids.drain(..) {
+            self.state.uninhibit(id);
+        }
+    }
+}
+
+async fn handle_connection(stream: UnixStream, state: &ControlState) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut held = HeldInhibitors {
+        state,
+        ids: Vec::new(),
+    };
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(Request::Events { follow }) => {
+                return stream_events(&mut writer, state, follow).await;
+            }
+            Ok(Request::Inhibit { who, why }) => {
+                let id = state.inhibit(&who, &why);
+                held.ids.push(id);
+                Response::ok(Some(json!({ "id": id })))
+            }
+            Ok(request) => handle_request(request, state).await,
+            Err(e) => Response::error(format!("invalid request: {}", e)),
+        };
+        write_line(&mut writer, &response).await?;
+    }
+    Ok(())
+}
+
+async fn write_line(
+    writer: &mut (impl AsyncWriteExt + Unpin),
+    value: &impl Serialize,
+) -> io::Result<()> {
+    let mut out = serde_json::to_string(value)?;
+    out.push('\n');
+    writer.write_all(out.as_bytes()).await
+}
+
+/// Writes the recent events, and with `follow` every further event, to `writer`.
+async fn stream_events(
+    writer: &mut (impl AsyncWriteExt + Unpin),
+    state: &ControlState,
+    follow: bool,
+) -> io::Result<()> {
+    let (recent, mut rx) = state.events.subscribe();
+    for event in &recent {
+        write_line(writer, event).await?;
+    }
+    if !follow {
+        return Ok(());
+    }
+    loop {
+        match rx.recv().await {
+            Ok(event) => write_line(writer, &event).await?,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                write_line(writer, &Event::now(EventKind::Lagged { missed })).await?
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+/// Collects the `status` document from the control state and the status published by the CPU
+/// manager.
+fn daemon_status(state: &ControlState) -> DaemonStatus {
+    let now = Instant::now();
+    let scheduled_floor = state.schedule.borrow().floor_at(SystemTime::now());
+    state.expire_leases(now);
+    let manager = state.manager.lock().unwrap().clone();
+    let leases = state.leases.lock().unwrap();
+    DaemonStatus {
+        schema_version: status::SCHEMA_VERSION,
+        paused: *state.paused.borrow(),
+        scheduled_floor,
+        leased_floor: leases.floor(),
+        leases: leases.leases(now),
+        inhibitors: state.inhibitors.lock().unwrap().inhibitors(now),
+        clients: leases.clients().clone(),
+        workload: *state.workload.lock().unwrap(),
+        sysfs_errors: METRICS.sysfs_errors(),
+        constraints: manager.as_ref().map(|manager| {
+            let config = &manager.config;
+            Constraints {
+                floor: scheduled_floor
+                    .unwrap_or(0)
+                    .max(leases.floor())
+                    .max(config.min_cpus),
+                min_cpus: config.min_cpus,
+                excluded_cpus: config.exclude_cpus.clone(),
+                housekeeping_cpu: manager.topology.housekeeping_cpu,
+                full_cores_only: config.full_cores_only,
+                granularity: config.granularity,
+                prefer_no_smt: config.prefer_no_smt,
+                no_smt: manager.topology.no_smt,
+            }
+        }),
+        config: manager.as_ref().map(|manager| manager.config.clone()),
+        topology: manager.as_ref().map(|manager| manager.topology.clone()),
+        governor: manager.map(|manager| manager.governor),
+        recent_decisions: DECISION_LOG.recent(status::STATUS_DECISIONS),
+    }
+}
+
+/// Connects to the control socket at `path`, sends a single request, and returns the response data.
+///
+/// # Arguments
+/// * `path` - The path of the control socket of the running daemon.
+/// * `request` - The JSON request, e.g. `{"command":"status"}`.
+///
+/// # Returns
+/// * `io::Result<Option<Value>>` - The `data` of the response, or an error if the daemon cannot be
+///   reached or refuses the request.
+pub async fn send_request(path: &Path, request: &Value) -> io::Result<Option<Value>> {
+    let mut stream = UnixStream::connect(path).await?;
+    write_line(&mut stream, request).await?;
+    read_response(&mut stream).await
+}
+
+/// Reads a single response from `stream` and returns its data, or an error if the request was refused.
+async fn read_response(stream: &mut UnixStream) -> io::Result<Option<Value>> {
+    let line = BufReader::new(stream)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "no response"))?;
+    let mut response: Value = serde_json::from_str(&line)?;
+    if response["ok"] != json!(true) {
+        let error = response["error"].as_str().unwrap_or("request failed");
+        return Err(io::Error::other(error.to_string()));
+    }
+    Ok(response.get_mut("data").map(Value::take))
+}
+
+/// Runs `command` while holding an inhibitor, like `systemd-inhibit`.
+///
+/// This function performs the following steps:
+/// 1. Connects to the control socket at `path` and takes an inhibitor for `who` and `why`.
+/// 2. Runs `command` with its arguments and waits for it to exit.
+/// 3. Closes the connection, which releases the inhibitor.
+///
+/// # Arguments
+/// * `path` - The path of the control socket of the running daemon.
+/// * `who` - The name reported as the holder of the inhibitor.
+/// * `why` - The reason reported for the inhibitor.
+/// * `command` - The program to run, followed by its arguments.
+///
+/// # Returns
+/// * `io::Result<ExitStatus>` - The exit status of `command`, or an error if the inhibitor cannot be
+///   taken or the command cannot be started.
+pub async fn run_inhibited(
+    path: &Path,
+    who: &str,
+    why: &str,
+    command: &[String],
+) -> io::Result<ExitStatus> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no command to run"))?;
+    let mut stream = UnixStream::connect(path).await?;
+    write_line(
+        &mut stream,
+        &json!({ "command": "inhibit", "who": who, "why": why }),
+    )
+    .await?;
+    read_response(&mut stream).await?;
+    let status = tokio::process::Command::new(program)
+        .args(args)
+        .status()
+        .await;
+    drop(stream);
+    status
+}
+
+/// Connects to the control socket at `path` and copies the event stream to stdout.
+///
+/// # Arguments
+/// * `path` - The path of the control socket of the running daemon.
+/// * `follow` - Whether to keep streaming new events after the recent ones.
+pub async fn print_events(path: &Path, follow: bool) -> io::Result<()> {
+    let mut stream = UnixStream::connect(path).await?;
+    write_line(
+        &mut stream,
+        &json!({ "command": "events", "follow": follow }),
+    )
+    .await?;
+    let mut lines = BufReader::new(stream).lines();
+    let mut stdout = tokio::io::stdout();
+    while let Some(line) = lines.next_line().await? {
+        stdout.write_all(line.as_bytes()).await?;
+        stdout.write_all(b"\n").await?;
+        stdout.flush().await?;
+    }
+    Ok(())
+}
+
+async fn handle_request(request: Request, state: &ControlState) -> Response {
+    match request {
+        Request::SetSchedule { plan } => {
+            let schedule = Schedule::new(plan);
+            info!("Received new schedule: {:?}", schedule);
+            state.events.emit(EventKind::ScheduleChanged {
+                schedule: schedule.clone(),
+            });
+            state.schedule.send_replace(schedule);
+            Response::ok(None)
+        }
+        Request::GetSchedule => Response::ok(serde_json::to_value(&*state.schedule.borrow()).ok()),
+        Request::AcquireLease {
+            client,
+            min_cpus,
+            ttl_secs,
+        } => {
+            let id = state.leases.lock().unwrap().acquire(
+                &client,
+                min_cpus,
+                Duration::from_secs(ttl_secs),
+            );
+            info!(
+                "Granted lease {} to {} for {} CPUs ({}s)",
+                id, client, min_cpus, ttl_secs
+            );
+            state.events.emit(EventKind::LeaseAcquired {
+                id,
+                client,
+                min_cpus,
+                ttl_secs,
+            });
+            Response::ok(Some(json!({ "id": id })))
+        }
+        Request::RenewLease { id, ttl_secs } => {
+            if state
+                .leases
+                .lock()
+                .unwrap()
+                .renew(id, Duration::from_secs(ttl_secs))
+            {
+                Response::ok(None)
+            } else {
+                Response::error(format!("no such lease: {}", id))
+            }
+        }
+        Request::ReleaseLease { id } => {
+            if state.leases.lock().unwrap().release(id) {
+                info!("Released lease {}", id);
+                state.events.emit(EventKind::LeaseReleased { id });
+                Response::ok(None)
+            } else {
+                Response::error(format!("no such lease: {}", id))
+            }
+        }
+        Request::Events { .. } => Response::error("events must be the only request"),
+        Request::Inhibit { .. } => Response::error("inhibit is handled by the connection"),
+        Request::Online { cpu } => {
+            let direction = Direction::Online;
+            state
+                .request(ManagerCommand::Hotplug { direction, cpu })
+                .await
+        }
+        Request::Offline { cpu } => {
+            let direction = Direction::Offline;
+            state
+                .request(ManagerCommand::Hotplug { direction, cpu })
+                .await
+        }
+        Request::Set {
+            upper_threshold,
+            lower_threshold,
+            hysteresis,
+            cooldown_secs,
+        } => {
+            match ProfileOverrides::new(upper_threshold, lower_threshold, hysteresis, cooldown_secs)
+            {
+                Ok(changes) => state.request(ManagerCommand::Tune(changes)).await,
+                Err(message) => Response::error(message),
+            }
+        }
+        Request::LogFilter { filter } => {
+            if let Some(filter) = filter {
+                if let Err(message) = logging::set_filter(&filter) {
+                    return Response::error(message);
+                }
+            }
+            Response::ok(Some(json!({ "filter": logging::filter() })))
+        }
+        Request::Pause => {
+            state.set_paused(true);
+            Response::ok(None)
+        }
+        Request::Resume => {
+            state.set_paused(false);
+            Response::ok(None)
+        }
+        Request::Energy => state.request(ManagerCommand::EnergyReport).await,
+        Request::Status => match serde_json::to_value(daemon_status(state)) {
+            Ok(data) => Response::ok(Some(data)),
+            Err(e) => Response::error(e.to_string()),
+        },
+    }
+}
@@ -0,0 +1,93 @@
+//! CPU lists in the kernel's `cpulist` format, e.g. `2,3,8-11`.
+//!
+//! The same format is used for `--exclude-cpus` and by sysfs list attributes such as
+//! `thread_siblings_list` and `package_cpus_list`.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CpuList(pub BTreeSet<usize>);
+
+impl CpuList {
+    pub fn contains(&self, id: usize) -> bool {
+        self.0.contains(&id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl FromStr for CpuList {
+    type Err = String;
+
+    fn from_str(list: &str) -> Result<Self, Self::Err> {
+        let parse = |id: &str| {
+            id.trim()
+                .parse::<usize>()
+                .map_err(|_| format!("invalid CPU number {:?} in CPU list {:?}", id, list))
+        };
+        let mut cpus = BTreeSet::new();
+        for part in list.split(',').filter(|part| !part.trim().is_empty()) {
+            match part.split_once('-') {
+                Some((first, last)) => {
+                    let (first, last) = (parse(first)?, parse(last)?);
+                    if first > last {
+                        return Err(format!("invalid CPU range {:?}", part));
+                    }
+                    cpus.extend(first..=last);
+                }
+                None => {
+                    cpus.insert(parse(part)?);
+                }
+            }
+        }
+        Ok(CpuList(cpus))
+    }
+}
+
+impl TryFrom<String> for CpuList {
+    type Error = String;
+
+    fn try_from(list: String) -> Result<Self, Self::Error> {
+        list.parse()
+    }
+}
+
+impl From<CpuList> for String {
+    fn from(list: CpuList) -> Self {
+        list.to_string()
+    }
+}
+
+impl fmt::Display for CpuList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ids: Vec<String> = self.0.iter().map(|id| id.to_string()).collect();
+        write!(f, "{}", ids.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ids_and_ranges() {
+        let list: CpuList = "2,3,8-11".parse().unwrap();
+        assert_eq!(list.0.into_iter().collect::<Vec<_>>(), [2, 3, 8, 9, 10, 11]);
+        assert!("".parse::<CpuList>().unwrap().is_empty());
+        for (sysfs, ids) in [
+            ("0-1\n", vec![0, 1]),
+            ("0,64\n", vec![0, 64]),
+            ("0-1,64-65", vec![0, 1, 64, 65]),
+        ] {
+            let list: CpuList = sysfs.trim().parse().unwrap();
+            assert_eq!(list.0.into_iter().collect::<Vec<_>>(), ids);
+        }
+        assert!("3-1".parse::<CpuList>().is_err());
+        assert!("a".parse::<CpuList>().is_err());
+    }
+}
@@ -0,0 +1,221 @@
+//! Frequency capping, instead of or before taking CPUs offline.
+//!
+//! With `--backend cpufreq`, the CPU manager lowers the `cpufreq/scaling_max_freq` of the managed
+//! online CPUs one step on low load and raises it one step on high load, and never offlines a CPU.
+//! Each CPU's range from `cpuinfo_min_freq` to `cpuinfo_max_freq` is split into `CAP_STEPS` steps,
+//! so CPUs of different speeds are capped alike. With `--backend tiered`, the cap is lowered all
+//! the way before any CPU is offlined, and every offline CPU is onlined again before it is raised.
+use crate::metrics::{SysfsAccess, METRICS};
+use crate::topology::SystemTopology;
+use std::io;
+use tracing::{info, warn};
+
+/// Number of steps between the maximum and the minimum frequency of a CPU.
+pub const CAP_STEPS: u32 = 4;
+
+/// The frequency cap applied to the managed CPUs, as a number of steps below the maximum.
+#[derive(Default)]
+pub struct FrequencyCap {
+    /// The current level, 0 when uncapped; `None` until read from the first CPU.
+    level: Option<u32>,
+    /// The level found at startup, restored on exit.
+    initial: Option<u32>,
+}
+
+impl FrequencyCap {
+    /// Returns the current level, if already known.
+    pub fn level(&self) -> Option<u32> {
+        self.level
+    }
+
+    /// Lowers the cap one step.
+    ///
+    /// # Returns
+    /// * `io::Result<bool>` - Whether the cap was lowered, `false` if already at the minimum
+    ///   frequency, or an error if no CPU could be capped.
+    pub async fn lower(&mut self, topology: &SystemTopology) -> io::Result<bool> {
+        let level = self.current(topology).await;
+        if level >= CAP_STEPS {
+            return Ok(false);
+        }
+        self.set_level(topology, level + 1).await?;
+        Ok(true)
+    }
+
+    /// Raises the cap one step.
+    ///
+    /// # Returns
+    /// * `io::Result<bool>` - Whether the cap was raised, `false` if already uncapped, or an error
+    ///   if no CPU could be uncapped.
+    pub async fn raise(&mut self, topology: &SystemTopology) -> io::Result<bool> {
+        let level = self.current(topology).await;
+        if level == 0 {
+            return Ok(false);
+        }
+        self.set_level(topology, level - 1).await?;
+        Ok(true)
+    }
+
+    /// Returns the cap to the level found at startup.
+    pub async fn restore(&mut self, topology: &SystemTopology) -> io::Result<()> {
+        self.current(topology).await;
+        match self.initial {
+            Some(initial) if Some(initial) != self.level => self.set_level(topology, initial).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the `scaling_max_freq` of `level` to every managed online CPU with cpufreq. A CPU
+    /// that rejects the write is logged and left as it is.
+    ///
+    /// # Returns
+    /// * `io::Result<()>` - An error if CPUs with cpufreq exist but none of them could be written.
+    pub async fn set_level(&mut self, topology: &SystemTopology, level: u32) -> io::Result<()> {
+        let level = level.min(CAP_STEPS);
+        let mut ids: Vec<usize> = topology
+            .cpus
+            .values()
+            .filter(|cpu| cpu.managed && cpu.online)
+            .map(|cpu| cpu.id)
+            .collect();
+        ids.sort_unstable();
+        let mut written = 0;
+        let mut last_error = None;
+        for id in ids {
+            let Some((min, max)) = limits(topology, id).await else {
+                continue;
+            };
+            let cap = max - (max - min) * u64::from(level) / u64::from(CAP_STEPS);
+            match topology
+                .sysfs
+                .write(id, "cpufreq/scaling_max_freq", &cap.to_string())
+                .await
+            {
+                Ok(()) => written += 1,
+                Err(e) => {
+                    METRICS.sysfs_error(SysfsAccess::CpufreqWrite, &e);
+                    warn!("Cannot cap the frequency of CPU {}: {}", id, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        if written == 0 {
+            if let Some(e) = last_error {
+                return Err(e);
+            }
+        }
+        info!(
+            "Frequency cap at step {} of {} below the maximum",
+            level, CAP_STEPS
+        );
+        self.level = Some(level);
+        Ok(())
+    }
+
+    /// Returns the current level, reading it from the first managed online CPU with cpufreq the
+    /// first time, so a cap left by an earlier run is picked up.
+    async fn current(&mut self, topology: &SystemTopology) -> u32 {
+        if let Some(level) = self.level {
+            return level;
+        }
+        let mut ids: Vec<usize> = topology
+            .cpus
+            .values()
+            .filter(|cpu| cpu.managed && cpu.online)
+            .map(|cpu| cpu.id)
+            .collect();
+        ids.sort_unstable();
+        let mut level = 0;
+        for id in ids {
+            let (Some((min, max)), Some(current)) = (
+                limits(topology, id).await,
+                read_khz(topology, id, "scaling_max_freq").await,
+            ) else {
+                continue;
+            };
+            if max > min {
+                let below = max.saturating_sub(current.min(max));
+                level = ((below * u64::from(CAP_STEPS) + (max - min) / 2) / (max - min)) as u32;
+            }
+            break;
+        }
+        let level = level.min(CAP_STEPS);
+        self.level = Some(level);
+        self.initial = Some(level);
+        level
+    }
+}
+
+/// Reads the minimum and maximum frequency of CPU `id`, or `None` without cpufreq.
+async fn limits(topology: &SystemTopology, id: usize) -> Option<(u64, u64)> {
+    let min = read_khz(topology, id, "cpuinfo_min_freq").await?;
+    let max = read_khz(topology, id, "cpuinfo_max_freq").await?;
+    (max >= min).then_some((min, max))
+}
+
+async fn read_khz(topology: &SystemTopology, id: usize, attr: &str) -> Option<u64> {
+    topology
+        .sysfs
+        .read(id, &format!("cpufreq/{}", attr))
+        .await
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sysfs::MockSysfs;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn steps_the_cap_of_every_cpu_through_its_own_range() {
+        let sysfs = Arc::new(
+            MockSysfs::new()
+                .with_cpu(0, 0, 0, "0")
+                .with_cpu(1, 1, 0, "1"),
+        );
+        for (id, min, max) in [(0, 800_000, 4_000_000), (1, 400_000, 2_000_000)] {
+            sysfs.set(id, "cpufreq/cpuinfo_min_freq", &min.to_string());
+            sysfs.set(id, "cpufreq/cpuinfo_max_freq", &max.to_string());
+            sysfs.set(id, "cpufreq/scaling_max_freq", &max.to_string());
+        }
+        let topology = SystemTopology::builder()
+            .backend(sysfs.clone())
+            .build()
+            .await
+            .unwrap();
+        let mut cap = FrequencyCap::default();
+        assert!(!cap.raise(&topology).await.unwrap());
+        assert!(cap.lower(&topology).await.unwrap());
+        assert_eq!(
+            sysfs.get(0, "cpufreq/scaling_max_freq").as_deref(),
+            Some("3200000")
+        );
+        assert_eq!(
+            sysfs.get(1, "cpufreq/scaling_max_freq").as_deref(),
+            Some("1600000")
+        );
+        for _ in 1..CAP_STEPS {
+            assert!(cap.lower(&topology).await.unwrap());
+        }
+        assert!(!cap.lower(&topology).await.unwrap());
+        assert_eq!(
+            sysfs.get(1, "cpufreq/scaling_max_freq").as_deref(),
+            Some("400000")
+        );
+
+        // A new run picks up the cap and restores the level it found.
+        sysfs.set(0, "cpufreq/scaling_max_freq", "2400000");
+        let mut cap = FrequencyCap::default();
+        assert!(cap.raise(&topology).await.unwrap());
+        assert_eq!(cap.level(), Some(1));
+        cap.restore(&topology).await.unwrap();
+        assert_eq!(
+            sysfs.get(0, "cpufreq/scaling_max_freq").as_deref(),
+            Some("2400000")
+        );
+    }
+}
@@ -0,0 +1,343 @@
+//! Limits on the idle states the CPUs may enter.
+//!
+//! With `--backend tiered` or `--idle-policy load`, the CPU manager keeps the deeper idle states of
+//! the managed online CPUs disabled through their `cpuidle/stateN/disable` files while the load is
+//! high, and allows them again one state at a time as the load drops, before any CPU is offlined. A
+//! deep idle state saves more power but takes longer to wake up from, so the limit trades wake-up
+//! latency for power the same way the frequency cap trades throughput. `--max-cstate` keeps the
+//! states deeper than a given one disabled with any backend and policy, and latency-sensitive mode
+//! those that take longer than `--latency-us` to wake up from, see `LatencyGuard`.
+use crate::metrics::{SysfsAccess, METRICS};
+use crate::topology::{CpuInfo, SystemTopology};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::io;
+use tracing::{info, warn};
+
+/// The shallowest limit: `state0`, polling on x86, is never disabled, and `state1` always stays
+/// allowed so idle CPUs do not spin.
+pub const SHALLOWEST_STATE: usize = 1;
+
+/// Whether the idle states follow the load, selected with `--idle-policy`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IdlePolicy {
+    /// Leave the idle states alone, except for `--max-cstate`.
+    #[default]
+    Static,
+    /// Allow deeper idle states as the load drops and disable them as it climbs, see `TierStage`.
+    Load,
+}
+
+/// The deepest idle state the managed CPUs may enter.
+#[derive(Default)]
+pub struct IdleLimit {
+    /// The index of the deepest allowed state; `None` until read from the first CPU.
+    deepest: Option<usize>,
+    /// The limit found at startup, restored on exit.
+    initial: Option<usize>,
+    /// The deepest state ever allowed, set by `--max-cstate`.
+    max: Option<usize>,
+    /// The deepest state allowed during latency-sensitive mode.
+    guard: Option<usize>,
+    /// The limit before latency-sensitive mode, restored when it ends.
+    guarded_from: Option<usize>,
+}
+
+impl IdleLimit {
+    /// Creates a limit that never allows states deeper than `max`, if given.
+    pub fn new(max: Option<usize>) -> Self {
+        IdleLimit {
+            max,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the index of the deepest allowed state, if already known.
+    pub fn deepest(&self) -> Option<usize> {
+        self.deepest
+    }
+
+    /// Allows one deeper idle state.
+    ///
+    /// # Returns
+    /// * `io::Result<bool>` - Whether a state was allowed, `false` if all states already are or
+    ///   the CPUs have no idle states, or an error if no CPU could be changed.
+    pub async fn deepen(&mut self, topology: &SystemTopology) -> io::Result<bool> {
+        let Some(deepest) = self.current(topology).await else {
+            return Ok(false);
+        };
+        if deepest >= self.ceiling(topology) {
+            return Ok(false);
+        }
+        self.set_deepest(topology, deepest + 1).await?;
+        Ok(true)
+    }
+
+    /// Disables the deepest allowed idle state, down to `SHALLOWEST_STATE`.
+    ///
+    /// # Returns
+    /// * `io::Result<bool>` - Whether a state was disabled, `false` if only the shallowest states
+    ///   are allowed already, or an error if no CPU could be changed.
+    pub async fn shallow(&mut self, topology: &SystemTopology) -> io::Result<bool> {
+        let Some(deepest) = self.current(topology).await else {
+            return Ok(false);
+        };
+        if deepest <= SHALLOWEST_STATE {
+            return Ok(false);
+        }
+        self.set_deepest(topology, deepest - 1).await?;
+        Ok(true)
+    }
+
+    /// Returns the deepest state the limit may allow: the deepest state of any managed CPU, or
+    /// `--max-cstate` or the latency-sensitive mode's limit if shallower.
+    pub fn ceiling(&self, topology: &SystemTopology) -> usize {
+        [self.max, self.guard]
+            .into_iter()
+            .flatten()
+            .fold(deepest_state(topology), usize::min)
+    }
+
+    /// Disables the states deeper than `guard` until called again with `None`, which restores the
+    /// limit from before, e.g. for latency-sensitive mode.
+    pub async fn set_guard(
+        &mut self,
+        topology: &SystemTopology,
+        guard: Option<usize>,
+    ) -> io::Result<()> {
+        self.guard = guard;
+        match guard {
+            Some(guard) => match self.current(topology).await {
+                Some(deepest) if deepest > guard => {
+                    self.guarded_from = Some(deepest);
+                    self.set_deepest(topology, guard).await
+                }
+                _ => Ok(()),
+            },
+            None => match self.guarded_from.take() {
+                Some(deepest) => self.set_deepest(topology, deepest).await,
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// Disables the states deeper than `--max-cstate`, if set and currently allowed.
+    pub async fn enforce_max(&mut self, topology: &SystemTopology) -> io::Result<()> {
+        let Some(max) = self.max else {
+            return Ok(());
+        };
+        match self.current(topology).await {
+            Some(deepest) if deepest > max => self.set_deepest(topology, max).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the limit to the one found at startup.
+    pub async fn restore(&mut self, topology: &SystemTopology) -> io::Result<()> {
+        self.current(topology).await;
+        match self.initial {
+            Some(initial) if Some(initial) != self.deepest => {
+                self.set_deepest(topology, initial).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Enables the idle states up to `deepest` and disables the deeper ones on every managed online
+    /// CPU. A CPU that rejects the write is logged and left as it is.
+    ///
+    /// # Returns
+    /// * `io::Result<()>` - An error if CPUs with idle states exist but none of them could be written.
+    pub async fn set_deepest(
+        &mut self,
+        topology: &SystemTopology,
+        deepest: usize,
+    ) -> io::Result<()> {
+        let deepest = deepest.max(SHALLOWEST_STATE);
+        let mut ids: Vec<usize> = topology
+            .cpus
+            .values()
+            .filter(|cpu| cpu.managed && cpu.online)
+            .map(|cpu| cpu.id)
+            .collect();
+        ids.sort_unstable();
+        write_limit(topology, &ids, deepest).await?;
+        info!("Idle states allowed down to state{}", deepest);
+        self.deepest = Some(deepest);
+        Ok(())
+    }
+
+    /// Writes the current limit, if known, to CPUs `ids`, e.g. after they are onlined. A CPU that
+    /// rejects the write is logged and left as it is.
+    pub async fn apply(&self, topology: &SystemTopology, ids: &[usize]) {
+        if let Some(deepest) = self.deepest {
+            let _ = write_limit(topology, ids, deepest).await;
+        }
+    }
+
+    /// Returns the current limit, reading it from the first managed online CPU with idle states the
+    /// first time, so a limit left by an earlier run is picked up. `None` without idle states.
+    async fn current(&mut self, topology: &SystemTopology) -> Option<usize> {
+        if self.deepest.is_some() {
+            return self.deepest;
+        }
+        let mut cpus: Vec<&CpuInfo> = topology
+            .cpus
+            .values()
+            .filter(|cpu| cpu.managed && cpu.online)
+            .filter(|cpu| state_indices(cpu).any(|state| state >= SHALLOWEST_STATE))
+            .collect();
+        cpus.sort_by_key(|cpu| cpu.id);
+        let cpu = cpus.first()?;
+        let mut deepest = SHALLOWEST_STATE;
+        for state in state_indices(cpu).filter(|&state| state > SHALLOWEST_STATE) {
+            let disabled = topology
+                .sysfs
+                .read(cpu.id, &format!("cpuidle/state{}/disable", state))
+                .await
+                .is_ok_and(|disable| disable.trim() != "0");
+            if !disabled {
+                deepest = deepest.max(state);
+            }
+        }
+        self.deepest = Some(deepest);
+        self.initial = Some(deepest);
+        self.deepest
+    }
+}
+
+/// Enables the idle states of CPUs `ids` up to `deepest` and disables the deeper ones, logging the
+/// CPUs that reject the write.
+///
+/// # Returns
+/// * `io::Result<()>` - An error if the CPUs have idle states but none of them could be written.
+async fn write_limit(topology: &SystemTopology, ids: &[usize], deepest: usize) -> io::Result<()> {
+    let mut written = 0;
+    let mut last_error = None;
+    for cpu in ids.iter().filter_map(|id| topology.cpus.get(id)) {
+        for state in state_indices(cpu).filter(|&state| state >= SHALLOWEST_STATE) {
+            let disable = if state > deepest { "1" } else { "0" };
+            match topology
+                .sysfs
+                .write(cpu.id, &format!("cpuidle/state{}/disable", state), disable)
+                .await
+            {
+                Ok(()) => written += 1,
+                Err(e) => {
+                    METRICS.sysfs_error(SysfsAccess::CpuidleWrite, &e);
+                    warn!(
+                        "Cannot change idle state {} of CPU {}: {}",
+                        state, cpu.id, e
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+    }
+    match last_error {
+        Some(e) if written == 0 => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the index of the deepest idle state of any managed CPU, 0 without idle states.
+pub fn deepest_state(topology: &SystemTopology) -> usize {
+    topology
+        .cpus
+        .values()
+        .filter(|cpu| cpu.managed)
+        .flat_map(state_indices)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Returns the index of the deepest idle state that every managed online CPU wakes up from within
+/// `latency_us`, as given by its `cpuidle/stateN/latency`, but at least `SHALLOWEST_STATE`. A
+/// state whose latency cannot be read counts as too slow.
+pub async fn deepest_within(topology: &SystemTopology, latency_us: u32) -> usize {
+    let mut deepest = deepest_state(topology);
+    for cpu in topology
+        .cpus
+        .values()
+        .filter(|cpu| cpu.managed && cpu.online)
+    {
+        let mut states: Vec<usize> = state_indices(cpu).collect();
+        states.sort_unstable();
+        for state in states.into_iter().filter(|&state| state > SHALLOWEST_STATE) {
+            let latency = topology
+                .sysfs
+                .read(cpu.id, &format!("cpuidle/state{}/latency", state))
+                .await
+                .ok()
+                .and_then(|latency| latency.trim().parse::<u32>().ok());
+            if latency.is_none_or(|latency| latency > latency_us) {
+                deepest = deepest.min(state - 1);
+                break;
+            }
+        }
+    }
+    deepest.max(SHALLOWEST_STATE)
+}
+
+/// Returns the indices of the idle states of `cpu`, e.g. 2 for `state2`.
+fn state_indices(cpu: &CpuInfo) -> impl Iterator<Item = usize> + '_ {
+    cpu.idle_states
+        .iter()
+        .filter_map(|name| name.strip_prefix("state")?.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sysfs::MockSysfs;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn allows_and_disables_the_deeper_idle_states() {
+        let sysfs = Arc::new(
+            MockSysfs::new()
+                .with_cpu(0, 0, 0, "0")
+                .with_cpu(1, 1, 0, "1"),
+        );
+        for id in [0, 1] {
+            for state in 0..4 {
+                sysfs.set(id, &format!("cpuidle/state{}/disable", state), "0");
+            }
+        }
+        sysfs.set(1, "cpuidle/state3/disable", "1");
+        let topology = SystemTopology::builder()
+            .backend(sysfs.clone())
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(deepest_state(&topology), 3);
+
+        let mut limit = IdleLimit::default();
+        assert!(!limit.deepen(&topology).await.unwrap());
+        assert!(limit.shallow(&topology).await.unwrap());
+        assert!(limit.shallow(&topology).await.unwrap());
+        assert!(!limit.shallow(&topology).await.unwrap());
+        assert_eq!(limit.deepest(), Some(SHALLOWEST_STATE));
+        for id in [0, 1] {
+            assert_eq!(
+                sysfs.get(id, "cpuidle/state1/disable").as_deref(),
+                Some("0")
+            );
+            assert_eq!(
+                sysfs.get(id, "cpuidle/state2/disable").as_deref(),
+                Some("1")
+            );
+            assert_eq!(
+                sysfs.get(id, "cpuidle/state3/disable").as_deref(),
+                Some("1")
+            );
+        }
+        assert!(limit.deepen(&topology).await.unwrap());
+        assert_eq!(sysfs.get(1, "cpuidle/state2/disable").as_deref(), Some("0"));
+
+        limit.restore(&topology).await.unwrap();
+        assert_eq!(sysfs.get(0, "cpuidle/state3/disable").as_deref(), Some("0"));
+        assert_eq!(sysfs.get(0, "cpuidle/state0/disable").as_deref(), Some("0"));
+    }
+}
@@ -0,0 +1,194 @@
+//! cgroup cpuset constraints on the CPUs the daemon manages.
+//!
+//! Inside a container, or on systems partitioned with systemd's `AllowedCPUs=`, the daemon's
+//! cgroup may only use some of the CPUs, and the others belong to someone else. Its cpuset is
+//! read from `/proc/self/cgroup` and the cgroup filesystem below the sysfs root, on the unified
+//! (v2) or the cpuset (v1) hierarchy, and the CPUs outside it are left unmanaged. A named target
+//! cgroup, e.g. the `system.slice` whose work the daemon sizes the CPUs for, narrows it further.
+//!
+//! The configured `cpuset.cpus` is used rather than `cpuset.cpus.effective`, which drops the
+//! offline CPUs and would keep the daemon from ever onlining them again. An empty `cpuset.cpus`
+//! inherits the parent's, so the nearest ancestor that sets one counts.
+use crate::cpu_list::CpuList;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// The cgroup filesystem, relative to the sysfs root.
+pub static CGROUP_DIR: &str = "fs/cgroup";
+
+/// The cgroups of the daemon process.
+pub static PROC_SELF_CGROUP: &str = "/proc/self/cgroup";
+
+/// Parses `/proc/<pid>/cgroup` and returns the path of the process's cgroup in the hierarchy that
+/// carries the cpuset controller, and whether it is the v1 cpuset hierarchy.
+pub fn parse_proc_cgroup(content: &str) -> Option<(String, bool)> {
+    let mut unified = None;
+    for line in content.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (_, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+        if controllers
+            .split(',')
+            .any(|controller| controller == "cpuset")
+        {
+            return Some((path.to_string(), true));
+        }
+        if controllers.is_empty() {
+            unified = Some(path.to_string());
+        }
+    }
+    unified.map(|path| (path, false))
+}
+
+/// Returns the directory of `cgroup`, given relative to the cgroup root, in the hierarchy that
+/// carries the cpuset controller: the unified (v2) hierarchy if mounted, else the v1 cpuset one.
+pub fn cgroup_dir(sysfs_root: &Path, cgroup: &str) -> PathBuf {
+    relative(&cpuset_root(sysfs_root), cgroup)
+}
+
+/// Returns the root of the hierarchy that carries the cpuset controller.
+fn cpuset_root(sysfs_root: &Path) -> PathBuf {
+    let cgroup_fs = sysfs_root.join(CGROUP_DIR);
+    if cgroup_fs.join("cgroup.controllers").exists() {
+        cgroup_fs
+    } else {
+        cgroup_fs.join("cpuset")
+    }
+}
+
+/// Returns the CPUs allowed by the cpuset of the daemon's own cgroup, if `own` is set, and of the
+/// `target` cgroup, if given, or `None` if neither restricts the CPUs. The cpuset of `actuated`,
+/// the cgroup the cpuset backend narrows, is skipped in favour of its parent's, since the CPUs
+/// taken away from it are still managed.
+///
+/// This function performs the following steps:
+/// 1. Reads the daemon's cgroup from `/proc/self/cgroup`.
+/// 2. Finds the nearest cgroup, from the given one up to the root, whose `cpuset.cpus` is set, in
+///    the hierarchy of the cgroup filesystem below `sysfs_root`.
+/// 3. Returns the intersection of the CPUs allowed for both cgroups.
+///
+/// # Returns
+/// * `io::Result<Option<CpuList>>` - The allowed CPUs, or an error if the target cgroup does not exist.
+pub async fn allowed_cpus(
+    sysfs_root: &Path,
+    own: bool,
+    target: Option<&str>,
+    actuated: Option<&Path>,
+) -> io::Result<Option<CpuList>> {
+    let cgroup_fs = sysfs_root.join(CGROUP_DIR);
+    let mut allowed: Option<CpuList> = None;
+    let mut restrict = |cpus: CpuList| {
+        allowed = Some(match allowed.take() {
+            Some(current) => CpuList(current.0.intersection(&cpus.0).copied().collect()),
+            None => cpus,
+        });
+    };
+    if own {
+        if let Some((path, v1)) = fs::read_to_string(PROC_SELF_CGROUP)
+            .await
+            .ok()
+            .as_deref()
+            .and_then(parse_proc_cgroup)
+        {
+            let root = if v1 {
+                cgroup_fs.join("cpuset")
+            } else {
+                cgroup_fs.clone()
+            };
+            if let Some(cpus) = nearest_cpuset(&root, &path, actuated).await {
+                restrict(cpus);
+            }
+        }
+    }
+    if let Some(target) = target {
+        let dir = cgroup_dir(sysfs_root, target);
+        if !dir.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("cgroup {} does not exist: {:?}", target, dir),
+            ));
+        }
+        if let Some(cpus) = nearest_cpuset(&cpuset_root(sysfs_root), target, actuated).await {
+            restrict(cpus);
+        }
+    }
+    Ok(allowed)
+}
+
+/// Returns the `cpuset.cpus` of cgroup `path` below `root`, or of its nearest ancestor that sets
+/// one, skipping the `skip` directory.
+async fn nearest_cpuset(root: &Path, path: &str, skip: Option<&Path>) -> Option<CpuList> {
+    let mut dir = relative(root, path);
+    loop {
+        if skip != Some(dir.as_path()) {
+            if let Some(cpus) = fs::read_to_string(dir.join("cpuset.cpus"))
+                .await
+                .ok()
+                .and_then(|cpus| cpus.trim().parse::<CpuList>().ok())
+                .filter(|cpus| !cpus.is_empty())
+            {
+                return Some(cpus);
+            }
+        }
+        if dir == root || !dir.pop() {
+            return None;
+        }
+    }
+}
+
+/// Joins cgroup `path`, which starts with a slash in `/proc/<pid>/cgroup`, to `root`.
+fn relative(root: &Path, path: &str) -> PathBuf {
+    root.join(path.trim_start_matches('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_cpuset_hierarchy() {
+        let v2 = "0::/system.slice/cpu-on-off.service\n";
+        assert_eq!(
+            parse_proc_cgroup(v2),
+            Some(("/system.slice/cpu-on-off.service".to_string(), false))
+        );
+        let v1 = "12:cpuset:/docker/abc\n11:memory:/docker/abc\n0::/docker/abc\n";
+        assert_eq!(
+            parse_proc_cgroup(v1),
+            Some(("/docker/abc".to_string(), true))
+        );
+    }
+
+    #[tokio::test]
+    async fn inherits_the_cpuset_of_the_nearest_ancestor() {
+        let root = std::env::temp_dir().join(format!("cpu-on-off-cgroup-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root).await;
+        let cgroup_fs = root.join(CGROUP_DIR);
+        let service = cgroup_fs.join("system.slice/cpu-on-off.service");
+        fs::create_dir_all(&service).await.unwrap();
+        fs::write(cgroup_fs.join("cgroup.controllers"), "cpuset cpu\n")
+            .await
+            .unwrap();
+        fs::write(cgroup_fs.join("system.slice/cpuset.cpus"), "2-5\n")
+            .await
+            .unwrap();
+        fs::write(service.join("cpuset.cpus"), "\n").await.unwrap();
+
+        let service_path = "system.slice/cpu-on-off.service";
+        let allowed = allowed_cpus(&root, false, Some(service_path), None).await;
+        assert_eq!(allowed.unwrap().unwrap().to_string(), "2,3,4,5");
+        assert!(allowed_cpus(&root, false, Some("user.slice"), None)
+            .await
+            .is_err());
+        assert_eq!(allowed_cpus(&root, false, None, None).await.unwrap(), None);
+
+        // The cpuset narrowed by the cpuset backend does not restrict the managed CPUs.
+        fs::write(cgroup_fs.join("cpuset.cpus"), "0-7\n")
+            .await
+            .unwrap();
+        let actuated = cgroup_dir(&root, "system.slice");
+        let allowed = allowed_cpus(&root, false, Some(service_path), Some(&actuated)).await;
+        assert_eq!(allowed.unwrap().unwrap().to_string(), "0,1,2,3,4,5,6,7");
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+//! Bounded in-memory log of the most recent control loop decisions.
+//!
+//! The log is dumped as JSON lines when the program panics or receives SIGABRT, so that the
+//! cycles leading up to a misbehavior can be analysed without relying on external log retention.
+use crate::events::Decision;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, TryLockError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{error, warn};
+
+/// Number of records kept in memory.
+pub const DECISION_LOG_LEN: usize = 256;
+
+pub static DEFAULT_CRASH_DUMP: &str = "/var/tmp/cpu-on-off-decisions.jsonl";
+
+/// The inputs and outcome of a single control loop cycle.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DecisionRecord {
+    /// Seconds since the UNIX epoch.
+    pub ts: f64,
+    pub load: f64,
+    pub online_cpus: usize,
+    pub floor: usize,
+    /// The action taken, or `None` if the cycle left the CPUs alone.
+    pub decision: Option<Decision>,
+    /// The load expected in the next cycle after the action changed the online CPUs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub predicted_load: Option<f64>,
+}
+
+impl DecisionRecord {
+    pub fn now(load: f64, online_cpus: usize, floor: usize, decision: Option<Decision>) -> Self {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        DecisionRecord {
+            ts,
+            load,
+            online_cpus,
+            floor,
+            decision,
+            predicted_load: None,
+        }
+    }
+}
+
+pub struct DecisionLog {
+    capacity: usize,
+    records: Mutex<VecDeque<DecisionRecord>>,
+}
+
+pub static DECISION_LOG: DecisionLog = DecisionLog::new(DECISION_LOG_LEN);
+
+impl DecisionLog {
+    pub const fn new(capacity: usize) -> Self {
+        DecisionLog {
+            capacity,
+            records: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Appends a record, dropping the oldest one once the log is full.
+    pub fn record(&self, record: DecisionRecord) {
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        if records.len() >= self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    /// Returns the `n` most recent records, oldest first.
+    pub fn recent(&self, n: usize) -> Vec<DecisionRecord> {
+        let records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        records
+            .iter()
+            .skip(records.len().saturating_sub(n))
+            .cloned()
+            .collect()
+    }
+
+    /// Writes all records to `path` as JSON lines, oldest first, and returns how many were written.
+    ///
+    /// Does not wait for the lock, since it may be called from a panic on the thread holding it.
+    pub fn dump(&self, path: &Path) -> io::Result<usize> {
+        let records = match self.records.try_lock() {
+            Ok(records) => records,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "decision log is locked",
+                ))
+            }
+        };
+        let mut out = BufWriter::new(File::create(path)?);
+        for record in records.iter() {
+            serde_json::to_writer(&mut out, record)?;
+            out.write_all(b"\n")?;
+        }
+        out.flush()?;
+        Ok(records.len())
+    }
+}
+
+/// Dumps the decision log to `path` and reports the outcome on stderr.
+pub fn dump_to(path: &Path) {
+    match DECISION_LOG.dump(path) {
+        Ok(n) => warn!("Dumped {} decision records to {:?}", n, path),
+        Err(e) => error!("Failed to dump decision records to {:?}: {}", path, e),
+    }
+}
+
+/// Installs a panic hook that dumps the decision log to `path` after the default panic message.
+pub fn install_panic_dump(path: PathBuf) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        dump_to(&path);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_most_recent_records() {
+        let log = DecisionLog::new(2);
+        for online_cpus in 1..=3 {
+            log.record(DecisionRecord::now(50.0, online_cpus, 0, None));
+        }
+        let path = std::env::temp_dir().join(format!("decision-log-{}.jsonl", std::process::id()));
+        assert_eq!(log.recent(1)[0].online_cpus, 3);
+        assert_eq!(log.dump(&path).unwrap(), 2);
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let online: Vec<u64> = content
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<serde_json::Value>(line).unwrap()["online_cpus"]
+                    .as_u64()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(online, vec![2, 3]);
+    }
+}
@@ -0,0 +1,220 @@
+//! Machine-readable events published by the daemon.
+//!
+//! Events are broadcast to all subscribers of the control socket as JSON lines, e.g.
+//! `{"ts":1760536800.25,"event":"hotplug","direction":"offline","cpus":[3,7],"ok":true}`.
+//! The field names are part of the interface: new fields and events may be added, but existing
+//! ones are not renamed or removed.
+use crate::metrics::Direction;
+use crate::schedule::Schedule;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Number of events buffered per subscriber before a slow subscriber starts missing events.
+const CHANNEL_LEN: usize = 256;
+
+/// Number of recent events replayed to new subscribers.
+const RECENT_LEN: usize = 64;
+
+/// Action taken, or attempted, by the control loop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Online,
+    Offline,
+    MeetFloor,
+    Consolidate,
+    Rebalance,
+    /// The load crossed the panic threshold and every offline CPU was onlined at once.
+    Panic,
+    /// The hottest package was above `--thermal-limit` and cores were offlined to cool it down.
+    Thermal,
+    /// The package power was above `--power-budget-watts` and cores were offlined to bring it down.
+    PowerBudget,
+    BlockedOnline,
+    BlockedOffline,
+}
+
+impl Decision {
+    /// Returns the name used in events and as a metric label.
+    pub fn label(self) -> &'static str {
+        match self {
+            Decision::Online => "online",
+            Decision::Offline => "offline",
+            Decision::MeetFloor => "meet_floor",
+            Decision::Consolidate => "consolidate",
+            Decision::Rebalance => "rebalance",
+            Decision::Panic => "panic",
+            Decision::Thermal => "thermal",
+            Decision::PowerBudget => "power_budget",
+            Decision::BlockedOnline => "blocked_online",
+            Decision::BlockedOffline => "blocked_offline",
+        }
+    }
+
+    /// Whether the decision changed the online CPUs, rather than being blocked by a limit.
+    pub fn acts(self) -> bool {
+        !matches!(self, Decision::BlockedOnline | Decision::BlockedOffline)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    /// The control loop decided to change the number of online CPUs.
+    Decision {
+        decision: Decision,
+        load: f64,
+        online_cpus: usize,
+        floor: usize,
+        /// Trace ID of the cycle, with `--exemplars`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        trace_id: Option<String>,
+    },
+    /// The load observed in the cycle after an action, compared with the load it predicted.
+    Outcome {
+        decision: Decision,
+        predicted: f64,
+        observed: f64,
+    },
+    /// A group of CPUs was onlined or offlined.
+    Hotplug {
+        direction: Direction,
+        cpus: Vec<usize>,
+        ok: bool,
+    },
+    /// CPUs were found onlined or offlined outside the daemon, e.g. by hand, and the topology was
+    /// updated to match.
+    ExternalHotplug {
+        direction: Direction,
+        cpus: Vec<usize>,
+    },
+    /// CPUs were hot-added to or removed from the system, e.g. vCPUs of a VM, and the topology was
+    /// scanned again.
+    TopologyChanged {
+        added: Vec<usize>,
+        removed: Vec<usize>,
+    },
+    ScheduleChanged {
+        schedule: Schedule,
+    },
+    LeaseAcquired {
+        id: u64,
+        client: String,
+        min_cpus: usize,
+        ttl_secs: u64,
+    },
+    LeaseReleased {
+        id: u64,
+    },
+    LeaseExpired {
+        id: u64,
+        client: String,
+    },
+    InhibitorTaken {
+        id: u64,
+        who: String,
+        why: String,
+    },
+    /// The inhibitor was released, usually because its control connection closed.
+    InhibitorReleased {
+        id: u64,
+        who: String,
+    },
+    /// The subscriber fell behind and `missed` events were dropped.
+    Lagged {
+        missed: u64,
+    },
+}
+
+/// An event with the time it was published, in seconds since the UNIX epoch.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Event {
+    pub ts: f64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+impl Event {
+    pub fn now(kind: EventKind) -> Self {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        Event { ts, kind }
+    }
+}
+
+/// Publishes events to all current subscribers and keeps the most recent ones for new subscribers.
+pub struct EventBus {
+    tx: broadcast::Sender<Event>,
+    recent: Mutex<VecDeque<Event>>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus {
+            tx: broadcast::Sender::new(CHANNEL_LEN),
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_LEN)),
+        }
+    }
+
+    /// Publishes an event. Never blocks; events without subscribers are only kept as recent events.
+    pub fn emit(&self, kind: EventKind) {
+        let event = Event::now(kind);
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() >= RECENT_LEN {
+            recent.pop_front();
+        }
+        recent.push_back(event.clone());
+        let _ = self.tx.send(event);
+    }
+
+    /// Returns the recent events and a receiver for all events published after them.
+    pub fn subscribe(&self) -> (Vec<Event>, broadcast::Receiver<Event>) {
+        let recent = self.recent.lock().unwrap();
+        (recent.iter().cloned().collect(), self.tx.subscribe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_events_as_flat_objects() {
+        let event = Event {
+            ts: 1.5,
+            kind: EventKind::Hotplug {
+                direction: Direction::Offline,
+                cpus: vec![3, 7],
+                ok: true,
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"ts":1.5,"event":"hotplug","direction":"offline","cpus":[3,7],"ok":true}"#
+        );
+    }
+
+    #[test]
+    fn replays_recent_events_to_new_subscribers() {
+        let bus = EventBus::new();
+        bus.emit(EventKind::LeaseReleased { id: 1 });
+        let (recent, mut rx) = bus.subscribe();
+        assert_eq!(recent.len(), 1);
+        bus.emit(EventKind::LeaseReleased { id: 2 });
+        assert!(matches!(
+            rx.try_recv().unwrap().kind,
+            EventKind::LeaseReleased { id: 2 }
+        ));
+    }
+}
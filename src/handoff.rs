@@ -0,0 +1,177 @@
+//! Handoff of a running daemon to a new binary without reverting the CPUs, for upgrades.
+//!
+//! On SIGUSR2, the daemon stops its control loop between two cycles, skips the exit policy, and
+//! `exec`s the binary at the path it was started from, which the package manager has replaced by
+//! then. The process keeps its PID, so systemd does not notice, and the new version finds:
+//!
+//! - The listening control socket, passed as an inherited file descriptor, so clients connecting
+//!   during the upgrade are queued rather than refused.
+//! - A serialized `Handoff` in the `CPU_ON_OFF_HANDOFF` environment variable with the state the
+//!   configuration file cannot restore: the pause, the schedule, the leases, the changes made with
+//!   `set`, the CPUs online at the first start for `--on-exit restore`, and the noise floor.
+//!
+//! The new daemon then starts from the CPUs currently online instead of onlining all of them, and
+//! reconciles the hotplug journal as on any start. Inhibitors are tied to their connections, which
+//! the `exec` closes, so they are released and have to be taken again.
+use crate::control::ControlState;
+use crate::lease::{LeaseSnapshot, LeaseTable};
+use crate::profile::ProfileOverrides;
+use crate::schedule::Schedule;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::time::Instant;
+use tokio::net::UnixListener;
+use tracing::info;
+
+/// Environment variable carrying the serialized handoff to the new binary.
+pub const HANDOFF_ENV: &str = "CPU_ON_OFF_HANDOFF";
+
+/// Version of the handoff format, raised whenever a field changes meaning.
+pub const HANDOFF_VERSION: u32 = 1;
+
+/// The state a daemon hands over to the binary it `exec`s.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Handoff {
+    pub version: u32,
+    /// File descriptor of the listening control socket, inherited across the `exec`, or `None` if
+    /// the previous daemon could not bind it.
+    pub listener_fd: Option<RawFd>,
+    pub paused: bool,
+    pub schedule: Schedule,
+    pub leases: LeaseSnapshot,
+    /// Threshold, hysteresis, and cooldown changes made with `set`, and the command-line overrides.
+    pub overrides: ProfileOverrides,
+    /// The CPUs online when the first daemon of the chain started, restored by `--on-exit restore`.
+    pub initial_online: BTreeSet<usize>,
+    /// The calibrated noise floor, if calibration had completed.
+    pub noise_floor: Option<f64>,
+}
+
+impl Handoff {
+    /// Reads the handoff left by the previous daemon, if this process was started by one.
+    ///
+    /// # Returns
+    /// * `io::Result<Option<Handoff>>` - The handoff, `None` on a regular start, or an error if the
+    ///   handoff cannot be parsed or comes from an incompatible version.
+    pub fn from_env() -> io::Result<Option<Handoff>> {
+        let Ok(blob) = std::env::var(HANDOFF_ENV) else {
+            return Ok(None);
+        };
+        let handoff: Handoff = serde_json::from_str(&blob)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if handoff.version != HANDOFF_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "handoff version {} is not supported, expected {}",
+                    handoff.version, HANDOFF_VERSION
+                ),
+            ));
+        }
+        Ok(Some(handoff))
+    }
+
+    /// Takes over the control socket listener inherited from the previous daemon, if any, and marks
+    /// it close-on-exec again so that it is not leaked to other programs.
+    pub fn listener(&self) -> Option<io::Result<UnixListener>> {
+        let fd = self.listener_fd?;
+        Some(set_cloexec(fd, true).and_then(|()| {
+            // Safety: the previous daemon passed this descriptor for us alone to take over.
+            let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            UnixListener::from_std(listener)
+        }))
+    }
+
+    /// Restores the pause, the schedule, and the leases of the previous daemon into `control`.
+    pub fn restore(&self, control: &ControlState) {
+        control.set_paused(self.paused);
+        control.schedule.send_replace(self.schedule.clone());
+        *control.leases.lock().unwrap() = LeaseTable::restore(self.leases.clone(), Instant::now());
+    }
+
+    /// Captures the pause, the schedule, and the leases of `control` into this handoff.
+    pub fn capture(&mut self, control: &ControlState) {
+        self.version = HANDOFF_VERSION;
+        self.paused = *control.paused.borrow();
+        self.schedule = control.schedule.borrow().clone();
+        self.leases = control.leases.lock().unwrap().snapshot(Instant::now());
+    }
+
+    /// Replaces this process with `exe`, run with the same arguments, passing it `listener`, if
+    /// any, and this handoff.
+    ///
+    /// # Returns
+    /// * `io::Error` - Only returns if the `exec` failed, with the reason.
+    pub fn exec(mut self, exe: &Path, listener: Option<&OwnedFd>) -> io::Error {
+        self.listener_fd = listener.map(|listener| listener.as_raw_fd());
+        if let Some(fd) = self.listener_fd {
+            if let Err(e) = set_cloexec(fd, false) {
+                return e;
+            }
+        }
+        let blob = match serde_json::to_string(&self) {
+            Ok(blob) => blob,
+            Err(e) => return io::Error::other(e),
+        };
+        info!("Handing over to {:?}", exe);
+        Command::new(exe)
+            .args(std::env::args_os().skip(1))
+            .env(HANDOFF_ENV, blob)
+            .exec()
+    }
+}
+
+/// Sets or clears the close-on-exec flag of `fd`.
+fn set_cloexec(fd: RawFd, cloexec: bool) -> io::Result<()> {
+    let flags = if cloexec { libc::FD_CLOEXEC } else { 0 };
+    // Safety: F_SETFD only changes the descriptor flags of `fd`.
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schedule::PlanEntry;
+    use std::time::Duration;
+
+    #[test]
+    fn carries_the_control_state_over() {
+        let control = ControlState::new();
+        control.set_paused(true);
+        control.schedule.send_replace(Schedule::new(vec![PlanEntry {
+            from: 100,
+            min_cpus: 4,
+        }]));
+        let id = control
+            .leases
+            .lock()
+            .unwrap()
+            .acquire("batch", 2, Duration::from_secs(60));
+
+        let mut handoff = Handoff::default();
+        handoff.capture(&control);
+        let blob = serde_json::to_string(&handoff).unwrap();
+        let handoff: Handoff = serde_json::from_str(&blob).unwrap();
+        assert_eq!(handoff.version, HANDOFF_VERSION);
+
+        let restored = ControlState::new();
+        handoff.restore(&restored);
+        assert!(*restored.paused.borrow());
+        assert_eq!(*restored.schedule.borrow(), *control.schedule.borrow());
+        let leases = restored.leases.lock().unwrap();
+        assert_eq!(leases.floor(), 2);
+        let lease = &leases.leases(Instant::now())[0];
+        assert_eq!((lease.id, lease.client.as_str()), (id, "batch"));
+        assert!(lease.expires_in_secs >= 58);
+    }
+}
@@ -0,0 +1,55 @@
+//! Minimal HTTP/1.1 client for the push-style outputs: the InfluxDB write API, webhooks, the vCPU
+//! companion, the Kubernetes API server, and the Prometheus Pushgateway. Plain `http://` only;
+//! TLS is left to a local proxy.
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Splits an `http://host:port/path` URL into its host and path, the latter `/` if absent.
+pub fn split_url(url: &str) -> Result<(String, String), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("unsupported URL {:?}: expected http://", url))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(format!("URL {:?} has no host", url));
+    }
+    Ok((host.to_string(), path.to_string()))
+}
+
+/// Sends `body` to `path` on `host` with `method` and fails unless the response status is 2xx.
+pub async fn send(
+    method: &str,
+    host: &str,
+    path: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    let mut stream = TcpStream::connect(host).await?;
+    let head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        method,
+        path,
+        host,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let status = response.split_whitespace().nth(1).unwrap_or("");
+    if status.starts_with('2') {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "unexpected response: {}",
+            response.lines().next().unwrap_or("")
+        )))
+    }
+}
@@ -0,0 +1,238 @@
+//! Export of per-cycle metrics in the InfluxDB line protocol.
+//!
+//! Lines are handed to a background task over a drop-oldest queue, so a slow or unreachable
+//! endpoint never delays the control loop.
+//!
+//! Supported endpoints:
+//! - `udp://host:port` for the InfluxDB UDP listener or Telegraf `socket_listener`.
+//! - `http://host:port/path?query` for the HTTP write API, e.g.
+//!   `http://localhost:8086/write?db=telegraf` or `http://localhost:8086/api/v2/write?org=o&bucket=b`.
+use crate::http;
+use crate::queue::{self, QueueSender};
+use crate::topology::SystemTopology;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Write as _};
+use std::io;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// Number of batches buffered before the oldest ones are dropped.
+const QUEUE_LEN: usize = 16;
+
+/// Longest time spent writing the queued batches on shutdown.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum InfluxUrl {
+    Udp { addr: String },
+    Http { host: String, path: String },
+}
+
+impl FromStr for InfluxUrl {
+    type Err = String;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        if let Some(addr) = url.strip_prefix("udp://") {
+            Ok(InfluxUrl::Udp {
+                addr: addr.trim_end_matches('/').to_string(),
+            })
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            let (host, path) = match rest.find('/') {
+                Some(i) => (&rest[..i], &rest[i..]),
+                None => (rest, "/write"),
+            };
+            Ok(InfluxUrl::Http {
+                host: host.to_string(),
+                path: path.to_string(),
+            })
+        } else {
+            Err(format!(
+                "unsupported InfluxDB URL {:?}: expected udp:// or http://",
+                url
+            ))
+        }
+    }
+}
+
+impl TryFrom<String> for InfluxUrl {
+    type Error = String;
+
+    fn try_from(url: String) -> Result<Self, Self::Error> {
+        url.parse()
+    }
+}
+
+impl From<InfluxUrl> for String {
+    fn from(url: InfluxUrl) -> Self {
+        url.to_string()
+    }
+}
+
+impl fmt::Display for InfluxUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InfluxUrl::Udp { addr } => write!(f, "udp://{}", addr),
+            InfluxUrl::Http { host, path } => write!(f, "http://{}{}", host, path),
+        }
+    }
+}
+
+/// Handle used by the control loop to queue line-protocol batches for export.
+pub struct InfluxSink {
+    tx: QueueSender<String>,
+    host: String,
+    writer: JoinHandle<()>,
+}
+
+impl InfluxSink {
+    /// Starts the background writer task for `url` and returns a handle to it.
+    pub fn spawn(url: InfluxUrl) -> Self {
+        let (tx, mut rx) = queue::channel::<String>("influx", QUEUE_LEN);
+        let host = hostname();
+        info!(
+            "Exporting metrics to InfluxDB at {:?} as host {}",
+            url, host
+        );
+        let writer = tokio::spawn(async move {
+            while let Some(batch) = rx.recv().await {
+                if let Err(e) = write(&url, &batch).await {
+                    warn!("InfluxDB write failed: {}", e);
+                }
+            }
+        });
+        InfluxSink { tx, host, writer }
+    }
+
+    /// Stops accepting batches and waits up to `FLUSH_TIMEOUT` for the queued ones to be written.
+    pub async fn close(self) {
+        drop(self.tx);
+        if tokio::time::timeout(FLUSH_TIMEOUT, self.writer)
+            .await
+            .is_err()
+        {
+            warn!(
+                "Gave up flushing InfluxDB metrics after {:?}",
+                FLUSH_TIMEOUT
+            );
+        }
+    }
+
+    /// Queues the metrics of the current cycle without waiting for the endpoint.
+    pub fn send(&self, topology: &SystemTopology) {
+        let batch = encode(topology, &self.host, SystemTime::now());
+        self.tx.push(batch);
+    }
+}
+
+/// Encodes the aggregate and per-CPU metrics of a cycle as line protocol.
+pub fn encode(topology: &SystemTopology, host: &str, now: SystemTime) -> String {
+    let timestamp = now
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let host = escape_tag(host);
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "cpu_on_off,host={} avg_c0={},online_cpus={}i,total_cpus={}i {}",
+        host,
+        topology.average_c0(),
+        topology.online_count(),
+        topology.cpus.len(),
+        timestamp
+    );
+
+    let mut ids: Vec<&usize> = topology.cpus.keys().collect();
+    ids.sort();
+    for id in ids {
+        let cpu = &topology.cpus[id];
+        let _ = write!(out, "cpu_on_off_cpu,host={},cpu={}", host, cpu.id);
+        if let Some(socket_id) = cpu.socket_id {
+            let _ = write!(out, ",socket={}", socket_id);
+        }
+        let _ = writeln!(
+            out,
+            " c0={},online={} {}",
+            cpu.c0_percentage, cpu.online, timestamp
+        );
+    }
+
+    out
+}
+
+async fn write(url: &InfluxUrl, batch: &str) -> io::Result<()> {
+    match url {
+        InfluxUrl::Udp { addr } => {
+            let socket = UdpSocket::bind("0.0.0.0:0").await?;
+            socket.send_to(batch.as_bytes(), addr.as_str()).await?;
+            Ok(())
+        }
+        InfluxUrl::Http { host, path } => {
+            http::send(
+                "POST",
+                host,
+                path,
+                "text/plain; charset=utf-8",
+                batch.as_bytes(),
+            )
+            .await
+        }
+    }
+}
+
+pub(crate) fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|s| s.trim().to_string())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Escapes commas, equals signs, and spaces, which are special in tag values.
+fn escape_tag(value: &str) -> String {
+    value
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn parses_udp_and_http_urls() {
+        assert_eq!(
+            "udp://localhost:8089".parse(),
+            Ok(InfluxUrl::Udp {
+                addr: "localhost:8089".to_string()
+            })
+        );
+        assert_eq!(
+            "http://localhost:8086/write?db=telegraf".parse(),
+            Ok(InfluxUrl::Http {
+                host: "localhost:8086".to_string(),
+                path: "/write?db=telegraf".to_string()
+            })
+        );
+        assert!("https://localhost:8086".parse::<InfluxUrl>().is_err());
+        let url: InfluxUrl = "http://localhost:8086/write?db=telegraf".parse().unwrap();
+        assert_eq!(url.to_string(), "http://localhost:8086/write?db=telegraf");
+    }
+
+    #[test]
+    fn encodes_aggregate_and_per_cpu_lines() {
+        let mut topology = SystemTopology::single_thread_cores(&[true]);
+        topology.cpus.get_mut(&0).unwrap().c0_percentage = 12.5;
+        let lines = encode(&topology, "my host", UNIX_EPOCH + Duration::from_secs(1));
+        assert_eq!(
+            lines,
+            "cpu_on_off,host=my\\ host avg_c0=12.5,online_cpus=1i,total_cpus=1i 1000000000\n\
+             cpu_on_off_cpu,host=my\\ host,cpu=0,socket=0 c0=12.5,online=true 1000000000\n"
+        );
+    }
+}
@@ -0,0 +1,83 @@
+//! Inhibitors through which external processes freeze the CPU configuration, after the
+//! semantics of systemd inhibitor locks.
+//!
+//! An inhibitor is tied to the control connection that took it and is released when that
+//! connection closes, so a backup job or benchmark that exits or crashes cannot leave the CPUs
+//! frozen. While any inhibitor is held, the control loop keeps sampling the load but neither onlines
+//! nor offlines CPUs, and manual hotplug requests are refused.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+#[derive(Clone, Debug)]
+pub struct Inhibitor {
+    pub id: u64,
+    /// The program or service holding the inhibitor, e.g. `nightly-backup`.
+    pub who: String,
+    /// Why the CPU configuration must not change, e.g. `benchmark run`.
+    pub why: String,
+    pub since: Instant,
+}
+
+/// Inhibitor as reported by the `status` command.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InhibitorStatus {
+    pub id: u64,
+    pub who: String,
+    pub why: String,
+    pub held_secs: u64,
+}
+
+#[derive(Default)]
+pub struct InhibitorTable {
+    next_id: u64,
+    inhibitors: BTreeMap<u64, Inhibitor>,
+}
+
+impl InhibitorTable {
+    /// Takes a new inhibitor and returns its ID.
+    pub fn take(&mut self, who: &str, why: &str) -> u64 {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.inhibitors.insert(
+            id,
+            Inhibitor {
+                id,
+                who: who.to_string(),
+                why: why.to_string(),
+                since: Instant::now(),
+            },
+        );
+        id
+    }
+
+    /// Releases an inhibitor and returns it, or `None` if it does not exist.
+    pub fn release(&mut self, id: u64) -> Option<Inhibitor> {
+        self.inhibitors.remove(&id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inhibitors.is_empty()
+    }
+
+    /// Returns the holders of the inhibitors, e.g. `nightly-backup (backup), bench (benchmark run)`.
+    pub fn holders(&self) -> String {
+        self.inhibitors
+            .values()
+            .map(|inhibitor| format!("{} ({})", inhibitor.who, inhibitor.why))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    pub fn inhibitors(&self, now: Instant) -> Vec<InhibitorStatus> {
+        self.inhibitors
+            .values()
+            .map(|inhibitor| InhibitorStatus {
+                id: inhibitor.id,
+                who: inhibitor.who.clone(),
+                why: inhibitor.why.clone(),
+                held_secs: now.saturating_duration_since(inhibitor.since).as_secs(),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,216 @@
+//! Retargeting of IRQs away from the CPUs about to be offlined.
+//!
+//! When a CPU goes offline, the kernel moves its interrupts to another CPU of its own choosing in
+//! the middle of the hotplug, and interrupts that could only be delivered there, e.g. a NIC queue
+//! affinitized by `irqbalance` or by hand, stall until then. With `--migrate-irqs`, every IRQ
+//! listed in `/proc/interrupts` whose `/proc/irq/<n>/smp_affinity_list` contains none of the CPUs
+//! that stay online is retargeted to them beforehand. With `--restore-irqs`, the original affinity
+//! is written back once all of its CPUs are online again.
+use crate::cpu_list::CpuList;
+use crate::stickiness::PROC_DIR;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::PathBuf;
+use tracing::{debug, info};
+
+/// An IRQ listed in `/proc/interrupts`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Irq {
+    pub number: u32,
+    /// The name of the last device action registered on the IRQ, e.g. `eth0-TxRx-0`.
+    pub name: String,
+}
+
+/// Parses `/proc/interrupts` into its numbered IRQs, skipping the architecture's special ones
+/// such as `NMI` and `LOC`.
+pub fn parse_interrupts(content: &str) -> Vec<Irq> {
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let (number, rest) = line.trim_start().split_once(':')?;
+            let number = number.parse().ok()?;
+            let name = rest.split_whitespace().last().unwrap_or_default();
+            Some(Irq {
+                number,
+                name: name.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Moves IRQs away from the CPUs about to be offlined and remembers where they came from.
+pub struct IrqMigrator {
+    proc_dir: PathBuf,
+    /// The original affinity of each IRQ moved away, until it is restored.
+    moved: BTreeMap<u32, CpuList>,
+}
+
+impl Default for IrqMigrator {
+    fn default() -> Self {
+        Self::new(PROC_DIR)
+    }
+}
+
+impl IrqMigrator {
+    /// Creates a migrator reading `interrupts` and `irq` below `proc_dir`.
+    pub fn new(proc_dir: impl Into<PathBuf>) -> Self {
+        IrqMigrator {
+            proc_dir: proc_dir.into(),
+            moved: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the IRQs moved away and their original affinity.
+    pub fn moved(&self) -> &BTreeMap<u32, CpuList> {
+        &self.moved
+    }
+
+    /// Retargets the IRQs that may not be delivered to any of `targets`, the CPUs that stay online,
+    /// to `targets`.
+    ///
+    /// This function performs the following steps:
+    /// 1. Reads the IRQs from `/proc/interrupts`.
+    /// 2. Reads the `smp_affinity_list` of each and skips the ones with a CPU in `targets`.
+    /// 3. Writes `targets` as the new affinity, remembering the original one unless the IRQ was
+    ///    already moved before. IRQs whose affinity the kernel manages itself reject the write,
+    ///    which is logged at the debug level.
+    ///
+    /// # Returns
+    /// * `io::Result<usize>` - The number of IRQs moved, or an error if `/proc/interrupts` cannot be read.
+    pub async fn migrate(&mut self, targets: &BTreeSet<usize>) -> io::Result<usize> {
+        if targets.is_empty() {
+            return Ok(0);
+        }
+        let target_list = CpuList(targets.clone());
+        let mut count = 0;
+        for irq in self.interrupts().await? {
+            let Some(affinity) = self.affinity(irq.number).await else {
+                continue;
+            };
+            if affinity.is_empty() || !affinity.0.is_disjoint(targets) {
+                continue;
+            }
+            match self.set_affinity(irq.number, &target_list).await {
+                Ok(()) => {
+                    info!(
+                        "Moved IRQ {} ({}) from CPUs {} to CPUs {}",
+                        irq.number, irq.name, affinity, target_list
+                    );
+                    self.moved.entry(irq.number).or_insert(affinity);
+                    count += 1;
+                }
+                Err(e) => debug!(
+                    "Cannot move IRQ {} ({}) from CPUs {}: {}",
+                    irq.number, irq.name, affinity, e
+                ),
+            }
+        }
+        Ok(count)
+    }
+
+    /// Writes back the original affinity of the moved IRQs whose CPUs are all in `online`.
+    ///
+    /// # Returns
+    /// * `usize` - The number of IRQs restored.
+    pub async fn restore(&mut self, online: &BTreeSet<usize>) -> usize {
+        let ready: Vec<(u32, CpuList)> = self
+            .moved
+            .iter()
+            .filter(|(_, original)| original.0.is_subset(online))
+            .map(|(&number, original)| (number, original.clone()))
+            .collect();
+        let mut count = 0;
+        for (number, original) in ready {
+            self.moved.remove(&number);
+            match self.set_affinity(number, &original).await {
+                Ok(()) => {
+                    info!("Restored IRQ {} to CPUs {}", number, original);
+                    count += 1;
+                }
+                Err(e) => debug!("Cannot restore IRQ {} to CPUs {}: {}", number, original, e),
+            }
+        }
+        count
+    }
+
+    async fn interrupts(&self) -> io::Result<Vec<Irq>> {
+        let content = tokio::fs::read_to_string(self.proc_dir.join("interrupts")).await?;
+        Ok(parse_interrupts(&content))
+    }
+
+    async fn affinity(&self, number: u32) -> Option<CpuList> {
+        let path = self
+            .proc_dir
+            .join(format!("irq/{}/smp_affinity_list", number));
+        tokio::fs::read_to_string(path)
+            .await
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    async fn set_affinity(&self, number: u32, cpus: &CpuList) -> io::Result<()> {
+        let path = self
+            .proc_dir
+            .join(format!("irq/{}/smp_affinity_list", number));
+        tokio::fs::write(path, cpus.to_string()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const INTERRUPTS: &str = "            CPU0       CPU1       CPU2       CPU3
+   0:         44          0          0          0   IO-APIC    2-edge      timer
+  24:          0          0       1200         30   PCI-MSI 524288-edge      eth0-TxRx-0
+  25:          0          0          0       4100   PCI-MSI 524289-edge      eth0-TxRx-1
+ NMI:          0          0          0          0   Non-maskable interrupts
+ LOC:       1234       1234       1234       1234   Local timer interrupts
+";
+
+    #[test]
+    fn parses_numbered_interrupts() {
+        let irqs = parse_interrupts(INTERRUPTS);
+        let numbers: Vec<u32> = irqs.iter().map(|irq| irq.number).collect();
+        assert_eq!(numbers, [0, 24, 25]);
+        assert_eq!(irqs[1].name, "eth0-TxRx-0");
+    }
+
+    #[tokio::test]
+    async fn moves_irqs_away_and_back() {
+        let root = std::env::temp_dir().join(format!("cpu-on-off-irq-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for (number, affinity) in [(0, "0-3"), (24, "2-3"), (25, "3")] {
+            let dir = root.join(format!("irq/{}", number));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("smp_affinity_list"), format!("{}\n", affinity)).unwrap();
+        }
+        fs::write(root.join("interrupts"), INTERRUPTS).unwrap();
+        let affinity = |number: u32| {
+            fs::read_to_string(root.join(format!("irq/{}/smp_affinity_list", number))).unwrap()
+        };
+
+        let mut migrator = IrqMigrator::new(&root);
+        let moved = migrator.migrate(&BTreeSet::from([0, 1, 2])).await;
+        assert_eq!(moved.unwrap(), 1);
+        assert_eq!(affinity(25), "0,1,2");
+        assert_eq!(affinity(24).trim(), "2-3");
+
+        let moved = migrator.migrate(&BTreeSet::from([0, 1])).await;
+        assert_eq!(moved.unwrap(), 1);
+        assert_eq!(affinity(24), "0,1");
+        // The original affinity is kept from the first move.
+        assert_eq!(migrator.moved()[&25].to_string(), "3");
+
+        assert_eq!(migrator.restore(&BTreeSet::from([0, 1, 2])).await, 0);
+        assert_eq!(migrator.restore(&BTreeSet::from([0, 1, 2, 3])).await, 2);
+        assert_eq!(affinity(24), "2,3");
+        assert_eq!(affinity(25), "3");
+        assert!(migrator.moved().is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
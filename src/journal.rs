@@ -0,0 +1,116 @@
+//! Intent journal of the hotplug writes.
+//!
+//! Before writing a CPU's `online` file, the daemon records which CPU it is about to online or
+//! offline, and it clears the record once it has read the new state back. If the daemon dies in
+//! between, e.g. killed by the OOM killer or a watchdog during a slow hotplug, the record survives,
+//! and the next start reconciles the interrupted operation against the state sysfs reports instead
+//! of assuming it either did or did not happen.
+//!
+//! The journal is a single JSON object, replaced atomically by writing a temporary file and
+//! renaming it, e.g. `{"cpu":3,"direction":"offline","pid":4242,"ts":1760536800.25}`. The default
+//! location below `/run` is cleared on reboot, along with the hotplug state it describes.
+use crate::metrics::Direction;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub static DEFAULT_HOTPLUG_JOURNAL: &str = "/run/cpu-on-off-hotplug.json";
+
+/// A hotplug write that was started but not yet verified.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Intent {
+    pub cpu: usize,
+    pub direction: Direction,
+    /// The process that started the write.
+    pub pid: u32,
+    /// Seconds since the UNIX epoch.
+    pub ts: f64,
+}
+
+impl Intent {
+    pub fn now(cpu: usize, direction: Direction) -> Self {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        Intent {
+            cpu,
+            direction,
+            pid: std::process::id(),
+            ts,
+        }
+    }
+}
+
+/// The journal file at a given path.
+#[derive(Clone, Debug)]
+pub struct HotplugJournal {
+    path: PathBuf,
+}
+
+impl HotplugJournal {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        HotplugJournal { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Records `intent` durably before the write it describes, replacing any earlier record.
+    pub fn begin(&self, intent: &Intent) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp)?;
+        serde_json::to_writer(&mut file, intent)?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+
+    /// Removes the record once the write has been verified.
+    pub fn clear(&self) -> io::Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the record left behind by a write that was never verified, if any.
+    pub fn pending(&self) -> io::Result<Option<Intent>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        serde_json::from_str(&content)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_intent_until_cleared() {
+        let path =
+            std::env::temp_dir().join(format!("cpu-on-off-journal-{}.json", std::process::id()));
+        let journal = HotplugJournal::new(&path);
+        assert_eq!(journal.pending().unwrap(), None);
+
+        let intent = Intent::now(3, Direction::Offline);
+        journal.begin(&intent).unwrap();
+        let pending = journal.pending().unwrap().unwrap();
+        assert_eq!((pending.cpu, pending.direction), (3, Direction::Offline));
+        assert_eq!(pending.pid, intent.pid);
+        // serde_json may round the last bit of a float when parsing it back.
+        assert!((pending.ts - intent.ts).abs() < 1e-3);
+
+        journal.clear().unwrap();
+        journal.clear().unwrap();
+        assert_eq!(journal.pending().unwrap(), None);
+    }
+}
@@ -0,0 +1,214 @@
+//! Publishes the online CPUs on the Kubernetes node object, for `--kube-api`.
+//!
+//! The scheduler places pods by the CPUs the node reports, so parked cores would otherwise still
+//! attract pods. Whenever the online CPUs change, the CPU manager patches the node through the API
+//! server, reached over plain HTTP through a local proxy such as `kubectl proxy`:
+//!
+//! ```text
+//! PATCH /api/v1/nodes/worker-1 HTTP/1.1
+//! Content-Type: application/merge-patch+json
+//!
+//! {"metadata":{"labels":{"cpu-on-off-rust/online-cpus":"12"},
+//!  "annotations":{"cpu-on-off-rust/offline-cpus":"6,7,14,15"}}}
+//! ```
+//!
+//! The node's `status`, allocatable CPUs included, is left alone: the kubelet owns it, subtracts
+//! its reservations, and rewrites it on every status update.
+use crate::cpu_list::CpuList;
+use crate::http;
+use crate::queue::{self, QueueSender};
+use crate::topology::SystemTopology;
+use serde_json::json;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Label carrying the number of online CPUs, for node selectors and affinities.
+pub const ONLINE_LABEL: &str = "cpu-on-off-rust/online-cpus";
+
+/// Annotation carrying the list of offline CPUs.
+pub const OFFLINE_ANNOTATION: &str = "cpu-on-off-rust/offline-cpus";
+
+/// Number of patches buffered before the oldest ones are dropped; only the latest one matters.
+const QUEUE_LEN: usize = 4;
+
+/// Time between two attempts to deliver a patch the API server did not accept.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Longest time spent delivering the last patch on shutdown.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The CPUs of the node as published on the node object.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeCpus {
+    /// The number of online CPUs.
+    pub online: usize,
+    /// The offline CPUs, by ID.
+    pub offline: CpuList,
+}
+
+impl NodeCpus {
+    pub fn new(topology: &SystemTopology) -> Self {
+        NodeCpus {
+            online: topology.online_count(),
+            offline: CpuList(
+                topology
+                    .cpus
+                    .values()
+                    .filter(|cpu| !cpu.online)
+                    .map(|cpu| cpu.id)
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Returns the merge patch of the node's labels and annotations.
+    pub fn metadata_patch(&self) -> serde_json::Value {
+        json!({
+            "metadata": {
+                "labels": { ONLINE_LABEL: self.online.to_string() },
+                "annotations": { OFFLINE_ANNOTATION: self.offline.to_string() },
+            }
+        })
+    }
+}
+
+/// Handle used by the control loop to queue patches of the node object.
+pub struct KubeNode {
+    tx: QueueSender<NodeCpus>,
+    /// The last CPUs queued, so an unchanged patch is not sent again.
+    last: Option<NodeCpus>,
+    writer: JoinHandle<()>,
+}
+
+impl KubeNode {
+    /// Starts the background task patching the node `node`, the host name if `None`, through the
+    /// API server at `url`, an `http://host:port` URL, and returns a handle to it. A patch the API
+    /// server does not accept is tried again every `RETRY_INTERVAL` until a newer one replaces it.
+    pub fn spawn(url: &str, node: Option<&str>) -> Result<Self, String> {
+        let (host, prefix) = http::split_url(url)?;
+        let node = node.map_or_else(crate::influx::hostname, str::to_string);
+        let path = format!("{}/api/v1/nodes/{}", prefix.trim_end_matches('/'), node);
+        let (tx, mut rx) = queue::channel::<NodeCpus>("kube", QUEUE_LEN);
+        info!("Publishing the online CPUs on the Kubernetes node {}", node);
+        let writer = tokio::spawn(async move {
+            let mut pending = rx.recv().await;
+            while let Some(cpus) = pending.take() {
+                match patch(&host, &path, &cpus).await {
+                    Ok(()) => {
+                        debug!(
+                            "Patched the node with {} online CPUs, {} offline",
+                            cpus.online, cpus.offline
+                        );
+                        pending = rx.recv().await;
+                    }
+                    Err(e) => {
+                        warn!("The Kubernetes API server did not accept the patch: {}", e);
+                        pending = tokio::select! {
+                            newer = rx.recv() => newer,
+                            _ = tokio::time::sleep(RETRY_INTERVAL) => Some(cpus),
+                        };
+                    }
+                }
+            }
+        });
+        Ok(KubeNode {
+            tx,
+            last: None,
+            writer,
+        })
+    }
+
+    /// Queues a patch describing `topology` if it differs from the last one, without waiting for
+    /// the API server.
+    pub fn update(&mut self, topology: &SystemTopology) {
+        let cpus = NodeCpus::new(topology);
+        if self.last.as_ref() == Some(&cpus) {
+            return;
+        }
+        self.last = Some(cpus.clone());
+        self.tx.push(cpus);
+    }
+
+    /// Stops accepting patches and waits up to `FLUSH_TIMEOUT` for the last one to be delivered.
+    pub async fn close(self) {
+        drop(self.tx);
+        if tokio::time::timeout(FLUSH_TIMEOUT, self.writer)
+            .await
+            .is_err()
+        {
+            warn!("Gave up patching the node after {:?}", FLUSH_TIMEOUT);
+        }
+    }
+}
+
+/// Sends the metadata patch of `cpus` to the node at `path`.
+async fn patch(host: &str, path: &str, cpus: &NodeCpus) -> std::io::Result<()> {
+    const MERGE_PATCH: &str = "application/merge-patch+json";
+    let body = cpus.metadata_patch().to_string();
+    http::send("PATCH", host, path, MERGE_PATCH, body.as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn patches_the_online_count_and_offline_list() {
+        let cpus = NodeCpus::new(&SystemTopology::single_thread_cores(&[
+            true, true, false, false,
+        ]));
+        assert_eq!(
+            cpus.metadata_patch(),
+            json!({
+                "metadata": {
+                    "labels": { "cpu-on-off-rust/online-cpus": "2" },
+                    "annotations": { "cpu-on-off-rust/offline-cpus": "2,3" },
+                }
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn patches_the_node_once_per_change() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 1024];
+                loop {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                    let text = String::from_utf8_lossy(&request);
+                    if text.ends_with('}') || read == 0 {
+                        break;
+                    }
+                }
+                stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+                let text = String::from_utf8(request).unwrap();
+                requests.push(text.lines().next().unwrap().to_string());
+                if requests.len() == 2 {
+                    return requests;
+                }
+            }
+            requests
+        });
+        let mut node = KubeNode::spawn(&url, Some("worker-1")).unwrap();
+        node.update(&SystemTopology::single_thread_cores(&[true, false]));
+        // Unchanged, so not sent again.
+        node.update(&SystemTopology::single_thread_cores(&[true, false]));
+        node.update(&SystemTopology::single_thread_cores(&[true, true]));
+        node.close().await;
+        assert_eq!(
+            server.await.unwrap(),
+            [
+                "PATCH /api/v1/nodes/worker-1 HTTP/1.1",
+                "PATCH /api/v1/nodes/worker-1 HTTP/1.1",
+            ]
+        );
+    }
+}
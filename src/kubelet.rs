@@ -0,0 +1,91 @@
+//! The CPUs the kubelet's static CPU manager assigned exclusively to containers, read from its
+//! `cpu_manager_state` checkpoint.
+//!
+//! With `--cpu-manager-policy=static`, the kubelet pins the containers of Guaranteed pods with
+//! integer CPU requests to CPUs of their own and records the assignments in the checkpoint:
+//!
+//! ```text
+//! {"policyName":"static","defaultCpuSet":"0,5-15","entries":{"<pod UID>":{"app":"1-4"}},"checksum":1}
+//! ```
+//!
+//! Offlining one of those CPUs would silently move the container onto the shared CPUs and break
+//! the kubelet's bookkeeping, so the CPU manager holds them online, see `SystemTopology::held`.
+use crate::cpu_list::CpuList;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+/// Where the kubelet keeps the CPU manager checkpoint by default.
+pub const CPU_MANAGER_STATE: &str = "/var/lib/kubelet/cpu_manager_state";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Checkpoint {
+    #[serde(default)]
+    entries: BTreeMap<String, Value>,
+}
+
+/// Extracts the CPUs assigned to containers from the contents of a `cpu_manager_state`
+/// checkpoint: the union of the CPU lists in `entries`, keyed by pod UID and container name, or
+/// by container ID alone in the checkpoints of kubelets before 1.18. The `none` policy leaves
+/// `entries` empty.
+pub fn parse_cpu_manager_state(content: &str) -> Result<CpuList, String> {
+    let checkpoint: Checkpoint = serde_json::from_str(content).map_err(|e| e.to_string())?;
+    let mut cpus = CpuList::default();
+    let mut add = |value: &Value| -> Result<(), String> {
+        let list = value
+            .as_str()
+            .ok_or_else(|| format!("unexpected CPU set {}", value))?;
+        cpus.0.extend(list.parse::<CpuList>()?.0);
+        Ok(())
+    };
+    for entry in checkpoint.entries.values() {
+        match entry {
+            Value::Object(containers) => containers.values().try_for_each(&mut add)?,
+            value => add(value)?,
+        }
+    }
+    Ok(cpus)
+}
+
+/// Reads the CPUs assigned exclusively to containers from the checkpoint at `path`.
+///
+/// # Returns
+/// * `io::Result<Option<CpuList>>` - The assigned CPUs, or `None` if there is no checkpoint, i.e.
+///   no kubelet runs on the machine.
+pub async fn exclusive_cpus(path: &Path) -> io::Result<Option<CpuList>> {
+    let content = match tokio::fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    parse_cpu_manager_state(&content)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collects_the_cpus_of_every_container() {
+        let state = r#"{"policyName":"static","defaultCpuSet":"0,7-15",
+            "entries":{"6b1f":{"app":"1-2","sidecar":"5"},"9c4e":{"db":"3-4"}},"checksum":1}"#;
+        assert_eq!(
+            parse_cpu_manager_state(state).unwrap().to_string(),
+            "1,2,3,4,5"
+        );
+        let before_1_18 = r#"{"policyName":"static","defaultCpuSet":"0,3-7",
+            "entries":{"docker://ab12":"1-2"},"checksum":1}"#;
+        assert_eq!(
+            parse_cpu_manager_state(before_1_18).unwrap().to_string(),
+            "1,2"
+        );
+        let none = r#"{"policyName":"none","defaultCpuSet":"","checksum":1}"#;
+        assert!(parse_cpu_manager_state(none).unwrap().is_empty());
+        assert!(parse_cpu_manager_state(r#"{"entries":{"x":{"y":7}}}"#).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+//! Leases through which external services reserve a minimum number of online CPUs.
+//!
+//! Each lease carries the name of the client that took it and expires unless renewed before its
+//! TTL runs out, so a crashed client cannot pin CPUs online forever. The reservations of all active
+//! leases add up, since each client is expected to ask for the CPUs it needs for itself.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+pub struct Lease {
+    pub id: u64,
+    pub client: String,
+    pub min_cpus: usize,
+    pub expires: Instant,
+}
+
+/// Per-client accounting of leases.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ClientStats {
+    pub active_leases: usize,
+    pub reserved_cpus: usize,
+    pub granted: u64,
+    pub renewed: u64,
+    pub released: u64,
+    pub expired: u64,
+}
+
+/// Lease as reported by the `status` command.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LeaseStatus {
+    pub id: u64,
+    pub client: String,
+    pub min_cpus: usize,
+    pub expires_in_secs: u64,
+}
+
+/// The leases and client accounting carried over a binary upgrade, see `handoff`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LeaseSnapshot {
+    pub next_id: u64,
+    /// Active leases with the milliseconds left until they expire.
+    pub leases: Vec<(u64, String, usize, u64)>,
+    pub clients: BTreeMap<String, ClientStats>,
+}
+
+#[derive(Default)]
+pub struct LeaseTable {
+    next_id: u64,
+    leases: BTreeMap<u64, Lease>,
+    clients: BTreeMap<String, ClientStats>,
+}
+
+impl LeaseTable {
+    /// Grants a new lease and returns its ID.
+    pub fn acquire(&mut self, client: &str, min_cpus: usize, ttl: Duration) -> u64 {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.leases.insert(
+            id,
+            Lease {
+                id,
+                client: client.to_string(),
+                min_cpus,
+                expires: Instant::now() + ttl,
+            },
+        );
+        let stats = self.clients.entry(client.to_string()).or_default();
+        stats.granted += 1;
+        stats.active_leases += 1;
+        stats.reserved_cpus += min_cpus;
+        id
+    }
+
+    /// Extends a lease to expire `ttl` from now. Returns `false` if the lease does not exist.
+    pub fn renew(&mut self, id: u64, ttl: Duration) -> bool {
+        let Some(lease) = self.leases.get_mut(&id) else {
+            return false;
+        };
+        lease.expires = Instant::now() + ttl;
+        if let Some(stats) = self.clients.get_mut(&lease.client) {
+            stats.renewed += 1;
+        }
+        true
+    }
+
+    /// Releases a lease. Returns `false` if the lease does not exist.
+    pub fn release(&mut self, id: u64) -> bool {
+        let Some(lease) = self.leases.remove(&id) else {
+            return false;
+        };
+        let stats = self.remove_from_stats(&lease);
+        stats.released += 1;
+        true
+    }
+
+    /// Removes all leases that expired before `now` and returns them.
+    pub fn expire(&mut self, now: Instant) -> Vec<Lease> {
+        let expired_ids: Vec<u64> = self
+            .leases
+            .values()
+            .filter(|lease| lease.expires <= now)
+            .map(|lease| lease.id)
+            .collect();
+
+        let mut expired = Vec::new();
+        for id in expired_ids {
+            if let Some(lease) = self.leases.remove(&id) {
+                let stats = self.remove_from_stats(&lease);
+                stats.expired += 1;
+                expired.push(lease);
+            }
+        }
+        expired
+    }
+
+    /// Returns the number of CPUs reserved by all active leases.
+    pub fn floor(&self) -> usize {
+        self.leases.values().map(|lease| lease.min_cpus).sum()
+    }
+
+    pub fn leases(&self, now: Instant) -> Vec<LeaseStatus> {
+        self.leases
+            .values()
+            .map(|lease| LeaseStatus {
+                id: lease.id,
+                client: lease.client.clone(),
+                min_cpus: lease.min_cpus,
+                expires_in_secs: lease.expires.saturating_duration_since(now).as_secs(),
+            })
+            .collect()
+    }
+
+    pub fn clients(&self) -> &BTreeMap<String, ClientStats> {
+        &self.clients
+    }
+
+    /// Captures the leases, keeping their IDs, so that clients can keep renewing them.
+    pub fn snapshot(&self, now: Instant) -> LeaseSnapshot {
+        LeaseSnapshot {
+            next_id: self.next_id,
+            leases: self
+                .leases
+                .values()
+                .map(|lease| {
+                    let left = lease.expires.saturating_duration_since(now);
+                    (
+                        lease.id,
+                        lease.client.clone(),
+                        lease.min_cpus,
+                        left.as_millis() as u64,
+                    )
+                })
+                .collect(),
+            clients: self.clients.clone(),
+        }
+    }
+
+    /// Rebuilds a table from a snapshot taken by `snapshot`.
+    pub fn restore(snapshot: LeaseSnapshot, now: Instant) -> Self {
+        let leases = snapshot
+            .leases
+            .into_iter()
+            .map(|(id, client, min_cpus, left_ms)| {
+                let expires = now + Duration::from_millis(left_ms);
+                (
+                    id,
+                    Lease {
+                        id,
+                        client,
+                        min_cpus,
+                        expires,
+                    },
+                )
+            })
+            .collect();
+        LeaseTable {
+            next_id: snapshot.next_id,
+            leases,
+            clients: snapshot.clients,
+        }
+    }
+
+    fn remove_from_stats(&mut self, lease: &Lease) -> &mut ClientStats {
+        let stats = self.clients.entry(lease.client.clone()).or_default();
+        stats.active_leases -= 1;
+        stats.reserved_cpus -= lease.min_cpus;
+        stats
+    }
+}
@@ -0,0 +1,72 @@
+//! Library for managing CPU states (online/offline) based on system load thresholds.
+//! It uses `tokio` for asynchronous operations; the `cpu-on-off-rust` binary is a thin
+//! command-line wrapper around it.
+//!
+//! # Overview
+//! - `topology`: `SystemTopology` scans the CPUs from sysfs and selects which ones to online or
+//!   offline next, through an `ActuationBackend` such as hotplug or a cgroup cpuset.
+//! - `load`: The `LoadMetric`s that sample the CPUs' load each cycle.
+//! - `manager`: `CpuManager` runs the control loop, turning the load into decisions under the
+//!   profile, the floor, and the other constraints.
+//! - `config`: `Settings` resolved from the command line and the configuration file.
+//! - `control`, `metrics`, `events`, and `sink`: How a running daemon is queried, steered, and
+//!   observed from the outside.
+pub mod actuation;
+pub mod affinity;
+pub mod boost;
+pub mod burst;
+pub mod config;
+pub mod control;
+pub mod cpu_list;
+pub mod cpufreq;
+pub mod cpuidle;
+pub mod cpuset;
+pub mod decision_log;
+pub mod events;
+pub mod handoff;
+pub mod http;
+pub mod influx;
+pub mod inhibit;
+pub mod irq;
+pub mod journal;
+pub mod kube;
+pub mod kubelet;
+pub mod lease;
+pub mod lid;
+pub mod load;
+pub mod logging;
+pub mod logind;
+pub mod manager;
+pub mod metrics;
+pub mod noise_floor;
+pub mod outcome;
+pub mod pm_qos;
+pub mod profile;
+pub mod pstate;
+pub mod queue;
+pub mod rapl;
+pub mod rate_limit;
+pub mod remote_config;
+pub mod schedule;
+pub mod sink;
+pub mod status;
+pub mod status_line;
+pub mod stickiness;
+pub mod strategy;
+pub mod sysfs;
+pub mod systemd;
+pub mod target;
+pub mod thermal;
+pub mod topology;
+pub mod uevent;
+pub mod vcpu;
+pub mod vulnerabilities;
+pub mod workload;
+
+pub use actuation::ActuationBackend;
+pub use config::{Args, Command, Settings};
+pub use manager::CpuManager;
+pub use sysfs::{MockSysfs, Sysfs, SysfsBackend};
+pub use topology::{
+    boot_cpu, online_all_cpus, CpuInfo, SystemTopology, SystemTopologyBuilder, TopologySnapshot,
+};
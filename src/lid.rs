@@ -0,0 +1,79 @@
+//! Lid and display power state, used to consolidate CPUs as soon as nobody is looking.
+//!
+//! The lid state comes from logind's `LidClosed` property. The display state comes from the
+//! `dpms` attribute of the connected DRM connectors in sysfs, which reads `Off` while a display
+//! is blanked.
+use crate::logind;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::sync::watch;
+
+/// The DRM class directory, relative to the sysfs root.
+pub static DRM_DIR: &str = "class/drm";
+
+/// Returns whether all connected displays in `drm_dir` are blanked, or `None` if no display is
+/// connected.
+pub async fn displays_off(drm_dir: &Path) -> io::Result<Option<bool>> {
+    let mut any_connected = false;
+    let mut read_dir = fs::read_dir(drm_dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let path = entry.path();
+        let Ok(status) = fs::read_to_string(path.join("status")).await else {
+            continue; // Not a connector, e.g. `card0` or `version`
+        };
+        if status.trim() != "connected" {
+            continue;
+        }
+        any_connected = true;
+        let dpms = fs::read_to_string(path.join("dpms")).await?;
+        if !is_dpms_off(&dpms) {
+            return Ok(Some(false));
+        }
+    }
+    Ok(any_connected.then_some(true))
+}
+
+/// Parses the `dpms` attribute of a DRM connector.
+pub fn is_dpms_off(dpms: &str) -> bool {
+    dpms.trim() != "On"
+}
+
+/// Returns whether the lid is closed or all connected displays below `sysfs_root` are blanked.
+///
+/// A source that cannot be read, e.g. logind on a server or DRM in a VM, does not count as away.
+pub async fn is_away(sysfs_root: &Path) -> bool {
+    let lid_closed = logind::read_lid_closed().await.unwrap_or(false);
+    let drm_dir = sysfs_root.join(DRM_DIR);
+    let displays_off = drm_dir.exists() && matches!(displays_off(&drm_dir).await, Ok(Some(true)));
+    lid_closed || displays_off
+}
+
+/// Starts polling the lid and display state in the background and returns a receiver that is
+/// true while the lid is closed or all displays are blanked.
+pub fn watch_away(sysfs_root: PathBuf) -> watch::Receiver<bool> {
+    let (tx, rx) = watch::channel(false);
+    tokio::spawn(async move {
+        loop {
+            let away = is_away(&sysfs_root).await;
+            tx.send_if_modified(|current| std::mem::replace(current, away) != away);
+            if tx.is_closed() {
+                break;
+            }
+            tokio::time::sleep(logind::POLL_INTERVAL).await;
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_dpms_states() {
+        assert!(!is_dpms_off("On\n"));
+        assert!(is_dpms_off("Off\n"));
+        assert!(is_dpms_off("Standby\n"));
+    }
+}
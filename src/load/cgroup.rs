@@ -0,0 +1,130 @@
+//! CPU usage of a single cgroup from its `cpu.stat`, for `--watch-cgroup`.
+use super::{LoadMetric, LoadSample};
+use crate::metrics::{SysfsAccess, METRICS};
+use crate::topology::SystemTopology;
+use async_trait::async_trait;
+use std::io;
+use std::path::PathBuf;
+use std::time::Instant;
+use tokio::fs;
+
+/// Uses the CPU time consumed by the tasks of one cgroup, e.g. a batch-processing slice, as the
+/// system-wide load, so unrelated background work neither onlines CPUs nor keeps them online.
+///
+/// The load is the share of the online CPUs' time the cgroup used since the previous sample: a
+/// cgroup keeping 2 of 8 online CPUs busy is at 25%. Only the unified (v2) hierarchy has
+/// `cpu.stat` with `usage_usec`.
+pub struct CgroupMetric {
+    path: PathBuf,
+    /// The cgroup's usage in microseconds at the previous sample, and when it was read.
+    last: (u64, Instant),
+}
+
+impl CgroupMetric {
+    /// Watches the cgroup in `dir`, failing if it has no `cpu.stat` with `usage_usec`. The usage
+    /// read here is the baseline of the first sample.
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = dir.into().join("cpu.stat");
+        let content = std::fs::read_to_string(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "cannot read {:?}: {}; the cgroup must be on cgroup v2",
+                    path, e
+                ),
+            )
+        })?;
+        let usage = parse_usage_usec(&content).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no usage_usec in {:?}", path),
+            )
+        })?;
+        Ok(CgroupMetric {
+            path,
+            last: (usage, Instant::now()),
+        })
+    }
+}
+
+/// Extracts the `usage_usec` value from the contents of a `cpu.stat` file.
+pub fn parse_usage_usec(content: &str) -> Option<u64> {
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[async_trait]
+impl LoadMetric for CgroupMetric {
+    fn name(&self) -> &'static str {
+        "cgroup"
+    }
+
+    /// Samples the cgroup's share of the online CPUs' time since the previous call.
+    ///
+    /// This function performs the following steps:
+    /// 1. Reads and parses the cgroup's `cpu.stat`.
+    /// 2. Divides the usage delta by the elapsed time times the number of online CPUs, clamped to
+    ///    100%.
+    /// 3. Stores the current usage and time for the next sample.
+    async fn sample(&mut self, topology: &SystemTopology) -> io::Result<LoadSample> {
+        let content = fs::read_to_string(&self.path)
+            .await
+            .inspect_err(|e| METRICS.sysfs_error(SysfsAccess::CgroupRead, e))?;
+        let usage = parse_usage_usec(&content).ok_or_else(|| {
+            let e = io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no usage_usec in {:?}", self.path),
+            );
+            METRICS.sysfs_error(SysfsAccess::CgroupRead, &e);
+            e
+        })?;
+        let now = Instant::now();
+        let (last_usage, last_time) = self.last;
+        self.last = (usage, now);
+        let capacity = now.duration_since(last_time).as_micros() as f64
+            * topology.online_count().max(1) as f64;
+        let load = if capacity == 0.0 {
+            0.0
+        } else {
+            100.0 * usage.saturating_sub(last_usage) as f64 / capacity
+        };
+        Ok(LoadSample {
+            system: Some(load.clamp(0.0, 100.0)),
+            ..LoadSample::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_usage_usec() {
+        let content = "usage_usec 1234567\nuser_usec 1000000\nsystem_usec 234567\n\
+                       nr_periods 0\nnr_throttled 0\nthrottled_usec 0\n";
+        assert_eq!(parse_usage_usec(content), Some(1234567));
+        assert_eq!(parse_usage_usec("nr_periods 0\n"), None);
+    }
+
+    #[tokio::test]
+    async fn samples_the_share_of_the_online_cpus_time() {
+        let dir = std::env::temp_dir().join(format!("cpu-on-off-cgroup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let stat = dir.join("cpu.stat");
+        std::fs::write(&stat, "usage_usec 1000\n").unwrap();
+        let topology = SystemTopology::single_thread_cores(&[true, true]);
+        let mut metric = CgroupMetric::new(&dir).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert_eq!(metric.sample(&topology).await.unwrap().system, Some(0.0));
+        // Far more than two CPUs could have used since, so the load is capped.
+        std::fs::write(&stat, "usage_usec 100001000\n").unwrap();
+        assert_eq!(metric.sample(&topology).await.unwrap().system, Some(100.0));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(CgroupMetric::new(&dir).is_err());
+    }
+}
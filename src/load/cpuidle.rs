@@ -0,0 +1,217 @@
+//! C0 residency computed from the cpuidle idle-state counters.
+//!
+//! Some cpuidle drivers update the residency in `stateN/time` lazily, e.g. only when a CPU leaves
+//! a deep state, so a CPU that idles through a whole interval can look fully busy. Every sample is
+//! therefore cross-checked with the `/proc/stat` utilization of the same CPUs: when a CPU entered
+//! its idle states (`stateN/usage` advanced) but its residency did not, or when the residency
+//! catches up with more idle time than the interval had, the `/proc/stat` value is used instead.
+use super::{LoadMetric, LoadSample, ProcStatMetric};
+use crate::metrics::{SysfsAccess, METRICS};
+use crate::sysfs::SysfsBackend;
+use crate::topology::{CpuInfo, SystemTopology};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// The idle residency in microseconds and the number of idle entries of a CPU, summed over its
+/// idle states.
+#[derive(Clone, Copy, Debug, Default)]
+struct IdleCounters {
+    time: u64,
+    usage: u64,
+}
+
+/// Computes the percentage of time each CPU spent outside of its idle states.
+pub struct CpuidleMetric {
+    last_update: Instant,
+    last_counters: HashMap<usize, IdleCounters>,
+    proc_stat: ProcStatMetric,
+}
+
+impl Default for CpuidleMetric {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CpuidleMetric {
+    pub fn new() -> Self {
+        Self::cross_checked_with(ProcStatMetric::new())
+    }
+
+    /// Creates the metric with `proc_stat` as the cross-check, e.g. reading a fake file in tests.
+    pub fn cross_checked_with(proc_stat: ProcStatMetric) -> Self {
+        CpuidleMetric {
+            last_update: Instant::now(),
+            last_counters: HashMap::new(),
+            proc_stat,
+        }
+    }
+
+    /// Checks whether the cpuidle counters exist, i.e. any CPU has a `cpuidle/stateN/time` file.
+    ///
+    /// In VMs and containers cpuidle is often missing, and every CPU would then appear fully loaded.
+    pub fn is_available(topology: &SystemTopology) -> bool {
+        topology.cpus.values().any(|cpu| {
+            cpu.idle_states.iter().any(|state| {
+                topology
+                    .sysfs
+                    .exists(cpu.id, &format!("cpuidle/{}/time", state))
+            })
+        })
+    }
+
+    /// Asynchronously computes the C0 state percentage (non-idle time) for a single CPU based on the actual interval.
+    ///
+    /// This function performs the following steps:
+    /// 1. Initializes the total idle time and idle entries to zero.
+    /// 2. Iterates over the CPU's idle states and reads the idle time and, where present, the number
+    ///    of entries for each state from the respective files.
+    /// 3. Sums them up to get the total idle time and entries.
+    /// 4. Calculates the deltas since the last update and stores the current totals.
+    /// 5. Calculates the C0 percentage as the proportion of non-idle time over the actual interval.
+    /// 6. Clamps the C0 percentage to the range [0.0, 100.0].
+    ///
+    /// # Arguments
+    /// * `cpu` - A reference to the `CpuInfo` struct representing the CPU.
+    /// * `sysfs` - The backend the cpuidle counters are read from.
+    /// * `actual_interval` - The duration since the last update.
+    ///
+    /// # Returns
+    /// * `io::Result<(f64, bool)>` - The C0 percentage, and whether the residency lagged behind: the
+    ///   CPU entered idle states without its idle time advancing, or more idle time was reported than
+    ///   the interval had.
+    async fn sample_single(
+        &mut self,
+        cpu: &CpuInfo,
+        sysfs: &dyn SysfsBackend,
+        actual_interval: Duration,
+    ) -> io::Result<(f64, bool)> {
+        let mut total = IdleCounters::default();
+        for state in &cpu.idle_states {
+            let state_dir = format!("cpuidle/{}", state);
+            if sysfs.exists(cpu.id, &state_dir) {
+                total.time += read_counter(sysfs, cpu.id, &format!("{}/time", state_dir)).await?;
+                let usage = format!("{}/usage", state_dir);
+                if sysfs.exists(cpu.id, &usage) {
+                    total.usage += read_counter(sysfs, cpu.id, &usage).await?;
+                }
+            }
+        }
+        let previous = self.last_counters.insert(cpu.id, total);
+        let last = previous.unwrap_or_default();
+        let idle_time_delta = total.time.saturating_sub(last.time);
+        let interval = actual_interval.as_micros() as f64;
+        // The first sample covers the time since boot and cannot be checked.
+        let lagging = previous.is_some()
+            && ((total.usage > last.usage && idle_time_delta == 0)
+                || idle_time_delta as f64 > interval);
+        let c0_percentage = 100.0 * (1.0 - (idle_time_delta as f64 / interval));
+        Ok((c0_percentage.clamp(0.0, 100.0), lagging))
+    }
+}
+
+/// Reads a cpuidle counter of CPU `id`, counting failures in the sysfs error metrics.
+async fn read_counter(sysfs: &dyn SysfsBackend, id: usize, attr: &str) -> io::Result<u64> {
+    sysfs
+        .read(id, attr)
+        .await
+        .inspect_err(|e| METRICS.sysfs_error(SysfsAccess::CpuidleRead, e))?
+        .trim()
+        .parse::<u64>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        .inspect_err(|e| METRICS.sysfs_error(SysfsAccess::CpuidleRead, e))
+}
+
+#[async_trait]
+impl LoadMetric for CpuidleMetric {
+    fn name(&self) -> &'static str {
+        "cpuidle"
+    }
+
+    /// Asynchronously samples the C0 state percentages (non-idle time) for all online CPUs.
+    ///
+    /// This function performs the following steps:
+    /// 1. Records the current time as `now`.
+    /// 2. Calculates the actual interval since the last sample by subtracting `self.last_update` from `now`.
+    /// 3. Updates `self.last_update` to the current time.
+    /// 4. Samples the `/proc/stat` utilization of the online CPUs as the cross-check, along with the
+    ///    niced time. If `/proc/stat` cannot be read, the sample goes without them.
+    /// 5. For each online CPU, calls `sample_single` to compute its C0 percentage based on the actual
+    ///    interval, and takes the `/proc/stat` utilization instead if the cpuidle residency lagged.
+    async fn sample(&mut self, topology: &SystemTopology) -> io::Result<LoadSample> {
+        let now = Instant::now();
+        let actual_interval = now.duration_since(self.last_update);
+        self.last_update = now;
+
+        let mut sample = LoadSample::default();
+        match self.proc_stat.sample(topology).await {
+            Ok(proc_stat) => {
+                sample.cross_check = proc_stat.per_cpu;
+                sample.nice = proc_stat.nice;
+            }
+            Err(e) => debug!("Cannot cross-check the cpuidle residency: {}", e),
+        }
+        for cpu in topology.cpus.values().filter(|cpu| cpu.online) {
+            let (c0, lagging) = self
+                .sample_single(cpu, &*topology.sysfs, actual_interval)
+                .await?;
+            let c0 = match sample.cross_check.get(&cpu.id) {
+                Some(&utilization) if lagging => {
+                    debug!(
+                        "The cpuidle residency of CPU {} lags, using /proc/stat: {:.2}% instead of {:.2}%",
+                        cpu.id, utilization, c0
+                    );
+                    METRICS.cpuidle_reconciled();
+                    sample.reconciled.push(cpu.id);
+                    utilization
+                }
+                _ => c0,
+            };
+            sample.per_cpu.insert(cpu.id, c0);
+        }
+        Ok(sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sysfs::MockSysfs;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn takes_proc_stat_when_the_residency_lags() {
+        let sysfs = Arc::new(
+            MockSysfs::new()
+                .with_cpu(0, 0, 0, "0")
+                .with_cpu(1, 1, 0, "1"),
+        );
+        for id in [0, 1] {
+            sysfs.set(id, "cpuidle/state0/time", "0");
+            sysfs.set(id, "cpuidle/state0/usage", "0");
+        }
+        let topology = SystemTopology::builder()
+            .backend(sysfs.clone())
+            .build()
+            .await
+            .unwrap();
+        let stat =
+            std::env::temp_dir().join(format!("cpu-on-off-proc-stat-{}", std::process::id()));
+        std::fs::write(&stat, "cpu0 0 0 0 0\ncpu1 0 0 0 0\n").unwrap();
+        let mut metric = CpuidleMetric::cross_checked_with(ProcStatMetric::with_path(&stat));
+        metric.sample(&topology).await.unwrap();
+
+        // CPU1 entered its idle state, but its residency is only updated when it leaves it.
+        std::fs::write(&stat, "cpu0 50 0 0 50\ncpu1 30 0 0 70\n").unwrap();
+        sysfs.set(1, "cpuidle/state0/usage", "12");
+        let sample = metric.sample(&topology).await.unwrap();
+        assert_eq!(sample.reconciled, [1]);
+        assert_eq!(sample.per_cpu[&1], 30.0);
+        assert_eq!(sample.per_cpu[&0], 100.0);
+        assert_eq!(sample.cross_check[&0], 50.0);
+        std::fs::remove_file(&stat).unwrap();
+    }
+}
@@ -0,0 +1,763 @@
+//! Scheduler telemetry from eBPF programs attached to the `sched` tracepoints, for `--metric ebpf`.
+//!
+//! Only built with the `ebpf` cargo feature. The programs are assembled here from raw instructions
+//! and loaded with the `bpf(2)` system call, so the build needs neither a BPF compiler nor libbpf:
+//!
+//! - `sched_wakeup` and `sched_wakeup_new` record when a task became runnable.
+//! - `sched_switch` adds the time the previous task ran to the CPU's running time, records when a
+//!   preempted task was queued again, and adds the time the next task waited since it was queued
+//!   to the CPU's runnable time and, if it was woken up, to its wakeup latency.
+//!
+//! The tracepoints are attached per CPU through `perf_event_open`, and again whenever a CPU comes
+//! back online. The field offsets are read from the tracepoints' `format` files, as they differ
+//! between kernels.
+use super::{LoadMetric, LoadSample};
+use crate::cpu_list::CpuList;
+use crate::metrics::{SysfsAccess, METRICS};
+use crate::topology::SystemTopology;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Where tracefs is mounted, by preference.
+pub static TRACEFS_DIRS: [&str; 2] = ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+
+/// The CPUs the kernel may ever bring online.
+pub static POSSIBLE_CPUS: &str = "/sys/devices/system/cpu/possible";
+
+/// The most tasks tracked between becoming runnable and running; the oldest are evicted first.
+const MAX_QUEUED_TASKS: u32 = 65536;
+
+/// The counters kept by the programs for each CPU, in nanoseconds: the layout of the values of the
+/// `stats` map.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CpuStats {
+    /// Time tasks ran on the CPU.
+    pub running: u64,
+    /// Time tasks waited in the CPU's run queue before running, after a wakeup or a preemption.
+    pub runnable: u64,
+    /// Time woken tasks waited before running.
+    pub latency: u64,
+    /// Number of woken tasks that ran.
+    pub wakeups: u64,
+    /// When the CPU last switched tasks, for the running time.
+    pub last_switch: u64,
+}
+
+const RUNNING: i16 = 0;
+const RUNNABLE: i16 = 8;
+const LATENCY: i16 = 16;
+const WAKEUPS: i16 = 24;
+const LAST_SWITCH: i16 = 32;
+
+/// The offset and size of a field of a tracepoint's record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Field {
+    pub offset: i16,
+    pub size: usize,
+}
+
+/// Finds the field `name` in the contents of a tracepoint's `format` file, e.g.
+/// `field:pid_t next_pid;\toffset:56;\tsize:4;\tsigned:1;`, with the fields separated by tabs.
+pub fn parse_field(format: &str, name: &str) -> Option<Field> {
+    format.lines().find_map(|line| {
+        let mut parts = line.trim().split(';').map(str::trim);
+        let declaration = parts.next()?.strip_prefix("field:")?;
+        if declaration.split_whitespace().last()? != name {
+            return None;
+        }
+        let offset = parts.next()?.strip_prefix("offset:")?.parse().ok()?;
+        let size = parts.next()?.strip_prefix("size:")?.parse().ok()?;
+        Some(Field { offset, size })
+    })
+}
+
+/// A tracepoint of the `sched` subsystem: its ID for `perf_event_open` and its `format` file.
+struct Tracepoint {
+    id: u64,
+    format: String,
+}
+
+impl Tracepoint {
+    fn read(tracefs: &Path, name: &str) -> io::Result<Self> {
+        let dir = tracefs.join("events/sched").join(name);
+        let id = std::fs::read_to_string(dir.join("id"))?;
+        Ok(Tracepoint {
+            id: id.trim().parse().map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", name, e))
+            })?,
+            format: std::fs::read_to_string(dir.join("format"))?,
+        })
+    }
+
+    fn field(&self, name: &str) -> io::Result<Field> {
+        parse_field(&self.format, name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no field {} in the tracepoint format", name),
+            )
+        })
+    }
+}
+
+// Instruction classes, sizes, modes, and operations of the BPF instruction set.
+const LD: u8 = 0x00;
+const LDX: u8 = 0x01;
+const ST: u8 = 0x02;
+const STX: u8 = 0x03;
+const JMP: u8 = 0x05;
+const ALU64: u8 = 0x07;
+const W: u8 = 0x00;
+const DW: u8 = 0x18;
+const IMM: u8 = 0x00;
+const MEM: u8 = 0x60;
+const K: u8 = 0x00;
+const X: u8 = 0x08;
+const ADD: u8 = 0x00;
+const SUB: u8 = 0x10;
+const AND: u8 = 0x50;
+const MOV: u8 = 0xb0;
+const JEQ: u8 = 0x10;
+const JGT: u8 = 0x20;
+const JNE: u8 = 0x50;
+const CALL: u8 = 0x80;
+const EXIT: u8 = 0x90;
+
+/// Marks the immediate of a 64-bit load as a map file descriptor.
+const PSEUDO_MAP_FD: u8 = 1;
+
+const MAP_LOOKUP_ELEM: i32 = 1;
+const MAP_UPDATE_ELEM: i32 = 2;
+const MAP_DELETE_ELEM: i32 = 3;
+const KTIME_GET_NS: i32 = 5;
+const GET_SMP_PROCESSOR_ID: i32 = 8;
+
+/// The bits of `prev_state` set for a task that went to sleep; a preempted or yielding task has
+/// none and is still runnable.
+const SLEEPING_STATES: i32 = 0xff;
+
+/// A BPF instruction.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Insn {
+    pub code: u8,
+    /// The destination register in the low nibble, the source register in the high one.
+    pub regs: u8,
+    pub off: i16,
+    pub imm: i32,
+}
+
+/// Assembles a program, resolving jumps to named labels.
+#[derive(Default)]
+struct Asm {
+    insns: Vec<Insn>,
+    labels: HashMap<&'static str, usize>,
+    jumps: Vec<(usize, &'static str)>,
+}
+
+impl Asm {
+    fn emit(&mut self, code: u8, dst: u8, src: u8, off: i16, imm: i32) -> &mut Self {
+        self.insns.push(Insn {
+            code,
+            regs: src << 4 | dst,
+            off,
+            imm,
+        });
+        self
+    }
+
+    fn label(&mut self, name: &'static str) -> &mut Self {
+        self.labels.insert(name, self.insns.len());
+        self
+    }
+
+    fn mov(&mut self, dst: u8, src: u8) -> &mut Self {
+        self.emit(ALU64 | MOV | X, dst, src, 0, 0)
+    }
+
+    fn mov_imm(&mut self, dst: u8, imm: i32) -> &mut Self {
+        self.emit(ALU64 | MOV | K, dst, 0, 0, imm)
+    }
+
+    fn alu(&mut self, op: u8, dst: u8, src: u8) -> &mut Self {
+        self.emit(ALU64 | op | X, dst, src, 0, 0)
+    }
+
+    fn alu_imm(&mut self, op: u8, dst: u8, imm: i32) -> &mut Self {
+        self.emit(ALU64 | op | K, dst, 0, 0, imm)
+    }
+
+    fn load(&mut self, size: u8, dst: u8, src: u8, off: i16) -> &mut Self {
+        self.emit(LDX | MEM | size, dst, src, off, 0)
+    }
+
+    /// Loads `field` of the record `src` points to, zero-extended.
+    fn load_field(&mut self, dst: u8, src: u8, field: Field) -> &mut Self {
+        let size = if field.size == 8 { DW } else { W };
+        self.load(size, dst, src, field.offset)
+    }
+
+    fn store(&mut self, size: u8, dst: u8, off: i16, src: u8) -> &mut Self {
+        self.emit(STX | MEM | size, dst, src, off, 0)
+    }
+
+    fn store_imm(&mut self, size: u8, dst: u8, off: i16, imm: i32) -> &mut Self {
+        self.emit(ST | MEM | size, dst, 0, off, imm)
+    }
+
+    /// Adds `src` to the 64-bit counter at `dst + off`, clobbering r5.
+    fn add_to(&mut self, dst: u8, off: i16, src: u8) -> &mut Self {
+        self.load(DW, 5, dst, off)
+            .alu(ADD, 5, src)
+            .store(DW, dst, off, 5)
+    }
+
+    fn load_map(&mut self, dst: u8, map: &OwnedFd) -> &mut Self {
+        self.emit(LD | DW | IMM, dst, PSEUDO_MAP_FD, 0, map.as_raw_fd())
+            .emit(0, 0, 0, 0, 0)
+    }
+
+    fn jump(&mut self, op: u8, dst: u8, src: u8, target: &'static str) -> &mut Self {
+        self.jumps.push((self.insns.len(), target));
+        self.emit(JMP | op | X, dst, src, 0, 0)
+    }
+
+    fn jump_imm(&mut self, op: u8, dst: u8, imm: i32, target: &'static str) -> &mut Self {
+        self.jumps.push((self.insns.len(), target));
+        self.emit(JMP | op | K, dst, 0, 0, imm)
+    }
+
+    fn call(&mut self, helper: i32) -> &mut Self {
+        self.emit(JMP | CALL, 0, 0, 0, helper)
+    }
+
+    fn exit(&mut self) -> &mut Self {
+        self.mov_imm(0, 0).emit(JMP | EXIT, 0, 0, 0, 0)
+    }
+
+    fn finish(mut self) -> Vec<Insn> {
+        for (at, target) in self.jumps {
+            self.insns[at].off = (self.labels[target] as isize - at as isize - 1) as i16;
+        }
+        self.insns
+    }
+}
+
+/// Assembles the program of `sched_wakeup` and `sched_wakeup_new`, recording when the task `pid`
+/// was woken up in `queued`.
+fn wakeup_program(pid: Field, queued: &OwnedFd) -> Vec<Insn> {
+    let mut asm = Asm::default();
+    asm.mov(6, 1)
+        .load_field(7, 6, pid)
+        .store(W, 10, -4, 7)
+        .call(KTIME_GET_NS)
+        .store(DW, 10, -24, 0)
+        .store_imm(DW, 10, -16, 1)
+        .load_map(1, queued)
+        .mov(2, 10)
+        .alu_imm(ADD, 2, -4)
+        .mov(3, 10)
+        .alu_imm(ADD, 3, -24)
+        .mov_imm(4, 0)
+        .call(MAP_UPDATE_ELEM)
+        .exit();
+    asm.finish()
+}
+
+/// The fields of `sched_switch` the switch program reads.
+struct SwitchFields {
+    prev_pid: Field,
+    prev_state: Field,
+    next_pid: Field,
+}
+
+/// Assembles the program of `sched_switch`, keeping the `CpuStats` of the current CPU in `stats`
+/// and the tasks waiting to run in `queued`.
+///
+/// Registers: r6 is the record, r7 the current time, r8 the CPU's `CpuStats`.
+fn switch_program(fields: &SwitchFields, stats: &OwnedFd, queued: &OwnedFd) -> Vec<Insn> {
+    let mut asm = Asm::default();
+    asm.mov(6, 1)
+        .call(KTIME_GET_NS)
+        .mov(7, 0)
+        .call(GET_SMP_PROCESSOR_ID)
+        .store(W, 10, -4, 0)
+        .load_map(1, stats)
+        .mov(2, 10)
+        .alu_imm(ADD, 2, -4)
+        .call(MAP_LOOKUP_ELEM)
+        .jump_imm(JEQ, 0, 0, "exit")
+        .mov(8, 0);
+    // The previous task ran since the last switch, unless it is the idle task.
+    asm.load_field(1, 6, fields.prev_pid)
+        .jump_imm(JEQ, 1, 0, "switched")
+        .load(DW, 2, 8, LAST_SWITCH)
+        .jump_imm(JEQ, 2, 0, "switched")
+        .mov(3, 7)
+        .alu(SUB, 3, 2)
+        .add_to(8, RUNNING, 3)
+        .label("switched")
+        .store(DW, 8, LAST_SWITCH, 7);
+    // A previous task that did not go to sleep is queued again.
+    asm.load_field(1, 6, fields.prev_pid)
+        .jump_imm(JEQ, 1, 0, "next")
+        .load_field(2, 6, fields.prev_state)
+        .alu_imm(AND, 2, SLEEPING_STATES)
+        .jump_imm(JNE, 2, 0, "next")
+        .store(W, 10, -4, 1)
+        .store(DW, 10, -24, 7)
+        .store_imm(DW, 10, -16, 0)
+        .load_map(1, queued)
+        .mov(2, 10)
+        .alu_imm(ADD, 2, -4)
+        .mov(3, 10)
+        .alu_imm(ADD, 3, -24)
+        .mov_imm(4, 0)
+        .call(MAP_UPDATE_ELEM);
+    // The next task waited since it was queued.
+    asm.label("next")
+        .load_field(1, 6, fields.next_pid)
+        .jump_imm(JEQ, 1, 0, "exit")
+        .store(W, 10, -4, 1)
+        .load_map(1, queued)
+        .mov(2, 10)
+        .alu_imm(ADD, 2, -4)
+        .call(MAP_LOOKUP_ELEM)
+        .jump_imm(JEQ, 0, 0, "exit")
+        .load(DW, 1, 0, 0)
+        .load(DW, 2, 0, 8)
+        .jump(JGT, 1, 7, "dequeue")
+        .mov(3, 7)
+        .alu(SUB, 3, 1)
+        .add_to(8, RUNNABLE, 3)
+        .jump_imm(JEQ, 2, 0, "dequeue")
+        .add_to(8, LATENCY, 3)
+        .mov_imm(3, 1)
+        .add_to(8, WAKEUPS, 3)
+        .label("dequeue")
+        .load_map(1, queued)
+        .mov(2, 10)
+        .alu_imm(ADD, 2, -4)
+        .call(MAP_DELETE_ELEM)
+        .label("exit")
+        .exit();
+    asm.finish()
+}
+
+const BPF_MAP_CREATE: libc::c_int = 0;
+const BPF_MAP_LOOKUP_ELEM: libc::c_int = 1;
+const BPF_MAP_UPDATE_ELEM: libc::c_int = 2;
+const BPF_PROG_LOAD: libc::c_int = 5;
+const BPF_MAP_TYPE_ARRAY: u32 = 2;
+const BPF_MAP_TYPE_LRU_HASH: u32 = 9;
+const BPF_PROG_TYPE_TRACEPOINT: u32 = 5;
+
+#[repr(C)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+struct MapElemAttr {
+    map_fd: u32,
+    pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+}
+
+/// Calls `bpf(2)` with the command `cmd` and returns its result.
+fn bpf<T>(cmd: libc::c_int, attr: &mut T) -> io::Result<libc::c_long> {
+    // SAFETY: `attr` is a properly sized `bpf_attr` prefix for `cmd`, and every pointer in it
+    // refers to memory that outlives the call.
+    let ret = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr as *mut T, size_of::<T>()) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+/// Wraps a file descriptor returned by `bpf(2)` or `perf_event_open(2)`.
+fn owned_fd(fd: libc::c_long) -> OwnedFd {
+    // SAFETY: `fd` was just returned by the kernel and is owned by nobody else.
+    unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) }
+}
+
+fn create_map(
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+) -> io::Result<OwnedFd> {
+    let mut attr = MapCreateAttr {
+        map_type,
+        key_size,
+        value_size,
+        max_entries,
+        map_flags: 0,
+    };
+    bpf(BPF_MAP_CREATE, &mut attr).map(owned_fd)
+}
+
+/// Loads a tracepoint program, returning the verifier's log as the error if it is rejected.
+fn load_program(insns: &[Insn]) -> io::Result<OwnedFd> {
+    let license = CString::new("GPL").unwrap();
+    let mut log = vec![0u8; 64 * 1024];
+    let mut attr = ProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_TRACEPOINT,
+        insn_cnt: insns.len() as u32,
+        insns: insns.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        log_level: 1,
+        log_size: log.len() as u32,
+        log_buf: log.as_mut_ptr() as u64,
+        kern_version: 0,
+        prog_flags: 0,
+    };
+    bpf(BPF_PROG_LOAD, &mut attr).map(owned_fd).map_err(|e| {
+        let end = log.iter().position(|&b| b == 0).unwrap_or(log.len());
+        let log = String::from_utf8_lossy(&log[..end]);
+        io::Error::new(e.kind(), format!("{}: {}", e, log.trim()))
+    })
+}
+
+/// Reads the `CpuStats` of `cpu` from the `stats` map.
+fn read_stats(stats: &OwnedFd, cpu: usize) -> io::Result<CpuStats> {
+    let key = cpu as u32;
+    let mut value = CpuStats::default();
+    let mut attr = MapElemAttr {
+        map_fd: stats.as_raw_fd() as u32,
+        pad: 0,
+        key: &key as *const u32 as u64,
+        value: &mut value as *mut CpuStats as u64,
+        flags: 0,
+    };
+    bpf(BPF_MAP_LOOKUP_ELEM, &mut attr)?;
+    Ok(value)
+}
+
+/// Clears the `CpuStats` of `cpu` in the `stats` map, so a CPU coming back online does not count
+/// the time it was offline as running.
+fn reset_stats(stats: &OwnedFd, cpu: usize) -> io::Result<()> {
+    let key = cpu as u32;
+    let value = CpuStats::default();
+    let mut attr = MapElemAttr {
+        map_fd: stats.as_raw_fd() as u32,
+        pad: 0,
+        key: &key as *const u32 as u64,
+        value: &value as *const CpuStats as u64,
+        flags: 0,
+    };
+    bpf(BPF_MAP_UPDATE_ELEM, &mut attr).map(drop)
+}
+
+const PERF_TYPE_TRACEPOINT: u32 = 2;
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 8;
+/// `_IO('$', 0)`
+const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 0x2400;
+/// `_IOW('$', 8, __u32)`
+const PERF_EVENT_IOC_SET_BPF: libc::c_ulong = 0x4004_2408;
+
+/// The first version of `perf_event_attr`, which is all a tracepoint needs.
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    /// Bit 0 is `disabled`.
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+}
+
+/// Attaches `program` to the tracepoint `id` on `cpu`, returning the perf event that holds it.
+fn attach(id: u64, cpu: usize, program: &OwnedFd) -> io::Result<OwnedFd> {
+    let mut attr = PerfEventAttr {
+        type_: PERF_TYPE_TRACEPOINT,
+        size: size_of::<PerfEventAttr>() as u32,
+        config: id,
+        sample_period: 1,
+        flags: 1,
+        wakeup_events: 1,
+        ..PerfEventAttr::default()
+    };
+    // Every task on `cpu`, in no group.
+    let (pid, group): (libc::pid_t, libc::c_int) = (-1, -1);
+    // SAFETY: `attr` is a valid `perf_event_attr` of the size it declares.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            &mut attr as *mut PerfEventAttr,
+            pid,
+            cpu as libc::c_int,
+            group,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let event = owned_fd(fd);
+    for (request, arg) in [
+        (PERF_EVENT_IOC_SET_BPF, program.as_raw_fd()),
+        (PERF_EVENT_IOC_ENABLE, 0),
+    ] {
+        // SAFETY: both requests take an integer argument.
+        if unsafe { libc::ioctl(event.as_raw_fd(), request as _, arg) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(event)
+}
+
+/// Computes the demand on each CPU from the scheduler's tracepoints: the time its tasks ran plus
+/// the time they were runnable but waited in its run queue, over the interval, like `schedstat`.
+/// The sample also carries the average wakeup latency, the time woken tasks waited before running,
+/// for `--wakeup-latency-us`.
+///
+/// Unlike C0 residency, the wakeup latency rises as soon as tasks have to wait for a CPU, before
+/// the online CPUs are saturated on average.
+pub struct EbpfMetric {
+    stats: OwnedFd,
+    /// Each tracepoint's ID and the program attached to it.
+    programs: Vec<(u64, OwnedFd)>,
+    /// The perf events holding the programs on each CPU online at the previous sample.
+    events: HashMap<usize, Vec<OwnedFd>>,
+    last_update: Instant,
+    last_stats: HashMap<usize, CpuStats>,
+    /// Kept for the programs referring to it.
+    _queued: OwnedFd,
+}
+
+impl EbpfMetric {
+    /// Loads the programs, failing if tracefs is not mounted or the kernel rejects them, e.g.
+    /// without root. They are attached to the online CPUs by the first sample.
+    pub fn new() -> io::Result<Self> {
+        Self::load().map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "cannot load the eBPF programs: {}; the ebpf metric needs root and tracefs",
+                    e
+                ),
+            )
+        })
+    }
+
+    fn load() -> io::Result<Self> {
+        let tracefs = TRACEFS_DIRS
+            .iter()
+            .map(PathBuf::from)
+            .find(|dir| dir.join("events/sched").is_dir())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "tracefs is not mounted"))?;
+        let possible: CpuList = std::fs::read_to_string(POSSIBLE_CPUS)?
+            .trim()
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let max_cpu = possible.0.iter().max().copied().unwrap_or(0);
+
+        let stats = create_map(
+            BPF_MAP_TYPE_ARRAY,
+            size_of::<u32>() as u32,
+            size_of::<CpuStats>() as u32,
+            max_cpu as u32 + 1,
+        )?;
+        let queued = create_map(
+            BPF_MAP_TYPE_LRU_HASH,
+            size_of::<u32>() as u32,
+            2 * size_of::<u64>() as u32,
+            MAX_QUEUED_TASKS,
+        )?;
+
+        let mut programs = Vec::new();
+        for name in ["sched_wakeup", "sched_wakeup_new"] {
+            let tracepoint = Tracepoint::read(&tracefs, name)?;
+            let program = wakeup_program(tracepoint.field("pid")?, &queued);
+            programs.push((tracepoint.id, load_program(&program)?));
+        }
+        let switch = Tracepoint::read(&tracefs, "sched_switch")?;
+        let fields = SwitchFields {
+            prev_pid: switch.field("prev_pid")?,
+            prev_state: switch.field("prev_state")?,
+            next_pid: switch.field("next_pid")?,
+        };
+        let program = switch_program(&fields, &stats, &queued);
+        programs.push((switch.id, load_program(&program)?));
+
+        Ok(EbpfMetric {
+            stats,
+            programs,
+            events: HashMap::new(),
+            last_update: Instant::now(),
+            last_stats: HashMap::new(),
+            _queued: queued,
+        })
+    }
+
+    /// Attaches the programs to the online CPUs they are not attached to yet, and detaches them
+    /// from the CPUs that went offline.
+    fn attach_online(&mut self, topology: &SystemTopology) -> io::Result<()> {
+        self.events
+            .retain(|id, _| topology.cpus.get(id).is_some_and(|cpu| cpu.online));
+        for cpu in topology.cpus.values().filter(|cpu| cpu.online) {
+            if self.events.contains_key(&cpu.id) {
+                continue;
+            }
+            reset_stats(&self.stats, cpu.id)?;
+            self.last_stats.remove(&cpu.id);
+            let events = self
+                .programs
+                .iter()
+                .map(|(id, program)| attach(*id, cpu.id, program))
+                .collect::<io::Result<_>>()?;
+            self.events.insert(cpu.id, events);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl LoadMetric for EbpfMetric {
+    fn name(&self) -> &'static str {
+        "ebpf"
+    }
+
+    /// Samples the demand of all online CPUs and the wakeup latency since the previous call.
+    ///
+    /// This function performs the following steps:
+    /// 1. Attaches the programs to the CPUs that came online and reads each online CPU's counters.
+    /// 2. For each online CPU, divides the running plus runnable time delta by the interval. A
+    ///    CPU without earlier counters, e.g. one just onlined, is at 0% until the next sample.
+    /// 3. Divides the wakeup latency delta of all online CPUs by their wakeups, if there were any.
+    /// 4. Stores the current counters for the next sample.
+    async fn sample(&mut self, topology: &SystemTopology) -> io::Result<LoadSample> {
+        let stats = self
+            .attach_online(topology)
+            .and_then(|()| {
+                self.events
+                    .keys()
+                    .map(|&id| Ok((id, read_stats(&self.stats, id)?)))
+                    .collect::<io::Result<HashMap<usize, CpuStats>>>()
+            })
+            .inspect_err(|e| METRICS.sysfs_error(SysfsAccess::BpfRead, e))?;
+        let now = Instant::now();
+        let interval = now.duration_since(self.last_update).as_nanos() as f64;
+        self.last_update = now;
+
+        let mut sample = LoadSample::default();
+        let (mut latency, mut wakeups) = (0, 0);
+        for (&id, current) in &stats {
+            let Some(last) = self.last_stats.get(&id) else {
+                sample.per_cpu.insert(id, 0.0);
+                continue;
+            };
+            let demand = current.running.saturating_sub(last.running)
+                + current.runnable.saturating_sub(last.runnable);
+            let load = if interval == 0.0 {
+                0.0
+            } else {
+                100.0 * demand as f64 / interval
+            };
+            sample.per_cpu.insert(id, load);
+            latency += current.latency.saturating_sub(last.latency);
+            wakeups += current.wakeups.saturating_sub(last.wakeups);
+        }
+        if wakeups > 0 {
+            sample.wakeup_latency_us = Some(latency as f64 / wakeups as f64 / 1000.0);
+        }
+        self.last_stats = stats;
+        Ok(sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHED_SWITCH: &str = "name: sched_switch\n\
+ID: 316\n\
+format:\n\
+\tfield:unsigned short common_type;\toffset:0;\tsize:2;\tsigned:0;\n\
+\tfield:int common_pid;\toffset:4;\tsize:4;\tsigned:1;\n\
+\n\
+\tfield:char prev_comm[16];\toffset:8;\tsize:16;\tsigned:0;\n\
+\tfield:pid_t prev_pid;\toffset:24;\tsize:4;\tsigned:1;\n\
+\tfield:int prev_prio;\toffset:28;\tsize:4;\tsigned:1;\n\
+\tfield:long prev_state;\toffset:32;\tsize:8;\tsigned:1;\n\
+\tfield:char next_comm[16];\toffset:40;\tsize:16;\tsigned:0;\n\
+\tfield:pid_t next_pid;\toffset:56;\tsize:4;\tsigned:1;\n\
+\tfield:int next_prio;\toffset:60;\tsize:4;\tsigned:1;\n";
+
+    #[test]
+    fn parses_tracepoint_fields() {
+        assert_eq!(
+            parse_field(SCHED_SWITCH, "prev_state"),
+            Some(Field {
+                offset: 32,
+                size: 8
+            })
+        );
+        assert_eq!(
+            parse_field(SCHED_SWITCH, "next_pid"),
+            Some(Field {
+                offset: 56,
+                size: 4
+            })
+        );
+        assert_eq!(parse_field(SCHED_SWITCH, "pid"), None);
+    }
+
+    #[test]
+    fn resolves_jumps_to_labels() {
+        let fields = SwitchFields {
+            prev_pid: parse_field(SCHED_SWITCH, "prev_pid").unwrap(),
+            prev_state: parse_field(SCHED_SWITCH, "prev_state").unwrap(),
+            next_pid: parse_field(SCHED_SWITCH, "next_pid").unwrap(),
+        };
+        let map: OwnedFd = std::fs::File::open("/dev/null").unwrap().into();
+        let program = switch_program(&fields, &map, &map);
+        let exit = program.len() - 2;
+        for (at, insn) in program.iter().enumerate() {
+            if insn.code & 0x07 == JMP && insn.code & 0xf0 != CALL && insn.code & 0xf0 != EXIT {
+                let target = at as isize + 1 + insn.off as isize;
+                assert!(target > at as isize && target <= exit as isize, "{}", at);
+            }
+        }
+        assert_eq!(
+            program[exit],
+            Insn {
+                code: ALU64 | MOV | K,
+                ..Insn::default()
+            }
+        );
+        assert_eq!(program.last().unwrap().code, JMP | EXIT);
+    }
+}
@@ -0,0 +1,130 @@
+//! Load metrics that drive the online/offline decisions.
+//!
+//! Each metric implements `LoadMetric` and is selected with `--metric`. The cpuidle-based C0
+//! residency is the default; `/proc/stat` utilization is used instead when cpuidle is missing, and
+//! alongside it to correct CPUs whose cpuidle counters lag.
+mod cgroup;
+mod cpuidle;
+#[cfg(feature = "ebpf")]
+mod ebpf;
+mod perf;
+mod procstat;
+mod psi;
+mod schedstat;
+mod window;
+
+pub use cgroup::CgroupMetric;
+pub use cpuidle::CpuidleMetric;
+#[cfg(feature = "ebpf")]
+pub use ebpf::EbpfMetric;
+pub use perf::PerfMetric;
+pub use procstat::{ProcStatMetric, StealReader};
+pub use psi::{PsiLine, PsiMetric};
+pub use schedstat::SchedstatMetric;
+pub use window::{LoadWindow, WindowAggregation};
+
+use crate::topology::SystemTopology;
+use async_trait::async_trait;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+/// Available load metrics.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MetricKind {
+    /// Non-idle (C0) residency from `cpuidle/stateN/time`.
+    Cpuidle,
+    /// Non-idle time from the per-CPU counters in `/proc/stat`.
+    ProcStat,
+    /// 10-second average of the `some` line of `/proc/pressure/cpu`.
+    PsiSome,
+    /// 10-second average of the `full` line of `/proc/pressure/cpu`.
+    PsiFull,
+    /// CPU usage of the cgroup set by `--watch-cgroup`, from its `cpu.stat`.
+    Cgroup,
+    /// Time running plus time waiting in the run queue from `/proc/schedstat`, above 100% while
+    /// tasks queue up.
+    Schedstat,
+    /// Busy cycles at the actual frequency from the APERF register, through `/dev/cpu/N/msr`.
+    Perf,
+    /// Time running plus time runnable, and the wakeup latency, traced by eBPF programs on the
+    /// `sched` tracepoints. Needs a build with the `ebpf` feature.
+    Ebpf,
+}
+
+/// A single sample of a load metric.
+#[derive(Clone, Debug, Default)]
+pub struct LoadSample {
+    /// Load of each sampled CPU in percent.
+    pub per_cpu: HashMap<usize, f64>,
+    /// System-wide load in percent for metrics that are not per CPU. When `None`, the average of
+    /// `per_cpu` over the online CPUs is used.
+    pub system: Option<f64>,
+    /// Utilization of each sampled CPU in percent according to `/proc/stat`, for metrics that
+    /// cross-check their own source against it; empty otherwise.
+    pub cross_check: HashMap<usize, f64>,
+    /// CPUs whose load was taken from `cross_check` because the metric's own source lagged.
+    pub reconciled: Vec<usize>,
+    /// Share of the online CPUs' time stolen by the hypervisor in percent, for metrics that measure
+    /// it themselves; `None` otherwise, and the CPU manager reads it from `/proc/stat`.
+    pub steal: Option<f64>,
+    /// Share of each sampled CPU's time spent running niced tasks in percent, for metrics that read
+    /// `/proc/stat`; empty otherwise.
+    pub nice: HashMap<usize, f64>,
+    /// Average time woken tasks waited before running in microseconds, for metrics that trace the
+    /// scheduler; `None` otherwise or if no task was woken up.
+    pub wakeup_latency_us: Option<f64>,
+}
+
+impl LoadSample {
+    /// Takes the time spent running niced tasks out of each CPU's load, for `--exclude-nice`, which the
+    /// settings only allow with the metrics that fill `nice`.
+    pub fn exclude_nice(&mut self) {
+        for (id, load) in self.per_cpu.iter_mut() {
+            if let Some(nice) = self.nice.get(id) {
+                *load = (*load - nice).max(0.0);
+            }
+        }
+    }
+}
+
+#[async_trait]
+pub trait LoadMetric: Send {
+    fn name(&self) -> &'static str;
+
+    /// Samples the load of the online CPUs of `topology` since the previous call.
+    async fn sample(&mut self, topology: &SystemTopology) -> io::Result<LoadSample>;
+}
+
+impl MetricKind {
+    /// Creates the metric, failing if its data source is not available on this system. `cgroup` is
+    /// the directory of the cgroup watched by the cgroup metric.
+    pub fn build(self, cgroup: Option<&Path>) -> io::Result<Box<dyn LoadMetric>> {
+        Ok(match self {
+            MetricKind::Cpuidle => Box::new(CpuidleMetric::new()),
+            MetricKind::ProcStat => Box::new(ProcStatMetric::new()),
+            MetricKind::PsiSome => Box::new(PsiMetric::new(PsiLine::Some)?),
+            MetricKind::PsiFull => Box::new(PsiMetric::new(PsiLine::Full)?),
+            MetricKind::Cgroup => Box::new(CgroupMetric::new(cgroup.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the cgroup metric needs --watch-cgroup",
+                )
+            })?)?),
+            MetricKind::Schedstat => Box::new(SchedstatMetric::new()?),
+            MetricKind::Perf => Box::new(PerfMetric::new()?),
+            #[cfg(feature = "ebpf")]
+            MetricKind::Ebpf => Box::new(EbpfMetric::new()?),
+            #[cfg(not(feature = "ebpf"))]
+            MetricKind::Ebpf => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "the ebpf metric needs a build with --features ebpf",
+                ))
+            }
+        })
+    }
+}
@@ -0,0 +1,232 @@
+//! Busy cycles counted by the CPUs themselves, read from the APERF model-specific register.
+//!
+//! x86 CPUs count the cycles at their actual frequency in APERF only while in C0, next to the time
+//! stamp counter that always runs at the nominal frequency. MPERF, which counts the C0 cycles at
+//! the nominal frequency, would give the plain busy time; APERF also weighs it by frequency.
+//! Unlike the cpuidle and `/proc/stat` counters, both are updated by the hardware, so neither a
+//! tickless kernel nor a cpuidle driver that accounts idle time lazily can skew them. The
+//! registers are read through the `msr` driver's `/dev/cpu/N/msr`.
+use super::{LoadMetric, LoadSample};
+use crate::metrics::{SysfsAccess, METRICS};
+use crate::topology::SystemTopology;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+pub static MSR_DIR: &str = "/dev/cpu";
+
+/// The time stamp counter, counting at the nominal frequency at all times.
+const MSR_TSC: u64 = 0x10;
+/// Cycles at the actual frequency while in C0.
+const MSR_APERF: u64 = 0xe8;
+
+/// The cycle counters of a CPU.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Cycles {
+    pub tsc: u64,
+    pub aperf: u64,
+}
+
+/// Computes the frequency-aware utilization of each CPU: the cycles it ran at its actual
+/// frequency over the cycles the time stamp counter advanced by.
+///
+/// A CPU busy all the time at its nominal frequency is at 100%, and one busy all the time at half
+/// of it at 50%, since it could do twice the work at the nominal frequency. Cycles above the
+/// nominal frequency, i.e. turbo, are clamped to 100%.
+pub struct PerfMetric {
+    dir: PathBuf,
+    last_cycles: HashMap<usize, Cycles>,
+}
+
+impl PerfMetric {
+    /// Reads the registers through `/dev/cpu`, failing if the `msr` driver is not loaded or the
+    /// registers cannot be read, e.g. on other architectures or without root.
+    pub fn new() -> io::Result<Self> {
+        let metric = Self::with_dir(MSR_DIR);
+        read_cycles(&metric.dir, 0).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "cannot read the APERF register of CPU 0: {}; the perf metric needs an x86 CPU, root, and the msr module",
+                    e
+                ),
+            )
+        })?;
+        Ok(metric)
+    }
+
+    /// Reads the registers of CPU N from `dir/N/msr` instead of `/dev/cpu`, e.g. fake files in
+    /// tests.
+    pub fn with_dir(dir: impl Into<PathBuf>) -> Self {
+        PerfMetric {
+            dir: dir.into(),
+            last_cycles: HashMap::new(),
+        }
+    }
+}
+
+/// Reads a 64-bit register at `offset` from an open `msr` device.
+fn read_msr(file: &File, offset: u64) -> io::Result<u64> {
+    let mut value = [0u8; 8];
+    file.read_exact_at(&mut value, offset)?;
+    Ok(u64::from_le_bytes(value))
+}
+
+/// Reads the cycle counters of CPU `id` from `dir/id/msr`.
+pub fn read_cycles(dir: &Path, id: usize) -> io::Result<Cycles> {
+    let file = File::open(dir.join(id.to_string()).join("msr"))?;
+    Ok(Cycles {
+        tsc: read_msr(&file, MSR_TSC)?,
+        aperf: read_msr(&file, MSR_APERF)?,
+    })
+}
+
+#[async_trait]
+impl LoadMetric for PerfMetric {
+    fn name(&self) -> &'static str {
+        "perf"
+    }
+
+    /// Samples the frequency-aware utilization of all online CPUs since the previous call.
+    ///
+    /// This function performs the following steps:
+    /// 1. Reads the cycle counters of the online CPUs on a blocking thread.
+    /// 2. For each online CPU, computes the APERF delta over the time stamp counter delta since
+    ///    the last sample, clamped to the range [0.0, 100.0]. A CPU without earlier counters, e.g.
+    ///    one just onlined, is at 0% until the next sample.
+    /// 3. Stores the current counters for the next sample.
+    async fn sample(&mut self, topology: &SystemTopology) -> io::Result<LoadSample> {
+        let dir = self.dir.clone();
+        let ids: Vec<usize> = topology
+            .cpus
+            .values()
+            .filter(|cpu| cpu.online)
+            .map(|cpu| cpu.id)
+            .collect();
+        let cycles = tokio::task::spawn_blocking(move || {
+            ids.into_iter()
+                .map(|id| Ok((id, read_cycles(&dir, id)?)))
+                .collect::<io::Result<HashMap<usize, Cycles>>>()
+        })
+        .await
+        .map_err(io::Error::other)?
+        .inspect_err(|e| METRICS.sysfs_error(SysfsAccess::MsrRead, e))?;
+
+        let mut sample = LoadSample::default();
+        for (&id, current) in &cycles {
+            let Some(last) = self.last_cycles.get(&id) else {
+                sample.per_cpu.insert(id, 0.0);
+                continue;
+            };
+            let tsc_delta = current.tsc.wrapping_sub(last.tsc);
+            let aperf_delta = current.aperf.wrapping_sub(last.aperf);
+            let utilization = if tsc_delta == 0 {
+                0.0
+            } else {
+                100.0 * aperf_delta as f64 / tsc_delta as f64
+            };
+            sample.per_cpu.insert(id, utilization.clamp(0.0, 100.0));
+        }
+        self.last_cycles = cycles;
+        Ok(sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_msr(dir: &Path, id: usize, cycles: Cycles) {
+        let cpu_dir = dir.join(id.to_string());
+        std::fs::create_dir_all(&cpu_dir).unwrap();
+        let mut msr = vec![0u8; 0x100];
+        for (offset, value) in [(MSR_TSC, cycles.tsc), (MSR_APERF, cycles.aperf)] {
+            let offset = offset as usize;
+            msr[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+        }
+        std::fs::write(cpu_dir.join("msr"), msr).unwrap();
+    }
+
+    #[tokio::test]
+    async fn counts_busy_cycles_at_the_actual_frequency() {
+        let dir = std::env::temp_dir().join(format!("cpu-on-off-msr-{}", std::process::id()));
+        let topology = SystemTopology::single_thread_cores(&[true, true]);
+        for id in 0..2 {
+            write_msr(&dir, id, Cycles::default());
+        }
+        let mut metric = PerfMetric::with_dir(&dir);
+        metric.sample(&topology).await.unwrap();
+
+        // CPU 0 ran all the time at half the nominal frequency, or half the time at the nominal
+        // frequency; CPU 1 turbo-boosted all the time.
+        let tsc = 1_000_000;
+        write_msr(
+            &dir,
+            0,
+            Cycles {
+                tsc,
+                aperf: 500_000,
+            },
+        );
+        write_msr(
+            &dir,
+            1,
+            Cycles {
+                tsc,
+                aperf: 1_300_000,
+            },
+        );
+        let sample = metric.sample(&topology).await.unwrap();
+        assert_eq!(sample.per_cpu[&0], 50.0);
+        assert_eq!(sample.per_cpu[&1], 100.0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(metric.sample(&topology).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn starts_over_for_a_cpu_onlined_again() {
+        let dir =
+            std::env::temp_dir().join(format!("cpu-on-off-msr-online-{}", std::process::id()));
+        let mut topology = SystemTopology::single_thread_cores(&[true, true]);
+        for id in 0..2 {
+            write_msr(&dir, id, Cycles::default());
+        }
+        let mut metric = PerfMetric::with_dir(&dir);
+        let sample = metric.sample(&topology).await.unwrap();
+        assert_eq!(sample.per_cpu[&1], 0.0);
+
+        topology.cpus.get_mut(&1).unwrap().online = false;
+        let sample = metric.sample(&topology).await.unwrap();
+        assert!(!sample.per_cpu.contains_key(&1));
+
+        // Onlined again, CPU 1 has no counters from the last sample to compare with, rather than
+        // the ones from before it went offline.
+        topology.cpus.get_mut(&1).unwrap().online = true;
+        write_msr(
+            &dir,
+            1,
+            Cycles {
+                tsc: 2_000_000,
+                aperf: 1_200_000,
+            },
+        );
+        let sample = metric.sample(&topology).await.unwrap();
+        assert_eq!(sample.per_cpu[&1], 0.0);
+        write_msr(
+            &dir,
+            1,
+            Cycles {
+                tsc: 3_000_000,
+                aperf: 1_700_000,
+            },
+        );
+        let sample = metric.sample(&topology).await.unwrap();
+        assert_eq!(sample.per_cpu[&1], 50.0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
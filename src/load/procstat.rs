@@ -0,0 +1,216 @@
+//! CPU utilization computed from the per-CPU time counters in `/proc/stat`.
+use super::{LoadMetric, LoadSample};
+use crate::metrics::{SysfsAccess, METRICS};
+use crate::topology::SystemTopology;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use tokio::fs;
+
+pub static PROC_STAT_PATH: &str = "/proc/stat";
+
+/// Busy, niced, stolen, and total time of a CPU in clock ticks since boot.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CpuTimes {
+    pub busy: u64,
+    /// Time spent running niced tasks, part of `busy`.
+    pub nice: u64,
+    /// Time the hypervisor ran something else while the vCPU wanted to run.
+    pub steal: u64,
+    pub total: u64,
+}
+
+/// Computes the percentage of time each CPU spent outside of idle, iowait, and steal, and the share
+/// of it spent running niced tasks.
+///
+/// Unlike the cpuidle counters, `/proc/stat` is available in VMs and containers, so this metric is
+/// used as the fallback when cpuidle is missing.
+pub struct ProcStatMetric {
+    path: PathBuf,
+    last_times: HashMap<usize, CpuTimes>,
+}
+
+impl Default for ProcStatMetric {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProcStatMetric {
+    pub fn new() -> Self {
+        Self::with_path(PROC_STAT_PATH)
+    }
+
+    /// Reads the counters from `path` instead of `/proc/stat`, e.g. a fake file in tests.
+    pub fn with_path(path: impl Into<PathBuf>) -> Self {
+        ProcStatMetric {
+            path: path.into(),
+            last_times: HashMap::new(),
+        }
+    }
+}
+
+/// Extracts the times of each `cpuN` line from the contents of `/proc/stat`.
+///
+/// The `user`, `nice`, `system`, `idle`, `iowait`, `irq`, `softirq`, and `steal` columns make up the
+/// total; `guest` time is already included in `user` and is not counted twice. Stolen time is not
+/// busy: the guest did no work while the hypervisor ran something else.
+pub fn parse_proc_stat(content: &str) -> HashMap<usize, CpuTimes> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let id = fields.next()?.strip_prefix("cpu")?.parse().ok()?;
+            let values: Vec<u64> = fields.take(8).filter_map(|v| v.parse().ok()).collect();
+            if values.len() < 4 {
+                return None;
+            }
+            let total: u64 = values.iter().sum();
+            let idle = values[3] + values.get(4).copied().unwrap_or(0);
+            let steal = values.get(7).copied().unwrap_or(0);
+            Some((
+                id,
+                CpuTimes {
+                    busy: total - idle - steal,
+                    nice: values[1],
+                    steal,
+                    total,
+                },
+            ))
+        })
+        .collect()
+}
+
+#[async_trait]
+impl LoadMetric for ProcStatMetric {
+    fn name(&self) -> &'static str {
+        "proc-stat"
+    }
+
+    /// Samples the utilization of all online CPUs since the previous call.
+    ///
+    /// This function performs the following steps:
+    /// 1. Reads and parses `/proc/stat`.
+    /// 2. For each online CPU, computes the busy and the niced time delta over the total time delta
+    ///    since the last sample.
+    /// 3. Stores the current times for the next sample.
+    async fn sample(&mut self, topology: &SystemTopology) -> io::Result<LoadSample> {
+        let content = fs::read_to_string(&self.path)
+            .await
+            .inspect_err(|e| METRICS.sysfs_error(SysfsAccess::ProcRead, e))?;
+        let times = parse_proc_stat(&content);
+
+        let mut sample = LoadSample::default();
+        for cpu in topology.cpus.values().filter(|cpu| cpu.online) {
+            let Some(&current) = times.get(&cpu.id) else {
+                continue;
+            };
+            let last = self.last_times.get(&cpu.id).copied().unwrap_or_default();
+            let total_delta = current.total.saturating_sub(last.total);
+            let busy_delta = current.busy.saturating_sub(last.busy);
+            let nice_delta = current.nice.saturating_sub(last.nice);
+            let (utilization, nice) = if total_delta == 0 {
+                (0.0, 0.0)
+            } else {
+                (
+                    100.0 * busy_delta as f64 / total_delta as f64,
+                    100.0 * nice_delta as f64 / total_delta as f64,
+                )
+            };
+            sample.per_cpu.insert(cpu.id, utilization.clamp(0.0, 100.0));
+            sample.nice.insert(cpu.id, nice.clamp(0.0, 100.0));
+        }
+        self.last_times = times;
+        Ok(sample)
+    }
+}
+
+/// Computes the share of the online CPUs' time stolen by the hypervisor from `/proc/stat`, for
+/// `--steal-limit` and the status, whatever the load metric.
+pub struct StealReader {
+    path: PathBuf,
+    last_times: HashMap<usize, CpuTimes>,
+}
+
+impl Default for StealReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StealReader {
+    pub fn new() -> Self {
+        Self::with_path(PROC_STAT_PATH)
+    }
+
+    /// Reads the counters from `path` instead of `/proc/stat`, e.g. a fake file in tests.
+    pub fn with_path(path: impl Into<PathBuf>) -> Self {
+        StealReader {
+            path: path.into(),
+            last_times: HashMap::new(),
+        }
+    }
+
+    /// Samples the stolen time of the online CPUs since the previous call.
+    ///
+    /// This function performs the following steps:
+    /// 1. Reads and parses `/proc/stat`.
+    /// 2. Sums the stolen and the total time deltas of the online CPUs since the last sample.
+    /// 3. Stores the current times for the next sample.
+    ///
+    /// # Returns
+    /// * `io::Result<Option<f64>>` - The stolen time in percent of the total, `None` if no time
+    ///   passed, or an error if `/proc/stat` cannot be read.
+    pub async fn sample(&mut self, topology: &SystemTopology) -> io::Result<Option<f64>> {
+        let content = fs::read_to_string(&self.path)
+            .await
+            .inspect_err(|e| METRICS.sysfs_error(SysfsAccess::ProcRead, e))?;
+        let times = parse_proc_stat(&content);
+
+        let (mut steal_sum, mut total_sum) = (0, 0);
+        for cpu in topology.cpus.values().filter(|cpu| cpu.online) {
+            let Some(&current) = times.get(&cpu.id) else {
+                continue;
+            };
+            let last = self.last_times.get(&cpu.id).copied().unwrap_or_default();
+            steal_sum += current.steal.saturating_sub(last.steal);
+            total_sum += current.total.saturating_sub(last.total);
+        }
+        self.last_times = times;
+        Ok((total_sum > 0).then(|| 100.0 * steal_sum as f64 / total_sum as f64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_per_cpu_lines() {
+        let content = "cpu  400 30 200 1000 100 0 0 50 0 0\n\
+                       cpu0 300 0 100 500 50 0 0 0 0 0\n\
+                       cpu1 100 30 100 500 50 0 0 50 0 0\n\
+                       intr 12345\n";
+        let times = parse_proc_stat(content);
+        assert_eq!(times.len(), 2);
+        assert_eq!(
+            times[&0],
+            CpuTimes {
+                busy: 400,
+                nice: 0,
+                steal: 0,
+                total: 950
+            }
+        );
+        assert_eq!(
+            times[&1],
+            CpuTimes {
+                busy: 230,
+                nice: 30,
+                steal: 50,
+                total: 830
+            }
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! CPU pressure stall information from `/proc/pressure/cpu`.
+use super::{LoadMetric, LoadSample};
+use crate::metrics::{SysfsAccess, METRICS};
+use crate::topology::SystemTopology;
+use async_trait::async_trait;
+use std::io;
+use std::path::Path;
+use tokio::fs;
+
+pub static PSI_CPU_PATH: &str = "/proc/pressure/cpu";
+
+/// Which PSI line to read: `some` (at least one task stalled) or `full` (all non-idle tasks stalled).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PsiLine {
+    Some,
+    Full,
+}
+
+impl PsiLine {
+    fn prefix(self) -> &'static str {
+        match self {
+            PsiLine::Some => "some",
+            PsiLine::Full => "full",
+        }
+    }
+}
+
+/// Uses the 10-second average of the CPU pressure as the system-wide load.
+///
+/// Unlike C0 residency, pressure keeps rising when all online CPUs are busy and runnable tasks
+/// queue up, so it can tell a saturated system from a merely busy one.
+pub struct PsiMetric {
+    line: PsiLine,
+}
+
+impl PsiMetric {
+    pub fn new(line: PsiLine) -> io::Result<Self> {
+        if !Path::new(PSI_CPU_PATH).exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "{} does not exist; the kernel needs CONFIG_PSI and psi=1",
+                    PSI_CPU_PATH
+                ),
+            ));
+        }
+        Ok(PsiMetric { line })
+    }
+}
+
+/// Extracts the `avg10` value of the requested line from the contents of a PSI file.
+pub fn parse_avg10(content: &str, line: PsiLine) -> Option<f64> {
+    content
+        .lines()
+        .find(|l| l.starts_with(line.prefix()))?
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+#[async_trait]
+impl LoadMetric for PsiMetric {
+    fn name(&self) -> &'static str {
+        match self.line {
+            PsiLine::Some => "psi-some",
+            PsiLine::Full => "psi-full",
+        }
+    }
+
+    async fn sample(&mut self, _topology: &SystemTopology) -> io::Result<LoadSample> {
+        let content = fs::read_to_string(PSI_CPU_PATH)
+            .await
+            .inspect_err(|e| METRICS.sysfs_error(SysfsAccess::ProcRead, e))?;
+        let avg10 = parse_avg10(&content, self.line).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no {} avg10 in {}", self.line.prefix(), PSI_CPU_PATH),
+            )
+        })?;
+        Ok(LoadSample {
+            system: Some(avg10),
+            ..LoadSample::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_some_and_full_avg10() {
+        let content = "some avg10=12.34 avg60=5.00 avg300=1.00 total=123456\n\
+                       full avg10=0.50 avg60=0.10 avg300=0.00 total=789\n";
+        assert_eq!(parse_avg10(content, PsiLine::Some), Some(12.34));
+        assert_eq!(parse_avg10(content, PsiLine::Full), Some(0.5));
+        assert_eq!(parse_avg10("some total=1\n", PsiLine::Some), None);
+    }
+}
@@ -0,0 +1,150 @@
+//! Run-queue demand computed from the per-CPU scheduler statistics in `/proc/schedstat`.
+use super::{LoadMetric, LoadSample};
+use crate::metrics::{SysfsAccess, METRICS};
+use crate::topology::SystemTopology;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::time::Instant;
+use tokio::fs;
+
+pub static SCHEDSTAT_PATH: &str = "/proc/schedstat";
+
+/// Time the tasks of a CPU spent running on it and waiting in its run queue, in nanoseconds since
+/// boot.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RunQueueTimes {
+    pub running: u64,
+    pub waiting: u64,
+}
+
+/// Computes the demand on each CPU as the time its tasks spent running plus the time they spent
+/// waiting in its run queue, over the interval.
+///
+/// A CPU that is busy all the time is at 100% with `cpuidle` and `proc-stat`, whether or not
+/// more tasks are queued behind the running one. Here, every task waiting on average adds another
+/// 100%, so a CPU running one task with one and a half queued behind it is at 250%, and the
+/// decisions and `--mode target` can tell a busy CPU from an overloaded one.
+pub struct SchedstatMetric {
+    path: PathBuf,
+    last_update: Instant,
+    last_times: HashMap<usize, RunQueueTimes>,
+}
+
+impl SchedstatMetric {
+    /// Reads the statistics from `/proc/schedstat`, failing if the kernel does not provide them.
+    pub fn new() -> io::Result<Self> {
+        Self::with_path(SCHEDSTAT_PATH)
+    }
+
+    /// Reads the statistics from `path` instead of `/proc/schedstat`, e.g. a fake file in tests.
+    /// The times read here are the baseline of the first sample.
+    pub fn with_path(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let content = std::fs::read_to_string(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "cannot read {:?}: {}; the kernel needs CONFIG_SCHED_INFO",
+                    path, e
+                ),
+            )
+        })?;
+        Ok(SchedstatMetric {
+            path,
+            last_update: Instant::now(),
+            last_times: parse_schedstat(&content),
+        })
+    }
+}
+
+/// Extracts the run-queue times of each `cpuN` line from the contents of `/proc/schedstat`.
+///
+/// The seventh and eighth fields after the CPU name are the time spent running and waiting; the
+/// `domainN` lines and the `version` and `timestamp` headers are skipped.
+pub fn parse_schedstat(content: &str) -> HashMap<usize, RunQueueTimes> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let id = fields.next()?.strip_prefix("cpu")?.parse().ok()?;
+            let mut values = fields.skip(6).map(|v| v.parse::<u64>().ok());
+            Some((
+                id,
+                RunQueueTimes {
+                    running: values.next()??,
+                    waiting: values.next()??,
+                },
+            ))
+        })
+        .collect()
+}
+
+#[async_trait]
+impl LoadMetric for SchedstatMetric {
+    fn name(&self) -> &'static str {
+        "schedstat"
+    }
+
+    /// Samples the run-queue demand of all online CPUs since the previous call.
+    ///
+    /// This function performs the following steps:
+    /// 1. Reads and parses `/proc/schedstat`, and calculates the interval since the last sample.
+    /// 2. For each online CPU, divides the running plus waiting time delta by the interval. A CPU
+    ///    without earlier times, e.g. one just onlined, is at 0% until the next sample.
+    /// 3. Stores the current times for the next sample.
+    async fn sample(&mut self, topology: &SystemTopology) -> io::Result<LoadSample> {
+        let content = fs::read_to_string(&self.path)
+            .await
+            .inspect_err(|e| METRICS.sysfs_error(SysfsAccess::ProcRead, e))?;
+        let times = parse_schedstat(&content);
+        let now = Instant::now();
+        let interval = now.duration_since(self.last_update).as_nanos() as f64;
+        self.last_update = now;
+
+        let mut sample = LoadSample::default();
+        for cpu in topology.cpus.values().filter(|cpu| cpu.online) {
+            let (Some(current), Some(last)) = (times.get(&cpu.id), self.last_times.get(&cpu.id))
+            else {
+                sample.per_cpu.insert(cpu.id, 0.0);
+                continue;
+            };
+            let demand = current.running.saturating_sub(last.running)
+                + current.waiting.saturating_sub(last.waiting);
+            let load = if interval == 0.0 {
+                0.0
+            } else {
+                100.0 * demand as f64 / interval
+            };
+            sample.per_cpu.insert(cpu.id, load);
+        }
+        self.last_times = times;
+        Ok(sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_per_cpu_lines() {
+        let content = "version 15\n\
+                       timestamp 4295000000\n\
+                       cpu0 0 0 0 0 0 0 2000000 500000 120\n\
+                       domain0 00000003 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0\n\
+                       cpu1 0 0 0 0 0 0 1000000 0 80\n\
+                       cpu2 0 0 0\n";
+        let times = parse_schedstat(content);
+        assert_eq!(times.len(), 2);
+        assert_eq!(
+            times[&0],
+            RunQueueTimes {
+                running: 2000000,
+                waiting: 500000
+            }
+        );
+        assert_eq!(times[&1].waiting, 0);
+    }
+}
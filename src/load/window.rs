@@ -0,0 +1,129 @@
+//! Sliding-window smoothing of the load samples.
+//!
+//! A single interval's load is noisy on bursty workloads and makes the thresholds flap. With
+//! `--window N`, each CPU's load, and the system-wide load of metrics that have one, is the mean,
+//! median, or exponentially weighted mean of its last N samples instead.
+use super::LoadSample;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+/// How the samples of a window are combined into a single load.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WindowAggregation {
+    /// The plain mean of the window.
+    #[default]
+    Mean,
+    /// The median of the window, which ignores a few outliers entirely.
+    Median,
+    /// Exponentially weighted mean, with a smoothing factor of 2 / (N + 1), which follows a
+    /// sustained change sooner than the mean.
+    Ewma,
+}
+
+impl WindowAggregation {
+    /// Combines `values`, oldest first. `values` must not be empty.
+    pub fn aggregate(self, values: &VecDeque<f64>) -> f64 {
+        match self {
+            WindowAggregation::Mean => values.iter().sum::<f64>() / values.len() as f64,
+            WindowAggregation::Median => {
+                let mut sorted: Vec<f64> = values.iter().copied().collect();
+                sorted.sort_by(f64::total_cmp);
+                let mid = sorted.len() / 2;
+                if sorted.len().is_multiple_of(2) {
+                    (sorted[mid - 1] + sorted[mid]) / 2.0
+                } else {
+                    sorted[mid]
+                }
+            }
+            WindowAggregation::Ewma => {
+                let alpha = 2.0 / (values.len() as f64 + 1.0);
+                let mut values = values.iter();
+                let first = *values.next().unwrap();
+                values.fold(first, |average, value| {
+                    alpha * value + (1.0 - alpha) * average
+                })
+            }
+        }
+    }
+}
+
+/// The most recent samples of each CPU and of the system-wide load.
+pub struct LoadWindow {
+    len: usize,
+    aggregation: WindowAggregation,
+    per_cpu: HashMap<usize, VecDeque<f64>>,
+    system: VecDeque<f64>,
+}
+
+impl LoadWindow {
+    /// Creates a window of `len` samples; a length of 1 leaves the samples as they are.
+    pub fn new(len: usize, aggregation: WindowAggregation) -> Self {
+        LoadWindow {
+            len: len.max(1),
+            aggregation,
+            per_cpu: HashMap::new(),
+            system: VecDeque::new(),
+        }
+    }
+
+    /// Adds `sample` to the window and returns it with each load replaced by the aggregate of its
+    /// window. CPUs missing from the sample, e.g. because they were offlined, lose their history,
+    /// so a CPU that comes back online starts afresh.
+    pub fn smooth(&mut self, mut sample: LoadSample) -> LoadSample {
+        if self.len == 1 {
+            return sample;
+        }
+        self.per_cpu.retain(|id, _| sample.per_cpu.contains_key(id));
+        for (id, load) in sample.per_cpu.iter_mut() {
+            let window = self.per_cpu.entry(*id).or_default();
+            push(window, *load, self.len);
+            *load = self.aggregation.aggregate(window);
+        }
+        match &mut sample.system {
+            Some(load) => {
+                push(&mut self.system, *load, self.len);
+                *load = self.aggregation.aggregate(&self.system);
+            }
+            None => self.system.clear(),
+        }
+        sample
+    }
+}
+
+fn push(window: &mut VecDeque<f64>, value: f64, len: usize) {
+    if window.len() == len {
+        window.pop_front();
+    }
+    window.push_back(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(loads: &[(usize, f64)]) -> LoadSample {
+        LoadSample {
+            per_cpu: loads.iter().copied().collect(),
+            ..LoadSample::default()
+        }
+    }
+
+    #[test]
+    fn aggregates_the_last_samples_of_each_cpu() {
+        let mut window = LoadWindow::new(3, WindowAggregation::Median);
+        window.smooth(sample(&[(1, 10.0), (2, 50.0)]));
+        window.smooth(sample(&[(1, 90.0), (2, 50.0)]));
+        let smoothed = window.smooth(sample(&[(1, 20.0)]));
+        assert_eq!(smoothed.per_cpu[&1], 20.0);
+        // CPU2 was offlined, so its history is gone when it comes back.
+        let smoothed = window.smooth(sample(&[(1, 30.0), (2, 80.0)]));
+        assert_eq!(smoothed.per_cpu[&1], 30.0);
+        assert_eq!(smoothed.per_cpu[&2], 80.0);
+
+        let values = VecDeque::from([10.0, 40.0, 40.0]);
+        assert_eq!(WindowAggregation::Mean.aggregate(&values), 30.0);
+        assert_eq!(WindowAggregation::Ewma.aggregate(&values), 32.5);
+    }
+}
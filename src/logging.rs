@@ -0,0 +1,137 @@
+//! Log output through `tracing`.
+//!
+//! Messages go to stdout, either as human-readable text or as one JSON object per line for log
+//! collectors. The per-cycle status line is logged at `info`, while the reasoning behind each
+//! cycle, such as "Load is optimal", is logged at `debug` so that journald is not flooded by
+//! default.
+//!
+//! Messages pass through an `EnvFilter` that can be replaced while the daemon runs, e.g. with
+//! `info,cpu_on_off_rust::topology=trace` to trace the hotplug code alone.
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::io::{self, IsTerminal};
+use std::sync::{Mutex, OnceLock};
+use tracing::Level;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+/// The installed filter, which can be swapped by `set_filter`, and its directives.
+static FILTER: OnceLock<(reload::Handle<EnvFilter, Registry>, Mutex<String>)> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Log levels from the most to the least severe.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    const ALL: [LogLevel; 5] = [
+        LogLevel::Error,
+        LogLevel::Warn,
+        LogLevel::Info,
+        LogLevel::Debug,
+        LogLevel::Trace,
+    ];
+
+    /// Returns the level made more verbose by `verbose` steps and quieter by `quiet` steps,
+    /// saturating at `error` and `trace`.
+    pub fn adjust(self, verbose: u8, quiet: u8) -> LogLevel {
+        let index = (self as i32 + verbose as i32 - quiet as i32).clamp(0, 4);
+        Self::ALL[index as usize]
+    }
+
+    /// Returns the level as a log filter directive, e.g. `info`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+
+    pub fn level(self) -> Level {
+        match self {
+            LogLevel::Error => Level::ERROR,
+            LogLevel::Warn => Level::WARN,
+            LogLevel::Info => Level::INFO,
+            LogLevel::Debug => Level::DEBUG,
+            LogLevel::Trace => Level::TRACE,
+        }
+    }
+}
+
+/// Installs the global subscriber, logging at `level` until the filter is changed with
+/// `set_filter`. Must be called once, before anything is logged.
+pub fn init(level: LogLevel, format: LogFormat) {
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(level.as_str()));
+    let _ = FILTER.set((handle, Mutex::new(level.as_str().to_string())));
+    let layer = tracing_subscriber::fmt::layer()
+        .with_ansi(io::stdout().is_terminal())
+        .with_target(false);
+    let registry = tracing_subscriber::registry().with(filter);
+    match format {
+        LogFormat::Text => registry.with(layer).init(),
+        LogFormat::Json => registry.with(layer.json()).init(),
+    }
+}
+
+/// Replaces the log filter with `directives` in the `RUST_LOG` syntax, e.g. `debug` or
+/// `info,cpu_on_off_rust::manager=debug`.
+///
+/// # Returns
+/// * `Result<String, String>` - The previous directives, or the reason the filter was refused.
+pub fn set_filter(directives: &str) -> Result<String, String> {
+    let filter = EnvFilter::try_new(directives)
+        .map_err(|e| format!("invalid log filter {:?}: {}", directives, e))?;
+    let (handle, current) = FILTER
+        .get()
+        .ok_or_else(|| "logging is not initialized".to_string())?;
+    handle.reload(filter).map_err(|e| e.to_string())?;
+    let previous = std::mem::replace(&mut *current.lock().unwrap(), directives.to_string());
+    tracing::info!("Log filter changed from {:?} to {:?}", previous, directives);
+    Ok(previous)
+}
+
+/// Returns the directives of the log filter in effect, if logging is initialized.
+pub fn filter() -> Option<String> {
+    FILTER
+        .get()
+        .map(|(_, current)| current.lock().unwrap().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adjusts_and_saturates_levels() {
+        assert_eq!(LogLevel::Info.adjust(1, 0), LogLevel::Debug);
+        assert_eq!(LogLevel::Info.adjust(0, 2), LogLevel::Error);
+        assert_eq!(LogLevel::Info.adjust(5, 0), LogLevel::Trace);
+        assert_eq!(LogLevel::Warn.adjust(0, 3), LogLevel::Error);
+        assert_eq!(LogLevel::Debug.adjust(1, 1), LogLevel::Debug);
+    }
+
+    #[test]
+    fn rejects_invalid_filters() {
+        let error = set_filter("cpu_on_off_rust=loud").unwrap_err();
+        assert!(error.starts_with("invalid log filter"), "{}", error);
+    }
+}
@@ -0,0 +1,103 @@
+//! Session idle and lid state from systemd-logind.
+//!
+//! The `IdleHint` property of the logind manager is true when all sessions are idle, and
+//! `LidClosed` when the lid is closed. They are read with `busctl`, which avoids linking a D-Bus
+//! client library for a couple of properties.
+use std::io;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::watch;
+use tracing::warn;
+
+/// How often logind properties are polled.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reads logind's `IdleHint` once.
+pub async fn read_idle_hint() -> io::Result<bool> {
+    read_bool_property("IdleHint").await
+}
+
+/// Reads logind's `LidClosed` once.
+pub async fn read_lid_closed() -> io::Result<bool> {
+    read_bool_property("LidClosed").await
+}
+
+/// Reads a boolean property of the logind manager object.
+async fn read_bool_property(property: &str) -> io::Result<bool> {
+    let output = Command::new("busctl")
+        .args([
+            "get-property",
+            "org.freedesktop.login1",
+            "/org/freedesktop/login1",
+            "org.freedesktop.login1.Manager",
+            property,
+        ])
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "busctl failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    parse_bool_property(&stdout).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected {} value: {:?}", property, stdout.trim()),
+        )
+    })
+}
+
+/// Parses a boolean property as printed by `busctl get-property`, e.g. `b true`.
+pub fn parse_bool_property(output: &str) -> Option<bool> {
+    match output.trim().strip_prefix("b ")? {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// Starts polling the idle hint in the background and returns a receiver of its value.
+///
+/// Read errors are reported once and treated as "not idle", so the load policy keeps running
+/// unchanged when logind is unavailable.
+pub fn watch_idle_hint() -> watch::Receiver<bool> {
+    let (tx, rx) = watch::channel(false);
+    tokio::spawn(async move {
+        let mut failing = false;
+        loop {
+            let idle = match read_idle_hint().await {
+                Ok(idle) => {
+                    failing = false;
+                    idle
+                }
+                Err(e) => {
+                    if !failing {
+                        warn!("Cannot read the logind idle hint: {}", e);
+                        failing = true;
+                    }
+                    false
+                }
+            };
+            tx.send_if_modified(|current| std::mem::replace(current, idle) != idle);
+            if tx.is_closed() {
+                break;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_busctl_booleans() {
+        assert_eq!(parse_bool_property("b true\n"), Some(true));
+        assert_eq!(parse_bool_property("b false\n"), Some(false));
+        assert_eq!(parse_bool_property("s \"yes\"\n"), None);
+    }
+}
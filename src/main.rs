@@ -1,597 +1,506 @@
 //! This program manages CPU states (online/offline) based on system load thresholds.
 //! It uses the `clap` crate for command-line argument parsing and `tokio` for asynchronous operations.
+//! The hotplug logic lives in the `cpu_on_off_rust` library; this binary wires it to the command line
+//! and to UNIX signals.
 //!
-//! # Command-line Arguments
-//! - `-u, --upper-threshold`: Upper load threshold percentage (default: 85)
-//! - `-l, --lower-threshold`: Lower load threshold percentage (default: 50)
-//!
-//! # Structures
-//! - `Args`: Holds the command-line arguments.
-//! - `CpuInfo`: Represents information about a single CPU.
-//! - `SystemTopology`: Represents the system's CPU topology and provides methods to manage CPU states.
-//!
-//! # Methods
-//! - `SystemTopology::new()`: Initializes the system topology by reading CPU information.
-//! - `SystemTopology::process_cpu()`: Processes individual CPU entries.
-//! - `SystemTopology::read_thread_siblings()`: Reads thread siblings for a CPU.
-//! - `SystemTopology::is_cpu_online()`: Checks if a CPU is online.
-//! - `SystemTopology::get_idle_states()`: Retrieves idle states for a CPU.
-//! - `SystemTopology::update_c0_percentages()`: Updates the C0 state percentages for all CPUs.
-//! - `SystemTopology::update_c0_single()`: Updates the C0 state percentage for a single CPU.
-//! - `SystemTopology::select_cpu_to_offline()`: Selects CPUs to offline based on load.
-//! - `SystemTopology::select_cpu_to_online()`: Selects CPUs to online based on load.
-//! - `SystemTopology::offline_cpu_group()`: Offlines a group of CPUs.
-//! - `SystemTopology::online_cpu_group()`: Onlines a group of CPUs.
-//! - `SystemTopology::print_summary()`: Prints a summary of the system topology.
-//!
-//! # Functions
-//! - `online_all_cpus()`: Onlines all CPUs.
-//! - `signal_handler()`: Handles UNIX signals (SIGINT, SIGTERM, SIGHUP).
-//! - `cpu_manager()`: Manages CPU states based on load thresholds and signals.
-//!
-//! # Main Function
-//! - Initializes the program, parses command-line arguments, onlines all CPUs, initializes the system topology, and starts the CPU manager and signal handler tasks.
+//! Without a subcommand, it resolves the settings, scans the topology, and runs the CPU manager
+//! until SIGINT or SIGTERM; SIGHUP reloads the configuration. With a subcommand, such as `status`
+//! or `pause`, it talks to the running daemon over the control socket instead. The options are
+//! listed by `--help` and described in the Readme.
 use clap::Parser;
-use std::collections::HashMap;
+use cpu_on_off_rust::actuation::BackendName;
+use cpu_on_off_rust::control::{self, ControlState, ManagerCommand};
+use cpu_on_off_rust::handoff::Handoff;
+use cpu_on_off_rust::influx::InfluxSink;
+use cpu_on_off_rust::journal::HotplugJournal;
+use cpu_on_off_rust::kube::KubeNode;
+use cpu_on_off_rust::metrics::{self, METRICS};
+use cpu_on_off_rust::sink::Sinks;
+use cpu_on_off_rust::target::ScalingMode;
+use cpu_on_off_rust::topology::StartFrom;
+use cpu_on_off_rust::vcpu::VcpuCompanion;
+use cpu_on_off_rust::{cpuset, decision_log};
+use cpu_on_off_rust::{lid, logging, logind, systemd, uevent};
+use cpu_on_off_rust::{online_all_cpus, Args, Command, CpuManager, Settings, SystemTopology};
+use serde_json::json;
 use std::io;
-use std::path::Path;
-use std::time::{Duration, Instant};
-use tokio::fs;
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::signal::unix::{signal, SignalKind};
-use tokio::sync::watch;
-
-static CPU_DIR: &str = "/sys/devices/system/cpu";
-
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// Upper load threshold percentage (default: 85)
-    #[arg(short = 'u', long, default_value_t = 85)]
-    upper_threshold: u8,
-
-    /// Lower load threshold percentage (default: 50)
-    #[arg(short = 'l', long, default_value_t = 50)]
-    lower_threshold: u8,
-}
-
-#[allow(dead_code)]
-#[derive(Clone)]
-struct CpuInfo {
-    id: usize,
-    core_id: Option<usize>,
-    socket_id: Option<usize>,
-    thread_siblings: Vec<usize>,
-    c0_percentage: f64,
-    online: bool,
-    last_total_idle_time: u64,
-    idle_states: Vec<String>,
-}
-
-struct SystemTopology {
-    cpus: HashMap<usize, CpuInfo>,
-    sockets: HashMap<usize, Vec<usize>>,
-    cpu0_socket: Option<usize>,
-    last_update: Instant,
-}
-
-impl SystemTopology {
-    async fn new() -> io::Result<Self> {
-        let mut cpus = HashMap::new();
-        let mut sockets = HashMap::new();
-        let mut cpu0_socket = None;
-
-        let cpu_dir = Path::new(CPU_DIR);
-        println!("Reading CPU information from: {:?}", cpu_dir);
-
-        let mut read_dir = fs::read_dir(cpu_dir).await?;
-        while let Some(entry) = read_dir.next_entry().await? {
-            Self::process_cpu(entry, &mut cpu0_socket, &mut cpus, &mut sockets).await;
-        }
-
-        println!("Finished reading CPU information");
-        println!("Found {} CPUs across {} sockets", cpus.len(), sockets.len());
-
-        Ok(SystemTopology {
-            cpus,
-            sockets,
-            cpu0_socket,
-            last_update: Instant::now(),
-        })
-    }
-
-    /// Asynchronously processes a CPU entry, extracting relevant information and updating the provided data structures.
-    ///
-    /// This function performs the following steps:
-    /// 1. Retrieves the path of the CPU entry.
-    /// 2. Extracts the CPU name from the path and checks if it starts with "cpu" and is followed by a valid number.
-    /// 3. Parses the CPU ID from the CPU name.
-    /// 4. Reads the core ID and socket ID from the respective files in the CPU's topology directory.
-    /// 5. Reads the thread siblings, online status, and idle states of the CPU.
-    /// 6. If the CPU ID is 0, updates the `cpu0_socket` with the socket ID.
-    /// 7. Creates a `CpuInfo` struct with the extracted information and inserts it into the `cpus` HashMap.
-    /// 8. Updates the `sockets` HashMap with the CPU ID if the socket ID is present.
-    ///
-    /// # Arguments
-    /// * `entry` - The directory entry representing the CPU.
-    /// * `cpu0_socket` - A mutable reference to an Option containing the socket ID of CPU0.
-    /// * `cpus` - A mutable reference to a HashMap storing information about all CPUs.
-    /// * `sockets` - A mutable reference to a HashMap storing the CPUs associated with each socket.
-    async fn process_cpu(
-        entry: fs::DirEntry,
-        cpu0_socket: &mut Option<usize>,
-        cpus: &mut HashMap<usize, CpuInfo>,
-        sockets: &mut HashMap<usize, Vec<usize>>,
-    ) {
-        let path = entry.path();
-        if let Some(cpu_name) = path.file_name().and_then(|n| n.to_str()) {
-            if cpu_name.starts_with("cpu") && cpu_name[3..].parse::<usize>().is_ok() {
-                let id = cpu_name[3..].parse().unwrap();
-                println!("Processing CPU {}", id);
-
-                let core_id = fs::read_to_string(path.join("topology/core_id"))
-                    .await
-                    .ok()
-                    .and_then(|s| s.trim().parse().ok());
-
-                let socket_id = fs::read_to_string(path.join("topology/physical_package_id"))
-                    .await
-                    .ok()
-                    .and_then(|s| s.trim().parse().ok());
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
 
-                let thread_siblings = Self::read_thread_siblings(&path).await;
-
-                let online = Self::is_cpu_online(&path).await;
-
-                let idle_states = Self::get_idle_states(&path).await;
-
-                if id == 0 {
-                    *cpu0_socket = socket_id;
-                }
-
-                let cpu_info = CpuInfo {
-                    id,
-                    core_id,
-                    socket_id,
-                    thread_siblings,
-                    c0_percentage: 0.0,
-                    online,
-                    last_total_idle_time: 0,
-                    idle_states,
-                };
-                cpus.insert(id, cpu_info);
-
-                if let Some(socket_id) = socket_id {
-                    sockets.entry(socket_id).or_default().push(id);
-                    // sockets.entry(socket_id).or_insert_with(Vec::new).push(id);
-                }
-            }
-        }
-    }
-
-    async fn read_thread_siblings(cpu_path: &Path) -> Vec<usize> {
-        let siblings_path = cpu_path.join("topology/thread_siblings_list");
-        fs::read_to_string(&siblings_path)
-            .await
-            .map(|s| s.split(',').filter_map(|n| n.trim().parse().ok()).collect())
-            .unwrap_or_default()
-    }
-
-    async fn is_cpu_online(cpu_path: &Path) -> bool {
-        let online_path = cpu_path.join("online");
-        if online_path.exists() {
-            fs::read_to_string(online_path)
-                .await
-                .map(|content| content.trim() == "1")
-                .unwrap_or(false)
-        } else {
-            true // CPU is always online if the 'online' file doesn't exist
-        }
-    }
-
-    async fn get_idle_states(cpu_path: &Path) -> Vec<String> {
-        let cpuidle_path = cpu_path.join("cpuidle");
-        let mut states = Vec::new();
-        if let Ok(mut entries) = fs::read_dir(cpuidle_path).await {
-            while let Ok(Some(entry)) = entries.next_entry().await {
-                if let Some(name) = entry.file_name().to_str() {
-                    if name.starts_with("state") {
-                        states.push(name.to_string());
-                    }
-                }
-            }
-        }
-        states.sort();
-        states
-    }
-
-    /// Asynchronously updates the C0 state percentages (non-idle time) for all online CPUs.
-    ///
-    /// This function performs the following steps:
-    /// 1. Records the current time as `now`.
-    /// 2. Calculates the actual interval since the last update by subtracting `self.last_update` from `now`.
-    /// 3. Updates `self.last_update` to the current time.
-    /// 4. Iterates over all CPUs in the `self.cpus` HashMap.
-    /// 5. For each online CPU, calls the `update_c0_single` method to update its C0 percentage based on the actual interval.
-    ///
-    /// # Returns
-    /// * `io::Result<()>` - Returns an `Ok(())` if successful, or an `io::Error` if an error occurs.
-    async fn update_c0_percentages(&mut self) -> io::Result<()> {
-        let now = Instant::now();
-        let actual_interval = now.duration_since(self.last_update);
-        self.last_update = now;
-
-        for cpu in self.cpus.values_mut() {
-            if cpu.online {
-                Self::update_c0_single(cpu, actual_interval).await?;
-            }
-        }
-        Ok(())
-    }
-
-    /// Asynchronously updates the C0 state percentage (non-idle time) for a single CPU based on the actual interval.
-    ///
-    /// This function performs the following steps:
-    /// 1. Constructs the path to the CPU's cpuidle directory.
-    /// 2. Initializes the total idle time to zero.
-    /// 3. Iterates over the CPU's idle states and reads the idle time for each state from the respective file.
-    /// 4. Sums up the idle times to get the total idle time.
-    /// 5. Calculates the delta of idle time since the last update.
-    /// 6. Updates the CPU's last total idle time with the current total idle time.
-    /// 7. Calculates the C0 percentage as the proportion of non-idle time over the actual interval.
-    /// 8. Clamps the C0 percentage to the range [0.0, 100.0].
-    ///
-    /// # Arguments
-    /// * `cpu` - A mutable reference to the `CpuInfo` struct representing the CPU.
-    /// * `actual_interval` - The duration since the last update.
-    async fn update_c0_single(
-        cpu: &mut CpuInfo,
-        actual_interval: Duration,
-    ) -> Result<(), io::Error> {
-        let cpuidle_path = Path::new(CPU_DIR)
-            .join(format!("cpu{}", cpu.id))
-            .join("cpuidle");
-        let mut total_idle_time = 0;
-        for state in &cpu.idle_states {
-            let state_path = cpuidle_path.join(state);
-            if state_path.exists() {
-                let time = fs::read_to_string(state_path.join("time"))
-                    .await?
-                    .trim()
-                    .parse::<u64>()
-                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-                total_idle_time += time;
-            }
-        }
-        let idle_time_delta = total_idle_time.saturating_sub(cpu.last_total_idle_time);
-        cpu.last_total_idle_time = total_idle_time;
-        cpu.c0_percentage =
-            100.0 * (1.0 - (idle_time_delta as f64 / actual_interval.as_micros() as f64));
-        cpu.c0_percentage = cpu.c0_percentage.clamp(0.0, 100.0);
-        Ok(())
-    }
-
-    /// Selects a group of CPUs to be offlined based on their current state and topology.
-    ///
-    /// This function performs the following steps:
-    /// 1. Filters the CPUs to get a list of online CPUs excluding CPU0.
-    /// 2. If there is only one or no online CPU (excluding CPU0), returns `None` to avoid offlining.
-    /// 3. Finds the CPU with the highest ID among the online CPUs.
-    /// 4. Collects the thread siblings of the selected CPU that are also online.
-    /// 5. Returns the list of online thread siblings to be offlined.
-    ///
-    /// # Returns
-    /// * `Option<Vec<usize>>` - A vector of CPU IDs to be offlined, or `None` if no CPUs can be offlined.
-    fn select_cpu_to_offline(&self) -> Option<Vec<usize>> {
-        let online_cpus: Vec<_> = self
-            .cpus
-            .values()
-            .filter(|cpu| cpu.online && cpu.id != 0) // Exclude CPU0
-            .collect();
-
-        if online_cpus.len() <= 1 {
-            return None; // Don't offline if only CPU0 or one other CPU is online
-        }
-
-        online_cpus.into_iter().max_by_key(|cpu| cpu.id).map(|cpu| {
-            let siblings = &cpu.thread_siblings;
-            siblings
-                .iter()
-                .filter(|&&sibling_id| {
-                    self.cpus
-                        .get(&sibling_id)
-                        .map_or(false, |sibling| sibling.online)
-                })
-                .copied()
-                .collect()
-        })
-    }
-
-    /// Selects a group of CPUs to be onlined based on their current state and topology.
-    ///
-    /// This function performs the following steps:
-    /// 1. Filters the CPUs to get a list of offline CPUs excluding CPU0.
-    /// 2. If all CPUs are already online, returns `None` to avoid onlining.
-    /// 3. Finds the CPU with the lowest ID among the offline CPUs.
-    /// 4. Collects the thread siblings of the selected CPU that are also offline.
-    /// 5. Returns the list of offline thread siblings to be onlined.
-    ///
-    /// # Returns
-    /// * `Option<Vec<usize>>` - A vector of CPU IDs to be onlined, or `None` if no CPUs can be onlined.
-    fn select_cpu_to_online(&self) -> Option<Vec<usize>> {
-        let offline_cpus: Vec<_> = self
-            .cpus
-            .values()
-            .filter(|cpu| !cpu.online && cpu.id != 0) // Exclude CPU0
-            .collect();
-
-        if offline_cpus.is_empty() {
-            return None; // Don't online if all CPUs are already online
-        }
-
-        offline_cpus
-            .into_iter()
-            .min_by_key(|cpu| cpu.id)
-            .map(|cpu| {
-                let siblings = &cpu.thread_siblings;
-                siblings
-                    .iter()
-                    .filter(|&&sibling_id| {
-                        self.cpus
-                            .get(&sibling_id)
-                            .map_or(false, |sibling| !sibling.online)
-                    })
-                    .copied()
-                    .collect()
-            })
-    }
-
-    async fn offline_cpu_group(&mut self, cpu_ids: &[usize]) -> io::Result<()> {
-        for &id in cpu_ids {
-            if id == 0 {
-                continue;
-            } // Never offline CPU0
-            let path = Path::new(CPU_DIR).join(format!("cpu{}", id)).join("online");
-            if path.exists() {
-                fs::write(&path, "0").await?;
-                if let Some(cpu) = self.cpus.get_mut(&id) {
-                    cpu.online = false;
-                }
-                println!("Offlined CPU {}", id);
-            } else {
-                println!("Cannot offline CPU {}: 'online' file does not exist", id);
-            }
-        }
-        Ok(())
-    }
-
-    async fn online_cpu_group(&mut self, cpu_ids: &[usize]) -> io::Result<()> {
-        for &id in cpu_ids {
-            if id == 0 {
-                continue;
-            } // CPU0 is always online
-            let path = Path::new(CPU_DIR).join(format!("cpu{}", id)).join("online");
-            if path.exists() {
-                fs::write(&path, "1").await?;
-                if let Some(cpu) = self.cpus.get_mut(&id) {
-                    cpu.online = true;
-                }
-                println!("Onlined CPU {}", id);
-            } else {
-                println!("Cannot online CPU {}: 'online' file does not exist", id);
-            }
-        }
-        Ok(())
-    }
-
-    fn print_summary(&self) {
-        println!("System Topology Summary:");
-        println!("Total CPUs: {}", self.cpus.len());
-        println!("Total Sockets: {}", self.sockets.len());
-        println!("CPU0 Socket: {:?}", self.cpu0_socket);
-
-        for (&socket_id, cpus) in &self.sockets {
-            println!("Socket {}: {} CPUs", socket_id, cpus.len());
-            let online_cpus = cpus
-                .iter()
-                .filter(|&&cpu_id| self.cpus[&cpu_id].online)
-                .count();
-            println!("  Online CPUs: {}", online_cpus);
-        }
-
-        // Print idle states for CPU0 as an example
-        if let Some(cpu0) = self.cpus.get(&0) {
-            println!("Idle states for CPU0: {:?}", cpu0.idle_states);
-        }
-    }
-}
-
-async fn online_all_cpus() -> io::Result<()> {
-    let cpu_dir = Path::new(CPU_DIR);
-    let mut read_dir = fs::read_dir(cpu_dir).await?;
-    while let Some(entry) = read_dir.next_entry().await? {
-        let path = entry.path();
-        if let Some(cpu_name) = path.file_name().and_then(|n| n.to_str()) {
-            if cpu_name.starts_with("cpu") && cpu_name[3..].parse::<usize>().is_ok() {
-                let id: usize = cpu_name[3..].parse().unwrap();
-                let online_path = path.join("online");
-                if id == 0 {
-                    continue;
-                } // Skip CPU0 since it's always online
-                if online_path.exists() {
-                    fs::write(&online_path, "1").await?;
-                    println!("Onlined CPU {}", id);
-                } else {
-                    println!("Cannot online CPU {}: 'online' file does not exist", id);
-                }
-            }
-        }
-    }
-    Ok(())
-}
-
-/// Handles UNIX signals (SIGINT, SIGTERM, SIGHUP) asynchronously.
+/// Handles UNIX signals (SIGINT, SIGTERM, SIGHUP, SIGUSR1, SIGUSR2, SIGABRT) asynchronously.
 ///
 /// This function performs the following steps:
-/// 1. Sets up signal handlers for SIGINT, SIGTERM, and SIGHUP using `tokio::signal::unix::signal`.
-/// 2. Initializes a flag to `false`.
-/// 3. Enters an infinite loop where it waits for any of the signals to be received using `tokio::select!`.
-/// 4. If SIGINT is received, it prints a message, calls `online_all_cpus` to online all CPUs, and breaks the loop.
-/// 5. If SIGTERM is received, it prints a message, calls `online_all_cpus` to online all CPUs, and breaks the loop.
-/// 6. If SIGHUP is received, it toggles the flag, sends the flag's value through the provided `watch::Sender`, and continues the loop.
-/// 7. After breaking the loop, it prints a shutdown message and performs any necessary cleanup.
+/// 1. Sets up signal handlers for SIGINT, SIGTERM, SIGHUP, SIGUSR1, SIGUSR2, and SIGABRT using `tokio::signal::unix::signal`.
+/// 2. Enters an infinite loop where it waits for any of the signals to be received using `tokio::select!`.
+/// 3. If SIGINT or SIGTERM is received, it prints a message and breaks the loop.
+/// 4. If SIGHUP is received, it spawns `reload_config`, which re-reads the configuration and hands the resolved
+///    settings to the CPU manager to re-scan the topology and apply them, and continues the loop.
+/// 5. If SIGUSR1 is received, it asks the CPU manager to log the topology, the per-CPU load, and the last ten
+///    decisions, and continues the loop.
+/// 6. If SIGUSR2 is received and the binary at `exe` exists, it breaks the loop to hand over to that
+///    binary, see `handoff`.
+/// 7. If SIGABRT is received, it dumps the decision log to `crash_dump` and aborts.
+/// 8. If `shutdown` is cancelled elsewhere, e.g. because the CPU manager failed, it breaks the loop.
+/// 9. After breaking the loop, it prints a shutdown message and cancels `shutdown`. Onlining the CPUs, or
+///    handing over, is left to `main`, once the CPU manager has stopped.
 ///
 /// # Arguments
-/// * `tx` - A `watch::Sender<bool>` used to send the flag's value when SIGHUP is received.
-async fn signal_handler(tx: watch::Sender<bool>) {
+/// * `args` - The command-line arguments, resolved again over the configuration file on SIGHUP.
+/// * `control` - The state shared with the control socket, through which reloads reach the CPU manager.
+/// * `crash_dump` - The path the decision log is dumped to when SIGABRT is received.
+/// * `exe` - The path of the binary to hand over to on SIGUSR2.
+/// * `shutdown` - The token cancelled to shut the program down.
+///
+/// # Returns
+/// * `bool` - Whether the program should hand over to `exe` instead of shutting down.
+async fn signal_handler(
+    args: Arc<Args>,
+    control: Arc<ControlState>,
+    crash_dump: PathBuf,
+    exe: PathBuf,
+    shutdown: CancellationToken,
+) -> bool {
     let mut sigint = signal(SignalKind::interrupt()).unwrap();
     let mut sigterm = signal(SignalKind::terminate()).unwrap();
     let mut sighup = signal(SignalKind::hangup()).unwrap();
+    let mut sigusr1 = signal(SignalKind::user_defined1()).unwrap();
+    let mut sigusr2 = signal(SignalKind::user_defined2()).unwrap();
+    let mut sigabrt = signal(SignalKind::from_raw(libc::SIGABRT)).unwrap();
 
-    let mut flag = false;
-
+    let mut upgrade = false;
     loop {
         tokio::select! {
+            _ = shutdown.cancelled() => break,
             _ = sigint.recv() => {
-                println!("Received SIGINT");
-                online_all_cpus().await.unwrap();
+                info!("Received SIGINT");
                 break;
             }
             _ = sigterm.recv() => {
-                println!("Received SIGTERM");
-                online_all_cpus().await.unwrap();
+                info!("Received SIGTERM");
                 break;
             }
             _ = sighup.recv() => {
-                println!("Received SIGHUP");
-                flag = !flag;
-                tx.send(flag).unwrap();
+                info!("Received SIGHUP, reloading the configuration");
+                tokio::spawn(reload_config(Arc::clone(&args), Arc::clone(&control)));
             }
-        }
-    }
-
-    // Cleanup code here
-    println!("Shutting down...");
-}
-
-/// Manages CPU states based on load thresholds and signals asynchronously.
-///
-/// This function performs the following steps:
-/// 1. Enters an infinite loop to continuously monitor and manage CPU states.
-/// 2. Checks if a HUP signal has been received using the `rx` receiver:
-///    - If a HUP signal is received, it prints a message, calls `online_all_cpus` to online all CPUs,
-///      and waits until the HUP signal is cleared.
-/// 3. Calls `update_c0_percentages` to update the C0 state percentages for all CPUs.
-/// 4. Calculates the total and average C0 state percentage for all online CPUs.
-/// 5. Prints the average C0 state percentage and the number of online CPUs.
-/// 6. Compares the average C0 state percentage with the upper and lower thresholds:
-///    - If the average C0 state percentage is above the upper threshold, it attempts to online more CPUs.
-///    - If the average C0 state percentage is below the lower threshold, it attempts to offline some CPUs.
-///    - If the average C0 state percentage is within the thresholds, it prints a message indicating no action is needed.
-/// 7. Sleeps for 1 second before repeating the loop.
-///
-/// # Arguments
-/// * `args` - A reference to the `Args` struct containing the command-line arguments.
-/// * `topology` - A mutable reference to the `SystemTopology` struct representing the system's CPU topology.
-/// * `rx` - A `watch::Receiver<bool>` used to receive signals indicating a HUP signal.
-async fn cpu_manager(
-    args: &Args,
-    topology: &mut SystemTopology,
-    rx: watch::Receiver<bool>,
-) -> io::Result<()> {
-    loop {
-        if *rx.borrow() {
-            println!("Received HUP signal...");
-            online_all_cpus().await?;
-            println!("Send Hup signal to restart...");
-            loop {
-                if !*rx.borrow() {
-                    break;
+            _ = sigusr1.recv() => {
+                info!("Received SIGUSR1, dumping the status");
+                let control = Arc::clone(&control);
+                tokio::spawn(async move {
+                    if let Err(e) = control.submit(ManagerCommand::DumpStatus).await {
+                        error!("Cannot dump the status: {}", e);
+                    }
+                });
+            }
+            _ = sigusr2.recv() => {
+                if !exe.exists() {
+                    error!("Received SIGUSR2, but cannot hand over: {:?} does not exist", exe);
+                    continue;
                 }
-                tokio::time::sleep(Duration::from_millis(100)).await;
+                info!("Received SIGUSR2, handing over to {:?}", exe);
+                upgrade = true;
+                break;
+            }
+            _ = sigabrt.recv() => {
+                info!("Received SIGABRT");
+                decision_log::dump_to(&crash_dump);
+                std::process::abort();
             }
         }
+    }
 
-        topology.update_c0_percentages().await?;
-
-        let total_c0: f64 = topology
-            .cpus
-            .values()
-            .filter(|cpu| cpu.online)
-            .map(|cpu| cpu.c0_percentage)
-            .sum();
-        let online_count = topology.cpus.values().filter(|cpu| cpu.online).count();
-        let avg_c0 = if online_count > 0 {
-            total_c0 / online_count as f64
-        } else {
-            0.0
-        };
+    if !upgrade {
+        info!("Shutting down...");
+    }
+    shutdown.cancel();
+    upgrade
+}
 
-        println!(
-            "Average C0 state percentage: {:.2}%, Online CPUs: {}",
-            avg_c0, online_count
+/// Re-reads the configuration, from `--config-url` if set and from the local file otherwise, and hands
+/// the resolved settings to the CPU manager. If the settings cannot be resolved or are refused, the
+/// previous ones stay in effect and the rejection is counted in the metrics.
+async fn reload_config(args: Arc<Args>, control: Arc<ControlState>) {
+    let settings = match Settings::fetch(&args).await {
+        Ok(Some(settings)) => Ok(settings),
+        Ok(None) => Settings::resolve(&args),
+        Err(e) => Err(e),
+    };
+    let outcome = match settings {
+        Ok(settings) => control
+            .submit(ManagerCommand::Reload(Box::new(settings)))
+            .await
+            .map(|_| ()),
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = outcome {
+        METRICS.config_reload_rejected();
+        error!(
+            "Cannot reload the configuration, keeping the current settings: {}",
+            e
         );
+    }
+}
 
-        if avg_c0 > args.upper_threshold as f64 {
-            if let Some(core_to_online) = topology.select_cpu_to_online() {
-                println!("High load detected, onlining core {:?}", core_to_online);
-                let _ = topology.online_cpu_group(&core_to_online).await;
-            } else {
-                println!("Cannot online more CPUs, already at maximum");
-            }
-        } else if avg_c0 < args.lower_threshold as f64 {
-            if let Some(core_to_offline) = topology.select_cpu_to_offline() {
-                println!("Low load detected, offlining core {:?}", core_to_offline);
-                let _ = topology.offline_cpu_group(&core_to_offline).await;
-            } else {
-                println!("Cannot offline more CPUs, already at minimum");
-            }
+/// Runs a client subcommand against the daemon whose control socket is at `path`, printing the
+/// response data as indented JSON, or on a single line for `status --json`. `inhibit` exits with
+/// the exit code of its command.
+async fn run_client(command: Command, path: &Path) -> io::Result<()> {
+    let compact = matches!(command, Command::Status { json: true });
+    let request = match command {
+        Command::Events { follow } => return control::print_events(path, follow).await,
+        Command::Inhibit { who, why, command } => {
+            let status = control::run_inhibited(path, &who, &why, &command).await?;
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        Command::Status { .. } => json!({ "command": "status" }),
+        Command::Energy => json!({ "command": "energy" }),
+        Command::Pause => json!({ "command": "pause" }),
+        Command::Resume => json!({ "command": "resume" }),
+        Command::LogFilter { filter } => json!({ "command": "log_filter", "filter": filter }),
+        Command::Set {
+            upper,
+            lower,
+            hysteresis,
+            cooldown_secs,
+        } => json!({
+            "command": "set",
+            "upper_threshold": upper,
+            "lower_threshold": lower,
+            "hysteresis": hysteresis,
+            "cooldown_secs": cooldown_secs,
+        }),
+    };
+    if let Some(data) = control::send_request(path, &request).await? {
+        if compact {
+            println!("{}", serde_json::to_string(&data)?);
         } else {
-            println!("Load is optimal, no action needed");
+            println!("{}", serde_json::to_string_pretty(&data)?);
         }
-
-        let _ = tokio::time::sleep(Duration::from_secs(1)).await;
     }
+    Ok(())
 }
 
 /// The main entry point for the CPU manager program.
 ///
 /// This function performs the following steps:
 /// 1. Parses command-line arguments using the `clap` crate.
-/// 2. Prints the starting message and the upper and lower load thresholds.
-/// 3. Calls `online_all_cpus` to ensure all CPUs are online at the start.
-/// 4. Initializes the system topology by creating a new `SystemTopology` instance and prints a summary of the system topology.
-/// 5. Creates a `watch` channel for signal handling.
-/// 6. Spawns two asynchronous tasks:
-///    - `main_task`: Runs the `cpu_manager` function to manage CPU states based on load thresholds.
-///    - `signal_task`: Runs the `signal_handler` function to handle UNIX signals.
-/// 7. Uses `tokio::select!` to wait for either the `main_task` or `signal_task` to complete.
-/// 8. Prints a message indicating which task completed and returns `Ok(())`.
+/// 2. Resolves the settings from the command line and the configuration file. For a client subcommand,
+///    sends it to the running daemon, prints the response, and returns. Otherwise, if `--config-url` is set,
+///    fetches the signed remote configuration and uses it instead of the local file, falling back to the
+///    local settings if it cannot be fetched or verified, and prints the settings.
+/// 3. Initializes the system topology by creating a new `SystemTopology` instance, which records the CPUs online
+///    at startup for `--on-exit restore`, and marks the excluded CPUs as unmanaged. Reconciles a hotplug write
+///    that a previous run left unverified in the journal.
+/// 4. Unless started with `--start-from current` or by a previous daemon handing over on SIGUSR2, calls `online_all_cpus` to ensure all CPUs that are not
+///    excluded are online at the start. Prints a summary of the system topology and reports readiness to systemd,
+///    unless readiness is deferred to the first full cycle with `--ready-after-first-cycle`.
+/// 5. Starts the Prometheus metrics server in the background if `--metrics-addr` is given.
+/// 6. Creates the shutdown token and the state shared with the control socket, and starts the control socket server in the
+///    background until shutdown, on the listener handed over by the previous daemon, if any. Restores the control state,
+///    the initial CPUs, the noise floor, and the tuning from the handoff, see `handoff`.
+/// 7. Spawns the `signal_handler` task, which reloads the configuration on SIGHUP and cancels the shutdown
+///    token on SIGINT, SIGTERM, or SIGUSR2.
+/// 8. Runs a `CpuManager` to manage CPU states based on load thresholds until the token is cancelled
+///    or the manager fails.
+/// 9. After SIGUSR2, flushes the output sinks and `exec`s the new binary with the handoff, skipping the exit action.
+///    Otherwise, or if the `exec` fails, tears down in order: once the control loop has stopped, runs the `--on-exit` action, flushes the output sinks, waits for the background tasks, and returns the first error, if any.
 ///
 /// # Returns
 /// * `Result<(), Box<dyn std::error::Error>>` - Returns `Ok(())` if successful, or an error if an error occurs.
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
-
-    println!("Starting CPU manager");
-    println!("Upper load threshold: {}%", args.upper_threshold);
-    println!("Lower load threshold: {}%", args.lower_threshold);
-    println!("Onlining all CPUs");
-    online_all_cpus().await?;
+    let mut args = Args::parse();
+    let settings = Settings::resolve(&args)?;
+    if let Some(command) = args.command.take() {
+        run_client(command, &settings.control_socket).await?;
+        return Ok(());
+    }
+    logging::init(settings.log_level, settings.log_format);
+    let settings = match Settings::fetch(&args).await {
+        Ok(Some(remote)) => remote,
+        Ok(None) => settings,
+        Err(e) => {
+            warn!(
+                "Cannot use the remote configuration, falling back to the local one: {}",
+                e
+            );
+            settings
+        }
+    };
+    let profile = &settings.profile;
 
-    let mut topology = SystemTopology::new().await?;
+    info!("Starting CPU manager");
+    info!("Profile: {:?}", profile.name);
+    if let Some(workload) = settings.workload {
+        info!("Workload: {:?}", workload);
+    }
+    info!("Upper load threshold: {}%", profile.upper_threshold);
+    info!("Lower load threshold: {}%", profile.lower_threshold);
+    info!(
+        "Hysteresis: {} samples, cooldown: {:?}, step: {} cores",
+        profile.hysteresis, profile.cooldown, profile.step
+    );
+    match settings.mode {
+        ScalingMode::Threshold => {}
+        ScalingMode::Target => info!("Target utilization: {}%", settings.target_util),
+        ScalingMode::Pid => info!(
+            "Target utilization: {}%, PID gains: Kp {}, Ki {}, Kd {}",
+            settings.target_util, settings.kp, settings.ki, settings.kd
+        ),
+    }
+    if let Some(panic_threshold) = settings.panic_threshold {
+        info!(
+            "Panic threshold: {}% ({:?} load)",
+            panic_threshold, settings.panic_on
+        );
+    }
+    if let Some(limit) = settings.max_transitions_per_minute {
+        info!("Transition limit: {} CPUs per minute", limit);
+    }
+    info!("Polling interval: {:?}", settings.interval);
+    if !settings.spike_filter.is_zero() {
+        info!("Spike filter: {:?}", settings.spike_filter);
+    }
+    info!("Load metric: {:?}", settings.metric);
+    if settings.window > 1 {
+        info!(
+            "Load smoothing: {:?} of {} samples",
+            settings.window_aggregation, settings.window
+        );
+    }
+    if !settings.exclude_cpus.is_empty() {
+        info!("Excluded CPUs: {}", settings.exclude_cpus);
+    }
+    if settings.calibration_samples > 0 {
+        info!(
+            "Noise floor calibration: {} samples",
+            settings.calibration_samples
+        );
+    }
+    if settings.min_cpus > 0 {
+        info!("Minimum online CPUs: {}", settings.min_cpus);
+    }
+    if settings.consolidate_on_lid {
+        info!("Consolidating when the lid closes or displays blank");
+    }
+    if settings.logind_idle {
+        info!("Following the logind idle hint");
+    }
+    if settings.uevents {
+        info!("Following the kernel's CPU uevents");
+    }
+    if settings.full_cores_only {
+        info!("Full cores only: half-online cores are not allowed");
+    }
+    if let Some(housekeeping_cpu) = settings.housekeeping_cpu {
+        info!(
+            "Housekeeping CPU: {}, the boot CPU is managed",
+            housekeeping_cpu
+        );
+    }
+    info!("Decision log crash dump: {:?}", settings.crash_dump);
+    decision_log::install_panic_dump(settings.crash_dump.clone());
+    info!("On exit: {:?}", settings.on_exit);
+
+    let exe = std::env::current_exe()?;
+    let handoff = Handoff::from_env().unwrap_or_else(|e| {
+        warn!(
+            "Cannot take over from the previous daemon, starting afresh: {}",
+            e
+        );
+        None
+    });
+
+    let backend_cgroup = cpuset::cgroup_dir(&settings.sysfs_root, &settings.backend_cgroup);
+    let cpuset = cpuset::allowed_cpus(
+        &settings.sysfs_root,
+        !settings.ignore_cpuset,
+        settings.restrict_to_cgroup.as_deref(),
+        (settings.backend == BackendName::Cpuset).then_some(backend_cgroup.as_path()),
+    )
+    .await?;
+    let mut topology = SystemTopology::builder()
+        .sysfs_root(&settings.sysfs_root)
+        .actuation(settings.backend, backend_cgroup)
+        .exclude_cpus(settings.exclude_cpus.clone())
+        .manage_isolated(settings.manage_isolated)
+        .cpuset(cpuset)
+        .full_cores_only(settings.full_cores_only)
+        .housekeeping_cpu(settings.housekeeping_cpu)
+        .deconfigure(settings.deconfigure)
+        .offline_order(settings.offline_order)
+        .strategy(settings.strategy().build())
+        .granularity(settings.granularity)
+        .prefer_no_smt(settings.prefer_no_smt)
+        .no_smt_if_vulnerable(settings.no_smt_if_vulnerable)
+        .prefer_offline(settings.prefer_offline)
+        .blacklist(settings.blacklist_after, settings.blacklist_for)
+        .journal(HotplugJournal::new(&settings.hotplug_journal))
+        .build()
+        .await?;
+    info!(
+        "Taking CPUs away through the {} backend",
+        topology.actuation.name()
+    );
+    if let Err(e) = topology.reconcile_journal().await {
+        warn!(
+            "Cannot reconcile the hotplug journal {:?}: {}",
+            settings.hotplug_journal, e
+        );
+    }
+    let start_from = if handoff.is_some() {
+        info!("Taking over from the previous daemon");
+        StartFrom::Current
+    } else {
+        settings.start_from
+    };
+    match start_from {
+        StartFrom::All => {
+            info!("Onlining all CPUs");
+            online_all_cpus(
+                &*topology.sysfs,
+                &*topology.actuation,
+                &topology.online_all_exclusions(),
+            )
+            .await?;
+            topology.refresh_online().await;
+        }
+        StartFrom::Current => {
+            let housekeeping = topology.housekeeping_cpu;
+            if topology
+                .cpus
+                .get(&housekeeping)
+                .is_some_and(|cpu| !cpu.online)
+            {
+                info!("Onlining housekeeping CPU {}", housekeeping);
+                topology.online_cpu_group(&[housekeeping]).await?;
+            }
+            info!(
+                "Starting from the {} CPUs currently online",
+                topology.online_count()
+            );
+        }
+    }
     topology.print_summary();
+    if !settings.ready_after_first_cycle {
+        if let Err(e) = systemd::notify("READY=1") {
+            warn!("Cannot notify systemd: {}", e);
+        }
+    }
 
-    let (tx, rx) = watch::channel(false);
-
-    let main_task = tokio::spawn(async move { cpu_manager(&args, &mut topology, rx).await });
-
-    let signal_task = tokio::spawn(signal_handler(tx));
+    if let Some(addr) = settings.metrics_addr {
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(addr).await {
+                error!("Metrics server failed: {}", e);
+            }
+        });
+    }
 
-    tokio::select! {
-        _ = main_task => println!("Main task completed"),
-        _ = signal_task => println!("Received shutdown signal"),
+    let shutdown = CancellationToken::new();
+    let control_state = Arc::new(ControlState::new());
+    let control_socket = settings.control_socket.clone();
+    let listener = match handoff.as_ref().and_then(Handoff::listener) {
+        Some(listener) => listener,
+        None => control::bind(&control_socket),
+    };
+    // Kept open for the binary to take over on SIGUSR2, even once the server has stopped.
+    let handoff_listener = listener
+        .as_ref()
+        .ok()
+        .and_then(|listener| listener.as_fd().try_clone_to_owned().ok());
+    let server_state = Arc::clone(&control_state);
+    let server_shutdown = shutdown.clone();
+    let control_task = tokio::spawn(async move {
+        let result = match listener {
+            Ok(listener) => tokio::select! {
+                result = control::serve_listener(listener, server_state) => result,
+                _ = server_shutdown.cancelled() => Ok(()),
+            },
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            error!("Control socket failed: {}", e);
+        }
+    });
+
+    let influx_url = settings.influx_url.clone();
+    let vcpu_companion = settings.vcpu_companion.clone();
+    let kube_api = settings.kube_api.clone();
+    let kube_node = settings.kube_node.clone();
+    let sinks = (!settings.sinks.is_empty())
+        .then(|| Sinks::spawn(&settings.sinks, &control_state.events))
+        .transpose()?;
+    let crash_dump = settings.crash_dump.clone();
+    let logind_idle = settings.logind_idle;
+    let uevents = settings.uevents;
+    let consolidate_on_lid = settings.consolidate_on_lid;
+    let sysfs_root = settings.sysfs_root.clone();
+    let paused = control_state.paused.subscribe();
+    let signal_state = Arc::clone(&control_state);
+    let mut manager = CpuManager::new(settings, topology, control_state)?;
+    if let Some(handoff) = &handoff {
+        manager.resume_from(handoff);
+    }
+    if let Some(url) = influx_url {
+        manager.set_influx_sink(InfluxSink::spawn(url));
     }
+    if let Some(sinks) = sinks {
+        manager.set_sinks(sinks);
+    }
+    if let Some(url) = vcpu_companion {
+        manager.set_vcpu_companion(VcpuCompanion::spawn(&url)?);
+    }
+    if let Some(url) = kube_api {
+        manager.set_kube_node(KubeNode::spawn(&url, kube_node.as_deref())?);
+    }
+    if logind_idle {
+        manager.set_idle_hint(logind::watch_idle_hint());
+    }
+    if consolidate_on_lid {
+        manager.set_away_hint(lid::watch_away(sysfs_root));
+    }
+    if uevents {
+        match uevent::watch_cpu_uevents() {
+            Ok(uevents) => manager.set_uevents(uevents),
+            Err(e) => warn!("Cannot subscribe to uevents, relying on polling: {}", e),
+        }
+    }
+    let signal_task = tokio::spawn(signal_handler(
+        Arc::new(args),
+        signal_state,
+        crash_dump,
+        exe.clone(),
+        shutdown.clone(),
+    ));
+
+    // Stop the policy: the control loop returns between two steps once shutdown is requested.
+    let result = manager.run(paused, shutdown.clone()).await;
+    if let Err(e) = &result {
+        error!("CPU manager failed: {}", e);
+    }
+    shutdown.cancel();
+    let upgrade = signal_task.await.unwrap_or(false);
+    let _ = control_task.await;
+
+    // Hand over to the new binary, leaving the CPUs and the control socket as they are.
+    if upgrade && result.is_ok() {
+        manager.close().await;
+        let e = manager.handoff().exec(&exe, handoff_listener.as_ref());
+        error!("Cannot hand over to {:?}, shutting down: {}", exe, e);
+    }
+    let _ = std::fs::remove_file(&control_socket);
+    let _ = systemd::notify("STOPPING=1");
+
+    // Run the exit action, then flush the sinks.
+    let exit_action = manager.run_exit_action().await;
+    manager.close().await;
+    info!("Shutdown complete");
 
+    result?;
+    exit_action?;
     Ok(())
 }
@@ -0,0 +1,2787 @@
+//! The control loop that onlines and offlines CPUs based on load.
+use crate::actuation::BackendName;
+use crate::affinity::{self, PinnedTaskPolicy};
+use crate::boost::{Boost, BoostPolicy};
+use crate::config::Settings;
+use crate::control::{ControlState, ManagerCommand};
+use crate::cpu_list::CpuList;
+use crate::cpufreq::{FrequencyCap, CAP_STEPS};
+use crate::cpuidle::{self, IdleLimit, IdlePolicy, SHALLOWEST_STATE};
+use crate::cpuset;
+use crate::decision_log::{DecisionRecord, DECISION_LOG};
+use crate::events::{Decision, EventKind};
+use crate::handoff::Handoff;
+use crate::influx::InfluxSink;
+use crate::irq::IrqMigrator;
+use crate::kube::KubeNode;
+use crate::kubelet;
+use crate::load::{CpuidleMetric, LoadMetric, LoadSample, LoadWindow, MetricKind, StealReader};
+use crate::logging::{self, LogFormat};
+use crate::metrics::{Direction, METRICS};
+use crate::noise_floor::NoiseFloor;
+use crate::outcome::OutcomeTracker;
+use crate::pm_qos::LatencyGuard;
+use crate::profile::{Profile, ProfileName, ProfileOverrides};
+use crate::pstate::{PstateRegime, PstateTuning};
+use crate::rapl::{self, EnergyMeter};
+use crate::rate_limit::TransitionLimiter;
+use crate::sink::Sinks;
+use crate::status::{GovernorStatus, ManagerStatus};
+use crate::status_line::{SocketLoad, StatusLine};
+use crate::stickiness::StickinessSampler;
+use crate::strategy::StrategyName;
+use crate::systemd;
+use crate::target::{self, PidController, ScalingMode};
+use crate::thermal::ThermalGuard;
+use crate::topology::{online_all_cpus, ExitPolicy, SystemTopology};
+use crate::uevent::{CpuUevent, UeventAction};
+use crate::vcpu::VcpuCompanion;
+use crate::workload::{self, Classifier, WorkloadMode, WorkloadStatus};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{BuildHasher, RandomState};
+use std::io::{self, IsTerminal};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, info_span, warn, Instrument, Span};
+
+/// Returns whether the idle states follow the load, with the tiered backend or `--idle-policy load`.
+fn idle_stage(settings: &Settings) -> bool {
+    settings.backend == BackendName::Tiered || settings.idle_policy == IdlePolicy::Load
+}
+
+/// Creates the load metric of `settings`, watching the `--watch-cgroup` cgroup below the cgroup
+/// filesystem of the sysfs root with the cgroup metric.
+fn build_metric(settings: &Settings) -> io::Result<Box<dyn LoadMetric>> {
+    let cgroup = settings.watch_cgroup.as_ref().map(|cgroup| {
+        settings
+            .sysfs_root
+            .join(cpuset::CGROUP_DIR)
+            .join(cgroup.trim_start_matches('/'))
+    });
+    settings.metric.build(cgroup.as_deref())
+}
+
+/// Returns a random trace ID of 32 hex digits, the W3C trace context format.
+fn new_trace_id() -> String {
+    let state = RandomState::new();
+    format!("{:016x}{:016x}", state.hash_one(1u8), state.hash_one(2u8))
+}
+
+/// Waits for the next CPU uevent, or forever without `--uevents` or once the subscription has ended.
+async fn next_uevent(uevents: &mut Option<mpsc::UnboundedReceiver<CpuUevent>>) -> CpuUevent {
+    if let Some(receiver) = uevents {
+        if let Some(event) = receiver.recv().await {
+            return event;
+        }
+        *uevents = None;
+    }
+    std::future::pending().await
+}
+
+/// Fraction of `--power-budget-watts` kept free: CPUs are only onlined while the package power is
+/// below the rest of the budget, so onlining does not push it straight over.
+const POWER_HEADROOM: f64 = 0.1;
+
+/// Number of recent decisions included in a status dump.
+const STATUS_DUMP_DECISIONS: usize = 10;
+
+/// Hysteresis counters and last action of a single socket, scaled on its own with `--per-socket` or
+/// rebalanced with `--rebalance-sockets`.
+#[derive(Default)]
+struct SocketState {
+    samples_above: u32,
+    samples_below: u32,
+    last_action: Option<Instant>,
+}
+
+/// The stages of the cpufreq and tiered backends and of `--idle-policy load`, in the order they are
+/// entered as the load drops: the frequency cap comes down, then deeper idle states are allowed,
+/// then cores are offlined. As the load climbs, they are left in the reverse order. Only the stages
+/// of the backend and idle policy in use are entered, e.g. the cpufreq backend never offlines.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TierStage {
+    Frequency,
+    Idle,
+    Offline,
+}
+
+impl TierStage {
+    pub fn label(self) -> &'static str {
+        match self {
+            TierStage::Frequency => "frequency",
+            TierStage::Idle => "idle",
+            TierStage::Offline => "offline",
+        }
+    }
+}
+
+/// Manages CPU states of a `SystemTopology` according to the resolved `Settings`.
+pub struct CpuManager {
+    settings: Settings,
+    topology: SystemTopology,
+    control: Arc<ControlState>,
+    metric: Box<dyn LoadMetric>,
+    /// The recent samples the load is smoothed over with `--window`.
+    window: LoadWindow,
+    influx: Option<InfluxSink>,
+    sinks: Option<Sinks>,
+    /// Asks the host to unplug and plug vCPUs with `--vcpu-companion`.
+    vcpu_companion: Option<VcpuCompanion>,
+    /// Publishes the online CPUs on the Kubernetes node with `--kube-api`.
+    kube_node: Option<KubeNode>,
+    classifier: Option<Classifier>,
+    noise_floor: NoiseFloor,
+    /// Samples the tasks' CPU affinity with `--stickiness`.
+    stickiness: Option<StickinessSampler>,
+    idle_hint: Option<watch::Receiver<bool>>,
+    idle: bool,
+    /// The selection strategy the topology was last given, see `follow_strategy`.
+    strategy: StrategyName,
+    away_hint: Option<watch::Receiver<bool>>,
+    away: bool,
+    /// The kernel's CPU uevents with `--uevents`, taken over by `run`.
+    uevents: Option<mpsc::UnboundedReceiver<CpuUevent>>,
+    color: bool,
+    previous_avg_c0: Option<f64>,
+    /// Reads the steal time from `/proc/stat` for metrics that do not report it.
+    steal_reader: StealReader,
+    /// The steal time of the latest cycle in percent, see `LoadSample::steal`.
+    steal: Option<f64>,
+    /// The wakeup latency of the latest sample in microseconds, see `LoadSample::wakeup_latency_us`.
+    wakeup_latency_us: Option<f64>,
+    samples_above: u32,
+    samples_below: u32,
+    last_action: Option<Instant>,
+    /// When the CPUs were last listed for hot-added or removed ones, see `rescan`.
+    last_rescan: Instant,
+    /// Whether a uevent reported a hot-added or removed CPU the topology has not been scanned for yet.
+    rescan_due: bool,
+    /// The CPUs hotplugged within the last minute, limited by `--max-transitions-per-minute`.
+    transitions: TransitionLimiter,
+    /// The IRQs moved away from offlined CPUs with `--migrate-irqs`.
+    irqs: IrqMigrator,
+    /// The frequency cap of the cpufreq and tiered backends.
+    freq_cap: Option<FrequencyCap>,
+    /// The deepest idle state allowed by the tiered backend, `--idle-policy load`, or `--max-cstate`.
+    idle_limit: Option<IdleLimit>,
+    /// The stage of the cpufreq and tiered backends and of `--idle-policy load`.
+    stage: Option<TierStage>,
+    /// Detects latency-sensitive mode with `--latency-us`.
+    latency_guard: Option<LatencyGuard>,
+    /// Tracks the package temperatures with `--thermal-limit`.
+    thermal: Option<ThermalGuard>,
+    /// The package energy by online CPU count, for the energy report.
+    energy: EnergyMeter,
+    /// Turbo, switched with `--boost-policy`.
+    boost: Boost,
+    /// The EPP and performance limit of the online CPUs, tuned with `--tune-pstate`.
+    pstate: PstateTuning,
+    /// The state of each socket with `--per-socket` or `--rebalance-sockets`.
+    sockets: HashMap<usize, SocketState>,
+    /// Drives the online capacity towards the target utilization with `--mode pid`.
+    pid: PidController,
+    /// The load predicted by the latest action, checked against the next cycle.
+    outcomes: OutcomeTracker,
+    /// Whether the first full cycle has completed.
+    ready: bool,
+    /// Interval between two systemd watchdog pings, half the watchdog timeout, if enabled.
+    watchdog: Option<Duration>,
+}
+
+impl CpuManager {
+    /// Creates a manager using the load metric selected by `settings`.
+    ///
+    /// If the cpuidle metric is selected but the cpuidle counters are missing, falls back to the
+    /// `/proc/stat` metric.
+    ///
+    /// # Returns
+    /// * `io::Result<CpuManager>` - The manager, or an error if the load metric is not available.
+    pub fn new(
+        mut settings: Settings,
+        mut topology: SystemTopology,
+        control: Arc<ControlState>,
+    ) -> io::Result<Self> {
+        topology.full_cores_only |= settings.full_cores_only;
+        if settings.metric == MetricKind::Cpuidle && !CpuidleMetric::is_available(&topology) {
+            warn!("cpuidle is not available, falling back to the proc-stat load metric");
+            settings.metric = MetricKind::ProcStat;
+        }
+        if settings.metric_validation && settings.metric != MetricKind::Cpuidle {
+            warn!("Metric validation only applies to the cpuidle load metric");
+        }
+        if settings.wakeup_latency_us.is_some() && settings.metric != MetricKind::Ebpf {
+            warn!("--wakeup-latency-us only applies to the ebpf load metric");
+        }
+        if settings.metric == MetricKind::Perf && settings.backend.caps_frequency() {
+            warn!("The perf load metric reads frequency caps as idle time, so the load drops as the backend caps the CPUs");
+        }
+        if settings.power_budget_watts.is_some() && !rapl::has_package_zones(&settings.sysfs_root) {
+            warn!("No RAPL package energy counters, the power budget has no effect");
+        }
+        if let Some(mode) = settings.workload {
+            *control.workload.lock().unwrap() = Some(WorkloadStatus {
+                mode,
+                class: mode.pinned(),
+                profile: settings.profile.name,
+            });
+        }
+        let manager = CpuManager {
+            metric: build_metric(&settings)?,
+            window: LoadWindow::new(settings.window, settings.window_aggregation),
+            pid: PidController::new(settings.kp, settings.ki, settings.kd),
+            classifier: (settings.workload == Some(WorkloadMode::Auto)).then(Classifier::new),
+            noise_floor: NoiseFloor::new(settings.calibration_samples),
+            stickiness: settings.stickiness.then(StickinessSampler::new),
+            freq_cap: settings
+                .backend
+                .caps_frequency()
+                .then(FrequencyCap::default),
+            idle_limit: (idle_stage(&settings)
+                || settings.max_cstate.is_some()
+                || settings.latency_us.is_some())
+            .then(|| IdleLimit::new(settings.max_cstate)),
+            latency_guard: settings.latency_us.map(LatencyGuard::new),
+            thermal: settings
+                .thermal_limit
+                .map(|limit| ThermalGuard::new(&settings.sysfs_root, f64::from(limit))),
+            stage: (settings.backend.caps_frequency() || idle_stage(&settings)).then(|| {
+                if settings.backend != BackendName::Cpufreq
+                    && topology.select_cpu_to_online().is_some()
+                {
+                    TierStage::Offline
+                } else if settings.backend.caps_frequency() {
+                    TierStage::Frequency
+                } else {
+                    TierStage::Idle
+                }
+            }),
+            energy: EnergyMeter::new(&settings.sysfs_root),
+            boost: Boost::default(),
+            pstate: PstateTuning::default(),
+            idle_hint: None,
+            idle: false,
+            strategy: settings.strategy(),
+            away_hint: None,
+            away: false,
+            uevents: None,
+            color: io::stdout().is_terminal() && settings.log_format == LogFormat::Text,
+            settings,
+            topology,
+            control,
+            influx: None,
+            sinks: None,
+            vcpu_companion: None,
+            kube_node: None,
+            previous_avg_c0: None,
+            steal_reader: StealReader::new(),
+            steal: None,
+            wakeup_latency_us: None,
+            samples_above: 0,
+            samples_below: 0,
+            last_action: None,
+            last_rescan: Instant::now(),
+            rescan_due: false,
+            transitions: TransitionLimiter::default(),
+            irqs: IrqMigrator::default(),
+            sockets: HashMap::new(),
+            outcomes: OutcomeTracker::default(),
+            ready: false,
+            watchdog: systemd::watchdog_timeout().map(|timeout| timeout / 2),
+        };
+        manager.publish_status();
+        Ok(manager)
+    }
+
+    /// Replaces the load metric selected by the settings, e.g. with a custom implementation.
+    pub fn set_metric(&mut self, metric: Box<dyn LoadMetric>) {
+        self.metric = metric;
+    }
+
+    /// Replaces the `/proc/stat` steal time reader, e.g. to read a fake file in tests.
+    pub fn set_steal_reader(&mut self, reader: StealReader) {
+        self.steal_reader = reader;
+    }
+
+    /// Replaces the latency guard of `--latency-us`, e.g. to read a fake device in tests.
+    pub fn set_latency_guard(&mut self, guard: LatencyGuard) {
+        if self.idle_limit.is_none() {
+            self.idle_limit = Some(IdleLimit::new(self.settings.max_cstate));
+        }
+        self.latency_guard = Some(guard);
+    }
+
+    /// Exports the metrics of every cycle to InfluxDB through `sink`.
+    pub fn set_influx_sink(&mut self, sink: InfluxSink) {
+        self.influx = Some(sink);
+    }
+
+    /// Hands the record of every cycle to the configured `sinks`.
+    pub fn set_sinks(&mut self, sinks: Sinks) {
+        self.sinks = Some(sinks);
+    }
+
+    /// Tells `companion` which vCPUs are offline and how many are wanted after every cycle, see
+    /// `VcpuCompanion`.
+    pub fn set_vcpu_companion(&mut self, companion: VcpuCompanion) {
+        self.vcpu_companion = Some(companion);
+    }
+
+    /// Patches the online CPUs onto the Kubernetes node through `node` after every cycle, see
+    /// `KubeNode`.
+    pub fn set_kube_node(&mut self, node: KubeNode) {
+        self.kube_node = Some(node);
+    }
+
+    /// Switches to the power-saver profile while `idle_hint` is true, e.g. while logind reports all
+    /// sessions as idle.
+    pub fn set_idle_hint(&mut self, idle_hint: watch::Receiver<bool>) {
+        self.idle_hint = Some(idle_hint);
+    }
+
+    /// Consolidates down to the floor as soon as `away_hint` becomes true, e.g. when the lid is
+    /// closed or all displays are blanked.
+    pub fn set_away_hint(&mut self, away_hint: watch::Receiver<bool>) {
+        self.away_hint = Some(away_hint);
+    }
+
+    /// Reacts to the kernel's CPU uevents as they arrive, see `handle_uevent`, instead of waiting for
+    /// the next cycle or rescan.
+    pub fn set_uevents(&mut self, uevents: mpsc::UnboundedReceiver<CpuUevent>) {
+        self.uevents = Some(uevents);
+    }
+
+    /// Returns the profile in effect: the power-saver profile while the session is idle, and the
+    /// configured or classified profile otherwise.
+    pub fn active_profile(&self) -> Profile {
+        if self.idle {
+            ProfileName::PowerSaver.profile()
+        } else {
+            self.settings.profile.clone()
+        }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn topology(&self) -> &SystemTopology {
+        &self.topology
+    }
+
+    /// Manages CPU states based on load thresholds, pausing, and control commands asynchronously.
+    ///
+    /// This function performs the following steps:
+    /// 1. Enters an infinite loop to continuously monitor and manage CPU states.
+    /// 2. Checks if the manager is paused by the control socket, using the `paused` receiver:
+    ///    - If paused, it calls `online_all_cpus` to online all CPUs that are not excluded, re-reads their
+    ///      online state, lifts the frequency cap, and waits until it is resumed, serving control socket commands meanwhile.
+    /// 3. Calls `step` to sample the load and act on it.
+    /// 4. Pings the systemd watchdog and updates the service status with the load and online CPUs.
+    /// 5. Waits for the polling interval before repeating the loop, serving control socket commands
+    ///    such as manual hotplug and tuning, and configuration reloads, as they arrive, and pinging the watchdog in between if
+    ///    the interval is longer than half the watchdog timeout. With `--uevents`, CPU uevents are
+    ///    handled as they arrive too, paused or not, see `handle_uevent`.
+    ///
+    /// The loop returns once `shutdown` is cancelled, but never in the middle of a step, so a
+    /// hotplug operation is never cut off.
+    ///
+    /// # Arguments
+    /// * `paused` - A `watch::Receiver<bool>` indicating whether the manager is paused.
+    /// * `shutdown` - A token cancelled when the program shuts down.
+    pub async fn run(
+        &mut self,
+        mut paused: watch::Receiver<bool>,
+        shutdown: CancellationToken,
+    ) -> io::Result<()> {
+        let control = Arc::clone(&self.control);
+        let ping_every = self.watchdog.unwrap_or(self.settings.interval);
+        let mut uevents = self.uevents.take();
+        loop {
+            if *paused.borrow_and_update() {
+                info!("Paused, onlining all CPUs");
+                online_all_cpus(
+                    &*self.topology.sysfs,
+                    &*self.topology.actuation,
+                    &self.topology.online_all_exclusions(),
+                )
+                .await?;
+                self.topology.refresh_online().await;
+                self.release_stages().await;
+                self.sample_energy().await;
+                self.publish_status();
+                self.notify_systemd(Some("Paused, all CPUs online"));
+                while *paused.borrow_and_update() {
+                    tokio::select! {
+                        _ = shutdown.cancelled() => return Ok(()),
+                        _ = paused.changed() => {}
+                        _ = control.requests_ready.notified() => self.serve_requests().await,
+                        event = next_uevent(&mut uevents) => self.handle_uevent(event).await,
+                        _ = tokio::time::sleep(ping_every), if self.watchdog.is_some() => {
+                            self.notify_systemd(None)
+                        }
+                    }
+                }
+                info!("Resumed");
+            }
+
+            self.step().await?;
+            self.notify_systemd(Some(&format!(
+                "Load {:.1}%, {}/{} CPUs online",
+                self.previous_avg_c0.unwrap_or_default(),
+                self.topology.online_count(),
+                self.topology.cpus.len()
+            )));
+
+            let next_step = tokio::time::Instant::now() + self.settings.interval;
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => return Ok(()),
+                    _ = tokio::time::sleep_until(next_step) => break,
+                    _ = control.requests_ready.notified() => self.serve_requests().await,
+                    event = next_uevent(&mut uevents) => self.handle_uevent(event).await,
+                    _ = tokio::time::sleep(ping_every), if self.watchdog.is_some() => {
+                        self.notify_systemd(None)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Pings the systemd watchdog, if enabled, and updates the service status to `status`, if given.
+    /// Does nothing when not running under systemd.
+    fn notify_systemd(&self, status: Option<&str>) {
+        let mut state = Vec::new();
+        if self.watchdog.is_some() {
+            state.push("WATCHDOG=1".to_string());
+        }
+        if let Some(status) = status {
+            state.push(format!("STATUS={}", status));
+        }
+        if state.is_empty() {
+            return;
+        }
+        if let Err(e) = systemd::notify(&state.join("\n")) {
+            debug!("Cannot notify systemd: {}", e);
+        }
+    }
+
+    /// Carries out the exit policy. Must be called after the control loop has stopped.
+    ///
+    /// - `restore`: returns the CPUs, the frequency cap, the idle state limit, turbo, and the EPP to
+    ///   their state at startup.
+    /// - `online-all`: onlines and uncaps every CPU, allows every idle state, enables turbo unless
+    ///   the boost policy is `leave`, and tunes the CPUs for performance with `--tune-pstate`.
+    /// - `leave`: leaves the CPUs as they are.
+    pub async fn run_exit_action(&mut self) -> io::Result<()> {
+        match self.settings.on_exit {
+            ExitPolicy::Restore => {
+                info!("Restoring the CPUs online at startup");
+                self.topology.restore_initial().await?;
+                if let Some(limit) = &mut self.idle_limit {
+                    limit.restore(&self.topology).await?;
+                }
+                self.boost.restore(&self.topology).await?;
+                self.pstate.restore(&self.topology).await?;
+                match &mut self.freq_cap {
+                    Some(cap) => cap.restore(&self.topology).await,
+                    None => Ok(()),
+                }
+            }
+            ExitPolicy::OnlineAll => {
+                info!("Onlining all CPUs");
+                online_all_cpus(
+                    &*self.topology.sysfs,
+                    &*self.topology.actuation,
+                    &self.topology.online_all_exclusions(),
+                )
+                .await?;
+                self.topology.refresh_online().await;
+                if let Some(limit) = &mut self.idle_limit {
+                    let deepest = cpuidle::deepest_state(&self.topology);
+                    limit.set_deepest(&self.topology, deepest).await?;
+                }
+                if self.settings.boost_policy != BoostPolicy::Leave {
+                    self.boost.set(&self.topology, true).await?;
+                }
+                if self.settings.tune_pstate {
+                    self.pstate
+                        .apply(
+                            &self.topology,
+                            PstateRegime::Performance,
+                            self.settings.power_max_perf_pct,
+                        )
+                        .await?;
+                }
+                match &mut self.freq_cap {
+                    Some(cap) => cap.set_level(&self.topology, 0).await,
+                    None => Ok(()),
+                }
+            }
+            ExitPolicy::Leave => {
+                info!("Leaving {} CPUs online", self.topology.online_count());
+                Ok(())
+            }
+        }
+    }
+
+    /// Logs the energy report, flushes the output sinks, such as the InfluxDB export, once the
+    /// control loop has stopped, and removes the ready file.
+    pub async fn close(&mut self) {
+        self.sample_energy().await;
+        if let Some(report) = self.energy.report() {
+            report.log();
+        }
+        if let Some(influx) = self.influx.take() {
+            influx.close().await;
+        }
+        if let Some(sinks) = self.sinks.take() {
+            sinks.close().await;
+        }
+        if let Some(companion) = self.vcpu_companion.take() {
+            companion.close().await;
+        }
+        if let Some(node) = self.kube_node.take() {
+            node.close().await;
+        }
+        if let Some(path) = self.settings.ready_file.as_ref().filter(|_| self.ready) {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("Cannot remove the ready file {:?}: {}", path, e);
+            }
+            self.ready = false;
+        }
+    }
+
+    /// Captures the state a new binary needs to take over from this daemon, see `handoff`. Must be
+    /// called after the control loop has stopped.
+    pub fn handoff(&self) -> Handoff {
+        let mut handoff = Handoff {
+            overrides: self.settings.overrides.clone(),
+            initial_online: self.topology.initial_online.clone(),
+            noise_floor: self.noise_floor.baseline(),
+            ..Handoff::default()
+        };
+        handoff.capture(&self.control);
+        handoff
+    }
+
+    /// Takes over the state of the daemon that handed over to this one: its control state, the
+    /// CPUs online when the first daemon started, the calibrated noise floor, and the changes made
+    /// with `set`.
+    pub fn resume_from(&mut self, handoff: &Handoff) {
+        handoff.restore(&self.control);
+        self.topology.initial_online = handoff.initial_online.clone();
+        if let Some(baseline) = handoff.noise_floor {
+            self.noise_floor = NoiseFloor::calibrated(baseline);
+        }
+        if let Err(e) = self.tune(&handoff.overrides) {
+            warn!("Cannot keep the tuning of the previous daemon: {}", e);
+        }
+        self.publish_status();
+    }
+
+    /// Reports that CPU management is active once the first full cycle, past the noise floor
+    /// calibration, has completed: sends `READY=1` to systemd with `--ready-after-first-cycle`, and
+    /// writes the process ID to the `--ready-file`, if set.
+    fn mark_ready(&mut self) {
+        self.ready = true;
+        if self.settings.ready_after_first_cycle {
+            if let Err(e) = systemd::notify("READY=1") {
+                warn!("Cannot notify systemd: {}", e);
+            }
+        }
+        if let Some(path) = &self.settings.ready_file {
+            match std::fs::write(path, format!("{}\n", std::process::id())) {
+                Ok(()) => info!("First cycle completed, wrote the ready file {:?}", path),
+                Err(e) => warn!("Cannot write the ready file {:?}: {}", path, e),
+            }
+        }
+    }
+
+    /// Carries out the pending commands of the control socket and replies to them.
+    async fn serve_requests(&mut self) {
+        for request in self.control.take_requests() {
+            let outcome = match request.command {
+                ManagerCommand::Hotplug { direction, cpu } => self
+                    .manual_hotplug(direction, cpu)
+                    .await
+                    .map(|cpus| Some(json!({ "cpus": cpus }))),
+                ManagerCommand::Tune(changes) => self.tune(&changes).map(Some),
+                ManagerCommand::Reload(settings) => self.reload(*settings).await.map(Some),
+                ManagerCommand::DumpStatus => {
+                    self.dump_status();
+                    Ok(None)
+                }
+                ManagerCommand::EnergyReport => self.energy_report(),
+            };
+            let _ = request.reply.send(outcome);
+        }
+        self.publish_status();
+    }
+
+    /// Publishes the settings, topology, and control loop state for the `status` command.
+    fn publish_status(&self) {
+        let profile = self.active_profile();
+        let cooldown_remaining = self.last_action.map_or(Duration::ZERO, |t| {
+            profile.cooldown.saturating_sub(t.elapsed())
+        });
+        let governor = GovernorStatus {
+            metric: self.metric.name().to_string(),
+            load: self.previous_avg_c0,
+            noise_floor: self.noise_floor.baseline(),
+            samples_above: self.samples_above,
+            samples_below: self.samples_below,
+            cooldown_remaining_secs: cooldown_remaining.as_secs_f64(),
+            idle: self.idle,
+            away: self.away,
+            hotplug_in_doubt: self.topology.hotplug_in_doubt(),
+            ready: self.ready,
+            transitions_last_minute: self.transitions.count(Instant::now()),
+            max_transitions_per_minute: self.settings.max_transitions_per_minute,
+            frequency_cap: self.freq_cap.as_ref().and_then(FrequencyCap::level),
+            deepest_idle_state: self.idle_limit.as_ref().and_then(IdleLimit::deepest),
+            tier_stage: self.stage,
+            latency_sensitive: self.latency_sensitive(),
+            package_temperature: self
+                .thermal
+                .as_ref()
+                .and_then(ThermalGuard::hottest)
+                .map(|temp| temp.celsius),
+            thermal_limited: self.thermal_limited(),
+            turbo: self.boost.enabled(),
+            pstate_regime: self.pstate.regime(),
+            package_power_watts: self.energy.watts(),
+            steal_percent: self.steal,
+            wakeup_latency_us: self.wakeup_latency_us,
+            active_profile: profile,
+        };
+        *self.control.manager.lock().unwrap() = Some(ManagerStatus {
+            config: self.settings.clone(),
+            topology: self.topology.snapshot(),
+            governor,
+        });
+    }
+
+    /// Applies `changes` on top of the current overrides and switches to the resulting profile.
+    /// The changes are kept when the profile is switched later, e.g. by workload classification.
+    ///
+    /// # Returns
+    /// * `Result<Value, String>` - The tunables now in effect, or the reason the changes were refused.
+    fn tune(&mut self, changes: &ProfileOverrides) -> Result<Value, String> {
+        let overrides = self.settings.overrides.merge(changes);
+        let name = self.settings.profile.name;
+        overrides.validate(&workload::candidate_profiles(self.settings.workload, name))?;
+        let profile = overrides.apply(name);
+        info!(
+            "Tuned the {:?} profile: upper {}%, lower {}%, hysteresis {} samples, cooldown {:?}",
+            name,
+            profile.upper_threshold,
+            profile.lower_threshold,
+            profile.hysteresis,
+            profile.cooldown
+        );
+        self.settings.overrides = overrides;
+        self.settings.profile = profile;
+        Ok(self.tunables())
+    }
+
+    /// Applies `settings` re-read from the configuration file, e.g. on SIGHUP, and re-scans the
+    /// topology.
+    ///
+    /// This function performs the following steps:
+    /// 1. Refuses the reload while a hotplug write is in doubt, since the re-scan would lose track
+    ///    of it.
+    /// 2. Keeps the settings that only take effect at startup, such as the metrics address, the
+    ///    control socket, the InfluxDB URL, the hotplug journal, the idle and lid hints, logging,
+    ///    and noise floor calibration, and warns if they changed.
+    /// 3. Re-scans the topology with the new excluded CPUs, housekeeping CPU, full-cores-only mode,
+    ///    and selection strategy, keeping the CPUs that were online at startup for the exit policy.
+    /// 4. Brings the state derived from the settings in line with them:
+    ///    - Rebuilds the load metric if it or the watched cgroup changed, and the workload
+    ///      classifier if the workload mode changed. In automatic workload mode, keeps the
+    ///      classified profile and applies the new overrides to it.
+    ///    - Restarts the smoothing window if it or the metric changed, and the PID controller if
+    ///      the scaling mode or its gains changed.
+    ///    - Restarts the thermal guard if `--thermal-limit` changed, and the stickiness sampler if
+    ///      `--stickiness` changed.
+    ///    - Restores the EPP if `--tune-pstate` was turned off, and applies a new log level.
+    /// 5. Replaces the settings, including any changes made with `set`, and restarts the hysteresis
+    ///    count.
+    ///
+    /// Nothing is changed if any step fails.
+    ///
+    /// # Returns
+    /// * `Result<Value, String>` - The tunables now in effect, or the reason the reload was refused.
+    async fn reload(&mut self, mut settings: Settings) -> Result<Value, String> {
+        if self.topology.hotplug_in_doubt() {
+            return Err("a hotplug write is still in progress".to_string());
+        }
+
+        let current = &self.settings;
+        let restart_only = [
+            (
+                "metrics_addr",
+                settings.metrics_addr != current.metrics_addr,
+            ),
+            (
+                "control_socket",
+                settings.control_socket != current.control_socket,
+            ),
+            ("influx_url", settings.influx_url != current.influx_url),
+            (
+                "vcpu_companion",
+                settings.vcpu_companion != current.vcpu_companion,
+            ),
+            ("kube_api", settings.kube_api != current.kube_api),
+            ("kube_node", settings.kube_node != current.kube_node),
+            ("sinks", settings.sinks != current.sinks),
+            ("crash_dump", settings.crash_dump != current.crash_dump),
+            (
+                "ready_after_first_cycle",
+                settings.ready_after_first_cycle != current.ready_after_first_cycle,
+            ),
+            ("ready_file", settings.ready_file != current.ready_file),
+            (
+                "hotplug_journal",
+                settings.hotplug_journal != current.hotplug_journal,
+            ),
+            ("sysfs_root", settings.sysfs_root != current.sysfs_root),
+            ("backend", settings.backend != current.backend),
+            ("max_cstate", settings.max_cstate != current.max_cstate),
+            ("idle_policy", settings.idle_policy != current.idle_policy),
+            ("latency_us", settings.latency_us != current.latency_us),
+            (
+                "backend_cgroup",
+                settings.backend_cgroup != current.backend_cgroup,
+            ),
+            (
+                "calibration_samples",
+                settings.calibration_samples != current.calibration_samples,
+            ),
+            ("logind_idle", settings.logind_idle != current.logind_idle),
+            ("uevents", settings.uevents != current.uevents),
+            (
+                "consolidate_on_lid",
+                settings.consolidate_on_lid != current.consolidate_on_lid,
+            ),
+            ("log_format", settings.log_format != current.log_format),
+        ];
+        let ignored: Vec<&str> = restart_only
+            .into_iter()
+            .filter_map(|(name, changed)| changed.then_some(name))
+            .collect();
+        if !ignored.is_empty() {
+            warn!(
+                "Ignoring changes that take effect after a restart: {}",
+                ignored.join(", ")
+            );
+        }
+        settings.metrics_addr = current.metrics_addr;
+        settings.control_socket = current.control_socket.clone();
+        settings.influx_url = current.influx_url.clone();
+        settings.vcpu_companion = current.vcpu_companion.clone();
+        settings.kube_api = current.kube_api.clone();
+        settings.kube_node = current.kube_node.clone();
+        settings.sinks = current.sinks.clone();
+        settings.crash_dump = current.crash_dump.clone();
+        settings.ready_after_first_cycle = current.ready_after_first_cycle;
+        settings.ready_file = current.ready_file.clone();
+        settings.hotplug_journal = current.hotplug_journal.clone();
+        settings.sysfs_root = current.sysfs_root.clone();
+        settings.backend = current.backend;
+        settings.max_cstate = current.max_cstate;
+        settings.idle_policy = current.idle_policy;
+        settings.latency_us = current.latency_us;
+        settings.backend_cgroup = current.backend_cgroup.clone();
+        settings.calibration_samples = current.calibration_samples;
+        settings.logind_idle = current.logind_idle;
+        settings.uevents = current.uevents;
+        settings.consolidate_on_lid = current.consolidate_on_lid;
+        settings.log_format = current.log_format;
+
+        let topology = self.scan_topology(&settings).await?;
+        if settings.metric == MetricKind::Cpuidle && !CpuidleMetric::is_available(&topology) {
+            warn!("cpuidle is not available, falling back to the proc-stat load metric");
+            settings.metric = MetricKind::ProcStat;
+        }
+        let metric = if settings.metric != self.settings.metric
+            || settings.watch_cgroup != self.settings.watch_cgroup
+        {
+            Some(build_metric(&settings).map_err(|e| e.to_string())?)
+        } else {
+            None
+        };
+
+        if settings.workload != self.settings.workload {
+            self.classifier = (settings.workload == Some(WorkloadMode::Auto)).then(Classifier::new);
+            *self.control.workload.lock().unwrap() = settings.workload.map(|mode| WorkloadStatus {
+                mode,
+                class: mode.pinned(),
+                profile: settings.profile.name,
+            });
+        } else if settings.workload == Some(WorkloadMode::Auto) {
+            settings.profile = settings.overrides.apply(self.settings.profile.name);
+        }
+        if (settings.mode, settings.kp, settings.ki, settings.kd)
+            != (
+                self.settings.mode,
+                self.settings.kp,
+                self.settings.ki,
+                self.settings.kd,
+            )
+        {
+            self.pid = PidController::new(settings.kp, settings.ki, settings.kd);
+        }
+        if metric.is_some()
+            || settings.window != self.settings.window
+            || settings.window_aggregation != self.settings.window_aggregation
+        {
+            self.window = LoadWindow::new(settings.window, settings.window_aggregation);
+        }
+        if let Some(metric) = metric {
+            self.metric = metric;
+        }
+        if settings.log_level != self.settings.log_level {
+            if let Err(e) = logging::set_filter(settings.log_level.as_str()) {
+                warn!("Cannot change the log level: {}", e);
+            }
+        }
+        if settings.stickiness != self.settings.stickiness {
+            self.stickiness = settings.stickiness.then(StickinessSampler::new);
+        }
+        if settings.thermal_limit != self.settings.thermal_limit {
+            self.thermal = settings
+                .thermal_limit
+                .map(|limit| ThermalGuard::new(&settings.sysfs_root, f64::from(limit)));
+        }
+        if self.settings.tune_pstate && !settings.tune_pstate {
+            if let Err(e) = self.pstate.restore(&self.topology).await {
+                warn!("Cannot restore the EPP: {}", e);
+            }
+        }
+        self.adopt_topology(topology);
+        self.settings = settings;
+        self.strategy = self.settings.strategy();
+        self.samples_above = 0;
+        self.samples_below = 0;
+        self.sockets.clear();
+
+        let profile = &self.settings.profile;
+        info!(
+            "Reloaded the configuration: {:?} profile, upper {}%, lower {}%, {} of {} CPUs managed",
+            profile.name,
+            profile.upper_threshold,
+            profile.lower_threshold,
+            self.topology.managed_count(),
+            self.topology.cpus.len()
+        );
+        Ok(self.tunables())
+    }
+
+    /// Scans the topology again under `settings`, through the sysfs backend, hotplug timeout, and
+    /// journal of the current one, for a reload or a rescan.
+    async fn scan_topology(&self, settings: &Settings) -> Result<SystemTopology, String> {
+        let backend_cgroup = cpuset::cgroup_dir(&settings.sysfs_root, &settings.backend_cgroup);
+        let cpuset = cpuset::allowed_cpus(
+            &settings.sysfs_root,
+            !settings.ignore_cpuset,
+            settings.restrict_to_cgroup.as_deref(),
+            (settings.backend == BackendName::Cpuset).then_some(backend_cgroup.as_path()),
+        )
+        .await
+        .map_err(|e| format!("cannot read the cgroup cpuset: {}", e))?;
+        let mut builder = SystemTopology::builder()
+            .backend(Arc::clone(&self.topology.sysfs))
+            .actuation(settings.backend, backend_cgroup)
+            .exclude_cpus(settings.exclude_cpus.clone())
+            .manage_isolated(settings.manage_isolated)
+            .cpuset(cpuset)
+            .full_cores_only(settings.full_cores_only)
+            .housekeeping_cpu(settings.housekeeping_cpu)
+            .deconfigure(settings.deconfigure)
+            .offline_order(settings.offline_order)
+            .strategy(settings.strategy().build())
+            .granularity(settings.granularity)
+            .prefer_no_smt(settings.prefer_no_smt)
+            .no_smt_if_vulnerable(settings.no_smt_if_vulnerable)
+            .prefer_offline(settings.prefer_offline)
+            .blacklist(settings.blacklist_after, settings.blacklist_for)
+            .hotplug_timeout(self.topology.hotplug_timeout);
+        if let Some(journal) = self.topology.journal.clone() {
+            builder = builder.journal(journal);
+        }
+        builder
+            .build()
+            .await
+            .map_err(|e| format!("cannot scan the topology: {}", e))
+    }
+
+    /// Replaces the topology with `topology`, a new scan of the same system, keeping what the
+    /// current one has learned: the initial online state, the load, the stickiness, and the hotplug
+    /// failures and blacklist. CPUs the current one does not know start out in the state they were
+    /// found in.
+    fn adopt_topology(&mut self, mut topology: SystemTopology) {
+        let mut initial_online = std::mem::take(&mut self.topology.initial_online);
+        for cpu in topology.cpus.values_mut() {
+            match self.topology.cpus.get(&cpu.id) {
+                Some(known) => cpu.c0_percentage = known.c0_percentage,
+                None if cpu.online => {
+                    initial_online.insert(cpu.id);
+                }
+                None => {}
+            }
+        }
+        initial_online.retain(|id| topology.cpus.contains_key(id));
+        topology.initial_online = initial_online;
+        topology.stickiness = std::mem::take(&mut self.topology.stickiness);
+        topology.hotplug_failures = std::mem::take(&mut self.topology.hotplug_failures);
+        topology.blacklist = std::mem::take(&mut self.topology.blacklist);
+        self.topology = topology;
+    }
+
+    /// Logs what the manager currently sees, e.g. on SIGUSR1: the topology summary, the profile and
+    /// the load that drove the last cycle, the energy report, the state and C0 percentage of every CPU, and the last
+    /// `STATUS_DUMP_DECISIONS` decisions.
+    fn dump_status(&self) {
+        self.topology.print_summary();
+        let profile = self.active_profile();
+        info!(
+            "Profile: {:?}, upper {}%, lower {}%, load {}, {}/{} CPUs online, paused: {}",
+            profile.name,
+            profile.upper_threshold,
+            profile.lower_threshold,
+            self.previous_avg_c0
+                .map_or("not sampled yet".to_string(), |load| format!(
+                    "{:.2}%",
+                    load
+                )),
+            self.topology.online_count(),
+            self.topology.cpus.len(),
+            *self.control.paused.borrow()
+        );
+        if let Some(limit) = self.settings.max_transitions_per_minute {
+            info!(
+                "Transitions in the last minute: {}/{}",
+                self.transitions.count(Instant::now()),
+                limit
+            );
+        }
+        if let Some(level) = self.freq_cap.as_ref().and_then(FrequencyCap::level) {
+            info!("Frequency cap: step {}/{}", level, CAP_STEPS);
+        }
+        if let Some(deepest) = self.idle_limit.as_ref().and_then(IdleLimit::deepest) {
+            info!("Idle states allowed down to state{}", deepest);
+        }
+        if let Some(stage) = self.stage.filter(|_| self.stages().len() > 1) {
+            info!("Stage: {}", stage.label());
+        }
+        if self.latency_sensitive() {
+            info!("Latency-sensitive mode is active, no CPUs are offlined");
+        }
+        if let Some(enabled) = self.boost.enabled() {
+            info!("Turbo: {}", if enabled { "enabled" } else { "disabled" });
+        }
+        if let Some(regime) = self.pstate.regime() {
+            info!("Online CPUs tuned for {}", regime.label());
+        }
+        if let Some(temp) = self.thermal.as_ref().and_then(ThermalGuard::hottest) {
+            info!(
+                "Hottest package: {:.1}°C{}",
+                temp.celsius,
+                if self.thermal_limited() {
+                    ", above the thermal limit, no CPUs are onlined"
+                } else {
+                    ""
+                }
+            );
+        }
+        if let (Some(budget), Some(watts)) = (self.settings.power_budget_watts, self.energy.watts())
+        {
+            info!("Package power: {:.1} W of a {:.1} W budget", watts, budget);
+        }
+        if let Some(report) = self.energy.report() {
+            report.log();
+        }
+        let mut cpus: Vec<_> = self.topology.cpus.values().collect();
+        cpus.sort_by_key(|cpu| cpu.id);
+        for cpu in cpus {
+            info!(
+                "CPU {}: {}{}, C0 {:.2}%",
+                cpu.id,
+                if cpu.online { "online" } else { "offline" },
+                if cpu.managed { "" } else { ", excluded" },
+                cpu.c0_percentage
+            );
+        }
+        for record in DECISION_LOG.recent(STATUS_DUMP_DECISIONS) {
+            info!(
+                "Decision at {:.3}: load {:.2}%, {} CPUs online, floor {}, action: {}",
+                record.ts,
+                record.load,
+                record.online_cpus,
+                record.floor,
+                record.decision.map_or("none", |decision| decision.label())
+            );
+        }
+    }
+
+    /// Picks up the CPUs onlined or offlined outside the daemon since the last cycle and publishes
+    /// them on the event bus.
+    async fn reconcile_online(&mut self) {
+        let changes = self.topology.reconcile_online().await;
+        for direction in [Direction::Online, Direction::Offline] {
+            let cpus: Vec<usize> = changes
+                .iter()
+                .filter(|&&(_, changed)| changed == direction)
+                .map(|&(id, _)| id)
+                .collect();
+            if !cpus.is_empty() {
+                self.control
+                    .events
+                    .emit(EventKind::ExternalHotplug { direction, cpus });
+            }
+        }
+    }
+
+    /// Lists the CPUs again every `--rescan-secs`, or right away once a uevent reported a CPU
+    /// hot-added or removed, and scans the topology again if CPUs were hot-added or removed since,
+    /// e.g. vCPUs of a VM, so new CPUs are managed from then on and removed ones forgotten. Publishes
+    /// the change on the event bus. Waits while a hotplug write is in doubt, and keeps the current
+    /// topology if the scan fails.
+    async fn rescan(&mut self) {
+        let interval_lapsed = self
+            .settings
+            .rescan_interval
+            .is_some_and(|interval| self.last_rescan.elapsed() >= interval);
+        if !(self.rescan_due || interval_lapsed) || self.topology.hotplug_in_doubt() {
+            return;
+        }
+        self.last_rescan = Instant::now();
+        self.rescan_due = false;
+        let ids: BTreeSet<usize> = match self.topology.sysfs.cpu_ids().await {
+            Ok(ids) => ids.into_iter().collect(),
+            Err(e) => {
+                warn!("Cannot list the CPUs: {}", e);
+                return;
+            }
+        };
+        let known: BTreeSet<usize> = self.topology.cpus.keys().copied().collect();
+        if ids == known {
+            return;
+        }
+        let added: Vec<usize> = ids.difference(&known).copied().collect();
+        let removed: Vec<usize> = known.difference(&ids).copied().collect();
+        let topology = match self.scan_topology(&self.settings).await {
+            Ok(topology) => topology,
+            Err(e) => {
+                warn!(
+                    "Cannot pick up the CPUs {:?} added and {:?} removed: {}",
+                    added, removed, e
+                );
+                return;
+            }
+        };
+        self.adopt_topology(topology);
+        self.strategy = self.settings.strategy();
+        info!(
+            "CPUs {:?} were added and {:?} removed, {} of {} CPUs managed",
+            added,
+            removed,
+            self.topology.managed_count(),
+            self.topology.cpus.len()
+        );
+        self.control
+            .events
+            .emit(EventKind::TopologyChanged { added, removed });
+    }
+
+    /// Handles a CPU uevent from `--uevents` between cycles:
+    /// - A CPU hot-added or removed has the topology scanned again right away, see `rescan`, once the
+    ///   CPUs hotplugged outside the daemon have been picked up, so the scan does not hide them.
+    /// - A CPU onlined or offlined while the topology says otherwise was hotplugged by another agent,
+    ///   which is picked up and reported right away, see `reconcile_online`. The daemon's own writes
+    ///   are recorded before their uevents arrive, so they are not mistaken for conflicts.
+    async fn handle_uevent(&mut self, event: CpuUevent) {
+        match event.action {
+            UeventAction::Add | UeventAction::Remove => {
+                if event.action == UeventAction::Add && self.topology.cpus.contains_key(&event.cpu)
+                {
+                    return;
+                }
+                self.reconcile_online().await;
+                self.rescan_due = true;
+                self.rescan().await;
+            }
+            UeventAction::Online | UeventAction::Offline => {
+                let online = event.action == UeventAction::Online;
+                if self
+                    .topology
+                    .cpus
+                    .get(&event.cpu)
+                    .is_none_or(|cpu| cpu.online == online)
+                {
+                    return;
+                }
+                self.reconcile_online().await;
+            }
+        }
+        self.publish_status();
+    }
+
+    /// Reads the package energy counters, charging the interval since the last read to the CPUs
+    /// online during it, see `EnergyMeter`.
+    async fn sample_energy(&mut self) {
+        let baseline_cpus = self.topology.online_count() - self.topology.managed_online_count()
+            + self.topology.managed_count();
+        self.energy
+            .sample(self.topology.online_count(), baseline_cpus, Instant::now())
+            .await;
+    }
+
+    /// Returns the energy report as response data, or why there is none.
+    fn energy_report(&self) -> Result<Option<Value>, String> {
+        if !self.energy.is_available() {
+            return Err("no RAPL package energy counters are available".to_string());
+        }
+        let report = self
+            .energy
+            .report()
+            .ok_or_else(|| "no energy measured yet".to_string())?;
+        serde_json::to_value(report)
+            .map(Some)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Returns the thresholds, hysteresis, and cooldown of the running profile as response data.
+    fn tunables(&self) -> Value {
+        let profile = &self.settings.profile;
+        json!({
+            "profile": profile.name,
+            "upper_threshold": profile.upper_threshold,
+            "lower_threshold": profile.lower_threshold,
+            "hysteresis": profile.hysteresis,
+            "cooldown_secs": profile.cooldown.as_secs_f64(),
+        })
+    }
+
+    /// Onlines or offlines `cpu` by hand, under the same rules as the control loop: excluded CPUs,
+    /// offlining the housekeeping CPU, and any change while an inhibitor is held are refused, and in
+    /// full-cores-only mode the whole core is changed.
+    ///
+    /// # Returns
+    /// * `Result<Vec<usize>, String>` - The CPUs changed, or the reason the request was refused.
+    async fn manual_hotplug(
+        &mut self,
+        direction: Direction,
+        cpu: usize,
+    ) -> Result<Vec<usize>, String> {
+        if let Some(holders) = self.control.inhibited_by() {
+            return Err(format!("CPU changes are inhibited by {}", holders));
+        }
+        let Some(info) = self.topology.cpus.get(&cpu) else {
+            return Err(format!("no such CPU: {}", cpu));
+        };
+        if !info.managed {
+            return Err(format!("CPU {} is excluded", cpu));
+        }
+        let housekeeping = self.topology.housekeeping_cpu;
+        if direction == Direction::Offline {
+            if cpu == housekeeping {
+                return Err(format!(
+                    "CPU {} is the housekeeping CPU and cannot be offlined",
+                    cpu
+                ));
+            }
+            if self.topology.full_cores_only && info.thread_siblings.contains(&housekeeping) {
+                return Err(format!(
+                    "CPU {} shares a core with housekeeping CPU {}, which stays online in full-cores-only mode",
+                    cpu, housekeeping
+                ));
+            }
+        }
+        if direction == Direction::Online && self.topology.no_smt {
+            if let Some(sibling) = info
+                .thread_siblings
+                .iter()
+                .find(|&&id| id != cpu && self.topology.cpus.get(&id).is_some_and(|cpu| cpu.online))
+            {
+                return Err(format!(
+                    "CPU {} shares a core with online CPU {}, and SMT is vulnerable on this CPU",
+                    cpu, sibling
+                ));
+            }
+        }
+        let cpus: Vec<usize> = if self.topology.full_cores_only {
+            self.topology.expand_to_cores(&[cpu])
+        } else {
+            vec![cpu]
+        }
+        .into_iter()
+        .filter(|id| self.topology.cpus.get(id).is_some_and(|cpu| cpu.managed))
+        .collect();
+        info!("Manual request: {:?} CPUs {:?}", direction, cpus);
+        self.hotplug(direction, &cpus)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(cpus)
+    }
+
+    /// Runs a single cycle of the control loop.
+    ///
+    /// This function performs the following steps:
+    /// 1. Brings the topology up to date: re-verifies a CPU whose hotplug write timed out, picks up
+    ///    the CPUs changed, hot-added, or removed outside the daemon, lets the selection consider
+    ///    the CPUs passed over after a failed write again, and holds the kubelet's exclusive CPUs
+    ///    online, see `SystemTopology::settle_in_doubt`, `reconcile_online`, `rescan`,
+    ///    `SystemTopology::begin_cycle`, and `hold_exclusive_cpus`. Then samples the load, see
+    ///    `sample_load`.
+    /// 2. Takes the system-wide load from the sample, or calculates the average C0 state percentage
+    ///    for all online CPUs if the metric is per CPU. During the calibration warm-up, records it
+    ///    as a noise floor sample and returns; afterwards, subtracts the noise floor. In automatic
+    ///    workload mode, feeds it to the classifier and switches the profile when the workload
+    ///    class changes. While the idle hint is set, the power-saver profile is used instead.
+    /// 3. Prints a status line with the average C0 state percentage, its trend, its distance to the
+    ///    thresholds, the number of online CPUs, and a per-socket breakdown, colored by threshold
+    ///    band when stdout is a terminal, and queues the metrics for the InfluxDB sink if one is
+    ///    set.
+    /// 4. Compares the average C0 state percentage with the profile's upper and lower thresholds
+    ///    and counts the consecutive samples spent above or below them. With `--mode target`,
+    ///    compares the online capacity with the capacity the load needs to run at the target
+    ///    utilization instead, see `target::desired_capacity`, and counts the samples that need
+    ///    more capacity, or less by at least the next core to offline. With `--mode pid`, the
+    ///    desired capacity is the online capacity plus the output of the PID controller for the
+    ///    difference between the two instead, see `PidController`. A run above the upper threshold
+    ///    that ends too early to online CPUs is counted as an ignored spike, see `end_spike`.
+    /// 5. In full-cores-only mode, onlines the offline siblings of partially online cores. While an
+    ///    inhibitor is held on the control socket, this and the following steps are skipped and the
+    ///    CPUs are left as they are.
+    /// 6. Expires stale leases. If the load is above `--panic-threshold`, compared as the average
+    ///    or the busiest CPU's load by `--panic-on`, it onlines every offline CPU at once,
+    ///    regardless of hysteresis and cooldown, and restarts the counts and the cooldown.
+    ///    Otherwise, if fewer CPUs are online than the floor set by `--min-cpus`, the current
+    ///    schedule, and the active leases, it onlines cores until the floor is met, regardless of
+    ///    load. If the lid has just been closed or the displays blanked, it offlines cores down to
+    ///    the floor, regardless of load, hysteresis, and cooldown.
+    /// 7. Otherwise, once the count reaches the profile's hysteresis and the cooldown since the
+    ///    last action has passed:
+    ///    - If the load is above the upper threshold, it onlines up to `step` cores. With
+    ///      `--spike-filter-secs`, the load must also have stayed above it for that long, see
+    ///      `online_after`. Nothing is onlined while the steal time is above `--steal-limit`, which
+    ///      also holds the panic threshold, see `steal_limited`. With `--wakeup-latency-us`, the
+    ///      load also counts as above it while woken tasks wait too long to run, see
+    ///      `wakeups_delayed`.
+    ///    - If the load is below the lower threshold, it offlines up to `step` cores without going
+    ///      below the floor.
+    ///    - If the load is within the thresholds, it prints a message indicating no action is
+    ///      needed.
+    ///
+    ///    With `--mode target` or `--mode pid`, it onlines or offlines as many cores as it takes to
+    ///    converge on the desired capacity in one step instead, see `scale_up` and `scale_down`.
+    ///
+    ///    With `--per-socket`, a per-CPU load metric, and more than one socket, step 7 is carried
+    ///    out by `scale_sockets` for each socket on its own instead. With `--rebalance-sockets`, a
+    ///    per-CPU load metric, and more than one socket, step 7 is preceded by `socket_imbalance`:
+    ///    if one socket is saturated and another idle, `rebalance_sockets` moves cores between
+    ///    them instead.
+    ///
+    /// Once the first cycle gets here, the readiness gate is opened, see `mark_ready`.
+    ///
+    /// With `--max-transitions-per-minute`, every hotplug is held by `hotplug` once the CPUs
+    /// hotplugged over the last minute reach the limit, and the action stops at the held group.
+    ///
+    /// Every action that changes the online CPUs predicts the next cycle's load, see
+    /// `OutcomeTracker`, and the next cycle publishes the outcome and counts it in the prediction
+    /// metrics.
+    ///
+    /// Every decision to act and every hotplug operation is also published on the control socket's
+    /// event bus, and every cycle is recorded in the in-memory decision log for crash dumps. With
+    /// `--vcpu-companion`, the host is told which vCPUs are offline and whether more are wanted,
+    /// see `VcpuCompanion`. With `--kube-api`, the online CPUs are patched onto the Kubernetes
+    /// node, see `KubeNode`. With `--exemplars`, the cycle runs in a `cycle` span carrying a new
+    /// trace ID, which is also attached to the decision counter and event.
+    pub async fn step(&mut self) -> io::Result<()> {
+        let trace_id = self.settings.exemplars.then(new_trace_id);
+        let span = match &trace_id {
+            Some(trace_id) => info_span!("cycle", trace_id = %trace_id),
+            None => Span::none(),
+        };
+        self.cycle(trace_id).instrument(span).await
+    }
+
+    /// Runs the cycle described in `step`, tagged with `trace_id` if exemplars are enabled.
+    async fn cycle(&mut self, trace_id: Option<String>) -> io::Result<()> {
+        if !self.topology.settle_in_doubt().await {
+            debug!("A hotplug write is still in progress, holding hotplug decisions");
+        }
+        self.reconcile_online().await;
+        self.rescan().await;
+        self.topology.begin_cycle();
+        self.hold_exclusive_cpus().await;
+        if let Some(limit) = &mut self.idle_limit {
+            if let Err(e) = limit.enforce_max(&self.topology).await {
+                warn!("Cannot disable the idle states beyond --max-cstate: {}", e);
+            }
+        }
+        self.guard_latency().await;
+        self.sample_energy().await;
+        let hot = match &mut self.thermal {
+            Some(thermal) => thermal.poll().await,
+            None => false,
+        };
+        let sample = self.sample_load().await?;
+        if let Some(sampler) = &mut self.stickiness {
+            match sampler.sample().await {
+                Ok(stickiness) => self.topology.stickiness = stickiness,
+                Err(e) => warn!("Failed to sample the tasks' CPU affinity: {}", e),
+            }
+        }
+
+        let per_socket = self.settings.per_socket
+            && !sample.per_cpu.is_empty()
+            && self.topology.sockets.len() > 1;
+        let rebalance = self.settings.rebalance_sockets
+            && !sample.per_cpu.is_empty()
+            && self.topology.sockets.len() > 1;
+        let raw_load = sample.system.unwrap_or_else(|| self.topology.average_c0());
+        let online_count = self.topology.online_count();
+        if self.noise_floor.baseline().is_none() {
+            // Spread over all managed CPUs, as if all were online, when starting from the current state.
+            let spread = raw_load * self.topology.managed_online_count() as f64
+                / self.topology.managed_count().max(1) as f64;
+            let collected = self.noise_floor.observe(spread);
+            info!(
+                "Calibrating noise floor ({}/{} samples): load {:.2}%",
+                collected, self.settings.calibration_samples, raw_load
+            );
+            if let Some(baseline) = self.noise_floor.baseline() {
+                info!("Noise floor calibrated at {:.2}%", baseline);
+            }
+            return Ok(());
+        }
+        let avg_c0 = self.noise_floor.subtract(
+            raw_load,
+            self.topology.managed_online_count(),
+            self.topology.managed_count(),
+        );
+        if let Some(outcome) = self.outcomes.observe(avg_c0, online_count) {
+            debug!(
+                "The {} decision predicted {:.2}%, observed {:.2}%",
+                outcome.decision.label(),
+                outcome.predicted,
+                outcome.observed
+            );
+            METRICS.prediction(&outcome);
+            self.control.events.emit(EventKind::Outcome {
+                decision: outcome.decision,
+                predicted: outcome.predicted,
+                observed: outcome.observed,
+            });
+        }
+        self.classify(avg_c0);
+        self.update_idle();
+        let profile = &self.active_profile();
+        self.follow_strategy(profile);
+
+        let sockets = self.topology.socket_loads();
+        let status = StatusLine {
+            avg_c0,
+            previous_avg_c0: self.previous_avg_c0,
+            online: online_count,
+            total: self.topology.cpus.len(),
+            sockets: &sockets,
+            profile,
+        };
+        info!("{}", status.format(self.color));
+        self.previous_avg_c0 = Some(avg_c0);
+        if let Some(influx) = &self.influx {
+            influx.send(&self.topology);
+        }
+
+        let online_capacity = self.topology.online_capacity();
+        let target_capacity =
+            target::desired_capacity(avg_c0, online_capacity, self.settings.target_util);
+        let desired = match self.settings.mode {
+            ScalingMode::Threshold => None,
+            ScalingMode::Target => Some(target_capacity),
+            ScalingMode::Pid => {
+                let change = self.pid.update(
+                    target_capacity - online_capacity,
+                    self.settings.interval.as_secs_f64(),
+                    self.topology.managed_capacity(),
+                );
+                debug!(
+                    "PID controller: target capacity {:.0}, change {:+.0}",
+                    target_capacity, change
+                );
+                Some(online_capacity + change)
+            }
+        };
+        let delayed = self.wakeups_delayed();
+        let (high, low) = match desired {
+            _ if delayed => {
+                debug!(
+                    "Wakeup latency of {:.0}us above the limit",
+                    self.wakeup_latency_us.unwrap_or_default()
+                );
+                METRICS.latency_triggered();
+                (true, false)
+            }
+            Some(desired) => (
+                desired > self.topology.online_capacity(),
+                self.can_shed(desired),
+            ),
+            None => (
+                avg_c0 > profile.upper_threshold as f64,
+                avg_c0 < profile.lower_threshold as f64,
+            ),
+        };
+        if !per_socket && !high {
+            self.end_spike(self.samples_above, profile.hysteresis, "the system");
+        }
+        if high {
+            self.samples_above += 1;
+            self.samples_below = 0;
+        } else if low {
+            self.samples_below += 1;
+            self.samples_above = 0;
+        } else {
+            self.samples_above = 0;
+            self.samples_below = 0;
+        }
+
+        let inhibited_by = self.control.inhibited_by();
+        if self.topology.full_cores_only && inhibited_by.is_none() {
+            let half_core_cpus = self.topology.half_core_cpus();
+            if !half_core_cpus.is_empty() {
+                info!(
+                    "Completing half-online cores: onlining {:?}",
+                    half_core_cpus
+                );
+                let _ = self.hotplug(Direction::Online, &half_core_cpus).await;
+            }
+        }
+
+        let profile = &self.active_profile();
+        let hysteresis = profile.hysteresis;
+        let online_after = self.online_after(hysteresis);
+        let cooling_down = self
+            .last_action
+            .is_some_and(|t| t.elapsed() < profile.cooldown);
+        self.control.expire_leases(Instant::now());
+        let floor = self.control.floor().max(self.settings.min_cpus);
+        let capacity_before = self.topology.online_capacity();
+
+        let over_budget = self.over_power_budget();
+        let panic_load = self.settings.panic_on.load(avg_c0, &self.topology);
+        let panicking = self
+            .settings
+            .panic_threshold
+            .is_some_and(|threshold| panic_load > f64::from(threshold));
+        let steal_limited = self.steal_limited();
+
+        let decision = if let Some(holders) = &inhibited_by {
+            debug!("CPU changes are inhibited by {}", holders);
+            None
+        } else if panicking
+            && !hot
+            && self.power_headroom()
+            && !steal_limited
+            && (self.topology.select_cpu_to_online().is_some() || self.throttled())
+        {
+            info!(
+                "Load of {:.2}% above the panic threshold, onlining all CPUs",
+                panic_load
+            );
+            self.online_all().await;
+            self.unthrottle().await;
+            if self.settings.boost_policy != BoostPolicy::Leave {
+                self.set_boost(true).await;
+            }
+            self.tune_pstate(PstateRegime::Performance).await;
+            self.samples_above = 0;
+            self.samples_below = 0;
+            self.sockets.clear();
+            self.last_action = Some(Instant::now());
+            Some(Decision::Panic)
+        } else if online_count < floor {
+            info!("Below the floor of {} CPUs", floor);
+            self.meet_floor(floor).await;
+            self.last_action = Some(Instant::now());
+            Some(Decision::MeetFloor)
+        } else if hot || over_budget {
+            let limit = if hot { "thermal limit" } else { "power budget" };
+            if cooling_down {
+                debug!("Package above the {}, waiting for cooldown", limit);
+                None
+            } else if self.shed_core(floor).await > 0 {
+                self.samples_below = 0;
+                self.last_action = Some(Instant::now());
+                Some(if hot {
+                    Decision::Thermal
+                } else {
+                    Decision::PowerBudget
+                })
+            } else {
+                debug!("Package above the {}, cannot offline more CPUs", limit);
+                None
+            }
+        } else if self.update_away() {
+            info!("Lid closed or displays off, consolidating");
+            self.consolidate(floor).await;
+            self.samples_below = 0;
+            self.last_action = Some(Instant::now());
+            Some(Decision::Consolidate)
+        } else if per_socket {
+            self.scale_sockets(floor).await
+        } else if let Some((busy, idle)) = rebalance
+            .then(|| self.socket_imbalance(cooling_down))
+            .flatten()
+        {
+            Some(self.rebalance_sockets(busy, idle, floor).await)
+        } else if self.samples_above > 0 {
+            if self.samples_above < online_after {
+                debug!(
+                    "High load detected ({}/{} samples)",
+                    self.samples_above, online_after
+                );
+                None
+            } else if cooling_down {
+                debug!("High load detected, waiting for cooldown");
+                None
+            } else if !self.power_headroom() {
+                debug!("High load detected, but the package power is close to the budget");
+                None
+            } else if steal_limited {
+                warn!(
+                    "High load detected, but {:.1}% of the time is stolen by the hypervisor, not onlining more CPUs",
+                    self.steal.unwrap_or_default()
+                );
+                METRICS.steal_limited();
+                None
+            } else if self.scale_up(desired.filter(|_| !delayed)).await > 0 {
+                self.samples_above = 0;
+                self.last_action = Some(Instant::now());
+                Some(Decision::Online)
+            } else {
+                debug!("Cannot online more CPUs, already at maximum");
+                METRICS.blocked_decision(Direction::Online);
+                Some(Decision::BlockedOnline)
+            }
+        } else if self.samples_below > 0 {
+            if self.samples_below < hysteresis {
+                debug!(
+                    "Low load detected ({}/{} samples)",
+                    self.samples_below, hysteresis
+                );
+                None
+            } else if cooling_down {
+                debug!("Low load detected, waiting for cooldown");
+                None
+            } else if self.scale_down(desired, floor).await > 0 {
+                self.samples_below = 0;
+                self.last_action = Some(Instant::now());
+                Some(Decision::Offline)
+            } else {
+                debug!("Cannot offline more CPUs, already at minimum");
+                METRICS.blocked_decision(Direction::Offline);
+                Some(Decision::BlockedOffline)
+            }
+        } else {
+            debug!("Load is optimal, no action needed");
+            None
+        };
+
+        if let Some(regime) = self.pstate.regime() {
+            self.tune_pstate(regime).await;
+        }
+        if let Some(companion) = &mut self.vcpu_companion {
+            companion.update(&self.topology, decision == Some(Decision::BlockedOnline));
+        }
+        if let Some(node) = &mut self.kube_node {
+            node.update(&self.topology);
+        }
+        let online_cpus = self.topology.online_count();
+        METRICS.update_topology(&self.topology, avg_c0);
+        if let Some(decision) = decision {
+            METRICS.decision(decision);
+            if let Some(trace_id) = &trace_id {
+                METRICS.decision_exemplar(decision, trace_id);
+            }
+            self.control.events.emit(EventKind::Decision {
+                decision,
+                load: avg_c0,
+                online_cpus,
+                floor,
+                trace_id,
+            });
+        }
+        let mut record = DecisionRecord::now(avg_c0, online_cpus, floor, decision);
+        if let Some(decision) = decision.filter(|decision| decision.acts()) {
+            record.predicted_load = Some(self.outcomes.predict(
+                decision,
+                avg_c0,
+                capacity_before,
+                self.topology.online_capacity(),
+                online_cpus,
+            ));
+        }
+        if let Some(sinks) = &self.sinks {
+            sinks.send(&record);
+        }
+        DECISION_LOG.record(record);
+        if !self.ready {
+            self.mark_ready();
+        }
+        self.publish_status();
+
+        Ok(())
+    }
+
+    /// Feeds the load to the workload classifier, if enabled, and switches to the profile suited to
+    /// the detected workload class.
+    fn classify(&mut self, load: f64) {
+        let Some(class) = self.classifier.as_mut().and_then(|c| c.push(load)) else {
+            return;
+        };
+        let name = class.profile_name();
+        if name != self.settings.profile.name {
+            info!(
+                "Workload classified as {:?}, switching to the {:?} profile",
+                class, name
+            );
+            self.settings.profile = self.settings.overrides.apply(name);
+        }
+        *self.control.workload.lock().unwrap() = Some(WorkloadStatus {
+            mode: WorkloadMode::Auto,
+            class: Some(class),
+            profile: name,
+        });
+    }
+
+    /// Samples the load metric and prepares the sample for the decision.
+    ///
+    /// This function performs the following steps:
+    /// 1. Samples the load metric. With `--metric-validation`, logs how far the loads are from
+    ///    `/proc/stat`, see `report_divergence`.
+    /// 2. With `--exclude-nice`, takes the time of niced tasks out of each CPU's load, see
+    ///    `LoadSample::exclude_nice`.
+    /// 3. Smooths the loads over the `--window` most recent samples, see `LoadWindow`, and stores
+    ///    the per-CPU loads in the topology.
+    /// 4. Keeps the wakeup latency of the sample and its steal time, read from `/proc/stat` unless
+    ///    the metric reports it, see `StealReader`.
+    ///
+    /// # Returns
+    /// * `io::Result<LoadSample>` - The smoothed sample, or an error if the metric cannot be
+    ///   sampled.
+    async fn sample_load(&mut self) -> io::Result<LoadSample> {
+        let mut sample = self.metric.sample(&self.topology).await?;
+        if self.settings.metric_validation {
+            self.report_divergence(&sample);
+        }
+        if self.settings.exclude_nice {
+            sample.exclude_nice();
+        }
+        let sample = self.window.smooth(sample);
+        self.topology.apply_sample(&sample);
+        let stolen = match self.steal_reader.sample(&self.topology).await {
+            Ok(stolen) => stolen,
+            Err(e) => {
+                debug!("Cannot read the steal time: {}", e);
+                None
+            }
+        };
+        self.steal = sample.steal.or(stolen);
+        self.wakeup_latency_us = sample.wakeup_latency_us;
+        Ok(sample)
+    }
+
+    /// Logs how far the load of each CPU is from its `/proc/stat` utilization, for
+    /// `--metric-validation`: a summary at the info level and every CPU at the debug level.
+    fn report_divergence(&self, sample: &LoadSample) {
+        let mut divergences: Vec<(usize, f64)> = sample
+            .per_cpu
+            .iter()
+            .filter_map(|(id, load)| Some((*id, load - sample.cross_check.get(id)?)))
+            .collect();
+        divergences.sort_by_key(|(id, _)| *id);
+        let Some(&(cpu, max)) = divergences
+            .iter()
+            .max_by(|a, b| a.1.abs().total_cmp(&b.1.abs()))
+        else {
+            debug!("No /proc/stat utilization to validate the load against");
+            return;
+        };
+        let mean = divergences.iter().map(|(_, d)| d.abs()).sum::<f64>() / divergences.len() as f64;
+        info!(
+            "Metric validation: {} and /proc/stat differ by {:.2} points on average, at most {:+.2} on CPU {}; {} CPUs taken from /proc/stat",
+            self.metric.name(),
+            mean,
+            max,
+            cpu,
+            sample.reconciled.len()
+        );
+        let per_cpu: Vec<String> = divergences
+            .iter()
+            .map(|(id, d)| format!("{}: {:+.2}", id, d))
+            .collect();
+        debug!("Metric divergence by CPU: {}", per_cpu.join(", "));
+    }
+
+    /// Follows the idle hint, if set. When the session becomes active again, the hysteresis counters
+    /// and the cooldown are reset so that the regular profile takes over immediately.
+    fn update_idle(&mut self) {
+        let Some(idle_hint) = &self.idle_hint else {
+            return;
+        };
+        let idle = *idle_hint.borrow();
+        if idle == self.idle {
+            return;
+        }
+        self.idle = idle;
+        if idle {
+            info!("Session is idle, switching to the power-saver profile");
+        } else {
+            info!(
+                "Session is active, reverting to the {:?} profile",
+                self.settings.profile.name
+            );
+            self.samples_above = 0;
+            self.samples_below = 0;
+            self.last_action = None;
+            self.sockets.clear();
+        }
+    }
+
+    /// Switches the topology to the selection strategy of `profile` if it differs from the current
+    /// one, e.g. once the session is idle or the workload class changed. `--strategy` takes
+    /// precedence over every profile's.
+    fn follow_strategy(&mut self, profile: &Profile) {
+        let strategy = self
+            .settings
+            .overrides
+            .strategy
+            .or(profile.strategy)
+            .unwrap_or_default();
+        if strategy != self.strategy {
+            info!("Switching to the {:?} selection strategy", strategy);
+            self.topology.strategy = strategy.build();
+            self.strategy = strategy;
+        }
+    }
+
+    /// Follows the away hint, if set, and returns whether it has just become true.
+    fn update_away(&mut self) -> bool {
+        let Some(away_hint) = &self.away_hint else {
+            return false;
+        };
+        let away = *away_hint.borrow();
+        let became_away = away && !self.away;
+        self.away = away;
+        became_away
+    }
+
+    /// Offlines cores until no more can be offlined without going below `floor`. With a frequency
+    /// cap, lowers it all the way first, and only that with the cpufreq backend. With an idle stage,
+    /// also allows every idle state up to `--max-cstate`. Enters the last stage, if any.
+    async fn consolidate(&mut self, floor: usize) {
+        if let Some(cap) = &mut self.freq_cap {
+            if let Err(e) = cap.set_level(&self.topology, CAP_STEPS).await {
+                warn!("Cannot cap the frequency: {}", e);
+            }
+        }
+        if idle_stage(&self.settings) {
+            if let Some(limit) = &mut self.idle_limit {
+                let ceiling = limit.ceiling(&self.topology);
+                if let Err(e) = limit.set_deepest(&self.topology, ceiling).await {
+                    warn!("Cannot allow the deeper idle states: {}", e);
+                }
+            }
+        }
+        if let Some(&last) = self.stages().last() {
+            self.enter_stage(last);
+        }
+        if self.settings.backend == BackendName::Cpufreq {
+            return;
+        }
+        while let Some(core_to_offline) = self.topology.select_cpu_to_offline() {
+            if self.topology.online_count() - core_to_offline.len() < floor {
+                break;
+            }
+            info!("Consolidating, offlining core {:?}", core_to_offline);
+            if self
+                .hotplug(Direction::Offline, &core_to_offline)
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    }
+
+    /// Onlines or offlines a group of CPUs and publishes a hotplug event with the outcome.
+    ///
+    /// With `--max-transitions-per-minute`, the group is left alone and a `WouldBlock` error
+    /// returned if its CPUs do not all fit into the transitions left over the last minute; only a
+    /// group whose writes all succeeded counts towards the limit. An offline is held the same way
+    /// during the latency-sensitive mode of `--latency-us`. Before an offline, the tasks pinned to
+    /// the CPUs are handled by `check_pinned_tasks`, which may hold it the same way, and with
+    /// `--migrate-irqs` their IRQs are retargeted. After an online, with `--restore-irqs`, the IRQs
+    /// whose CPUs are all back are restored.
+    async fn hotplug(&mut self, direction: Direction, cpus: &[usize]) -> io::Result<()> {
+        if let Some(limit) = self.settings.max_transitions_per_minute {
+            if !self.transitions.allows(cpus.len(), limit, Instant::now()) {
+                debug!(
+                    "Holding {:?} of CPUs {:?}: the limit of {} transitions per minute is reached",
+                    direction, cpus, limit
+                );
+                METRICS.rate_limited();
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!("the limit of {} transitions per minute is reached", limit),
+                ));
+            }
+        }
+        if direction == Direction::Offline && self.latency_sensitive() {
+            debug!(
+                "Holding the offline of CPUs {:?}: latency-sensitive mode is active",
+                cpus
+            );
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "latency-sensitive mode is active",
+            ));
+        }
+        if direction == Direction::Offline {
+            self.check_pinned_tasks(cpus).await?;
+            if self.settings.migrate_irqs {
+                self.migrate_irqs(cpus).await;
+            }
+        }
+        let result = match direction {
+            Direction::Online => self.topology.online_cpu_group(cpus).await,
+            Direction::Offline => self.topology.offline_cpu_group(cpus).await,
+        };
+        if result.is_ok() {
+            self.transitions.record(cpus.len(), Instant::now());
+        }
+        if direction == Direction::Online {
+            if let Some(limit) = &self.idle_limit {
+                limit.apply(&self.topology, cpus).await;
+            }
+        }
+        if direction == Direction::Online && self.settings.restore_irqs {
+            let online = self.online_ids();
+            self.irqs.restore(&online).await;
+        }
+        self.control.events.emit(EventKind::Hotplug {
+            direction,
+            cpus: cpus.to_vec(),
+            ok: result.is_ok(),
+        });
+        result
+    }
+
+    /// Reads the CPUs the kubelet's static CPU manager assigned exclusively to containers from
+    /// `--kubelet-state` and holds them and their thread siblings online, see
+    /// `SystemTopology::held`. Logs every change of the held CPUs. A checkpoint that cannot be read
+    /// is logged and keeps the CPUs held so far.
+    async fn hold_exclusive_cpus(&mut self) {
+        let held = match kubelet::exclusive_cpus(&self.settings.kubelet_state).await {
+            Ok(held) => held.unwrap_or_default(),
+            Err(e) => {
+                warn!(
+                    "Cannot read the kubelet's CPU manager state {:?}: {}",
+                    self.settings.kubelet_state, e
+                );
+                return;
+            }
+        };
+        if held != self.topology.held {
+            if held.is_empty() {
+                info!("No CPUs are assigned exclusively to Kubernetes containers anymore");
+            } else {
+                info!(
+                    "Holding the CPUs {} assigned exclusively to Kubernetes containers online",
+                    held
+                );
+            }
+            self.topology.held = held;
+        }
+    }
+
+    /// Looks for tasks that may only run on `cpus` before they are offlined, and handles them by
+    /// `--pinned-tasks`: logs a warning for each, refuses the offline with a `WouldBlock` error, or
+    /// re-pins them to the CPUs that stay online. A failed scan is logged and does not hold the
+    /// offline.
+    async fn check_pinned_tasks(&self, cpus: &[usize]) -> io::Result<()> {
+        let tasks = match affinity::pinned_tasks(cpus).await {
+            Ok(tasks) => tasks,
+            Err(e) => {
+                warn!("Failed to scan the tasks' CPU affinity: {}", e);
+                return Ok(());
+            }
+        };
+        if tasks.is_empty() {
+            return Ok(());
+        }
+        METRICS.pinned_task_conflict();
+        match self.settings.pinned_tasks {
+            PinnedTaskPolicy::Warn => {
+                for task in &tasks {
+                    warn!(
+                        "Offlining CPUs {:?} strands thread {} ({}) of process {}, which may only run on CPUs {}",
+                        cpus, task.tid, task.name, task.pid, task.allowed
+                    );
+                }
+            }
+            PinnedTaskPolicy::Skip => {
+                let tids: Vec<u32> = tasks.iter().map(|task| task.tid).collect();
+                warn!(
+                    "Keeping CPUs {:?} online: threads {:?} may only run on them",
+                    cpus, tids
+                );
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!("threads {:?} are pinned to the CPUs", tids),
+                ));
+            }
+            PinnedTaskPolicy::Repin => {
+                let mut remaining = self.online_ids();
+                remaining.retain(|id| !cpus.contains(id));
+                let remaining_list = CpuList(remaining.clone());
+                for task in &tasks {
+                    match affinity::repin(task.tid, &remaining) {
+                        Ok(()) => info!(
+                            "Re-pinned thread {} ({}) of process {} from CPUs {} to CPUs {}",
+                            task.tid, task.name, task.pid, task.allowed, remaining_list
+                        ),
+                        Err(e) => warn!(
+                            "Failed to re-pin thread {} ({}) of process {} from CPUs {}: {}",
+                            task.tid, task.name, task.pid, task.allowed, e
+                        ),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Retargets the IRQs that may only be delivered to `cpus` to the CPUs that stay online, for
+    /// `--migrate-irqs`.
+    async fn migrate_irqs(&mut self, cpus: &[usize]) {
+        let mut targets = self.online_ids();
+        targets.retain(|id| !cpus.contains(id));
+        match self.irqs.migrate(&targets).await {
+            Ok(count) => METRICS.irqs_migrated(count),
+            Err(e) => warn!("Failed to read the IRQs to retarget: {}", e),
+        }
+    }
+
+    /// Returns the IDs of the online CPUs.
+    fn online_ids(&self) -> BTreeSet<usize> {
+        self.topology
+            .cpus
+            .values()
+            .filter(|cpu| cpu.online)
+            .map(|cpu| cpu.id)
+            .collect()
+    }
+
+    /// Onlines every managed offline CPU, core by core, until none is left or a write fails.
+    async fn online_all(&mut self) {
+        while let Some(core_to_online) = self.topology.select_cpu_to_online() {
+            if self
+                .hotplug(Direction::Online, &core_to_online)
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    }
+
+    /// Onlines cores until at least `floor` CPUs are online or no offline core is left.
+    async fn meet_floor(&mut self, floor: usize) {
+        while self.topology.online_count() < floor {
+            let Some(core_to_online) = self.topology.select_cpu_to_online() else {
+                debug!("Cannot online more CPUs, already at maximum");
+                METRICS.blocked_decision(Direction::Online);
+                break;
+            };
+            info!("Onlining core {:?} to meet the floor", core_to_online);
+            if self
+                .hotplug(Direction::Online, &core_to_online)
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    }
+
+    /// Onlines up to `step` cores, of `socket` if given, and returns how many were onlined. Stops
+    /// early at a failed write, or if hotplug is held, e.g. by the transition rate limit.
+    async fn online_cores(&mut self, socket: Option<usize>) -> usize {
+        let mut onlined = 0;
+        while onlined < self.active_profile().step {
+            let selected = match socket {
+                Some(socket) => self.topology.select_cpu_to_online_on(socket),
+                None => self.topology.select_cpu_to_online(),
+            };
+            let Some(core_to_online) = selected else {
+                break;
+            };
+            info!("High load detected, onlining core {:?}", core_to_online);
+            if self
+                .hotplug(Direction::Online, &core_to_online)
+                .await
+                .is_err()
+            {
+                break;
+            }
+            onlined += 1;
+        }
+        onlined
+    }
+
+    /// Offlines up to `step` cores, of `socket` if given, without going below `floor`, and returns
+    /// how many were offlined. The last online core of `socket` is kept, so its load stays measurable.
+    /// Stops early at a failed write, or if hotplug is held, e.g. by the transition rate limit.
+    async fn offline_cores(&mut self, socket: Option<usize>, floor: usize) -> usize {
+        let mut offlined = 0;
+        while offlined < self.active_profile().step {
+            let selected = match socket {
+                Some(socket) => self.topology.select_cpu_to_offline_on(socket),
+                None => self.topology.select_cpu_to_offline(),
+            };
+            let Some(core_to_offline) = selected else {
+                break;
+            };
+            if self.topology.online_count() - core_to_offline.len() < floor {
+                info!("Floor of {} CPUs reached", floor);
+                break;
+            }
+            if let Some(socket) = socket {
+                let socket_online = self.topology.sockets[&socket]
+                    .iter()
+                    .filter(|id| self.topology.cpus[id].online)
+                    .count();
+                if socket_online <= core_to_offline.len() {
+                    break;
+                }
+            }
+            info!("Low load detected, offlining core {:?}", core_to_offline);
+            if self
+                .hotplug(Direction::Offline, &core_to_offline)
+                .await
+                .is_err()
+            {
+                break;
+            }
+            offlined += 1;
+        }
+        offlined
+    }
+
+    /// Onlines up to `step` cores, or with `--mode target` as many as it takes for the online
+    /// capacity to reach `desired`, and returns how many were selected. With the cpufreq and tiered
+    /// backends and `--idle-policy load`, only onlines cores in the offline stage, see `ascend`.
+    /// With `--boost-policy turbo-first`, enables turbo instead if it is disabled, and with
+    /// `cores-first`, disables it before onlining and enables it once every core is online. With
+    /// `--tune-pstate`, also tunes the online CPUs for performance.
+    async fn scale_up(&mut self, desired: Option<f64>) -> usize {
+        self.tune_pstate(PstateRegime::Performance).await;
+        if let Some(stepped) = self.ascend().await {
+            return stepped;
+        }
+        match self.settings.boost_policy {
+            BoostPolicy::Leave => {}
+            BoostPolicy::TurboFirst => {
+                if self.set_boost(true).await {
+                    return 1;
+                }
+            }
+            BoostPolicy::CoresFirst => {
+                if self.topology.select_cpu_to_online().is_none() {
+                    return usize::from(self.set_boost(true).await);
+                }
+                self.set_boost(false).await;
+            }
+        }
+        self.online_for_load(desired).await
+    }
+
+    /// Onlines cores for `scale_up`.
+    async fn online_for_load(&mut self, desired: Option<f64>) -> usize {
+        let Some(desired) = desired else {
+            return self.online_cores(None).await;
+        };
+        let mut onlined = 0;
+        while self.topology.online_capacity() < desired {
+            let Some(core_to_online) = self.topology.select_cpu_to_online() else {
+                break;
+            };
+            info!(
+                "Above the target utilization, onlining core {:?}",
+                core_to_online
+            );
+            if self
+                .hotplug(Direction::Online, &core_to_online)
+                .await
+                .is_err()
+            {
+                break;
+            }
+            onlined += 1;
+        }
+        onlined
+    }
+
+    /// Offlines up to `step` cores, or with `--mode target` as many as can go without the online
+    /// capacity dropping below `desired`, without going below `floor`, and returns how many were
+    /// selected. With the cpufreq and tiered backends and `--idle-policy load`, only offlines cores
+    /// in the offline stage, see `descend`. With `--boost-policy cores-first`, disables turbo instead
+    /// if it is enabled, and with `turbo-first`, disables it once no core can be offlined. With
+    /// `--tune-pstate`, also tunes the online CPUs for power.
+    async fn scale_down(&mut self, desired: Option<f64>, floor: usize) -> usize {
+        self.tune_pstate(PstateRegime::Power).await;
+        if let Some(stepped) = self.descend().await {
+            return stepped;
+        }
+        if self.settings.boost_policy == BoostPolicy::CoresFirst && self.set_boost(false).await {
+            return 1;
+        }
+        let offlined = self.offline_for_load(desired, floor).await;
+        if offlined == 0 && self.settings.boost_policy == BoostPolicy::TurboFirst {
+            return usize::from(self.set_boost(false).await);
+        }
+        offlined
+    }
+
+    /// Offlines cores for `scale_down`.
+    async fn offline_for_load(&mut self, desired: Option<f64>, floor: usize) -> usize {
+        let Some(desired) = desired else {
+            return self.offline_cores(None, floor).await;
+        };
+        let mut offlined = 0;
+        while self.can_shed(desired) {
+            let Some(core_to_offline) = self.topology.select_cpu_to_offline() else {
+                break;
+            };
+            if self.topology.online_count() - core_to_offline.len() < floor {
+                info!("Floor of {} CPUs reached", floor);
+                break;
+            }
+            info!(
+                "Below the target utilization, offlining core {:?}",
+                core_to_offline
+            );
+            if self
+                .hotplug(Direction::Offline, &core_to_offline)
+                .await
+                .is_err()
+            {
+                break;
+            }
+            offlined += 1;
+        }
+        offlined
+    }
+
+    /// Takes the stages one step further as the load drops.
+    ///
+    /// This function performs the following steps:
+    /// 1. In the frequency stage, lowers the frequency cap one step.
+    /// 2. In the idle stage, allows one deeper idle state, up to `--max-cstate`.
+    /// 3. If the stage can go no further, enters the next one and starts over, or stops in the last
+    ///    one, e.g. with the cpufreq backend.
+    ///
+    /// # Returns
+    /// * `Option<usize>` - The steps taken, or `None` in the offline stage and without stages,
+    ///   where cores are offlined instead.
+    async fn descend(&mut self) -> Option<usize> {
+        loop {
+            let stage = self.stage?;
+            let stepped = match stage {
+                TierStage::Frequency => self.lower_cap().await,
+                TierStage::Idle => self.deepen_idle().await,
+                TierStage::Offline => return None,
+            };
+            if stepped {
+                return Some(1);
+            }
+            let stages = self.stages();
+            let position = stages.iter().position(|&s| s == stage)?;
+            let Some(&next) = stages.get(position + 1) else {
+                return Some(0);
+            };
+            self.enter_stage(next);
+        }
+    }
+
+    /// Takes the stages one step back as the load climbs.
+    ///
+    /// This function performs the following steps:
+    /// 1. In the offline stage, leaves the onlining to the caller while any core can be onlined.
+    /// 2. In the idle stage, disables the deepest allowed idle state, down to `state1`.
+    /// 3. In the frequency stage, raises the frequency cap one step.
+    /// 4. If the stage can go no further, enters the previous one and starts over. In the first
+    ///    one, goes back to the offline stage if cores are still offline, e.g. offlined by hand.
+    ///
+    /// # Returns
+    /// * `Option<usize>` - The steps taken, or `None` in the offline stage and without stages,
+    ///   where cores are onlined instead.
+    async fn ascend(&mut self) -> Option<usize> {
+        loop {
+            let stage = self.stage?;
+            let stepped = match stage {
+                TierStage::Offline if self.topology.select_cpu_to_online().is_some() => {
+                    return None
+                }
+                TierStage::Offline => false,
+                TierStage::Idle => self.shallow_idle().await,
+                TierStage::Frequency => self.raise_cap().await,
+            };
+            if stepped {
+                return Some(1);
+            }
+            let stages = self.stages();
+            let position = stages.iter().position(|&s| s == stage)?;
+            if let Some(previous) = position.checked_sub(1) {
+                self.enter_stage(stages[previous]);
+            } else if stages.contains(&TierStage::Offline)
+                && self.topology.select_cpu_to_online().is_some()
+            {
+                self.enter_stage(TierStage::Offline);
+                return None;
+            } else {
+                return Some(0);
+            }
+        }
+    }
+
+    /// Returns the stages of the backend and idle policy in use, in the order they are entered as
+    /// the load drops.
+    fn stages(&self) -> Vec<TierStage> {
+        [
+            (TierStage::Frequency, self.freq_cap.is_some()),
+            (TierStage::Idle, idle_stage(&self.settings)),
+            (
+                TierStage::Offline,
+                self.settings.backend != BackendName::Cpufreq,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(stage, used)| used.then_some(stage))
+        .collect()
+    }
+
+    /// Moves the stages to `stage`, if in use.
+    fn enter_stage(&mut self, stage: TierStage) {
+        if self.stage.is_some_and(|current| current != stage) {
+            info!("Entering the {} stage", stage.label());
+            self.stage = Some(stage);
+        }
+    }
+
+    /// Polls the latency guard of `--latency-us` and, as latency-sensitive mode starts, disables
+    /// the idle states that every CPU cannot wake up from within the bound, or as it ends, restores
+    /// the limit from before.
+    async fn guard_latency(&mut self) {
+        let Some(guard) = &mut self.latency_guard else {
+            return;
+        };
+        let Some(active) = guard.poll().await else {
+            return;
+        };
+        let bound = if active {
+            Some(cpuidle::deepest_within(&self.topology, guard.limit_us()).await)
+        } else {
+            None
+        };
+        if let Some(limit) = &mut self.idle_limit {
+            if let Err(e) = limit.set_guard(&self.topology, bound).await {
+                warn!(
+                    "Cannot limit the idle states for latency-sensitive mode: {}",
+                    e
+                );
+            }
+        }
+    }
+
+    /// Returns whether latency-sensitive mode is active with `--latency-us`.
+    fn latency_sensitive(&self) -> bool {
+        self.latency_guard
+            .as_ref()
+            .is_some_and(LatencyGuard::is_active)
+    }
+
+    /// Returns whether the hottest package is above `--thermal-limit`.
+    fn thermal_limited(&self) -> bool {
+        self.thermal.as_ref().is_some_and(ThermalGuard::is_hot)
+    }
+
+    /// Enables or disables turbo, and returns whether it was switched.
+    async fn set_boost(&mut self, enabled: bool) -> bool {
+        self.boost
+            .set(&self.topology, enabled)
+            .await
+            .inspect_err(|e| warn!("Cannot switch turbo: {}", e))
+            .unwrap_or(false)
+    }
+
+    /// Moves the online CPUs to `regime` with `--tune-pstate`, including the ones onlined since they
+    /// were last tuned.
+    async fn tune_pstate(&mut self, regime: PstateRegime) {
+        if !self.settings.tune_pstate {
+            return;
+        }
+        if let Err(e) = self
+            .pstate
+            .apply(&self.topology, regime, self.settings.power_max_perf_pct)
+            .await
+        {
+            warn!("Cannot tune the online CPUs for {}: {}", regime.label(), e);
+        }
+    }
+
+    /// Returns whether the package power of the last interval exceeds `--power-budget-watts`.
+    fn over_power_budget(&self) -> bool {
+        match (self.settings.power_budget_watts, self.energy.watts()) {
+            (Some(budget), Some(watts)) => watts > budget,
+            _ => false,
+        }
+    }
+
+    /// Returns whether the package power of the last interval leaves room below
+    /// `--power-budget-watts` to online more CPUs: `POWER_HEADROOM` of the budget. Always true
+    /// without a budget or a reading.
+    fn power_headroom(&self) -> bool {
+        match (self.settings.power_budget_watts, self.energy.watts()) {
+            (Some(budget), Some(watts)) => watts <= budget * (1.0 - POWER_HEADROOM),
+            _ => true,
+        }
+    }
+
+    /// Returns whether the steal time of the latest cycle is above `--steal-limit`: the host is
+    /// oversubscribed, so more vCPUs would only compete for the same physical CPUs. Always false
+    /// without a limit or a reading.
+    fn steal_limited(&self) -> bool {
+        match (self.settings.steal_limit, self.steal) {
+            (Some(limit), Some(steal)) => steal > limit,
+            _ => false,
+        }
+    }
+
+    /// Returns whether woken tasks waited longer than `--wakeup-latency-us` on average over the
+    /// latest sample, so more CPUs are wanted whatever the load. Always false without a limit or
+    /// the ebpf load metric.
+    fn wakeups_delayed(&self) -> bool {
+        match (self.settings.wakeup_latency_us, self.wakeup_latency_us) {
+            (Some(limit), Some(latency)) => latency > f64::from(limit),
+            _ => false,
+        }
+    }
+
+    /// Takes capacity away while the hottest package is above `--thermal-limit` or the package
+    /// power above `--power-budget-watts`, and returns how many steps were taken.
+    ///
+    /// This function performs the following steps:
+    /// 1. With the cpufreq backend, lowers the frequency cap one step instead.
+    /// 2. Otherwise offlines one core, without going below `floor`: of the hottest package if too
+    ///    hot, unless it has only one online core left or its socket is unknown, and of any package
+    ///    otherwise.
+    async fn shed_core(&mut self, floor: usize) -> usize {
+        if self.settings.backend == BackendName::Cpufreq {
+            return usize::from(self.lower_cap().await);
+        }
+        let hottest = self
+            .thermal
+            .as_ref()
+            .filter(|thermal| thermal.is_hot())
+            .and_then(ThermalGuard::hottest)
+            .and_then(|temp| temp.socket)
+            .filter(|socket| self.topology.sockets.contains_key(socket));
+        let on_socket = hottest.and_then(|socket| {
+            let core = self.topology.select_cpu_to_offline_on(socket)?;
+            let socket_online = self.topology.sockets[&socket]
+                .iter()
+                .filter(|id| self.topology.cpus[id].online)
+                .count();
+            (socket_online > core.len()).then_some(core)
+        });
+        let Some(core_to_offline) = on_socket.or_else(|| self.topology.select_cpu_to_offline())
+        else {
+            return 0;
+        };
+        if self.topology.online_count() - core_to_offline.len() < floor {
+            info!("Floor of {} CPUs reached", floor);
+            return 0;
+        }
+        let reason = if self.thermal_limited() {
+            "Package above the thermal limit"
+        } else {
+            "Package power above the budget"
+        };
+        info!("{}, offlining core {:?}", reason, core_to_offline);
+        // A failed core is tried again next cycle, until the blacklist takes it out.
+        usize::from(
+            self.hotplug(Direction::Offline, &core_to_offline)
+                .await
+                .is_ok(),
+        )
+    }
+
+    /// Allows one deeper idle state, and returns whether one was allowed.
+    async fn deepen_idle(&mut self) -> bool {
+        let Some(limit) = &mut self.idle_limit else {
+            return false;
+        };
+        limit
+            .deepen(&self.topology)
+            .await
+            .inspect_err(|e| warn!("Cannot allow a deeper idle state: {}", e))
+            .unwrap_or(false)
+    }
+
+    /// Disables the deepest allowed idle state, and returns whether one was disabled.
+    async fn shallow_idle(&mut self) -> bool {
+        let Some(limit) = &mut self.idle_limit else {
+            return false;
+        };
+        limit
+            .shallow(&self.topology)
+            .await
+            .inspect_err(|e| warn!("Cannot disable the deepest idle state: {}", e))
+            .unwrap_or(false)
+    }
+
+    /// Lowers the frequency cap one step, and returns whether it was lowered.
+    async fn lower_cap(&mut self) -> bool {
+        let Some(cap) = &mut self.freq_cap else {
+            return false;
+        };
+        cap.lower(&self.topology)
+            .await
+            .inspect_err(|e| warn!("Cannot lower the frequency cap: {}", e))
+            .unwrap_or(false)
+    }
+
+    /// Raises the frequency cap one step, and returns whether it was raised.
+    async fn raise_cap(&mut self) -> bool {
+        let Some(cap) = &mut self.freq_cap else {
+            return false;
+        };
+        cap.raise(&self.topology)
+            .await
+            .inspect_err(|e| warn!("Cannot raise the frequency cap: {}", e))
+            .unwrap_or(false)
+    }
+
+    /// Lifts the frequency cap entirely, if any.
+    async fn uncap(&mut self) {
+        let Some(cap) = &mut self.freq_cap else {
+            return;
+        };
+        if cap.level() != Some(0) {
+            if let Err(e) = cap.set_level(&self.topology, 0).await {
+                warn!("Cannot lift the frequency cap: {}", e);
+            }
+        }
+    }
+
+    /// Lifts the frequency cap and, with an idle stage, allows only the shallowest idle states: the
+    /// first stage at its top, e.g. on a panic.
+    async fn unthrottle(&mut self) {
+        self.uncap().await;
+        if idle_stage(&self.settings) {
+            if let Some(limit) = &mut self.idle_limit {
+                if limit.deepest() != Some(SHALLOWEST_STATE) {
+                    if let Err(e) = limit.set_deepest(&self.topology, SHALLOWEST_STATE).await {
+                        warn!("Cannot disable the deeper idle states: {}", e);
+                    }
+                }
+            }
+        }
+        if let Some(&first) = self.stages().first() {
+            self.enter_stage(first);
+        }
+    }
+
+    /// Lifts the frequency cap and, with an idle stage, allows every idle state up to
+    /// `--max-cstate` again, as the kernel does by default, and returns turbo and the EPP to their
+    /// state at startup, e.g. while paused.
+    async fn release_stages(&mut self) {
+        self.uncap().await;
+        if let Err(e) = self.boost.restore(&self.topology).await {
+            warn!("Cannot restore turbo: {}", e);
+        }
+        if let Err(e) = self.pstate.restore(&self.topology).await {
+            warn!("Cannot restore the EPP: {}", e);
+        }
+        if idle_stage(&self.settings) {
+            if let Some(limit) = &mut self.idle_limit {
+                let ceiling = limit.ceiling(&self.topology);
+                if limit.deepest() != Some(ceiling) {
+                    if let Err(e) = limit.set_deepest(&self.topology, ceiling).await {
+                        warn!("Cannot allow the deeper idle states: {}", e);
+                    }
+                }
+            }
+        }
+        if let Some(&first) = self.stages().first() {
+            self.enter_stage(first);
+        }
+    }
+
+    /// Returns whether the frequency is known to be capped or, with an idle stage, deeper idle
+    /// states than the shallowest are known to be allowed.
+    fn throttled(&self) -> bool {
+        let capped = self
+            .freq_cap
+            .as_ref()
+            .and_then(FrequencyCap::level)
+            .is_some_and(|level| level > 0);
+        let deepened = idle_stage(&self.settings)
+            && self
+                .idle_limit
+                .as_ref()
+                .and_then(IdleLimit::deepest)
+                .is_some_and(|deepest| deepest > SHALLOWEST_STATE);
+        capped || deepened
+    }
+
+    /// Whether the next core to offline can go without the online capacity dropping below `desired`.
+    fn can_shed(&self, desired: f64) -> bool {
+        self.topology.select_cpu_to_offline().is_some_and(|core| {
+            self.topology.online_capacity() - self.topology.group_capacity(&core) >= desired
+        })
+    }
+
+    /// Scales each socket against its own average load, for `--per-socket`.
+    ///
+    /// This function performs the following steps:
+    /// 1. Counts the consecutive samples each socket's average C0 percentage spends above the upper or
+    ///    below the lower threshold of the active profile.
+    /// 2. For each socket whose count reaches the hysteresis and whose cooldown has passed, onlines or
+    ///    offlines up to `step` of its cores, without going below `floor` and keeping its last online core.
+    ///    Onlining waits while the package power leaves no headroom below the power budget or the
+    ///    steal time is above `--steal-limit`.
+    ///
+    /// # Returns
+    /// * `Option<Decision>` - The decision published for the cycle: onlining if any socket onlined
+    ///   cores, offlining if any offlined, the blocked decision if a socket could not act, or `None`.
+    async fn scale_sockets(&mut self, floor: usize) -> Option<Decision> {
+        let profile = self.active_profile();
+        let online_after = self.online_after(profile.hysteresis);
+        let mut decisions = Vec::new();
+        for load in self.count_socket_samples() {
+            let socket = load.socket_id;
+            let state = self.sockets.entry(socket).or_default();
+            if state
+                .last_action
+                .is_some_and(|t| t.elapsed() < profile.cooldown)
+            {
+                continue;
+            }
+            let decision = if state.samples_above >= online_after {
+                debug!("High load on socket {}: {:.2}%", socket, load.avg_c0);
+                if !self.power_headroom() {
+                    debug!(
+                        "High load on socket {}, but the package power is close to the budget",
+                        socket
+                    );
+                    continue;
+                }
+                if self.steal_limited() {
+                    warn!(
+                        "High load on socket {}, but {:.1}% of the time is stolen by the hypervisor, not onlining more CPUs",
+                        socket,
+                        self.steal.unwrap_or_default()
+                    );
+                    METRICS.steal_limited();
+                    continue;
+                }
+                if self.online_cores(Some(socket)).await > 0 {
+                    Decision::Online
+                } else {
+                    METRICS.blocked_decision(Direction::Online);
+                    Decision::BlockedOnline
+                }
+            } else if state.samples_below >= profile.hysteresis {
+                debug!("Low load on socket {}: {:.2}%", socket, load.avg_c0);
+                if self.offline_cores(Some(socket), floor).await > 0 {
+                    Decision::Offline
+                } else {
+                    METRICS.blocked_decision(Direction::Offline);
+                    Decision::BlockedOffline
+                }
+            } else {
+                continue;
+            };
+            let state = self.sockets.entry(socket).or_default();
+            if matches!(decision, Decision::Online | Decision::Offline) {
+                state.samples_above = 0;
+                state.samples_below = 0;
+                state.last_action = Some(Instant::now());
+                self.last_action = state.last_action;
+            }
+            decisions.push(decision);
+        }
+        [
+            Decision::Online,
+            Decision::Offline,
+            Decision::BlockedOnline,
+            Decision::BlockedOffline,
+        ]
+        .into_iter()
+        .find(|decision| decisions.contains(decision))
+    }
+
+    /// Counts the consecutive samples each socket's average C0 percentage spends above the upper or
+    /// below the lower threshold of the active profile, and returns the socket loads.
+    fn count_socket_samples(&mut self) -> Vec<SocketLoad> {
+        let profile = self.active_profile();
+        let loads = self.topology.socket_loads();
+        for load in &loads {
+            let samples_above = self
+                .sockets
+                .get(&load.socket_id)
+                .map_or(0, |s| s.samples_above);
+            if load.avg_c0 <= profile.upper_threshold as f64 {
+                let socket = format!("socket {}", load.socket_id);
+                self.end_spike(samples_above, profile.hysteresis, &socket);
+            }
+            let state = self.sockets.entry(load.socket_id).or_default();
+            if load.avg_c0 > profile.upper_threshold as f64 {
+                state.samples_above += 1;
+                state.samples_below = 0;
+            } else if load.avg_c0 < profile.lower_threshold as f64 {
+                state.samples_below += 1;
+                state.samples_above = 0;
+            } else {
+                state.samples_above = 0;
+                state.samples_below = 0;
+            }
+        }
+        loads
+    }
+
+    /// Returns the number of consecutive samples above the upper threshold required before onlining:
+    /// the hysteresis, or as many as cover `--spike-filter-secs` if that is longer.
+    fn online_after(&self, hysteresis: u32) -> u32 {
+        let spike_samples =
+            self.settings.spike_filter.as_secs_f64() / self.settings.interval.as_secs_f64();
+        hysteresis.max(spike_samples.ceil() as u32)
+    }
+
+    /// Records the end of a run of `samples` above the upper threshold on `scope`, the system or a
+    /// socket, as an ignored spike if the spike filter is enabled and the run was too short to online
+    /// CPUs. The samples still count toward the load history, e.g. for workload classification.
+    fn end_spike(&self, samples: u32, hysteresis: u32, scope: &str) {
+        if samples == 0 || self.settings.spike_filter.is_zero() {
+            return;
+        }
+        if samples < self.online_after(hysteresis) {
+            let length = self.settings.interval * samples;
+            debug!("Ignored a load spike of {:?} on {}", length, scope);
+            METRICS.spike_ignored();
+        }
+    }
+
+    /// Looks for a saturated and an idle socket, for `--rebalance-sockets`.
+    ///
+    /// This function performs the following steps:
+    /// 1. Counts the per-socket samples above and below the thresholds, as `scale_sockets` does.
+    /// 2. Unless `cooling_down`, picks the busiest socket whose count above the upper threshold has
+    ///    reached the hysteresis and that still has a core to online, and the least busy socket whose
+    ///    count below the lower threshold has reached it and that still has a core to offline.
+    ///
+    /// # Returns
+    /// * `Option<(usize, usize)>` - The busy and the idle socket, or `None` if there is no such pair.
+    fn socket_imbalance(&mut self, cooling_down: bool) -> Option<(usize, usize)> {
+        let hysteresis = self.active_profile().hysteresis;
+        let online_after = self.online_after(hysteresis);
+        let loads = self.count_socket_samples();
+        if cooling_down {
+            return None;
+        }
+        let counted = |socket: usize, above: bool| {
+            self.sockets.get(&socket).is_some_and(|state| {
+                if above {
+                    state.samples_above >= online_after
+                } else {
+                    state.samples_below >= hysteresis
+                }
+            })
+        };
+        let busy = loads
+            .iter()
+            .filter(|load| counted(load.socket_id, true))
+            .filter(|load| {
+                self.topology
+                    .select_cpu_to_online_on(load.socket_id)
+                    .is_some()
+            })
+            .max_by(|a, b| a.avg_c0.total_cmp(&b.avg_c0))?;
+        let idle = loads
+            .iter()
+            .filter(|load| counted(load.socket_id, false))
+            .filter(|load| {
+                self.topology
+                    .select_cpu_to_offline_on(load.socket_id)
+                    .is_some()
+            })
+            .min_by(|a, b| a.avg_c0.total_cmp(&b.avg_c0))?;
+        info!(
+            "Socket {} saturated at {:.2}% while socket {} idles at {:.2}%, rebalancing",
+            busy.socket_id, busy.avg_c0, idle.socket_id, idle.avg_c0
+        );
+        Some((busy.socket_id, idle.socket_id))
+    }
+
+    /// Onlines up to `step` cores of socket `busy` and offlines up to `step` cores of socket `idle`
+    /// in the same cycle, without going below `floor`, and resets both sockets' hysteresis counts.
+    /// Socket `busy` gets no cores while the package power leaves no headroom below the power budget
+    /// or the steal time is above `--steal-limit`.
+    ///
+    /// # Returns
+    /// * `Decision` - The rebalancing if either socket changed, or the blocked onlining if the floor
+    ///   or failed writes left both as they were.
+    async fn rebalance_sockets(&mut self, busy: usize, idle: usize, floor: usize) -> Decision {
+        let onlined = if !self.power_headroom() {
+            debug!(
+                "Socket {} is saturated, but the package power is close to the budget",
+                busy
+            );
+            0
+        } else if self.steal_limited() {
+            warn!(
+                "Socket {} is saturated, but {:.1}% of the time is stolen by the hypervisor, not onlining more CPUs",
+                busy,
+                self.steal.unwrap_or_default()
+            );
+            METRICS.steal_limited();
+            0
+        } else {
+            self.online_cores(Some(busy)).await
+        };
+        let offlined = self.offline_cores(Some(idle), floor).await;
+        if onlined == 0 && offlined == 0 {
+            debug!("Cannot move cores from socket {} to socket {}", idle, busy);
+            METRICS.blocked_decision(Direction::Online);
+            return Decision::BlockedOnline;
+        }
+        for socket in [busy, idle] {
+            let state = self.sockets.entry(socket).or_default();
+            state.samples_above = 0;
+            state.samples_below = 0;
+        }
+        self.last_action = Some(Instant::now());
+        Decision::Rebalance
+    }
+}
@@ -0,0 +1,920 @@
+//! Process-wide counters and gauges, and a minimal Prometheus text-format exporter.
+//!
+//! The counters are plain atomics in a global `METRICS` instance so that any part of the
+//! program can record an event without threading a handle through every call site. The gauges
+//! are a snapshot of the topology taken by the control loop on every cycle.
+//!
+//! Scrapers that accept OpenMetrics get the decision counters with exemplars: the trace ID of the
+//! most recent cycle that took each decision, which also tags that cycle's log lines.
+use crate::events::Decision;
+use crate::outcome::Outcome;
+use crate::topology::SystemTopology;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{info, warn};
+
+/// Direction of a hotplug operation, used as a metric label.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Online,
+    Offline,
+}
+
+impl Direction {
+    pub fn label(self) -> &'static str {
+        match self {
+            Direction::Online => "online",
+            Direction::Offline => "offline",
+        }
+    }
+}
+
+/// Kind of access that failed, used as a metric label.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SysfsAccess {
+    /// Reading a CPU's `topology` attributes at startup or on reload.
+    TopologyRead,
+    /// Reading a CPU's `online` file, or its `configure` file on s390x.
+    OnlineRead,
+    /// Writing a CPU's `online` file.
+    OnlineWrite,
+    /// Writing a CPU's `cpufreq` limits.
+    CpufreqWrite,
+    /// Reading a CPU's `cpuidle` states or counters.
+    CpuidleRead,
+    /// Writing a CPU's `cpuidle` state `disable` files.
+    CpuidleWrite,
+    /// Reading `/proc/stat`, `/proc/pressure/cpu`, or `/proc/schedstat` for the load metrics.
+    ProcRead,
+    /// Reading the `cpu.stat` of the cgroup watched by the cgroup load metric.
+    CgroupRead,
+    /// Reading a CPU's model-specific registers for the perf load metric.
+    MsrRead,
+    /// Attaching the eBPF programs of the ebpf load metric or reading their counters.
+    BpfRead,
+}
+
+impl SysfsAccess {
+    fn label(self) -> &'static str {
+        match self {
+            SysfsAccess::TopologyRead => "topology_read",
+            SysfsAccess::OnlineRead => "online_read",
+            SysfsAccess::OnlineWrite => "online_write",
+            SysfsAccess::CpufreqWrite => "cpufreq_write",
+            SysfsAccess::CpuidleRead => "cpuidle_read",
+            SysfsAccess::CpuidleWrite => "cpuidle_write",
+            SysfsAccess::ProcRead => "proc_read",
+            SysfsAccess::CgroupRead => "cgroup_read",
+            SysfsAccess::MsrRead => "msr_read",
+            SysfsAccess::BpfRead => "bpf_read",
+        }
+    }
+}
+
+/// Count of the sysfs errors of one kind of access and one error number, as reported by `status`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SysfsErrorCount {
+    pub access: SysfsAccess,
+    /// The error number, e.g. `EBUSY`, or the error kind for errors that have none, e.g.
+    /// `InvalidData` for an unparsable attribute or `TimedOut` for a stuck hotplug write.
+    pub errno: String,
+    pub count: u64,
+}
+
+/// Returns the name of the error number of `error`, or its kind if it has none.
+pub fn errno_label(error: &io::Error) -> String {
+    let Some(code) = error.raw_os_error() else {
+        return format!("{:?}", error.kind());
+    };
+    let name = match code {
+        libc::EACCES => "EACCES",
+        libc::EAGAIN => "EAGAIN",
+        libc::EBUSY => "EBUSY",
+        libc::EINTR => "EINTR",
+        libc::EINVAL => "EINVAL",
+        libc::EIO => "EIO",
+        libc::ENODEV => "ENODEV",
+        libc::ENOENT => "ENOENT",
+        libc::ENOMEM => "ENOMEM",
+        libc::ENOSPC => "ENOSPC",
+        libc::ENXIO => "ENXIO",
+        libc::EPERM => "EPERM",
+        libc::EROFS => "EROFS",
+        libc::ETIMEDOUT => "ETIMEDOUT",
+        _ => return format!("errno_{}", code),
+    };
+    name.to_string()
+}
+
+/// Exposition format of the metrics endpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// The Prometheus text format, version 0.0.4.
+    Prometheus,
+    /// OpenMetrics 1.0, which adds exemplars to the decision counters.
+    OpenMetrics,
+}
+
+impl Format {
+    /// Picks the format from the `Accept` header of a scrape request.
+    pub fn negotiate(accept: &str) -> Format {
+        if accept.contains("application/openmetrics-text") {
+            Format::OpenMetrics
+        } else {
+            Format::Prometheus
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Prometheus => "text/plain; version=0.0.4",
+            Format::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        }
+    }
+}
+
+/// Accuracy of the load predictions of one kind of decision.
+#[derive(Default)]
+struct PredictionStats {
+    count: u64,
+    hits: u64,
+    abs_error_sum: f64,
+}
+
+/// The trace ID of the latest cycle that took a decision, and when it was recorded.
+struct Exemplar {
+    trace_id: String,
+    ts: f64,
+}
+
+pub struct Metrics {
+    hotplug_failures_online: AtomicU64,
+    hotplug_failures_offline: AtomicU64,
+    sysfs_read_failures: AtomicU64,
+    sysfs_errors: Mutex<BTreeMap<(SysfsAccess, String), u64>>,
+    hotplug_timeouts: AtomicU64,
+    hotplug_retries: AtomicU64,
+    cpus_blacklisted: AtomicU64,
+    interrupted_hotplugs: AtomicU64,
+    external_hotplugs_online: AtomicU64,
+    external_hotplugs_offline: AtomicU64,
+    config_reloads_rejected: AtomicU64,
+    spikes_ignored: AtomicU64,
+    rate_limited: AtomicU64,
+    steal_limited: AtomicU64,
+    latency_triggered: AtomicU64,
+    pinned_task_conflicts: AtomicU64,
+    irqs_migrated: AtomicU64,
+    cpuidle_reconciled: AtomicU64,
+    blocked_decisions_online: AtomicU64,
+    blocked_decisions_offline: AtomicU64,
+    sink_dropped: Mutex<BTreeMap<&'static str, u64>>,
+    hotplug_transitions_online: AtomicU64,
+    hotplug_transitions_offline: AtomicU64,
+    decisions: Mutex<BTreeMap<&'static str, u64>>,
+    decision_exemplars: Mutex<BTreeMap<&'static str, Exemplar>>,
+    predictions: Mutex<BTreeMap<&'static str, PredictionStats>>,
+    snapshot: Mutex<Option<Snapshot>>,
+}
+
+/// Gauges taken from the topology at the end of a cycle.
+struct Snapshot {
+    load: f64,
+    online: usize,
+    total: usize,
+    /// C0 percentage and online state of each CPU, by ID.
+    cpus: BTreeMap<usize, (f64, bool)>,
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            hotplug_failures_online: AtomicU64::new(0),
+            hotplug_failures_offline: AtomicU64::new(0),
+            sysfs_read_failures: AtomicU64::new(0),
+            sysfs_errors: Mutex::new(BTreeMap::new()),
+            hotplug_timeouts: AtomicU64::new(0),
+            hotplug_retries: AtomicU64::new(0),
+            cpus_blacklisted: AtomicU64::new(0),
+            interrupted_hotplugs: AtomicU64::new(0),
+            external_hotplugs_online: AtomicU64::new(0),
+            external_hotplugs_offline: AtomicU64::new(0),
+            config_reloads_rejected: AtomicU64::new(0),
+            spikes_ignored: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            steal_limited: AtomicU64::new(0),
+            latency_triggered: AtomicU64::new(0),
+            pinned_task_conflicts: AtomicU64::new(0),
+            irqs_migrated: AtomicU64::new(0),
+            cpuidle_reconciled: AtomicU64::new(0),
+            blocked_decisions_online: AtomicU64::new(0),
+            blocked_decisions_offline: AtomicU64::new(0),
+            sink_dropped: Mutex::new(BTreeMap::new()),
+            hotplug_transitions_online: AtomicU64::new(0),
+            hotplug_transitions_offline: AtomicU64::new(0),
+            decisions: Mutex::new(BTreeMap::new()),
+            decision_exemplars: Mutex::new(BTreeMap::new()),
+            predictions: Mutex::new(BTreeMap::new()),
+            snapshot: Mutex::new(None),
+        }
+    }
+
+    /// Records a failed write to a CPU's `online` file.
+    pub fn hotplug_failure(&self, direction: Direction) {
+        match direction {
+            Direction::Online => &self.hotplug_failures_online,
+            Direction::Offline => &self.hotplug_failures_offline,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a write to a CPU's `online` file that did not complete in time.
+    pub fn hotplug_timeout(&self) {
+        self.hotplug_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a write to a CPU's `online` file retried after the kernel reported the CPU busy.
+    pub fn hotplug_retry(&self) {
+        self.hotplug_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a CPU blacklisted after too many failed hotplug writes in a row.
+    pub fn cpu_blacklisted(&self) {
+        self.cpus_blacklisted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a hotplug write of a previous run that was found unverified in the journal.
+    pub fn interrupted_hotplug(&self) {
+        self.interrupted_hotplugs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a CPU found onlined or offlined outside the daemon.
+    pub fn external_hotplug(&self, direction: Direction) {
+        match direction {
+            Direction::Online => &self.external_hotplugs_online,
+            Direction::Offline => &self.external_hotplugs_offline,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a configuration reload that was refused, leaving the previous settings in effect.
+    pub fn config_reload_rejected(&self) {
+        self.config_reloads_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a load spike above the upper threshold that ended before the spike filter let it
+    /// online CPUs.
+    pub fn spike_ignored(&self) {
+        self.spikes_ignored.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a group of CPUs left as they are because hotplugging it would exceed
+    /// `--max-transitions-per-minute`.
+    pub fn rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a cycle whose scale-up was suppressed because the steal time exceeded
+    /// `--steal-limit`.
+    pub fn steal_limited(&self) {
+        self.steal_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a cycle whose load counted as high because the wakeup latency exceeded
+    /// `--wakeup-latency-us`.
+    pub fn latency_triggered(&self) {
+        self.latency_triggered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records an offline of CPUs that some tasks were pinned to, see `--pinned-tasks`.
+    pub fn pinned_task_conflict(&self) {
+        self.pinned_task_conflicts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records `count` IRQs retargeted away from CPUs about to be offlined.
+    pub fn irqs_migrated(&self, count: usize) {
+        self.irqs_migrated
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Records a CPU load taken from `/proc/stat` because the CPU's cpuidle residency lagged.
+    pub fn cpuidle_reconciled(&self) {
+        self.cpuidle_reconciled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a failed sysfs or `/proc` access, classified by the kind of access and the error
+    /// number. Failed reads also count toward the read failures.
+    pub fn sysfs_error(&self, access: SysfsAccess, error: &io::Error) {
+        if access != SysfsAccess::OnlineWrite {
+            self.sysfs_read_failures.fetch_add(1, Ordering::Relaxed);
+        }
+        *self
+            .sysfs_errors
+            .lock()
+            .unwrap()
+            .entry((access, errno_label(error)))
+            .or_default() += 1;
+    }
+
+    /// Returns the sysfs error counts by kind of access and error number.
+    pub fn sysfs_errors(&self) -> Vec<SysfsErrorCount> {
+        self.sysfs_errors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((access, errno), &count)| SysfsErrorCount {
+                access: *access,
+                errno: errno.clone(),
+                count,
+            })
+            .collect()
+    }
+
+    /// Records a decision that could not be carried out because a limit was reached.
+    pub fn blocked_decision(&self, direction: Direction) {
+        match direction {
+            Direction::Online => &self.blocked_decisions_online,
+            Direction::Offline => &self.blocked_decisions_offline,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records an entry dropped from the queue of the output sink `sink` because it fell behind.
+    pub fn sink_dropped(&self, sink: &'static str) {
+        *self.sink_dropped.lock().unwrap().entry(sink).or_default() += 1;
+    }
+
+    /// Records a CPU that changed state.
+    pub fn hotplug_transition(&self, direction: Direction) {
+        match direction {
+            Direction::Online => &self.hotplug_transitions_online,
+            Direction::Offline => &self.hotplug_transitions_offline,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a decision of the control loop.
+    pub fn decision(&self, decision: Decision) {
+        *self
+            .decisions
+            .lock()
+            .unwrap()
+            .entry(decision.label())
+            .or_default() += 1;
+    }
+
+    /// Attaches the trace ID of the cycle that took `decision` to its counter as an exemplar.
+    pub fn decision_exemplar(&self, decision: Decision, trace_id: &str) {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        self.decision_exemplars.lock().unwrap().insert(
+            decision.label(),
+            Exemplar {
+                trace_id: trace_id.to_string(),
+                ts,
+            },
+        );
+    }
+
+    /// Records how far the load observed after a decision was from the load it predicted.
+    pub fn prediction(&self, outcome: &Outcome) {
+        let mut predictions = self.predictions.lock().unwrap();
+        let stats = predictions.entry(outcome.decision.label()).or_default();
+        stats.count += 1;
+        stats.hits += u64::from(outcome.hit());
+        stats.abs_error_sum += outcome.error().abs();
+    }
+
+    /// Updates the gauges from the topology and the load that drove the decisions of this cycle.
+    pub fn update_topology(&self, topology: &SystemTopology, load: f64) {
+        let cpus = topology
+            .cpus
+            .values()
+            .map(|cpu| (cpu.id, (cpu.c0_percentage, cpu.online)))
+            .collect();
+        *self.snapshot.lock().unwrap() = Some(Snapshot {
+            load,
+            online: topology.online_count(),
+            total: topology.cpus.len(),
+            cpus,
+        });
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        self.render_as(Format::Prometheus)
+    }
+
+    /// Renders all metrics in `format`.
+    pub fn render_as(&self, format: Format) -> String {
+        let mut out = String::new();
+
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_hotplug_failures_total",
+            "Failed writes to CPU online files.",
+        );
+        for direction in [Direction::Online, Direction::Offline] {
+            let value = match direction {
+                Direction::Online => &self.hotplug_failures_online,
+                Direction::Offline => &self.hotplug_failures_offline,
+            };
+            let _ = writeln!(
+                out,
+                "cpu_on_off_hotplug_failures_total{{direction=\"{}\"}} {}",
+                direction.label(),
+                value.load(Ordering::Relaxed)
+            );
+        }
+
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_hotplug_timeouts_total",
+            "Writes to CPU online files that did not complete in time.",
+        );
+        let _ = writeln!(
+            out,
+            "cpu_on_off_hotplug_timeouts_total {}",
+            self.hotplug_timeouts.load(Ordering::Relaxed)
+        );
+
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_hotplug_retries_total",
+            "Writes to CPU online files retried after the CPU was busy.",
+        );
+        let _ = writeln!(
+            out,
+            "cpu_on_off_hotplug_retries_total {}",
+            self.hotplug_retries.load(Ordering::Relaxed)
+        );
+
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_cpus_blacklisted_total",
+            "CPUs blacklisted after too many failed hotplug writes in a row.",
+        );
+        let _ = writeln!(
+            out,
+            "cpu_on_off_cpus_blacklisted_total {}",
+            self.cpus_blacklisted.load(Ordering::Relaxed)
+        );
+
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_interrupted_hotplugs_total",
+            "Hotplug writes of a previous run that were reconciled at startup.",
+        );
+        let _ = writeln!(
+            out,
+            "cpu_on_off_interrupted_hotplugs_total {}",
+            self.interrupted_hotplugs.load(Ordering::Relaxed)
+        );
+
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_external_hotplugs_total",
+            "CPUs found onlined or offlined outside the daemon.",
+        );
+        for direction in [Direction::Online, Direction::Offline] {
+            let value = match direction {
+                Direction::Online => &self.external_hotplugs_online,
+                Direction::Offline => &self.external_hotplugs_offline,
+            };
+            let _ = writeln!(
+                out,
+                "cpu_on_off_external_hotplugs_total{{direction=\"{}\"}} {}",
+                direction.label(),
+                value.load(Ordering::Relaxed)
+            );
+        }
+
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_config_reloads_rejected_total",
+            "Configuration reloads refused because the new settings were invalid.",
+        );
+        let _ = writeln!(
+            out,
+            "cpu_on_off_config_reloads_rejected_total {}",
+            self.config_reloads_rejected.load(Ordering::Relaxed)
+        );
+
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_spikes_ignored_total",
+            "Load spikes above the upper threshold too short to online CPUs.",
+        );
+        let _ = writeln!(
+            out,
+            "cpu_on_off_spikes_ignored_total {}",
+            self.spikes_ignored.load(Ordering::Relaxed)
+        );
+
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_rate_limited_total",
+            "Hotplug operations held back by the transition rate limit.",
+        );
+        let _ = writeln!(
+            out,
+            "cpu_on_off_rate_limited_total {}",
+            self.rate_limited.load(Ordering::Relaxed)
+        );
+
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_steal_limited_total",
+            "Scale-ups suppressed because the steal time exceeded the steal limit.",
+        );
+        let _ = writeln!(
+            out,
+            "cpu_on_off_steal_limited_total {}",
+            self.steal_limited.load(Ordering::Relaxed)
+        );
+
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_latency_triggered_total",
+            "Cycles whose load counted as high because the wakeup latency exceeded the limit.",
+        );
+        let _ = writeln!(
+            out,
+            "cpu_on_off_latency_triggered_total {}",
+            self.latency_triggered.load(Ordering::Relaxed)
+        );
+
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_pinned_task_conflicts_total",
+            "Offlines of CPUs that some tasks were pinned to.",
+        );
+        let _ = writeln!(
+            out,
+            "cpu_on_off_pinned_task_conflicts_total {}",
+            self.pinned_task_conflicts.load(Ordering::Relaxed)
+        );
+
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_irqs_migrated_total",
+            "IRQs retargeted away from CPUs about to be offlined.",
+        );
+        let _ = writeln!(
+            out,
+            "cpu_on_off_irqs_migrated_total {}",
+            self.irqs_migrated.load(Ordering::Relaxed)
+        );
+
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_cpuidle_reconciled_total",
+            "CPU loads taken from /proc/stat because the cpuidle residency lagged.",
+        );
+        let _ = writeln!(
+            out,
+            "cpu_on_off_cpuidle_reconciled_total {}",
+            self.cpuidle_reconciled.load(Ordering::Relaxed)
+        );
+
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_sysfs_read_failures_total",
+            "Failed reads of sysfs attributes.",
+        );
+        let _ = writeln!(
+            out,
+            "cpu_on_off_sysfs_read_failures_total {}",
+            self.sysfs_read_failures.load(Ordering::Relaxed)
+        );
+
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_sysfs_errors_total",
+            "Failed sysfs and /proc accesses by kind of access and error number.",
+        );
+        for ((access, errno), value) in self.sysfs_errors.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "cpu_on_off_sysfs_errors_total{{access=\"{}\",errno=\"{}\"}} {}",
+                access.label(),
+                errno,
+                value
+            );
+        }
+
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_blocked_decisions_total",
+            "Scaling decisions blocked by a limit.",
+        );
+        for direction in [Direction::Online, Direction::Offline] {
+            let value = match direction {
+                Direction::Online => &self.blocked_decisions_online,
+                Direction::Offline => &self.blocked_decisions_offline,
+            };
+            let _ = writeln!(
+                out,
+                "cpu_on_off_blocked_decisions_total{{direction=\"{}\"}} {}",
+                direction.label(),
+                value.load(Ordering::Relaxed)
+            );
+        }
+
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_sink_dropped_total",
+            "Entries dropped because an output sink fell behind.",
+        );
+        for (sink, value) in self.sink_dropped.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "cpu_on_off_sink_dropped_total{{sink=\"{}\"}} {}",
+                sink, value
+            );
+        }
+
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_hotplug_transitions_total",
+            "CPUs onlined or offlined.",
+        );
+        for direction in [Direction::Online, Direction::Offline] {
+            let value = match direction {
+                Direction::Online => &self.hotplug_transitions_online,
+                Direction::Offline => &self.hotplug_transitions_offline,
+            };
+            let _ = writeln!(
+                out,
+                "cpu_on_off_hotplug_transitions_total{{direction=\"{}\"}} {}",
+                direction.label(),
+                value.load(Ordering::Relaxed)
+            );
+        }
+
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_decisions_total",
+            "Actions taken or attempted by the control loop.",
+        );
+        let exemplars = self.decision_exemplars.lock().unwrap();
+        for (decision, value) in self.decisions.lock().unwrap().iter() {
+            let _ = write!(
+                out,
+                "cpu_on_off_decisions_total{{decision=\"{}\"}} {}",
+                decision, value
+            );
+            if let (Format::OpenMetrics, Some(exemplar)) = (format, exemplars.get(decision)) {
+                let _ = write!(
+                    out,
+                    " # {{trace_id=\"{}\"}} 1 {:.3}",
+                    exemplar.trace_id, exemplar.ts
+                );
+            }
+            out.push('\n');
+        }
+        drop(exemplars);
+
+        let predictions = self.predictions.lock().unwrap();
+        let _ = writeln!(
+            out,
+            "# HELP cpu_on_off_prediction_error_percent Distance between the load predicted by an action and the load observed in the next cycle."
+        );
+        let _ = writeln!(out, "# TYPE cpu_on_off_prediction_error_percent summary");
+        for (decision, stats) in predictions.iter() {
+            let _ = writeln!(
+                out,
+                "cpu_on_off_prediction_error_percent_sum{{decision=\"{}\"}} {}",
+                decision, stats.abs_error_sum
+            );
+            let _ = writeln!(
+                out,
+                "cpu_on_off_prediction_error_percent_count{{decision=\"{}\"}} {}",
+                decision, stats.count
+            );
+        }
+        counter_header(
+            &mut out,
+            format,
+            "cpu_on_off_prediction_hits_total",
+            "Predictions within 10 points of the load observed in the next cycle.",
+        );
+        for (decision, stats) in predictions.iter() {
+            let _ = writeln!(
+                out,
+                "cpu_on_off_prediction_hits_total{{decision=\"{}\"}} {}",
+                decision, stats.hits
+            );
+        }
+        drop(predictions);
+
+        if let Some(snapshot) = &*self.snapshot.lock().unwrap() {
+            let _ = writeln!(
+                out,
+                "# HELP cpu_on_off_load_percent Load that drove the decisions of the last cycle."
+            );
+            let _ = writeln!(out, "# TYPE cpu_on_off_load_percent gauge");
+            let _ = writeln!(out, "cpu_on_off_load_percent {}", snapshot.load);
+
+            let _ = writeln!(out, "# HELP cpu_on_off_online_cpus Number of online CPUs.");
+            let _ = writeln!(out, "# TYPE cpu_on_off_online_cpus gauge");
+            let _ = writeln!(out, "cpu_on_off_online_cpus {}", snapshot.online);
+
+            let _ = writeln!(out, "# HELP cpu_on_off_cpus Number of CPUs.");
+            let _ = writeln!(out, "# TYPE cpu_on_off_cpus gauge");
+            let _ = writeln!(out, "cpu_on_off_cpus {}", snapshot.total);
+
+            let _ = writeln!(
+                out,
+                "# HELP cpu_on_off_cpu_c0_percent C0 residency of each CPU in the last cycle."
+            );
+            let _ = writeln!(out, "# TYPE cpu_on_off_cpu_c0_percent gauge");
+            for (id, (c0, _)) in &snapshot.cpus {
+                let _ = writeln!(out, "cpu_on_off_cpu_c0_percent{{cpu=\"{}\"}} {}", id, c0);
+            }
+
+            let _ = writeln!(
+                out,
+                "# HELP cpu_on_off_cpu_online Whether each CPU is online (1) or offline (0)."
+            );
+            let _ = writeln!(out, "# TYPE cpu_on_off_cpu_online gauge");
+            for (id, (_, online)) in &snapshot.cpus {
+                let _ = writeln!(
+                    out,
+                    "cpu_on_off_cpu_online{{cpu=\"{}\"}} {}",
+                    id, *online as u8
+                );
+            }
+        }
+
+        if format == Format::OpenMetrics {
+            out.push_str("# EOF\n");
+        }
+        out
+    }
+}
+
+/// Writes the `HELP` and `TYPE` lines of the counter `name`. OpenMetrics names the metric family
+/// without the `_total` suffix of its samples.
+fn counter_header(out: &mut String, format: Format, name: &str, help: &str) {
+    let family = match format {
+        Format::Prometheus => name,
+        Format::OpenMetrics => name.trim_end_matches("_total"),
+    };
+    let _ = writeln!(out, "# HELP {} {}", family, help);
+    let _ = writeln!(out, "# TYPE {} counter", family);
+}
+
+/// Serves `GET /metrics` on the given address until an accept error occurs.
+pub async fn serve(addr: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Serving metrics on http://{}/metrics", addr);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream).await {
+                warn!("Metrics connection error: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream) -> io::Result<()> {
+    let mut buf = [0u8; 1024];
+    let n = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+    let accept = request
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("accept"))
+        .map_or("", |(_, value)| value);
+    let format = Format::negotiate(accept);
+
+    let (status, body) = if method == "GET" && path == "/metrics" {
+        ("200 OK", METRICS.render_as(format))
+    } else {
+        ("404 Not Found", String::new())
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        format.content_type(),
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_per_cpu_gauges_and_decisions() {
+        let metrics = Metrics::new();
+        let mut topology = SystemTopology::single_thread_cores(&[false, false]);
+        topology.cpus.get_mut(&1).unwrap().c0_percentage = 42.5;
+        metrics.update_topology(&topology, 10.0);
+        metrics.decision(Decision::Offline);
+        metrics.hotplug_transition(Direction::Offline);
+        metrics.prediction(&Outcome {
+            decision: Decision::Offline,
+            predicted: 40.0,
+            observed: 52.5,
+        });
+
+        let out = metrics.render();
+        assert!(out.contains("cpu_on_off_cpu_c0_percent{cpu=\"1\"} 42.5\n"));
+        assert!(out.contains("cpu_on_off_cpu_online{cpu=\"1\"} 0\n"));
+        assert!(out.contains("cpu_on_off_online_cpus 0\n"));
+        assert!(out.contains("cpu_on_off_decisions_total{decision=\"offline\"} 1\n"));
+        assert!(out.contains("cpu_on_off_hotplug_transitions_total{direction=\"offline\"} 1\n"));
+        assert!(
+            out.contains("cpu_on_off_prediction_error_percent_sum{decision=\"offline\"} 12.5\n")
+        );
+        assert!(out.contains("cpu_on_off_prediction_hits_total{decision=\"offline\"} 0\n"));
+    }
+
+    #[test]
+    fn attaches_exemplars_only_in_openmetrics() {
+        let metrics = Metrics::new();
+        metrics.decision(Decision::Online);
+        metrics.decision_exemplar(Decision::Online, "4bf92f3577b34da6a3ce929d0e0e4736");
+
+        assert!(metrics
+            .render()
+            .contains("cpu_on_off_decisions_total{decision=\"online\"} 1\n"));
+        let out = metrics.render_as(Format::negotiate(
+            "application/openmetrics-text;version=1.0.0,text/plain;q=0.5",
+        ));
+        assert!(out.contains("# TYPE cpu_on_off_decisions counter\n"));
+        assert!(out.contains(
+            "cpu_on_off_decisions_total{decision=\"online\"} 1 # {trace_id=\"4bf92f3577b34da6a3ce929d0e0e4736\"} 1 "
+        ));
+        assert!(out.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn classifies_sysfs_errors_by_errno() {
+        let metrics = Metrics::new();
+        let busy = io::Error::from_raw_os_error(libc::EBUSY);
+        metrics.sysfs_error(SysfsAccess::OnlineWrite, &busy);
+        metrics.sysfs_error(SysfsAccess::OnlineWrite, &busy);
+        let unparsable = io::Error::new(io::ErrorKind::InvalidData, "not a number");
+        metrics.sysfs_error(SysfsAccess::CpuidleRead, &unparsable);
+
+        let out = metrics.render();
+        assert!(out.contains(
+            "cpu_on_off_sysfs_errors_total{access=\"online_write\",errno=\"EBUSY\"} 2\n"
+        ));
+        assert!(out.contains("cpu_on_off_sysfs_read_failures_total 1\n"));
+        assert_eq!(
+            metrics.sysfs_errors()[1],
+            SysfsErrorCount {
+                access: SysfsAccess::CpuidleRead,
+                errno: "InvalidData".to_string(),
+                count: 1,
+            }
+        );
+    }
+}
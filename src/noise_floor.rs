@@ -0,0 +1,85 @@
+//! Calibration of the background load present on an otherwise idle system.
+//!
+//! Kernel threads, timers, and the daemon itself keep every machine slightly busy, and how much
+//! depends on the machine size. The noise floor is measured during a warm-up period right after
+//! startup, while all CPUs are online, and subtracted from later loads so that the thresholds
+//! represent the real workload.
+
+/// Collects warm-up samples and, once calibrated, removes the noise floor from loads.
+pub struct NoiseFloor {
+    target: usize,
+    samples: Vec<f64>,
+    /// Noise floor in percent of all CPUs, set once calibration is complete.
+    baseline: Option<f64>,
+}
+
+impl NoiseFloor {
+    /// Creates a calibration over the first `target` samples.
+    pub fn new(target: usize) -> Self {
+        NoiseFloor {
+            target,
+            samples: Vec::with_capacity(target),
+            baseline: (target == 0).then_some(0.0),
+        }
+    }
+
+    /// Creates a noise floor that is already calibrated at `baseline`, e.g. by the daemon that
+    /// handed over to this one.
+    pub fn calibrated(baseline: f64) -> Self {
+        NoiseFloor {
+            target: 0,
+            samples: Vec::new(),
+            baseline: Some(baseline),
+        }
+    }
+
+    pub fn baseline(&self) -> Option<f64> {
+        self.baseline
+    }
+
+    /// Records a warm-up sample of the average load over all CPUs. Returns the number of samples
+    /// collected so far; the median of the samples becomes the baseline once `target` is reached.
+    pub fn observe(&mut self, load: f64) -> usize {
+        self.samples.push(load);
+        if self.samples.len() >= self.target {
+            let mut sorted = self.samples.clone();
+            sorted.sort_by(f64::total_cmp);
+            self.baseline = Some(sorted[sorted.len() / 2]);
+        }
+        self.samples.len()
+    }
+
+    /// Removes the noise floor from `load`, the average load of `online` out of `total` CPUs.
+    ///
+    /// The baseline was measured with all CPUs online, so the background work spreads over fewer
+    /// CPUs once some are offline and takes a proportionally larger share of each.
+    pub fn subtract(&self, load: f64, online: usize, total: usize) -> f64 {
+        let Some(baseline) = self.baseline else {
+            return load;
+        };
+        if online == 0 {
+            return load;
+        }
+        let noise = baseline * total as f64 / online as f64;
+        (load - noise).max(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calibrates_to_the_median_and_scales_with_online_cpus() {
+        let mut noise = NoiseFloor::new(3);
+        assert_eq!(noise.observe(2.0), 1);
+        assert_eq!(noise.baseline(), None);
+        noise.observe(50.0);
+        noise.observe(3.0);
+        assert_eq!(noise.baseline(), Some(3.0));
+
+        assert_eq!(noise.subtract(33.0, 8, 8), 30.0);
+        assert_eq!(noise.subtract(33.0, 4, 8), 27.0);
+        assert_eq!(noise.subtract(1.0, 8, 8), 0.0);
+    }
+}
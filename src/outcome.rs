@@ -0,0 +1,109 @@
+//! Predicted versus observed outcome of the control loop's actions.
+//!
+//! When a cycle onlines or offlines CPUs, the busy capacity is expected to stay the same and spread
+//! over the new online capacity, so the load should change in inverse proportion to it. The load
+//! of the next cycle is the observed outcome. Comparing the two shows how well this model holds on
+//! a given machine and workload, which has to be known before a predictive policy can be trusted.
+use crate::events::Decision;
+use serde::{Deserialize, Serialize};
+
+/// Largest difference, in percentage points, between the predicted and the observed load for a
+/// prediction to count as a hit.
+pub const PREDICTION_TOLERANCE: f64 = 10.0;
+
+/// Returns the load expected once `load`, measured over `capacity_before`, spreads over
+/// `capacity_after`, capped at 100%.
+pub fn predict_load(load: f64, capacity_before: f64, capacity_after: f64) -> f64 {
+    if capacity_after <= 0.0 {
+        return load;
+    }
+    (load * capacity_before / capacity_after).clamp(0.0, 100.0)
+}
+
+/// The load a decision predicted for the next cycle, and what that cycle measured.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Outcome {
+    pub decision: Decision,
+    pub predicted: f64,
+    pub observed: f64,
+}
+
+impl Outcome {
+    /// Returns how far the observed load was above (positive) or below the predicted one.
+    pub fn error(&self) -> f64 {
+        self.observed - self.predicted
+    }
+
+    /// Whether the observed load was within `PREDICTION_TOLERANCE` of the predicted one.
+    pub fn hit(&self) -> bool {
+        self.error().abs() <= PREDICTION_TOLERANCE
+    }
+}
+
+/// A prediction waiting for the next cycle's load.
+#[derive(Clone, Copy, Debug)]
+struct Prediction {
+    decision: Decision,
+    predicted: f64,
+    online_cpus: usize,
+}
+
+/// Holds the prediction of the latest action until the next cycle observes its outcome.
+#[derive(Default)]
+pub struct OutcomeTracker {
+    pending: Option<Prediction>,
+}
+
+impl OutcomeTracker {
+    /// Records the load predicted after `decision` changed the online capacity, leaving
+    /// `online_cpus` CPUs online, and returns it.
+    pub fn predict(
+        &mut self,
+        decision: Decision,
+        load: f64,
+        capacity_before: f64,
+        capacity_after: f64,
+        online_cpus: usize,
+    ) -> f64 {
+        let predicted = predict_load(load, capacity_before, capacity_after);
+        self.pending = Some(Prediction {
+            decision,
+            predicted,
+            online_cpus,
+        });
+        predicted
+    }
+
+    /// Compares the pending prediction, if any, with the `load` observed while `online_cpus` CPUs
+    /// are online. A prediction is dropped without an outcome if the online CPUs changed in
+    /// between, e.g. because the program was paused or a CPU was hotplugged by hand.
+    pub fn observe(&mut self, load: f64, online_cpus: usize) -> Option<Outcome> {
+        let prediction = self.pending.take()?;
+        (prediction.online_cpus == online_cpus).then_some(Outcome {
+            decision: prediction.decision,
+            predicted: prediction.predicted,
+            observed: load,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_the_next_load_with_the_prediction() {
+        let mut tracker = OutcomeTracker::default();
+        // 30% over 8 CPUs is expected to be 40% over 6.
+        let predicted = tracker.predict(Decision::Offline, 30.0, 8.0, 6.0, 6);
+        assert_eq!(predicted, 40.0);
+        let outcome = tracker.observe(47.5, 6).unwrap();
+        assert_eq!(outcome.error(), 7.5);
+        assert!(outcome.hit());
+        assert_eq!(tracker.observe(47.5, 6), None);
+
+        tracker.predict(Decision::Online, 90.0, 4.0, 6.0, 6);
+        assert_eq!(tracker.observe(60.0, 8), None);
+        assert_eq!(predict_load(90.0, 8.0, 4.0), 100.0);
+    }
+}
@@ -0,0 +1,127 @@
+//! Latency-sensitive mode, detected through the kernel's CPU latency QoS.
+//!
+//! Audio servers, trading engines, and benchmarks like `cyclictest` hold `/dev/cpu_dma_latency`
+//! open with a latency target while they need quick wake-ups. Reading the device returns the
+//! strictest target requested by any process, as a native-endian 32-bit integer in microseconds,
+//! without adding a request of its own. With `--latency-us`, the CPU manager treats a target at or
+//! below the given bound as latency-sensitive mode: it offlines no CPUs and disables the idle
+//! states whose exit latency exceeds the bound until the target is relaxed again.
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::{info, warn};
+
+/// The kernel's CPU latency QoS device.
+pub static CPU_DMA_LATENCY: &str = "/dev/cpu_dma_latency";
+
+/// Tracks whether latency-sensitive mode is active.
+pub struct LatencyGuard {
+    path: PathBuf,
+    limit_us: u32,
+    active: bool,
+    /// Whether a failed read was already logged.
+    warned: bool,
+}
+
+impl LatencyGuard {
+    /// Creates a guard that is active while the CPU latency target is at most `limit_us`.
+    pub fn new(limit_us: u32) -> Self {
+        Self::with_path(limit_us, CPU_DMA_LATENCY)
+    }
+
+    /// Reads the target from `path` instead of `/dev/cpu_dma_latency`, e.g. a fake file in tests.
+    pub fn with_path(limit_us: u32, path: impl Into<PathBuf>) -> Self {
+        LatencyGuard {
+            path: path.into(),
+            limit_us,
+            active: false,
+            warned: false,
+        }
+    }
+
+    /// Returns the latency bound set by `--latency-us`.
+    pub fn limit_us(&self) -> u32 {
+        self.limit_us
+    }
+
+    /// Returns whether latency-sensitive mode is active as of the last poll.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Reads the current CPU latency target. A target that cannot be read, e.g. in a container
+    /// without the device, leaves latency-sensitive mode inactive and is logged once.
+    ///
+    /// # Returns
+    /// * `Option<bool>` - Whether latency-sensitive mode is now active, if that changed.
+    pub async fn poll(&mut self) -> Option<bool> {
+        let active = match read_target(&self.path).await {
+            Ok(target_us) => {
+                self.warned = false;
+                target_us <= i64::from(self.limit_us)
+            }
+            Err(e) => {
+                if !self.warned {
+                    warn!(
+                        "Cannot read the CPU latency target from {:?}: {}",
+                        self.path, e
+                    );
+                    self.warned = true;
+                }
+                false
+            }
+        };
+        if active == self.active {
+            return None;
+        }
+        self.active = active;
+        if active {
+            info!(
+                "CPU latency target at or below {}us, entering latency-sensitive mode",
+                self.limit_us
+            );
+        } else {
+            info!("CPU latency target relaxed, leaving latency-sensitive mode");
+        }
+        Some(active)
+    }
+}
+
+/// Reads the CPU latency target in microseconds from `path`.
+async fn read_target(path: &Path) -> io::Result<i64> {
+    let content = fs::read(path).await?;
+    parse_target(&content).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected CPU latency target: {:?}", content),
+        )
+    })
+}
+
+/// Parses the contents of `/dev/cpu_dma_latency`: a native-endian 32-bit integer, or a decimal
+/// number as written by some tools and tests.
+pub fn parse_target(content: &[u8]) -> Option<i64> {
+    if let Ok(bytes) = <[u8; 4]>::try_from(content) {
+        if !bytes.iter().all(u8::is_ascii_digit) {
+            return Some(i64::from(i32::from_ne_bytes(bytes)));
+        }
+    }
+    std::str::from_utf8(content).ok()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_binary_and_decimal_targets() {
+        assert_eq!(parse_target(&20i32.to_ne_bytes()), Some(20));
+        assert_eq!(
+            parse_target(&2_000_000_000i32.to_ne_bytes()),
+            Some(2_000_000_000)
+        );
+        assert_eq!(parse_target(b"1000"), Some(1000));
+        assert_eq!(parse_target(b"15\n"), Some(15));
+        assert_eq!(parse_target(b"x"), None);
+    }
+}
@@ -0,0 +1,196 @@
+//! Named tuning profiles.
+//!
+//! A profile bundles the load thresholds with the dynamics of the control loop, since
+//! power-saving and performance deployments want different reaction speeds and not just
+//! different set points.
+use crate::strategy::StrategyName;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProfileName {
+    PowerSaver,
+    Balanced,
+    Performance,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: ProfileName,
+    /// Upper load threshold percentage.
+    pub upper_threshold: u8,
+    /// Lower load threshold percentage.
+    pub lower_threshold: u8,
+    /// Number of consecutive samples beyond a threshold required before acting.
+    pub hysteresis: u32,
+    /// Minimum time between two hotplug actions.
+    pub cooldown: Duration,
+    /// Number of cores onlined or offlined per action.
+    pub step: usize,
+    /// How the CPU to hotplug next is picked, or `None` for the default strategy.
+    #[serde(default)]
+    pub strategy: Option<StrategyName>,
+}
+
+/// User overrides applied on top of whichever profile is in effect.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ProfileOverrides {
+    pub upper_threshold: Option<u8>,
+    pub lower_threshold: Option<u8>,
+    pub hysteresis: Option<u32>,
+    pub cooldown: Option<Duration>,
+    /// The selection strategy given with `--strategy`, which takes precedence over the profile's.
+    #[serde(default)]
+    pub strategy: Option<StrategyName>,
+}
+
+impl ProfileOverrides {
+    /// Builds overrides from option values, checking that the hysteresis is at least one sample
+    /// and the cooldown a non-negative number of seconds.
+    pub fn new(
+        upper_threshold: Option<u8>,
+        lower_threshold: Option<u8>,
+        hysteresis: Option<u32>,
+        cooldown_secs: Option<f64>,
+    ) -> Result<Self, String> {
+        if hysteresis == Some(0) {
+            return Err("hysteresis must be at least 1 sample".to_string());
+        }
+        let cooldown = match cooldown_secs {
+            Some(secs) if !(secs >= 0.0 && secs.is_finite()) => {
+                return Err(format!(
+                    "cooldown must be a non-negative number of seconds, got {}",
+                    secs
+                ))
+            }
+            secs => secs.map(Duration::from_secs_f64),
+        };
+        Ok(ProfileOverrides {
+            upper_threshold,
+            lower_threshold,
+            hysteresis,
+            cooldown,
+            strategy: None,
+        })
+    }
+
+    /// Returns these overrides with the ones set in `changes` taking precedence.
+    pub fn merge(&self, changes: &ProfileOverrides) -> ProfileOverrides {
+        ProfileOverrides {
+            upper_threshold: changes.upper_threshold.or(self.upper_threshold),
+            lower_threshold: changes.lower_threshold.or(self.lower_threshold),
+            hysteresis: changes.hysteresis.or(self.hysteresis),
+            cooldown: changes.cooldown.or(self.cooldown),
+            strategy: changes.strategy.or(self.strategy),
+        }
+    }
+
+    /// Checks that the thresholds satisfy lower < upper <= 100 in each of the profiles `names`
+    /// once the overrides are applied.
+    pub fn validate(&self, names: &[ProfileName]) -> Result<(), String> {
+        for &name in names {
+            let profile = self.apply(name);
+            if profile.upper_threshold > 100 || profile.lower_threshold >= profile.upper_threshold {
+                return Err(format!(
+                    "thresholds must satisfy lower < upper <= 100, got lower={} upper={} for the {:?} profile",
+                    profile.lower_threshold, profile.upper_threshold, name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the profile `name` with the overrides applied.
+    pub fn apply(&self, name: ProfileName) -> Profile {
+        let mut profile = name.profile();
+        if let Some(upper) = self.upper_threshold {
+            profile.upper_threshold = upper;
+        }
+        if let Some(lower) = self.lower_threshold {
+            profile.lower_threshold = lower;
+        }
+        if let Some(hysteresis) = self.hysteresis {
+            profile.hysteresis = hysteresis;
+        }
+        if let Some(cooldown) = self.cooldown {
+            profile.cooldown = cooldown;
+        }
+        if let Some(strategy) = self.strategy {
+            profile.strategy = Some(strategy);
+        }
+        profile
+    }
+}
+
+impl ProfileName {
+    pub fn profile(self) -> Profile {
+        match self {
+            ProfileName::PowerSaver => Profile {
+                name: self,
+                upper_threshold: 90,
+                lower_threshold: 60,
+                hysteresis: 3,
+                cooldown: Duration::from_secs(5),
+                step: 1,
+                strategy: Some(StrategyName::TopologyPacking),
+            },
+            ProfileName::Balanced => Profile {
+                name: self,
+                upper_threshold: 85,
+                lower_threshold: 50,
+                hysteresis: 1,
+                cooldown: Duration::ZERO,
+                step: 1,
+                strategy: Some(StrategyName::HighestId),
+            },
+            ProfileName::Performance => Profile {
+                name: self,
+                upper_threshold: 70,
+                lower_threshold: 30,
+                hysteresis: 2,
+                cooldown: Duration::from_secs(2),
+                step: 2,
+                strategy: Some(StrategyName::MostLoadedFirstOnline),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_and_validates_overrides() {
+        assert!(ProfileOverrides::new(None, None, Some(0), None).is_err());
+        assert!(ProfileOverrides::new(None, None, None, Some(-1.0)).is_err());
+
+        let base = ProfileOverrides::new(Some(80), Some(40), None, None).unwrap();
+        let changes = ProfileOverrides::new(Some(95), None, Some(4), None).unwrap();
+        let merged = base.merge(&changes);
+        assert_eq!(merged.upper_threshold, Some(95));
+        assert_eq!(merged.lower_threshold, Some(40));
+        assert_eq!(merged.apply(ProfileName::Balanced).hysteresis, 4);
+        assert_eq!(
+            merged.apply(ProfileName::PowerSaver).strategy,
+            Some(StrategyName::TopologyPacking)
+        );
+        let strategy = ProfileOverrides {
+            strategy: Some(StrategyName::RoundRobin),
+            ..ProfileOverrides::default()
+        };
+        assert_eq!(
+            merged
+                .merge(&strategy)
+                .apply(ProfileName::PowerSaver)
+                .strategy,
+            Some(StrategyName::RoundRobin)
+        );
+
+        assert!(merged.validate(&[ProfileName::Balanced]).is_ok());
+        let inverted = merged.merge(&ProfileOverrides::new(None, Some(95), None, None).unwrap());
+        assert!(inverted.validate(&[ProfileName::Balanced]).is_err());
+    }
+}
@@ -0,0 +1,265 @@
+//! Performance tuning of the online CPUs, selected with `--tune-pstate`.
+//!
+//! Offlining cores saves the power of the cores taken away, while the cores that remain online keep
+//! running as eagerly as under high load. With `--tune-pstate`, the CPU manager also moves the
+//! online CPUs to a power-biased regime as the load drops, and back to a performance-biased one as
+//! it climbs:
+//!
+//! - `cpufreq/energy_performance_preference` of every online CPU, the EPP hint of `intel_pstate` and
+//!   `amd-pstate`, is set to `power`, and to `balance_performance`, the kernel default, again.
+//! - `intel_pstate/max_perf_pct` is lowered to `--power-max-perf-pct`, and raised to 100 again.
+//!
+//! Either attribute is skipped where the driver lacks it. The values found at startup are restored on
+//! exit and while paused.
+use crate::metrics::{SysfsAccess, METRICS};
+use crate::topology::SystemTopology;
+use serde::{Deserialize, Serialize};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use tracing::{info, warn};
+
+/// The per-CPU energy performance preference attribute.
+static EPP_ATTR: &str = "cpufreq/energy_performance_preference";
+
+/// The system-wide performance limit of `intel_pstate`, in percent of the maximum.
+static MAX_PERF_ATTR: &str = "intel_pstate/max_perf_pct";
+
+/// The default of `--power-max-perf-pct`.
+pub const DEFAULT_POWER_MAX_PERF_PCT: u32 = 60;
+
+/// The regime the online CPUs are tuned for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PstateRegime {
+    /// Entered as the load drops: EPP `power` and a lowered `max_perf_pct`.
+    Power,
+    /// Entered as the load climbs: EPP `balance_performance` and `max_perf_pct` at 100.
+    Performance,
+}
+
+impl PstateRegime {
+    pub fn label(self) -> &'static str {
+        match self {
+            PstateRegime::Power => "power",
+            PstateRegime::Performance => "performance",
+        }
+    }
+
+    fn epp(self) -> &'static str {
+        match self {
+            PstateRegime::Power => "power",
+            PstateRegime::Performance => "balance_performance",
+        }
+    }
+}
+
+/// The EPP and performance limit of the online CPUs.
+#[derive(Default)]
+pub struct PstateTuning {
+    /// The current regime, `None` until first switched and after a restore.
+    regime: Option<PstateRegime>,
+    /// The CPUs already tuned for the current regime.
+    tuned: BTreeSet<usize>,
+    /// The EPP of each CPU found before it was first tuned, restored on exit.
+    initial_epp: BTreeMap<usize, String>,
+    /// The `max_perf_pct` found at startup, `None` until read or without `intel_pstate`.
+    initial_max_perf: Option<u32>,
+    /// Whether `max_perf_pct` was looked up.
+    probed: bool,
+}
+
+impl PstateTuning {
+    /// Returns the current regime, if switched.
+    pub fn regime(&self) -> Option<PstateRegime> {
+        self.regime
+    }
+
+    /// Moves the managed online CPUs to `regime`. CPUs already tuned for it are left alone, so
+    /// calling it again only tunes the CPUs onlined since. A CPU that rejects the write is logged
+    /// and left as it is.
+    ///
+    /// This function performs the following steps:
+    /// 1. Reads `max_perf_pct` the first time, to restore it later.
+    /// 2. When the regime changes, writes the `max_perf_pct` of `regime`: `power_max_perf_pct` for
+    ///    `Power` and 100 for `Performance`.
+    /// 3. Writes the EPP of `regime` to every managed online CPU not yet tuned for it, reading its
+    ///    previous EPP the first time.
+    ///
+    /// # Returns
+    /// * `io::Result<bool>` - Whether the regime changed, or an error if CPUs with an EPP exist but
+    ///   none of them could be written.
+    pub async fn apply(
+        &mut self,
+        topology: &SystemTopology,
+        regime: PstateRegime,
+        power_max_perf_pct: u32,
+    ) -> io::Result<bool> {
+        if !self.probed {
+            self.probed = true;
+            self.initial_max_perf = read_max_perf(topology).await;
+        }
+        let changed = self.regime != Some(regime);
+        if changed {
+            self.tuned.clear();
+            if self.initial_max_perf.is_some() {
+                let pct = match regime {
+                    PstateRegime::Power => power_max_perf_pct,
+                    PstateRegime::Performance => 100,
+                };
+                write_max_perf(topology, pct).await;
+            }
+        }
+        self.tuned
+            .retain(|id| topology.cpus.get(id).is_some_and(|cpu| cpu.online));
+        let mut ids: Vec<usize> = topology
+            .cpus
+            .values()
+            .filter(|cpu| cpu.managed && cpu.online && !self.tuned.contains(&cpu.id))
+            .map(|cpu| cpu.id)
+            .collect();
+        ids.sort_unstable();
+        let mut written = 0;
+        let mut last_error = None;
+        for id in ids {
+            if let Entry::Vacant(entry) = self.initial_epp.entry(id) {
+                let Ok(epp) = topology.sysfs.read(id, EPP_ATTR).await else {
+                    continue;
+                };
+                entry.insert(epp.trim().to_string());
+            }
+            match topology.sysfs.write(id, EPP_ATTR, regime.epp()).await {
+                Ok(()) => {
+                    self.tuned.insert(id);
+                    written += 1;
+                }
+                Err(e) => {
+                    METRICS.sysfs_error(SysfsAccess::CpufreqWrite, &e);
+                    warn!("Cannot set the EPP of CPU {}: {}", id, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        if written == 0 {
+            if let Some(e) = last_error {
+                return Err(e);
+            }
+        }
+        if changed {
+            info!("Online CPUs tuned for {}", regime.label());
+            self.regime = Some(regime);
+        }
+        Ok(changed)
+    }
+
+    /// Returns the EPP of the online CPUs and `max_perf_pct` to the values found before they were
+    /// first tuned.
+    pub async fn restore(&mut self, topology: &SystemTopology) -> io::Result<()> {
+        if self.regime.take().is_none() {
+            return Ok(());
+        }
+        self.tuned.clear();
+        if let Some(pct) = self.initial_max_perf {
+            write_max_perf(topology, pct).await;
+        }
+        let mut last_error = None;
+        for (&id, epp) in &self.initial_epp {
+            if !topology.cpus.get(&id).is_some_and(|cpu| cpu.online) {
+                continue;
+            }
+            if let Err(e) = topology.sysfs.write(id, EPP_ATTR, epp).await {
+                METRICS.sysfs_error(SysfsAccess::CpufreqWrite, &e);
+                warn!("Cannot restore the EPP of CPU {}: {}", id, e);
+                last_error = Some(e);
+            }
+        }
+        info!("Online CPUs returned to their EPP at startup");
+        last_error.map_or(Ok(()), Err)
+    }
+}
+
+async fn read_max_perf(topology: &SystemTopology) -> Option<u32> {
+    topology
+        .sysfs
+        .read_global(MAX_PERF_ATTR)
+        .await
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Writes `max_perf_pct`, logging a failure: the EPP is still worth tuning without it.
+async fn write_max_perf(topology: &SystemTopology, pct: u32) {
+    if let Err(e) = topology
+        .sysfs
+        .write_global(MAX_PERF_ATTR, &pct.to_string())
+        .await
+    {
+        METRICS.sysfs_error(SysfsAccess::CpufreqWrite, &e);
+        warn!("Cannot set the intel_pstate performance limit: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sysfs::{MockSysfs, SysfsBackend};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn tunes_each_online_cpu_once_per_regime_and_restores() {
+        let sysfs = Arc::new(
+            MockSysfs::new()
+                .with_cpu(0, 0, 0, "0")
+                .with_cpu(1, 1, 0, "1"),
+        );
+        sysfs.set(0, EPP_ATTR, "balance_performance");
+        sysfs.set(1, EPP_ATTR, "performance");
+        sysfs.set_global(MAX_PERF_ATTR, "100");
+        let topology = SystemTopology::builder()
+            .backend(sysfs.clone())
+            .build()
+            .await
+            .unwrap();
+        let mut tuning = PstateTuning::default();
+        assert!(tuning
+            .apply(&topology, PstateRegime::Power, 50)
+            .await
+            .unwrap());
+        assert_eq!(sysfs.get(0, EPP_ATTR).as_deref(), Some("power"));
+        assert_eq!(sysfs.get(1, EPP_ATTR).as_deref(), Some("power"));
+        assert_eq!(sysfs.read_global(MAX_PERF_ATTR).await.unwrap(), "50\n");
+
+        // CPUs already tuned for the regime are not written again.
+        sysfs.set(1, EPP_ATTR, "balance_power");
+        assert!(!tuning
+            .apply(&topology, PstateRegime::Power, 50)
+            .await
+            .unwrap());
+        assert_eq!(sysfs.get(1, EPP_ATTR).as_deref(), Some("balance_power"));
+
+        assert!(tuning
+            .apply(&topology, PstateRegime::Performance, 50)
+            .await
+            .unwrap());
+        assert_eq!(
+            sysfs.get(1, EPP_ATTR).as_deref(),
+            Some("balance_performance")
+        );
+        assert_eq!(sysfs.read_global(MAX_PERF_ATTR).await.unwrap(), "100\n");
+
+        tuning
+            .apply(&topology, PstateRegime::Power, 50)
+            .await
+            .unwrap();
+        tuning.restore(&topology).await.unwrap();
+        assert_eq!(tuning.regime(), None);
+        assert_eq!(
+            sysfs.get(0, EPP_ATTR).as_deref(),
+            Some("balance_performance")
+        );
+        assert_eq!(sysfs.get(1, EPP_ATTR).as_deref(), Some("performance"));
+        assert_eq!(sysfs.read_global(MAX_PERF_ATTR).await.unwrap(), "100\n");
+    }
+}
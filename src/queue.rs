@@ -0,0 +1,106 @@
+//! Bounded queues between the control loop and slow output sinks.
+//!
+//! Pushing never blocks: when the queue is full, the oldest entry is dropped to make room, so a
+//! stalled sink only loses stale data and never delays the control loop. Drops are counted per
+//! sink in the `cpu_on_off_sink_dropped_total` metric.
+use crate::metrics::METRICS;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+struct Shared<T> {
+    name: &'static str,
+    capacity: usize,
+    items: Mutex<VecDeque<T>>,
+    notify: Notify,
+    closed: AtomicBool,
+}
+
+/// Sending half of a drop-oldest queue, held by the control loop.
+pub struct QueueSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Receiving half of a drop-oldest queue, held by the sink's task.
+pub struct QueueReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Creates a queue for the sink `name` holding at most `capacity` entries.
+pub fn channel<T>(name: &'static str, capacity: usize) -> (QueueSender<T>, QueueReceiver<T>) {
+    let shared = Arc::new(Shared {
+        name,
+        capacity: capacity.max(1),
+        items: Mutex::new(VecDeque::with_capacity(capacity)),
+        notify: Notify::new(),
+        closed: AtomicBool::new(false),
+    });
+    (
+        QueueSender {
+            shared: Arc::clone(&shared),
+        },
+        QueueReceiver { shared },
+    )
+}
+
+impl<T> QueueSender<T> {
+    /// Queues `item`, dropping the oldest entry if the queue is full. Returns `true` if an entry was dropped.
+    pub fn push(&self, item: T) -> bool {
+        let dropped = {
+            let mut items = self.shared.items.lock().unwrap();
+            let dropped = if items.len() >= self.shared.capacity {
+                items.pop_front();
+                true
+            } else {
+                false
+            };
+            items.push_back(item);
+            dropped
+        };
+        if dropped {
+            METRICS.sink_dropped(self.shared.name);
+        }
+        self.shared.notify.notify_one();
+        dropped
+    }
+}
+
+impl<T> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+        self.shared.notify.notify_one();
+    }
+}
+
+impl<T> QueueReceiver<T> {
+    /// Waits for the next entry. Returns `None` once the sender is gone and the queue is drained.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            if let Some(item) = self.shared.items.lock().unwrap().pop_front() {
+                return Some(item);
+            }
+            if self.shared.closed.load(Ordering::Acquire) {
+                return None;
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drops_oldest_entries_when_full() {
+        let (tx, mut rx) = channel("test", 2);
+        assert!(!tx.push(1));
+        assert!(!tx.push(2));
+        assert!(tx.push(3));
+        drop(tx);
+        assert_eq!(rx.recv().await, Some(2));
+        assert_eq!(rx.recv().await, Some(3));
+        assert_eq!(rx.recv().await, None);
+    }
+}
@@ -0,0 +1,395 @@
+//! Package energy from RAPL, for the energy report.
+//!
+//! The `intel_rapl` powercap driver, which also serves AMD CPUs since Zen, exposes one top-level
+//! zone per package, `intel-rapl:N`, whose `energy_uj` counts the energy consumed by the package in
+//! microjoules and wraps around at `max_energy_range_uj`. The CPU manager reads the counters every
+//! cycle and charges the energy of each interval to the number of CPUs that were online during it.
+//! The power of the latest interval also drives `--power-budget-watts`.
+//!
+//! The report estimates the energy the offlining saved by comparing the energy used with what the
+//! packages would have used with every managed CPU online over the same time: the average power
+//! measured with all of them online or, if they were not online long enough, the power extrapolated
+//! along a line fitted through the average power at each online count. The estimate ignores that
+//! the load itself changes over time, so it is only a guide.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::fs;
+use tracing::{debug, info, warn};
+
+/// The powercap class directory, relative to the sysfs root.
+pub static POWERCAP_DIR: &str = "class/powercap";
+
+/// Seconds with every managed CPU online after which their average power is taken as the
+/// baseline, rather than extrapolated from the other online counts.
+pub const BASELINE_MIN_SECS: f64 = 60.0;
+
+/// The energy counter of a package.
+struct Domain {
+    energy_uj: PathBuf,
+    /// The value at which the counter wraps around to zero.
+    range_uj: u64,
+    last_uj: Option<u64>,
+}
+
+/// Returns the top-level RAPL zones of the packages below `sysfs_root`, e.g.
+/// `class/powercap/intel-rapl:0`, in the order of their index. Subzones such as the cores or DRAM of
+/// a package, e.g. `intel-rapl:0:0`, are left out, since the package counter includes them.
+pub async fn package_zones(sysfs_root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut zones = Vec::new();
+    let mut entries = fs::read_dir(sysfs_root.join(POWERCAP_DIR)).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(index) = name
+            .strip_prefix("intel-rapl:")
+            .and_then(|index| index.parse::<usize>().ok())
+        else {
+            continue;
+        };
+        let kind = fs::read_to_string(entry.path().join("name"))
+            .await
+            .unwrap_or_default();
+        if kind.trim().starts_with("package") {
+            zones.push((index, entry.path()));
+        }
+    }
+    zones.sort();
+    Ok(zones.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Returns whether `sysfs_root` has any RAPL package zone, for the startup checks, which cannot
+/// wait for the first sample.
+pub fn has_package_zones(sysfs_root: &Path) -> bool {
+    std::fs::read_dir(sysfs_root.join(POWERCAP_DIR)).is_ok_and(|entries| {
+        entries.flatten().any(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            name.strip_prefix("intel-rapl:")
+                .is_some_and(|index| index.parse::<usize>().is_ok())
+                && std::fs::read_to_string(entry.path().join("name"))
+                    .is_ok_and(|kind| kind.trim().starts_with("package"))
+        })
+    })
+}
+
+/// Reads a counter in microjoules.
+async fn read_uj(path: &Path) -> io::Result<u64> {
+    fs::read_to_string(path)
+        .await?
+        .trim()
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Time and energy charged to one online CPU count.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct EnergyBucket {
+    seconds: f64,
+    joules: f64,
+}
+
+impl EnergyBucket {
+    fn watts(&self) -> f64 {
+        self.joules / self.seconds
+    }
+}
+
+/// The average power while a given number of CPUs were online.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OnlineCountEnergy {
+    pub online_cpus: usize,
+    pub seconds: f64,
+    pub average_watts: f64,
+}
+
+/// The energy used by the packages since the daemon started, and the estimated savings.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EnergyReport {
+    /// Seconds of measured intervals.
+    pub seconds: f64,
+    /// Energy used by all packages.
+    pub energy_wh: f64,
+    pub average_watts: f64,
+    /// The online CPU count of the baseline: every managed CPU online.
+    pub baseline_cpus: usize,
+    /// The estimated average power with every managed CPU online, `None` until it can be estimated.
+    pub baseline_watts: Option<f64>,
+    /// The estimated energy saved against the baseline; negative if more was used.
+    pub saved_wh: Option<f64>,
+    /// The average power at each online CPU count, fewest CPUs first.
+    pub by_online_cpus: Vec<OnlineCountEnergy>,
+}
+
+impl EnergyReport {
+    /// Logs the report at the info level.
+    pub fn log(&self) {
+        info!(
+            "Energy: {:.2} Wh over {:.0}s, {:.1} W on average",
+            self.energy_wh, self.seconds, self.average_watts
+        );
+        for count in &self.by_online_cpus {
+            info!(
+                "Energy with {} CPUs online: {:.0}s, {:.1} W on average",
+                count.online_cpus, count.seconds, count.average_watts
+            );
+        }
+        match (self.baseline_watts, self.saved_wh) {
+            (Some(baseline), Some(saved)) => info!(
+                "Estimated savings: {:.2} Wh against {:.1} W with all {} CPUs online",
+                saved, baseline, self.baseline_cpus
+            ),
+            _ => info!("Estimated savings: not enough data for a baseline with all CPUs online"),
+        }
+    }
+}
+
+/// Accumulates the package energy by online CPU count.
+pub struct EnergyMeter {
+    sysfs_root: PathBuf,
+    /// The package counters, `None` until looked up on the first sample.
+    domains: Option<Vec<Domain>>,
+    /// When the counters were last read, and the CPUs online since.
+    last: Option<(Instant, usize)>,
+    buckets: BTreeMap<usize, EnergyBucket>,
+    baseline_cpus: usize,
+    /// The power of the last interval.
+    watts: Option<f64>,
+    /// Whether a failed read was already logged.
+    warned: bool,
+}
+
+impl EnergyMeter {
+    /// Creates a meter for the RAPL zones below `sysfs_root`.
+    pub fn new(sysfs_root: impl Into<PathBuf>) -> Self {
+        EnergyMeter {
+            sysfs_root: sysfs_root.into(),
+            domains: None,
+            last: None,
+            buckets: BTreeMap::new(),
+            baseline_cpus: 0,
+            watts: None,
+            warned: false,
+        }
+    }
+
+    /// Returns whether package energy counters were found, once looked up.
+    pub fn is_available(&self) -> bool {
+        self.domains
+            .as_ref()
+            .is_some_and(|domains| !domains.is_empty())
+    }
+
+    /// Returns the average power of all packages over the interval before the last sample, `None`
+    /// without package counters or if the last sample failed.
+    pub fn watts(&self) -> Option<f64> {
+        self.watts
+    }
+
+    /// Reads the package counters and charges the energy used since the last sample to the CPUs
+    /// online since then.
+    ///
+    /// This function performs the following steps:
+    /// 1. On the first call, looks up the package zones and their wrap-around ranges. Without any,
+    ///    logs it at the debug level and does nothing from then on.
+    /// 2. Reads every counter, adding the range to a counter that wrapped around.
+    /// 3. If every counter was read, charges the energy and the time since the last sample to the
+    ///    online count of the last sample, and keeps their ratio as the current power. A failed read
+    ///    drops the interval and is logged once.
+    /// 4. Records `online` as the count for the next interval, and `baseline_cpus`, the count with
+    ///    every managed CPU online.
+    pub async fn sample(&mut self, online: usize, baseline_cpus: usize, now: Instant) {
+        if self.domains.is_none() {
+            self.domains = Some(self.discover().await);
+        }
+        let Some(domains) = self.domains.as_mut().filter(|domains| !domains.is_empty()) else {
+            return;
+        };
+        let mut joules = 0.0;
+        let mut complete = true;
+        for domain in domains.iter_mut() {
+            match read_uj(&domain.energy_uj).await {
+                Ok(uj) => {
+                    if let Some(last) = domain.last_uj {
+                        let delta = if uj >= last {
+                            uj - last
+                        } else {
+                            uj + domain.range_uj.saturating_sub(last)
+                        };
+                        joules += delta as f64 / 1e6;
+                    }
+                    domain.last_uj = Some(uj);
+                }
+                Err(e) => {
+                    if !self.warned {
+                        warn!(
+                            "Cannot read the energy counter {:?}: {}",
+                            domain.energy_uj, e
+                        );
+                        self.warned = true;
+                    }
+                    domain.last_uj = None;
+                    complete = false;
+                }
+            }
+        }
+        self.watts = None;
+        if complete {
+            self.warned = false;
+            if let Some((since, online)) = self.last {
+                let seconds = now.duration_since(since).as_secs_f64();
+                if seconds > 0.0 {
+                    let bucket = self.buckets.entry(online).or_default();
+                    bucket.seconds += seconds;
+                    bucket.joules += joules;
+                    self.watts = Some(joules / seconds);
+                }
+            }
+        }
+        self.last = complete.then_some((now, online));
+        self.baseline_cpus = baseline_cpus;
+    }
+
+    /// Looks up the package counters and their ranges.
+    async fn discover(&self) -> Vec<Domain> {
+        let zones = match package_zones(&self.sysfs_root).await {
+            Ok(zones) => zones,
+            Err(e) => {
+                debug!(
+                    "No RAPL energy counters, the energy report is disabled: {}",
+                    e
+                );
+                return Vec::new();
+            }
+        };
+        let mut domains = Vec::new();
+        for zone in zones {
+            let range_uj = read_uj(&zone.join("max_energy_range_uj"))
+                .await
+                .unwrap_or(u64::MAX);
+            domains.push(Domain {
+                energy_uj: zone.join("energy_uj"),
+                range_uj,
+                last_uj: None,
+            });
+        }
+        if domains.is_empty() {
+            debug!("No RAPL package zones, the energy report is disabled");
+        } else {
+            info!("Measuring the energy of {} packages", domains.len());
+        }
+        domains
+    }
+
+    /// Returns the report of the energy measured so far, or `None` without package counters or
+    /// before the first full interval.
+    pub fn report(&self) -> Option<EnergyReport> {
+        let seconds: f64 = self.buckets.values().map(|bucket| bucket.seconds).sum();
+        if seconds <= 0.0 {
+            return None;
+        }
+        let joules: f64 = self.buckets.values().map(|bucket| bucket.joules).sum();
+        let baseline_watts = self.baseline_watts();
+        Some(EnergyReport {
+            seconds,
+            energy_wh: joules / 3600.0,
+            average_watts: joules / seconds,
+            baseline_cpus: self.baseline_cpus,
+            baseline_watts,
+            saved_wh: baseline_watts.map(|watts| (watts * seconds - joules) / 3600.0),
+            by_online_cpus: self
+                .buckets
+                .iter()
+                .map(|(&online_cpus, bucket)| OnlineCountEnergy {
+                    online_cpus,
+                    seconds: bucket.seconds,
+                    average_watts: bucket.watts(),
+                })
+                .collect(),
+        })
+    }
+
+    /// Estimates the average power with every managed CPU online: as measured, if they were online
+    /// for `BASELINE_MIN_SECS`, or else along the least-squares line through the average power at
+    /// each online count, weighted by time, if at least two counts were measured.
+    fn baseline_watts(&self) -> Option<f64> {
+        if let Some(bucket) = self.buckets.get(&self.baseline_cpus) {
+            if bucket.seconds >= BASELINE_MIN_SECS {
+                return Some(bucket.watts());
+            }
+        }
+        if self.buckets.len() < 2 {
+            return None;
+        }
+        let weight: f64 = self.buckets.values().map(|bucket| bucket.seconds).sum();
+        let mean = |f: &dyn Fn(usize, &EnergyBucket) -> f64| {
+            self.buckets
+                .iter()
+                .map(|(&online, bucket)| f(online, bucket) * bucket.seconds)
+                .sum::<f64>()
+                / weight
+        };
+        let mean_x = mean(&|online, _| online as f64);
+        let mean_y = mean(&|_, bucket| bucket.watts());
+        let covariance =
+            mean(&|online, bucket| (online as f64 - mean_x) * (bucket.watts() - mean_y));
+        let variance = mean(&|online, _| (online as f64 - mean_x).powi(2));
+        let slope = covariance / variance;
+        Some(mean_y + slope * (self.baseline_cpus as f64 - mean_x))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn write(path: PathBuf, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+        fs::write(path, content).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn charges_the_package_energy_to_the_online_count() {
+        let root = std::env::temp_dir().join(format!("cpu-on-off-rapl-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root).await;
+        let powercap = root.join(POWERCAP_DIR);
+        for (package, energy) in [(0, "999000000\n"), (1, "100000000\n")] {
+            let zone = powercap.join(format!("intel-rapl:{}", package));
+            write(zone.join("name"), &format!("package-{}\n", package)).await;
+            write(zone.join("max_energy_range_uj"), "1000000000\n").await;
+            write(zone.join("energy_uj"), energy).await;
+        }
+        // The cores of package 0, already counted by the package.
+        write(powercap.join("intel-rapl:0:0/name"), "core\n").await;
+        write(powercap.join("intel-rapl:0:0/energy_uj"), "0\n").await;
+        assert!(has_package_zones(&root));
+        assert!(!has_package_zones(&root.join("missing")));
+
+        let mut meter = EnergyMeter::new(&root);
+        let start = Instant::now();
+        meter.sample(16, 16, start).await;
+        assert!(meter.is_available());
+        assert_eq!(meter.report(), None);
+
+        // 10s with 16 CPUs at 100 W, across a wrap-around of package 0.
+        write(powercap.join("intel-rapl:0/energy_uj"), "499000000\n").await;
+        write(powercap.join("intel-rapl:1/energy_uj"), "600000000\n").await;
+        meter.sample(8, 16, start + Duration::from_secs(10)).await;
+        assert!((meter.watts().unwrap() - 100.0).abs() < 1e-9);
+        // 10s with 8 CPUs at 60 W.
+        write(powercap.join("intel-rapl:0/energy_uj"), "799000000\n").await;
+        write(powercap.join("intel-rapl:1/energy_uj"), "900000000\n").await;
+        meter.sample(8, 16, start + Duration::from_secs(20)).await;
+
+        let report = meter.report().unwrap();
+        assert_eq!(report.seconds, 20.0);
+        assert!((report.average_watts - 80.0).abs() < 1e-9);
+        assert_eq!(report.by_online_cpus[0].online_cpus, 8);
+        assert!((report.by_online_cpus[0].average_watts - 60.0).abs() < 1e-9);
+        // Too short to measure the baseline, so extrapolated through both counts.
+        assert!((report.baseline_watts.unwrap() - 100.0).abs() < 1e-9);
+        assert!((report.saved_wh.unwrap() - 400.0 / 3600.0).abs() < 1e-9);
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+//! Limit on the number of CPU hotplug transitions per minute.
+//!
+//! Every hotplug rebuilds the scheduler domains and waits for RCU grace periods, so a policy that
+//! flaps, or a set of aggressive thresholds, could keep the kernel busy with hotplug alone. With
+//! `--max-transitions-per-minute`, each CPU written to in either direction counts as a transition,
+//! and a group of CPUs is only hotplugged if all of it fits into the transitions left over the
+//! last minute.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Period the transitions are counted over.
+pub const WINDOW: Duration = Duration::from_secs(60);
+
+/// Remembers when the recent transitions happened.
+#[derive(Debug, Default)]
+pub struct TransitionLimiter {
+    recent: VecDeque<Instant>,
+}
+
+impl TransitionLimiter {
+    /// Returns the number of transitions in the `WINDOW` up to `now`.
+    pub fn count(&self, now: Instant) -> usize {
+        self.recent
+            .iter()
+            .filter(|&&t| now.saturating_duration_since(t) < WINDOW)
+            .count()
+    }
+
+    /// Whether `transitions` more fit into `limit` transitions per `WINDOW` at `now`.
+    pub fn allows(&self, transitions: usize, limit: u32, now: Instant) -> bool {
+        self.count(now) + transitions <= limit as usize
+    }
+
+    /// Records `transitions` made at `now` and forgets the ones that left the window.
+    pub fn record(&mut self, transitions: usize, now: Instant) {
+        while self
+            .recent
+            .front()
+            .is_some_and(|&t| now.saturating_duration_since(t) >= WINDOW)
+        {
+            self.recent.pop_front();
+        }
+        self.recent.extend(std::iter::repeat_n(now, transitions));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_transitions_over_the_last_minute() {
+        let start = Instant::now();
+        let mut limiter = TransitionLimiter::default();
+        limiter.record(2, start);
+        limiter.record(1, start + Duration::from_secs(30));
+        assert_eq!(limiter.count(start + Duration::from_secs(30)), 3);
+        assert!(limiter.allows(1, 4, start + Duration::from_secs(30)));
+        // A whole core of two CPUs does not fit.
+        assert!(!limiter.allows(2, 4, start + Duration::from_secs(30)));
+        // The first two transitions have left the window.
+        assert_eq!(limiter.count(start + WINDOW), 1);
+        assert!(limiter.allows(2, 4, start + WINDOW));
+    }
+}
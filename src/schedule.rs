@@ -0,0 +1,47 @@
+//! Time-indexed plans of minimum online CPU counts pushed by an external orchestrator.
+use serde::{Deserialize, Deserializer, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A single step of a plan: from `from` (seconds since the UNIX epoch) onwards, keep at
+/// least `min_cpus` CPUs online.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlanEntry {
+    pub from: u64,
+    pub min_cpus: usize,
+}
+
+/// A plan of online CPU floors, sorted by start time.
+///
+/// Each entry stays in effect until the next one starts; before the first entry there is no floor.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Schedule {
+    entries: Vec<PlanEntry>,
+}
+
+impl<'de> Deserialize<'de> for Schedule {
+    /// Deserializes through `Schedule::new` so that the entries are sorted.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Entries {
+            entries: Vec<PlanEntry>,
+        }
+        Entries::deserialize(deserializer).map(|e| Schedule::new(e.entries))
+    }
+}
+
+impl Schedule {
+    pub fn new(mut entries: Vec<PlanEntry>) -> Self {
+        entries.sort_by_key(|entry| entry.from);
+        Schedule { entries }
+    }
+
+    /// Returns the floor in effect at `now`, if any.
+    pub fn floor_at(&self, now: SystemTime) -> Option<usize> {
+        let now = now.duration_since(UNIX_EPOCH).ok()?.as_secs();
+        self.entries
+            .iter()
+            .take_while(|entry| entry.from <= now)
+            .last()
+            .map(|entry| entry.min_cpus)
+    }
+}
@@ -0,0 +1,328 @@
+//! Pluggable outputs for the per-cycle records and the events of the control loop.
+//!
+//! Each sink listed under `[[sinks]]` in the configuration file implements `Sink` and runs in its
+//! own task behind a drop-oldest queue, so a slow or unreachable endpoint never delays the control
+//! loop or the other sinks. Every sink receives a `cycle` record after each control cycle and an
+//! `event` record for every event published on the control socket's event bus.
+//!
+//! Available sinks:
+//! - `stdout`: one JSON object per line on standard output.
+//! - `jsonl`: one JSON object per line, appended to a file.
+//! - `prometheus`: the metrics of the `/metrics` endpoint, pushed to a Pushgateway after each cycle.
+//! - `webhook`: each record as JSON, posted to an HTTP endpoint.
+//! - `mqtt`: each record as JSON, published to an MQTT broker.
+mod mqtt;
+
+pub use mqtt::MqttSink;
+
+use crate::decision_log::DecisionRecord;
+use crate::events::{Event, EventBus};
+use crate::http;
+use crate::metrics::METRICS;
+use crate::queue::{self, QueueSender};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// Number of records buffered per sink before the oldest ones are dropped.
+const QUEUE_LEN: usize = 64;
+
+/// Longest time spent delivering the queued records on shutdown.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A record delivered to the sinks, serialized with a `record` field naming its kind.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum SinkRecord {
+    /// The load, online CPUs, floor, and decision of a control cycle.
+    Cycle(DecisionRecord),
+    /// An event of the event bus, such as a decision, a hotplug operation, or a lease change.
+    Event(Event),
+}
+
+/// An output for the records of the control loop.
+#[async_trait]
+pub trait Sink: Send {
+    /// Delivers a single record. Called from the sink's own task, so it may wait for I/O.
+    async fn deliver(&mut self, record: &SinkRecord) -> io::Result<()>;
+
+    /// Writes out anything still buffered, once the last record has been delivered.
+    async fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A sink as configured in a `[[sinks]]` table of the configuration file.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum SinkConfig {
+    Stdout,
+    Jsonl {
+        path: PathBuf,
+    },
+    /// `url` names the Pushgateway group, e.g. `http://localhost:9091/metrics/job/cpu-on-off`.
+    Prometheus {
+        url: String,
+    },
+    Webhook {
+        url: String,
+    },
+    /// `broker` is `host:port`; `client_id` defaults to `cpu-on-off-<pid>`.
+    Mqtt {
+        broker: String,
+        topic: String,
+        #[serde(default)]
+        client_id: Option<String>,
+    },
+}
+
+impl SinkConfig {
+    /// Returns the name used in logs and as the label of the queue's drop counter.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SinkConfig::Stdout => "stdout",
+            SinkConfig::Jsonl { .. } => "jsonl",
+            SinkConfig::Prometheus { .. } => "prometheus",
+            SinkConfig::Webhook { .. } => "webhook",
+            SinkConfig::Mqtt { .. } => "mqtt",
+        }
+    }
+
+    /// Checks the URLs and names, so that mistakes are reported when the configuration is loaded.
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            SinkConfig::Prometheus { url } | SinkConfig::Webhook { url } => {
+                http::split_url(url).map(|_| ())
+            }
+            SinkConfig::Mqtt { broker, topic, .. } => {
+                if broker.is_empty() || topic.is_empty() {
+                    Err("mqtt sinks need a broker and a topic".to_string())
+                } else {
+                    Ok(())
+                }
+            }
+            SinkConfig::Stdout | SinkConfig::Jsonl { .. } => Ok(()),
+        }
+    }
+
+    /// Creates the sink, opening its file if it has one.
+    pub fn build(&self) -> io::Result<Box<dyn Sink>> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+        Ok(match self {
+            SinkConfig::Stdout => Box::new(StdoutSink),
+            SinkConfig::Jsonl { path } => Box::new(JsonlSink {
+                file: OpenOptions::new().create(true).append(true).open(path)?,
+            }),
+            SinkConfig::Prometheus { url } => {
+                let (host, path) = http::split_url(url).map_err(invalid)?;
+                Box::new(PrometheusSink { host, path })
+            }
+            SinkConfig::Webhook { url } => {
+                let (host, path) = http::split_url(url).map_err(invalid)?;
+                Box::new(WebhookSink { host, path })
+            }
+            SinkConfig::Mqtt {
+                broker,
+                topic,
+                client_id,
+            } => {
+                let client_id = client_id
+                    .clone()
+                    .unwrap_or_else(|| format!("cpu-on-off-{}", std::process::id()));
+                Box::new(MqttSink::new(broker, topic, &client_id))
+            }
+        })
+    }
+}
+
+/// Handle used by the control loop to fan the records out to all configured sinks.
+pub struct Sinks {
+    senders: Arc<Vec<QueueSender<SinkRecord>>>,
+    writers: Vec<JoinHandle<()>>,
+    forwarder: JoinHandle<()>,
+}
+
+impl Sinks {
+    /// Builds the sinks of `configs`, starts a task for each, and forwards the events of `events`
+    /// to them.
+    pub fn spawn(configs: &[SinkConfig], events: &EventBus) -> io::Result<Self> {
+        let mut senders = Vec::new();
+        let mut writers = Vec::new();
+        for config in configs {
+            let name = config.name();
+            let mut sink = config.build()?;
+            let (tx, mut rx) = queue::channel::<SinkRecord>(name, QUEUE_LEN);
+            info!("Writing records to the {} sink", name);
+            writers.push(tokio::spawn(async move {
+                while let Some(record) = rx.recv().await {
+                    if let Err(e) = sink.deliver(&record).await {
+                        warn!("The {} sink failed: {}", name, e);
+                    }
+                }
+                if let Err(e) = sink.flush().await {
+                    warn!("Cannot flush the {} sink: {}", name, e);
+                }
+            }));
+            senders.push(tx);
+        }
+        let senders = Arc::new(senders);
+
+        let (_, mut rx) = events.subscribe();
+        let forward_to = Arc::clone(&senders);
+        let forwarder = tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => push(&forward_to, SinkRecord::Event(event)),
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("The sinks missed {} events", missed);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+        Ok(Sinks {
+            senders,
+            writers,
+            forwarder,
+        })
+    }
+
+    /// Queues the record of a control cycle for every sink without waiting for them.
+    pub fn send(&self, record: &DecisionRecord) {
+        push(&self.senders, SinkRecord::Cycle(record.clone()));
+    }
+
+    /// Stops forwarding events and waits up to `FLUSH_TIMEOUT` for the queued records to be delivered.
+    pub async fn close(self) {
+        self.forwarder.abort();
+        let _ = self.forwarder.await;
+        drop(self.senders);
+        let writers = async {
+            for writer in self.writers {
+                let _ = writer.await;
+            }
+        };
+        if tokio::time::timeout(FLUSH_TIMEOUT, writers).await.is_err() {
+            warn!("Gave up flushing the sinks after {:?}", FLUSH_TIMEOUT);
+        }
+    }
+}
+
+fn push(senders: &[QueueSender<SinkRecord>], record: SinkRecord) {
+    for tx in senders {
+        tx.push(record.clone());
+    }
+}
+
+/// Writes each record as a line of JSON to standard output.
+struct StdoutSink;
+
+#[async_trait]
+impl Sink for StdoutSink {
+    async fn deliver(&mut self, record: &SinkRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        io::stdout().lock().write_all(&line)
+    }
+}
+
+/// Appends each record as a line of JSON to a file.
+struct JsonlSink {
+    file: File,
+}
+
+#[async_trait]
+impl Sink for JsonlSink {
+    async fn deliver(&mut self, record: &SinkRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.write_all(&line)
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// Pushes the Prometheus metrics to a Pushgateway group after every cycle, for hosts that a
+/// Prometheus server cannot scrape. Events are not pushed; they are counted in the metrics.
+struct PrometheusSink {
+    host: String,
+    path: String,
+}
+
+#[async_trait]
+impl Sink for PrometheusSink {
+    async fn deliver(&mut self, record: &SinkRecord) -> io::Result<()> {
+        if !matches!(record, SinkRecord::Cycle(_)) {
+            return Ok(());
+        }
+        let body = METRICS.render();
+        let content_type = "text/plain; version=0.0.4";
+        http::send("PUT", &self.host, &self.path, content_type, body.as_bytes()).await
+    }
+}
+
+/// Posts each record as JSON to an HTTP endpoint.
+struct WebhookSink {
+    host: String,
+    path: String,
+}
+
+#[async_trait]
+impl Sink for WebhookSink {
+    async fn deliver(&mut self, record: &SinkRecord) -> io::Result<()> {
+        let body = serde_json::to_vec(record)?;
+        http::send("POST", &self.host, &self.path, "application/json", &body).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FileConfig;
+    use crate::events::{Decision, EventKind};
+
+    #[tokio::test]
+    async fn fans_records_out_from_the_config_file() {
+        let path =
+            std::env::temp_dir().join(format!("cpu-on-off-sink-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = FileConfig::parse(
+            &format!(
+                "[[sinks]]\ntype = \"jsonl\"\npath = {:?}\n\n[[sinks]]\ntype = \"mqtt\"\nbroker = \"localhost:1883\"\ntopic = \"cpu\"\n",
+                path
+            ),
+            "test",
+        )
+        .unwrap();
+        let configs = config.sinks.unwrap();
+        assert_eq!(configs[1].name(), "mqtt");
+
+        let bus = EventBus::new();
+        let sinks = Sinks::spawn(&configs[..1], &bus).unwrap();
+        sinks.send(&DecisionRecord::now(12.5, 4, 2, Some(Decision::Offline)));
+        bus.emit(EventKind::LeaseReleased { id: 7 });
+        tokio::task::yield_now().await;
+        sinks.close().await;
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["record"], "cycle");
+        assert_eq!(records[0]["decision"], "offline");
+        assert_eq!(records[1]["record"], "event");
+        assert_eq!(records[1]["event"], "lease_released");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
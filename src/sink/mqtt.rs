@@ -0,0 +1,130 @@
+//! Minimal MQTT 3.1.1 publisher: QoS 0 messages over plain TCP, without authentication or TLS,
+//! which suits a broker on the local network or a local bridge.
+use super::{Sink, SinkRecord};
+use async_trait::async_trait;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Publishes each record as JSON to a topic, reconnecting on the next record after a failure.
+pub struct MqttSink {
+    broker: String,
+    topic: String,
+    client_id: String,
+    stream: Option<TcpStream>,
+}
+
+impl MqttSink {
+    pub fn new(broker: &str, topic: &str, client_id: &str) -> Self {
+        MqttSink {
+            broker: broker.to_string(),
+            topic: topic.to_string(),
+            client_id: client_id.to_string(),
+            stream: None,
+        }
+    }
+
+    /// Connects to the broker and waits for it to accept the connection.
+    async fn connect(&self) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect(&self.broker).await?;
+        stream.write_all(&connect_packet(&self.client_id)).await?;
+        let mut connack = [0; 4];
+        stream.read_exact(&mut connack).await?;
+        match connack {
+            [0x20, 0x02, _, 0] => Ok(stream),
+            [0x20, 0x02, _, code] => Err(io::Error::other(format!(
+                "the broker refused the connection with code {}",
+                code
+            ))),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected reply to CONNECT",
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for MqttSink {
+    async fn deliver(&mut self, record: &SinkRecord) -> io::Result<()> {
+        let packet = publish_packet(&self.topic, &serde_json::to_vec(record)?);
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => self.stream.insert(self.connect().await?),
+        };
+        if let Err(e) = stream.write_all(&packet).await {
+            self.stream = None;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        match self.stream.take() {
+            Some(mut stream) => stream.write_all(&[0xe0, 0x00]).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Encodes a CONNECT packet with a clean session and the keep-alive disabled, as records may be
+/// further apart than any keep-alive interval.
+fn connect_packet(client_id: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_string(&mut body, "MQTT");
+    body.extend_from_slice(&[4, 0x02, 0, 0]);
+    put_string(&mut body, client_id);
+    packet(0x10, &body)
+}
+
+/// Encodes a QoS 0 PUBLISH packet of `payload` to `topic`.
+fn publish_packet(topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_string(&mut body, topic);
+    body.extend_from_slice(payload);
+    packet(0x30, &body)
+}
+
+/// Prefixes `body` with the fixed header: the packet type and the variable-length body length.
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![kind];
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    out.extend_from_slice(body);
+    out
+}
+
+fn put_string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_connect_and_publish_packets() {
+        assert_eq!(
+            connect_packet("c1"),
+            [0x10, 14, 0, 4, b'M', b'Q', b'T', b'T', 4, 0x02, 0, 0, 0, 2, b'c', b'1']
+        );
+        assert_eq!(
+            publish_packet("t", b"{}"),
+            [0x30, 5, 0, 1, b't', b'{', b'}']
+        );
+        let long = publish_packet("t", &[0; 318]);
+        assert_eq!(&long[..3], [0x30, 0xc1, 0x02]);
+        assert_eq!(long.len(), 3 + 321);
+    }
+}
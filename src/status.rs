@@ -0,0 +1,178 @@
+//! Versioned document returned by the control socket's `status` command.
+//!
+//! Consumers such as dashboards, fleet agents, and the `status` subcommand parse this document, so
+//! it carries a `schema_version`. Within a version, fields may be added but are never renamed,
+//! removed, or changed in type or meaning; consumers must ignore fields they do not know. Any other
+//! change increments `SCHEMA_VERSION`.
+use crate::config::Settings;
+use crate::cpu_list::CpuList;
+use crate::decision_log::DecisionRecord;
+use crate::inhibit::InhibitorStatus;
+use crate::lease::{ClientStats, LeaseStatus};
+use crate::manager::TierStage;
+use crate::metrics::SysfsErrorCount;
+use crate::profile::Profile;
+use crate::pstate::PstateRegime;
+use crate::topology::{Granularity, TopologySnapshot};
+use crate::workload::WorkloadStatus;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Version of the `DaemonStatus` schema.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Number of recent decisions included in the status.
+pub const STATUS_DECISIONS: usize = 10;
+
+/// The full state of the daemon.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    pub schema_version: u32,
+    pub paused: bool,
+    /// Minimum online CPUs of the current schedule entry, if any.
+    pub scheduled_floor: Option<usize>,
+    /// CPUs reserved by the active leases.
+    pub leased_floor: usize,
+    pub leases: Vec<LeaseStatus>,
+    /// The inhibitors held on control connections; CPU changes are frozen while any is listed.
+    #[serde(default)]
+    pub inhibitors: Vec<InhibitorStatus>,
+    pub clients: BTreeMap<String, ClientStats>,
+    pub workload: Option<WorkloadStatus>,
+    pub sysfs_errors: Vec<SysfsErrorCount>,
+    /// The limits on which CPUs may be offlined; `None` until the CPU manager has started.
+    pub constraints: Option<Constraints>,
+    /// The settings in effect, including tuning changes; `None` until the CPU manager has started.
+    pub config: Option<Settings>,
+    /// The CPUs and their load as of the latest cycle; `None` until the CPU manager has started.
+    pub topology: Option<TopologySnapshot>,
+    /// The control loop's state; `None` until the CPU manager has started.
+    pub governor: Option<GovernorStatus>,
+    /// The most recent cycles, oldest first.
+    pub recent_decisions: Vec<DecisionRecord>,
+}
+
+/// The limits on the CPUs the control loop may offline.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Constraints {
+    /// The effective floor: the largest of `min_cpus`, the scheduled floor, and the leased floor.
+    pub floor: usize,
+    pub min_cpus: usize,
+    pub excluded_cpus: CpuList,
+    pub housekeeping_cpu: usize,
+    pub full_cores_only: bool,
+    pub granularity: Granularity,
+    #[serde(default)]
+    pub prefer_no_smt: bool,
+    /// Whether SMT siblings are never onlined, with `--no-smt-if-vulnerable` on a CPU that reports
+    /// SMT as vulnerable.
+    #[serde(default)]
+    pub no_smt: bool,
+}
+
+/// The state of the control loop, as published by the CPU manager after every cycle and command.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GovernorStatus {
+    /// The profile in effect, e.g. the power-saver profile while the session is idle.
+    pub active_profile: Profile,
+    /// Name of the load metric.
+    pub metric: String,
+    /// Load that drove the latest cycle, after noise floor subtraction; `None` before the first one.
+    pub load: Option<f64>,
+    /// Calibrated noise floor, `None` while calibrating.
+    pub noise_floor: Option<f64>,
+    pub samples_above: u32,
+    pub samples_below: u32,
+    /// Time left until the cooldown allows the next action.
+    pub cooldown_remaining_secs: f64,
+    /// Whether the session is idle according to logind.
+    pub idle: bool,
+    /// Whether the lid is closed or all displays are off.
+    pub away: bool,
+    /// Whether a hotplug write that timed out is still in progress.
+    pub hotplug_in_doubt: bool,
+    /// Whether the first full cycle has completed.
+    pub ready: bool,
+    /// CPUs onlined or offlined within the last minute.
+    #[serde(default)]
+    pub transitions_last_minute: usize,
+    /// The limit set by `--max-transitions-per-minute`, if any.
+    #[serde(default)]
+    pub max_transitions_per_minute: Option<u32>,
+    /// Steps of the frequency cap below the maximum frequency with the cpufreq and tiered
+    /// backends, once known.
+    #[serde(default)]
+    pub frequency_cap: Option<u32>,
+    /// The index of the deepest idle state allowed by the tiered backend, `--idle-policy load`, or
+    /// `--max-cstate`; `None` without them or until read.
+    #[serde(default)]
+    pub deepest_idle_state: Option<usize>,
+    /// The stage of the cpufreq and tiered backends and `--idle-policy load`; `None` without them.
+    #[serde(default)]
+    pub tier_stage: Option<TierStage>,
+    /// Whether latency-sensitive mode of `--latency-us` holds every offline.
+    #[serde(default)]
+    pub latency_sensitive: bool,
+    /// The temperature of the hottest package in degrees Celsius with `--thermal-limit`, once read.
+    #[serde(default)]
+    pub package_temperature: Option<f64>,
+    /// Whether the hottest package is above `--thermal-limit` and no CPUs are onlined.
+    #[serde(default)]
+    pub thermal_limited: bool,
+    /// Whether turbo is enabled, once switched with `--boost-policy`.
+    #[serde(default)]
+    pub turbo: Option<bool>,
+    /// The regime the online CPUs are tuned for with `--tune-pstate`, once switched.
+    #[serde(default)]
+    pub pstate_regime: Option<PstateRegime>,
+    /// The package power over the latest cycle in watts, from RAPL; `None` without it.
+    #[serde(default)]
+    pub package_power_watts: Option<f64>,
+    /// The share of the online CPUs' time stolen by the hypervisor over the latest cycle in
+    /// percent, from `/proc/stat` unless the load metric reports it; `None` before the first full
+    /// cycle or if `/proc/stat` cannot be read.
+    #[serde(default)]
+    pub steal_percent: Option<f64>,
+    /// The average time woken tasks waited before running over the latest cycle in microseconds,
+    /// from the ebpf load metric; `None` with other load metrics or without wakeups.
+    #[serde(default)]
+    pub wakeup_latency_us: Option<f64>,
+}
+
+/// The parts of the status only the CPU manager knows, published through `ControlState::manager`.
+#[derive(Clone, Debug)]
+pub struct ManagerStatus {
+    pub config: Settings,
+    pub topology: TopologySnapshot,
+    pub governor: GovernorStatus,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn ignores_fields_added_within_a_version() {
+        let mut document = json!({
+            "schema_version": SCHEMA_VERSION,
+            "paused": false,
+            "scheduled_floor": null,
+            "leased_floor": 4,
+            "leases": [{"id": 1, "client": "batch", "min_cpus": 4, "expires_in_secs": 30}],
+            "clients": {},
+            "workload": null,
+            "sysfs_errors": [{"access": "online_write", "errno": "EBUSY", "count": 2}],
+            "constraints": null,
+            "config": null,
+            "topology": null,
+            "governor": null,
+            "recent_decisions": [],
+        });
+        document["added_later"] = json!({ "any": "thing" });
+        let status: DaemonStatus = serde_json::from_value(document).unwrap();
+        assert_eq!(status.schema_version, 1);
+        assert_eq!(status.leases[0].client, "batch");
+        assert_eq!(status.sysfs_errors[0].count, 2);
+    }
+}
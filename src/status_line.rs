@@ -0,0 +1,116 @@
+//! Formatting of the status line printed on every cycle of the CPU manager.
+use crate::profile::Profile;
+use serde::{Deserialize, Serialize};
+
+/// Average load and online CPU count of a single socket.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SocketLoad {
+    pub socket_id: usize,
+    pub avg_c0: f64,
+    pub online: usize,
+    pub total: usize,
+}
+
+/// Changes in the aggregate load smaller than this many percentage points show as a flat trend.
+const TREND_DEADBAND: f64 = 1.0;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Band {
+    High,
+    Optimal,
+    Low,
+}
+
+impl Band {
+    fn of(load: f64, profile: &Profile) -> Self {
+        if load > profile.upper_threshold as f64 {
+            Band::High
+        } else if load < profile.lower_threshold as f64 {
+            Band::Low
+        } else {
+            Band::Optimal
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Band::High => "high",
+            Band::Optimal => "optimal",
+            Band::Low => "low",
+        }
+    }
+
+    fn ansi_color(self) -> &'static str {
+        match self {
+            Band::High => "\x1b[31m",
+            Band::Optimal => "\x1b[32m",
+            Band::Low => "\x1b[36m",
+        }
+    }
+}
+
+pub struct StatusLine<'a> {
+    pub avg_c0: f64,
+    pub previous_avg_c0: Option<f64>,
+    pub online: usize,
+    pub total: usize,
+    pub sockets: &'a [SocketLoad],
+    pub profile: &'a Profile,
+}
+
+impl StatusLine<'_> {
+    /// Formats the status line, e.g.
+    /// `Load 72.31% ↑ optimal (upper -12.69, lower +22.31) | Online 12/16 | S0 75.10% 8/8, S1 68.40% 4/8`.
+    ///
+    /// When `color` is set, loads are wrapped in ANSI colors matching their threshold band.
+    pub fn format(&self, color: bool) -> String {
+        let paint = |text: String, band: Band| {
+            if color {
+                format!("{}{}\x1b[0m", band.ansi_color(), text)
+            } else {
+                text
+            }
+        };
+
+        let band = Band::of(self.avg_c0, self.profile);
+        let trend = match self.previous_avg_c0 {
+            Some(previous) if self.avg_c0 - previous > TREND_DEADBAND => "↑",
+            Some(previous) if previous - self.avg_c0 > TREND_DEADBAND => "↓",
+            _ => "→",
+        };
+
+        let mut line = format!(
+            "Load {} {} {} (upper {:+.2}, lower {:+.2}) | Online {}/{}",
+            paint(format!("{:.2}%", self.avg_c0), band),
+            trend,
+            paint(band.label().to_string(), band),
+            self.avg_c0 - self.profile.upper_threshold as f64,
+            self.avg_c0 - self.profile.lower_threshold as f64,
+            self.online,
+            self.total,
+        );
+
+        if !self.sockets.is_empty() {
+            let sockets: Vec<String> = self
+                .sockets
+                .iter()
+                .map(|socket| {
+                    format!(
+                        "S{} {} {}/{}",
+                        socket.socket_id,
+                        paint(
+                            format!("{:.2}%", socket.avg_c0),
+                            Band::of(socket.avg_c0, self.profile)
+                        ),
+                        socket.online,
+                        socket.total
+                    )
+                })
+                .collect();
+            line.push_str(" | ");
+            line.push_str(&sockets.join(", "));
+        }
+
+        line
+    }
+}
@@ -0,0 +1,124 @@
+//! Affinity of recently active tasks to CPUs, sampled from `/proc`.
+//!
+//! Offlining a CPU forces the kernel to migrate every task that would run on it, which costs cache
+//! warmth and, for tasks pinned there, breaks their affinity. `/proc/<pid>/task/<tid>/stat`
+//! records the CPU each thread last ran on and its accumulated run time; a thread whose run time
+//! grew since the previous sample is recently active. The number of recently active threads that
+//! last ran on a CPU is its stickiness, and parking the core with the lowest stickiness forces the
+//! fewest migrations.
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub static PROC_DIR: &str = "/proc";
+
+/// The fields of a thread's `stat` file used for the stickiness.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TaskStat {
+    /// User and system time in clock ticks.
+    pub runtime: u64,
+    /// The CPU the thread last ran on.
+    pub processor: usize,
+}
+
+/// Parses a `/proc/<pid>/task/<tid>/stat` line. The command name may contain spaces and
+/// parentheses, so the fields are counted from its closing parenthesis.
+pub fn parse_task_stat(content: &str) -> Option<TaskStat> {
+    let (_, fields) = content.rsplit_once(')')?;
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+    // Fields 14 (utime), 15 (stime), and 39 (processor) of proc(5), after pid, comm, and state.
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    let processor = fields.get(36)?.parse().ok()?;
+    Some(TaskStat {
+        runtime: utime + stime,
+        processor,
+    })
+}
+
+/// Counts the recently active threads per CPU from one sample to the next.
+#[derive(Default)]
+pub struct StickinessSampler {
+    last_runtime: HashMap<u32, u64>,
+}
+
+impl StickinessSampler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Scans the threads of all processes and returns the number of threads that ran since the
+    /// previous call, by the CPU they last ran on. Threads seen for the first time do not count.
+    pub async fn sample(&mut self) -> io::Result<HashMap<usize, usize>> {
+        let tasks = tokio::task::spawn_blocking(|| read_tasks(Path::new(PROC_DIR)))
+            .await
+            .map_err(io::Error::other)??;
+        let mut counts = HashMap::new();
+        let mut runtimes = HashMap::with_capacity(tasks.len());
+        for (tid, stat) in tasks {
+            if self
+                .last_runtime
+                .get(&tid)
+                .is_some_and(|&last| stat.runtime > last)
+            {
+                *counts.entry(stat.processor).or_default() += 1;
+            }
+            runtimes.insert(tid, stat.runtime);
+        }
+        self.last_runtime = runtimes;
+        Ok(counts)
+    }
+}
+
+/// Reads the `stat` file of every thread below `proc_dir`. Processes and threads that exit during
+/// the scan are skipped.
+fn read_tasks(proc_dir: &Path) -> io::Result<Vec<(u32, TaskStat)>> {
+    let mut tasks = Vec::new();
+    for process in fs::read_dir(proc_dir)?.flatten() {
+        if process
+            .file_name()
+            .to_str()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .is_none()
+        {
+            continue;
+        }
+        let Ok(threads) = fs::read_dir(process.path().join("task")) else {
+            continue;
+        };
+        for thread in threads.flatten() {
+            let Some(tid) = thread.file_name().to_str().and_then(|tid| tid.parse().ok()) else {
+                continue;
+            };
+            if let Some(stat) = fs::read_to_string(thread.path().join("stat"))
+                .ok()
+                .as_deref()
+                .and_then(parse_task_stat)
+            {
+                tasks.push((tid, stat));
+            }
+        }
+    }
+    Ok(tasks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_thread_stat_lines() {
+        let line = "4242 (tokio (rt) x) S 1 4242 4242 0 -1 4194560 1535 0 0 0 120 35 0 0 20 0 \
+                    8 0 1234 123456789 1234 18446744073709551615 1 1 0 0 0 0 0 4096 0 0 0 0 17 \
+                    5 0 0 0 0 0\n";
+        assert_eq!(
+            parse_task_stat(line),
+            Some(TaskStat {
+                runtime: 155,
+                processor: 5,
+            })
+        );
+        assert_eq!(parse_task_stat("4242 (short) S 1 2 3"), None);
+    }
+}
@@ -0,0 +1,367 @@
+//! Selection strategies: which CPU's group is offlined or onlined next.
+//!
+//! `SystemTopology` narrows the candidates down to the CPUs it may hotplug, the class
+//! `prefer_offline` vacates first, and the first socket in the `offline_order` drain order, and
+//! leaves the choice among the remaining ones to its `SelectionStrategy`. The chosen CPU is then
+//! widened to its group under `granularity`. `--strategy` picks one of the built-in strategies;
+//! library users can plug in their own with `SystemTopologyBuilder::strategy()`.
+use crate::metrics::Direction;
+use crate::topology::{CpuInfo, SystemTopology};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Picks the CPU whose group is hotplugged next.
+pub trait SelectionStrategy: Send + Sync {
+    /// Picks the CPU to offline among the managed online `candidates`, or `None` to offline nothing.
+    fn pick_offline<'a>(
+        &self,
+        topology: &SystemTopology,
+        candidates: &[&'a CpuInfo],
+    ) -> Option<&'a CpuInfo>;
+
+    /// Picks the CPU to online among the managed offline `candidates`, or `None` to online nothing.
+    fn pick_online<'a>(
+        &self,
+        topology: &SystemTopology,
+        candidates: &[&'a CpuInfo],
+    ) -> Option<&'a CpuInfo>;
+
+    /// Called once the hotplug of CPU `id` in `direction` has been verified, for strategies that
+    /// remember their past choices.
+    fn hotplugged(&self, _id: usize, _direction: Direction) {}
+}
+
+/// The built-in selection strategies.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StrategyName {
+    /// Offline the highest CPU ID, or the core with the fewest active tasks with `--stickiness`,
+    /// and online the lowest.
+    #[default]
+    HighestId,
+    /// Offline the core whose thread siblings do the least work together, and online the lowest ID.
+    LeastLoaded,
+    /// Online on the busiest socket and cluster first, and offline the highest CPU ID.
+    MostLoadedFirstOnline,
+    /// Keep the online CPUs packed into as few sockets and clusters as possible.
+    TopologyPacking,
+    /// Offline the CPU online for the longest, and online the CPU offline for the longest, so that
+    /// every core takes its turn.
+    RoundRobin,
+}
+
+impl StrategyName {
+    /// Creates a new instance of the strategy.
+    pub fn build(self) -> Arc<dyn SelectionStrategy> {
+        match self {
+            StrategyName::HighestId => Arc::new(HighestId),
+            StrategyName::LeastLoaded => Arc::new(LeastLoaded),
+            StrategyName::MostLoadedFirstOnline => Arc::new(MostLoadedFirstOnline),
+            StrategyName::TopologyPacking => Arc::new(TopologyPacking),
+            StrategyName::RoundRobin => Arc::new(RoundRobin::default()),
+        }
+    }
+}
+
+/// Offlines the CPU with the highest ID, after the core with the lowest stickiness, and onlines
+/// the CPU with the lowest ID.
+pub struct HighestId;
+
+impl SelectionStrategy for HighestId {
+    fn pick_offline<'a>(
+        &self,
+        topology: &SystemTopology,
+        candidates: &[&'a CpuInfo],
+    ) -> Option<&'a CpuInfo> {
+        candidates
+            .iter()
+            .copied()
+            .min_by_key(|cpu| (topology.core_stickiness(cpu), Reverse(cpu.id)))
+    }
+
+    fn pick_online<'a>(
+        &self,
+        _topology: &SystemTopology,
+        candidates: &[&'a CpuInfo],
+    ) -> Option<&'a CpuInfo> {
+        candidates.iter().copied().min_by_key(|cpu| cpu.id)
+    }
+}
+
+/// Offlines the CPU whose group has the lowest combined C0 percentage, see
+/// `SystemTopology::group_c0`, the highest ID among equals, so the CPUs doing the least work are
+/// evicted rather than a busy one that may run a pinned task. Onlines like `HighestId`.
+pub struct LeastLoaded;
+
+impl SelectionStrategy for LeastLoaded {
+    fn pick_offline<'a>(
+        &self,
+        topology: &SystemTopology,
+        candidates: &[&'a CpuInfo],
+    ) -> Option<&'a CpuInfo> {
+        candidates.iter().copied().min_by(|a, b| {
+            topology
+                .group_c0(a)
+                .total_cmp(&topology.group_c0(b))
+                .then(b.id.cmp(&a.id))
+        })
+    }
+
+    fn pick_online<'a>(
+        &self,
+        topology: &SystemTopology,
+        candidates: &[&'a CpuInfo],
+    ) -> Option<&'a CpuInfo> {
+        HighestId.pick_online(topology, candidates)
+    }
+}
+
+/// Onlines the CPU on the socket, then the cluster, with the highest average load, the lowest ID
+/// among equals, so the capacity is added where the work is. Offlines like `HighestId`.
+pub struct MostLoadedFirstOnline;
+
+impl SelectionStrategy for MostLoadedFirstOnline {
+    fn pick_offline<'a>(
+        &self,
+        topology: &SystemTopology,
+        candidates: &[&'a CpuInfo],
+    ) -> Option<&'a CpuInfo> {
+        HighestId.pick_offline(topology, candidates)
+    }
+
+    fn pick_online<'a>(
+        &self,
+        topology: &SystemTopology,
+        candidates: &[&'a CpuInfo],
+    ) -> Option<&'a CpuInfo> {
+        let socket_loads: HashMap<usize, f64> = topology
+            .socket_loads()
+            .into_iter()
+            .map(|socket| (socket.socket_id, socket.avg_c0))
+            .collect();
+        let socket_load =
+            |cpu: &CpuInfo| cpu.socket_id.and_then(|id| socket_loads.get(&id).copied());
+        candidates.iter().copied().min_by(|a, b| {
+            cmp_load(socket_load(b), socket_load(a))
+                .then(cmp_load(
+                    cluster_load(topology, b),
+                    cluster_load(topology, a),
+                ))
+                .then(a.id.cmp(&b.id))
+        })
+    }
+}
+
+/// Offlines the CPU on the socket, then the cluster, with the fewest CPUs online, so it empties
+/// before the next one is touched, and onlines the CPU on the socket, then the cluster, with the
+/// most CPUs online, so it fills up before another one is woken up. Among equals, the highest ID
+/// is offlined and the lowest onlined.
+pub struct TopologyPacking;
+
+impl SelectionStrategy for TopologyPacking {
+    fn pick_offline<'a>(
+        &self,
+        topology: &SystemTopology,
+        candidates: &[&'a CpuInfo],
+    ) -> Option<&'a CpuInfo> {
+        candidates
+            .iter()
+            .copied()
+            .min_by_key(|cpu| (online_neighbours(topology, cpu), Reverse(cpu.id)))
+    }
+
+    fn pick_online<'a>(
+        &self,
+        topology: &SystemTopology,
+        candidates: &[&'a CpuInfo],
+    ) -> Option<&'a CpuInfo> {
+        candidates
+            .iter()
+            .copied()
+            .min_by_key(|cpu| (Reverse(online_neighbours(topology, cpu)), cpu.id))
+    }
+}
+
+/// Offlines the CPU whose last hotplug is the oldest, and onlines the CPU whose last hotplug is the
+/// oldest, so the CPUs take turns instead of the same ones always doing the work. CPUs never
+/// hotplugged come first, the highest ID first when offlining and the lowest when onlining.
+#[derive(Default)]
+pub struct RoundRobin {
+    /// The sequence number of the last hotplug of each CPU, and the last sequence number used.
+    history: Mutex<(HashMap<usize, u64>, u64)>,
+}
+
+impl RoundRobin {
+    /// Returns the sequence number of the last hotplug of CPU `id`, 0 if never hotplugged.
+    fn last_hotplug(&self, id: usize) -> u64 {
+        let history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        history.0.get(&id).copied().unwrap_or(0)
+    }
+}
+
+impl SelectionStrategy for RoundRobin {
+    fn pick_offline<'a>(
+        &self,
+        _topology: &SystemTopology,
+        candidates: &[&'a CpuInfo],
+    ) -> Option<&'a CpuInfo> {
+        candidates
+            .iter()
+            .copied()
+            .min_by_key(|cpu| (self.last_hotplug(cpu.id), Reverse(cpu.id)))
+    }
+
+    fn pick_online<'a>(
+        &self,
+        _topology: &SystemTopology,
+        candidates: &[&'a CpuInfo],
+    ) -> Option<&'a CpuInfo> {
+        candidates
+            .iter()
+            .copied()
+            .min_by_key(|cpu| (self.last_hotplug(cpu.id), cpu.id))
+    }
+
+    fn hotplugged(&self, id: usize, _direction: Direction) {
+        let mut history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        history.1 += 1;
+        let sequence = history.1;
+        history.0.insert(id, sequence);
+    }
+}
+
+/// Compares two optional loads, a missing one as the lowest.
+fn cmp_load(a: Option<f64>, b: Option<f64>) -> Ordering {
+    a.unwrap_or(f64::NEG_INFINITY)
+        .total_cmp(&b.unwrap_or(f64::NEG_INFINITY))
+}
+
+/// Returns the average C0 percentage of the online CPUs of the cluster of `cpu`, if it has one.
+fn cluster_load(topology: &SystemTopology, cpu: &CpuInfo) -> Option<f64> {
+    let cluster_id = cpu.cluster_id?;
+    let loads: Vec<f64> = topology
+        .cpus
+        .values()
+        .filter(|other| other.online && other.cluster_id == Some(cluster_id))
+        .map(|other| other.c0_percentage)
+        .collect();
+    (!loads.is_empty()).then(|| loads.iter().sum::<f64>() / loads.len() as f64)
+}
+
+/// Returns the number of online CPUs on the socket and in the cluster of `cpu`.
+fn online_neighbours(topology: &SystemTopology, cpu: &CpuInfo) -> (usize, usize) {
+    let online = topology.cpus.values().filter(|other| other.online);
+    let socket = online
+        .clone()
+        .filter(|other| other.socket_id.is_some() && other.socket_id == cpu.socket_id)
+        .count();
+    let cluster = online
+        .filter(|other| other.cluster_id.is_some() && other.cluster_id == cpu.cluster_id)
+        .count();
+    (socket, cluster)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a topology of single-thread CPUs on two sockets of `cpus` CPUs each, with the given
+    /// loads and online state.
+    fn topology(cpus: &[(f64, bool)]) -> SystemTopology {
+        let half = cpus.len() / 2;
+        SystemTopology::from_cpus(
+            cpus.iter()
+                .enumerate()
+                .map(|(id, &(c0_percentage, online))| CpuInfo {
+                    id,
+                    core_id: Some(id),
+                    socket_id: Some(id / half),
+                    thread_siblings: vec![id],
+                    cluster_id: None,
+                    capacity: None,
+                    c0_percentage,
+                    online,
+                    configured: None,
+                    idle_states: Vec::new(),
+                    managed: true,
+                }),
+        )
+    }
+
+    fn with_strategy(mut topology: SystemTopology, name: StrategyName) -> SystemTopology {
+        topology.strategy = name.build();
+        topology
+    }
+
+    #[test]
+    fn least_loaded_offlines_the_core_with_the_lowest_combined_load() {
+        // Two-thread cores {1, 2} and {3, 4}: CPU 4 is the idlest CPU, but its sibling is busy.
+        let mut topology = with_strategy(
+            topology(&[
+                (50.0, true),
+                (20.0, true),
+                (20.0, true),
+                (60.0, true),
+                (5.0, true),
+                (0.0, true),
+            ]),
+            StrategyName::LeastLoaded,
+        );
+        for (id, siblings) in [(1, [1, 2]), (2, [1, 2]), (3, [3, 4]), (4, [3, 4])] {
+            topology.cpus.get_mut(&id).unwrap().thread_siblings = siblings.to_vec();
+        }
+        topology.cpus.get_mut(&5).unwrap().online = false;
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn least_loaded_offlines_the_idlest_cpu() {
+        let topology = with_strategy(
+            topology(&[(50.0, true), (10.0, true), (80.0, true), (10.0, true)]),
+            StrategyName::LeastLoaded,
+        );
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![3]));
+    }
+
+    #[test]
+    fn most_loaded_first_online_wakes_the_busy_socket() {
+        let topology = with_strategy(
+            topology(&[(10.0, true), (0.0, false), (90.0, true), (0.0, false)]),
+            StrategyName::MostLoadedFirstOnline,
+        );
+        assert_eq!(topology.select_cpu_to_online(), Some(vec![3]));
+    }
+
+    #[test]
+    fn topology_packing_empties_the_sparser_socket_first() {
+        let topology = with_strategy(
+            topology(&[
+                (10.0, true),
+                (10.0, true),
+                (10.0, true),
+                (10.0, true),
+                (10.0, false),
+                (10.0, false),
+            ]),
+            StrategyName::TopologyPacking,
+        );
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![3]));
+        assert_eq!(topology.select_cpu_to_online(), Some(vec![4]));
+    }
+
+    #[test]
+    fn round_robin_rotates_through_the_cpus() {
+        let mut topology = with_strategy(
+            topology(&[(10.0, true), (10.0, true), (10.0, true), (10.0, true)]),
+            StrategyName::RoundRobin,
+        );
+        topology.cpus.get_mut(&3).unwrap().online = false;
+        topology.strategy.hotplugged(3, Direction::Offline);
+        topology.cpus.get_mut(&3).unwrap().online = true;
+        topology.strategy.hotplugged(3, Direction::Online);
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![2]));
+    }
+}
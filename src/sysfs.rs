@@ -0,0 +1,312 @@
+//! Access to the per-CPU sysfs attributes.
+//!
+//! `SystemTopology` and the cpuidle load metric read and write the attributes below
+//! `/sys/devices/system/cpu/cpuN`, such as `online`, `topology/core_id`, or
+//! `cpuidle/state1/time`, through the `SysfsBackend` trait. `Sysfs` is the real directory tree;
+//! `MockSysfs` keeps the attributes in memory, so the selection and policy code can be exercised
+//! without root or a multi-core machine.
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::fs;
+
+/// Reads and writes the attributes of the CPUs. Attributes are named by their path relative to
+/// the CPU's directory, e.g. `topology/thread_siblings_list`.
+#[async_trait]
+pub trait SysfsBackend: Send + Sync {
+    /// Returns the IDs of all CPUs, in no particular order.
+    async fn cpu_ids(&self) -> io::Result<Vec<usize>>;
+
+    /// Returns whether attribute or directory `attr` of CPU `id` exists.
+    fn exists(&self, id: usize, attr: &str) -> bool;
+
+    /// Reads attribute `attr` of CPU `id`.
+    async fn read(&self, id: usize, attr: &str) -> io::Result<String>;
+
+    /// Writes `value` to attribute `attr` of CPU `id`. May block for as long as the kernel takes,
+    /// e.g. to complete a hotplug operation.
+    async fn write(&self, id: usize, attr: &str, value: &str) -> io::Result<()>;
+
+    /// Lists the entries of directory `dir` of CPU `id`, e.g. the idle states in `cpuidle`.
+    async fn list(&self, id: usize, dir: &str) -> io::Result<Vec<String>>;
+
+    /// Reads attribute `attr` of the CPU directory itself, e.g. `isolated`. Backends without such
+    /// attributes report them as missing.
+    async fn read_global(&self, attr: &str) -> io::Result<String> {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} does not exist", attr),
+        ))
+    }
+
+    /// Writes `value` to attribute `attr` of the CPU directory itself, e.g. `intel_pstate/no_turbo`.
+    /// Backends without such attributes report them as missing.
+    async fn write_global(&self, attr: &str, _value: &str) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} does not exist", attr),
+        ))
+    }
+}
+
+/// The CPU attributes of a sysfs directory tree, `/sys/devices/system/cpu` on a live system.
+pub struct Sysfs {
+    cpu_dir: PathBuf,
+}
+
+impl Sysfs {
+    pub fn new(cpu_dir: impl Into<PathBuf>) -> Self {
+        Sysfs {
+            cpu_dir: cpu_dir.into(),
+        }
+    }
+
+    /// Returns the directory of the CPUs.
+    pub fn cpu_dir(&self) -> &Path {
+        &self.cpu_dir
+    }
+
+    fn path(&self, id: usize, attr: &str) -> PathBuf {
+        self.cpu_dir.join(format!("cpu{}", id)).join(attr)
+    }
+}
+
+#[async_trait]
+impl SysfsBackend for Sysfs {
+    async fn cpu_ids(&self) -> io::Result<Vec<usize>> {
+        let mut ids = Vec::new();
+        let mut read_dir = fs::read_dir(&self.cpu_dir).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            if let Some(id) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix("cpu"))
+                .and_then(|id| id.parse().ok())
+            {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    fn exists(&self, id: usize, attr: &str) -> bool {
+        self.path(id, attr).exists()
+    }
+
+    async fn read(&self, id: usize, attr: &str) -> io::Result<String> {
+        fs::read_to_string(self.path(id, attr)).await
+    }
+
+    async fn write(&self, id: usize, attr: &str, value: &str) -> io::Result<()> {
+        let path = self.path(id, attr);
+        let value = value.to_string();
+        tokio::task::spawn_blocking(move || std::fs::write(path, value))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    async fn list(&self, id: usize, dir: &str) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut read_dir = fs::read_dir(self.path(id, dir)).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            names.extend(entry.file_name().to_str().map(str::to_string));
+        }
+        Ok(names)
+    }
+
+    async fn read_global(&self, attr: &str) -> io::Result<String> {
+        fs::read_to_string(self.cpu_dir.join(attr)).await
+    }
+
+    async fn write_global(&self, attr: &str, value: &str) -> io::Result<()> {
+        let path = self.cpu_dir.join(attr);
+        let value = value.to_string();
+        tokio::task::spawn_blocking(move || std::fs::write(path, value))
+            .await
+            .map_err(io::Error::other)?
+    }
+}
+
+/// CPU attributes kept in memory, for tests.
+///
+/// Directories exist implicitly while they contain an attribute. As in sysfs, only attributes
+/// that exist can be written.
+#[derive(Default)]
+pub struct MockSysfs {
+    attrs: Mutex<BTreeMap<(usize, String), String>>,
+    globals: Mutex<BTreeMap<String, String>>,
+    /// The OS error and the number of writes left to fail, per CPU attribute.
+    failures: Mutex<BTreeMap<(usize, String), (i32, u32)>>,
+}
+
+impl MockSysfs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a CPU with the given core, socket, and online state, and thread siblings as listed in
+    /// the kernel's `cpulist` format. CPU0 gets no `online` file, as on most kernels.
+    pub fn with_cpu(self, id: usize, core_id: usize, socket_id: usize, siblings: &str) -> Self {
+        self.set(id, "topology/core_id", &core_id.to_string());
+        self.set(id, "topology/physical_package_id", &socket_id.to_string());
+        self.set(id, "topology/thread_siblings_list", siblings);
+        if id != 0 {
+            self.set(id, "online", "1");
+        }
+        self
+    }
+
+    /// Creates or overwrites attribute `attr` of CPU `id`.
+    pub fn set(&self, id: usize, attr: &str, value: &str) {
+        let mut attrs = self.attrs.lock().unwrap();
+        attrs.insert((id, attr.to_string()), value.to_string());
+    }
+
+    /// Creates or overwrites attribute `attr` of the CPU directory itself.
+    pub fn set_global(&self, attr: &str, value: &str) {
+        let mut globals = self.globals.lock().unwrap();
+        globals.insert(attr.to_string(), value.to_string());
+    }
+
+    /// Removes attribute `attr` of CPU `id`, e.g. the `online` file of a CPU the kernel cannot
+    /// offline.
+    pub fn remove(&self, id: usize, attr: &str) {
+        let mut attrs = self.attrs.lock().unwrap();
+        attrs.remove(&(id, attr.to_string()));
+    }
+
+    /// Returns attribute `attr` of CPU `id`, if it exists.
+    pub fn get(&self, id: usize, attr: &str) -> Option<String> {
+        let attrs = self.attrs.lock().unwrap();
+        attrs.get(&(id, attr.to_string())).cloned()
+    }
+
+    /// Makes the next `count` writes to attribute `attr` of CPU `id` fail with OS error `errno`,
+    /// e.g. `libc::EBUSY`, leaving the attribute as it is.
+    pub fn fail_writes(&self, id: usize, attr: &str, errno: i32, count: u32) {
+        let mut failures = self.failures.lock().unwrap();
+        failures.insert((id, attr.to_string()), (errno, count));
+    }
+
+    fn not_found(id: usize, attr: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("cpu{}/{} does not exist", id, attr),
+        )
+    }
+}
+
+#[async_trait]
+impl SysfsBackend for MockSysfs {
+    async fn cpu_ids(&self) -> io::Result<Vec<usize>> {
+        let attrs = self.attrs.lock().unwrap();
+        let mut ids: Vec<usize> = attrs.keys().map(|(id, _)| *id).collect();
+        ids.dedup();
+        Ok(ids)
+    }
+
+    fn exists(&self, id: usize, attr: &str) -> bool {
+        let attrs = self.attrs.lock().unwrap();
+        let dir = format!("{}/", attr);
+        attrs
+            .keys()
+            .any(|(cpu, name)| *cpu == id && (name == attr || name.starts_with(&dir)))
+    }
+
+    async fn read(&self, id: usize, attr: &str) -> io::Result<String> {
+        self.get(id, attr)
+            .map(|value| format!("{}\n", value))
+            .ok_or_else(|| Self::not_found(id, attr))
+    }
+
+    async fn write(&self, id: usize, attr: &str, value: &str) -> io::Result<()> {
+        if let Some((errno, count)) = self
+            .failures
+            .lock()
+            .unwrap()
+            .get_mut(&(id, attr.to_string()))
+            .filter(|(_, count)| *count > 0)
+        {
+            *count -= 1;
+            return Err(io::Error::from_raw_os_error(*errno));
+        }
+        let mut attrs = self.attrs.lock().unwrap();
+        let current = attrs
+            .get_mut(&(id, attr.to_string()))
+            .ok_or_else(|| Self::not_found(id, attr))?;
+        *current = value.to_string();
+        Ok(())
+    }
+
+    async fn list(&self, id: usize, dir: &str) -> io::Result<Vec<String>> {
+        let attrs = self.attrs.lock().unwrap();
+        let prefix = format!("{}/", dir);
+        let mut names: Vec<String> = attrs
+            .keys()
+            .filter(|(cpu, _)| *cpu == id)
+            .filter_map(|(_, name)| name.strip_prefix(&prefix))
+            .filter_map(|rest| rest.split('/').next())
+            .map(str::to_string)
+            .collect();
+        names.dedup();
+        if names.is_empty() {
+            return Err(Self::not_found(id, dir));
+        }
+        Ok(names)
+    }
+
+    async fn read_global(&self, attr: &str) -> io::Result<String> {
+        let globals = self.globals.lock().unwrap();
+        globals
+            .get(attr)
+            .map(|value| format!("{}\n", value))
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist", attr))
+            })
+    }
+
+    async fn write_global(&self, attr: &str, value: &str) -> io::Result<()> {
+        let mut globals = self.globals.lock().unwrap();
+        let current = globals.get_mut(attr).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist", attr))
+        })?;
+        *current = value.to_string();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn mock_behaves_like_a_directory_tree() {
+        let sysfs = MockSysfs::new()
+            .with_cpu(0, 0, 0, "0")
+            .with_cpu(1, 1, 0, "1");
+        sysfs.set(1, "cpuidle/state0/time", "10");
+        sysfs.set(1, "cpuidle/state1/time", "20");
+
+        assert_eq!(sysfs.cpu_ids().await.unwrap(), [0, 1]);
+        assert!(!sysfs.exists(0, "online"));
+        assert!(sysfs.exists(1, "cpuidle"));
+        assert_eq!(sysfs.read(1, "topology/core_id").await.unwrap(), "1\n");
+        assert_eq!(
+            sysfs.list(1, "cpuidle").await.unwrap(),
+            ["state0", "state1"]
+        );
+
+        sysfs.set_global("isolated", "1");
+        assert_eq!(sysfs.read_global("isolated").await.unwrap(), "1\n");
+        assert!(sysfs.read_global("nohz_full").await.is_err());
+        sysfs.write_global("isolated", "2").await.unwrap();
+        assert_eq!(sysfs.read_global("isolated").await.unwrap(), "2\n");
+        assert!(sysfs.write_global("nohz_full", "1").await.is_err());
+
+        sysfs.write(1, "online", "0").await.unwrap();
+        assert_eq!(sysfs.get(1, "online").as_deref(), Some("0"));
+        assert!(sysfs.write(0, "online", "0").await.is_err());
+    }
+}
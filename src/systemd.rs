@@ -0,0 +1,63 @@
+//! Readiness, watchdog, and status notifications to systemd (`sd_notify`).
+//!
+//! When run as a `Type=notify` service, systemd passes the path of a datagram socket in
+//! `NOTIFY_SOCKET`, and with `WatchdogSec=` the expected ping interval in `WATCHDOG_USEC`. Outside
+//! of systemd the variables are unset and notifications do nothing.
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+/// Sends `state`, e.g. `READY=1` or `STATUS=...`, to the service manager.
+///
+/// # Returns
+/// * `io::Result<bool>` - Whether a notification was sent, i.e. whether `NOTIFY_SOCKET` is set.
+pub fn notify(state: &str) -> io::Result<bool> {
+    match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => notify_to(&path, state).map(|()| true),
+        None => Ok(false),
+    }
+}
+
+/// Sends `state` to the notification socket at `path`. A leading `@` denotes an abstract socket.
+fn notify_to(path: &OsStr, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/// Returns the watchdog timeout set with `WatchdogSec=`, or `None` if the watchdog is disabled or
+/// meant for another process.
+pub fn watchdog_timeout() -> Option<Duration> {
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_datagrams_to_the_notify_socket() {
+        let path = env::temp_dir().join(format!("cpu-on-off-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        notify_to(path.as_os_str(), "READY=1\nSTATUS=Starting").unwrap();
+        let mut buf = [0; 64];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1\nSTATUS=Starting");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
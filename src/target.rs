@@ -0,0 +1,109 @@
+//! Proportional scaling towards a target utilization, as an alternative to the thresholds.
+//!
+//! With `--mode target --target-util 70`, each cycle computes how much capacity the busy time
+//! would need to run at 70% and onlines or offlines as many cores as it takes to get there in one
+//! step, instead of a profile's `step` cores per cycle. A load spike that needs twice the online
+//! CPUs is met within a single cycle rather than after one ramp step per cooldown.
+//!
+//! With `--mode pid`, the difference between that capacity and the online one is the error of a
+//! PID controller instead, whose output is the change in online capacity. A proportional gain
+//! below 1 approaches the target over several cycles, the integral term removes the remaining
+//! offset, and the derivative term damps fast swings.
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// Default target utilization percentage of `--mode target` and `--mode pid`.
+pub const DEFAULT_TARGET_UTIL: u8 = 70;
+
+/// How the control loop decides how many CPUs to online or offline.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScalingMode {
+    /// Online or offline the profile's `step` cores whenever the load crosses its thresholds.
+    #[default]
+    Threshold,
+    /// Online or offline as many cores as needed for the load to reach the target utilization.
+    Target,
+    /// Online or offline the cores a PID controller asks for to reach the target utilization.
+    Pid,
+}
+
+/// Default proportional gain of `--mode pid`.
+pub const DEFAULT_KP: f64 = 0.5;
+
+/// Default integral gain of `--mode pid`, per second.
+pub const DEFAULT_KI: f64 = 0.1;
+
+/// Default derivative gain of `--mode pid`, in seconds.
+pub const DEFAULT_KD: f64 = 0.0;
+
+/// A PID controller over the capacity error, in the units of `SystemTopology::online_capacity`.
+#[derive(Clone, Debug)]
+pub struct PidController {
+    kp: f64,
+    ki: f64,
+    kd: f64,
+    integral: f64,
+    previous_error: Option<f64>,
+}
+
+impl PidController {
+    pub fn new(kp: f64, ki: f64, kd: f64) -> Self {
+        PidController {
+            kp,
+            ki,
+            kd,
+            integral: 0.0,
+            previous_error: None,
+        }
+    }
+
+    /// Feeds the `error` of a cycle that lasted `dt` seconds and returns the change in online
+    /// capacity to make. The integral term is kept within `limit`, e.g. the managed capacity, so it
+    /// does not wind up while the CPUs cannot follow, such as at the floor or while inhibited.
+    pub fn update(&mut self, error: f64, dt: f64, limit: f64) -> f64 {
+        if self.ki > 0.0 {
+            self.integral = (self.integral + error * dt).clamp(-limit / self.ki, limit / self.ki);
+        }
+        let derivative = match self.previous_error {
+            Some(previous) if dt > 0.0 => (error - previous) / dt,
+            _ => 0.0,
+        };
+        self.previous_error = Some(error);
+        self.kp * error + self.ki * self.integral + self.kd * derivative
+    }
+}
+
+/// Returns the online capacity, in the units of `online_capacity`, that would run the busy part of
+/// `load`, measured over `online_capacity`, at `target_util` percent.
+pub fn desired_capacity(load: f64, online_capacity: f64, target_util: u8) -> f64 {
+    let busy = load / 100.0 * online_capacity;
+    busy * 100.0 / f64::from(target_util.max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_the_capacity_for_the_target() {
+        // 8 CPUs at 35% would be 4 CPUs at 70%.
+        assert_eq!(desired_capacity(35.0, 8.0, 70), 4.0);
+        // 4 saturated CPUs need at least 6 (5.7) to get down to 70%.
+        assert!((desired_capacity(100.0, 4.0, 70) - 5.714).abs() < 0.001);
+        assert_eq!(desired_capacity(0.0, 8.0, 70), 0.0);
+    }
+
+    #[test]
+    fn pid_accumulates_a_persistent_error() {
+        let mut pid = PidController::new(0.5, 0.1, 1.0);
+        assert_eq!(pid.update(4.0, 1.0, 16.0), 2.0 + 0.4);
+        // The integral keeps growing, while the unchanged error has no derivative.
+        assert_eq!(pid.update(4.0, 1.0, 16.0), 2.0 + 0.8);
+        // The error halved within a second.
+        assert_eq!(pid.update(2.0, 1.0, 16.0), 1.0 + 1.0 - 2.0);
+        // The integral term stays within the limit.
+        let mut pid = PidController::new(0.0, 1.0, 0.0);
+        assert_eq!(pid.update(100.0, 1.0, 16.0), 16.0);
+    }
+}
@@ -0,0 +1,250 @@
+//! Package temperatures from hwmon, for `--thermal-limit`.
+//!
+//! The `coretemp` driver of Intel CPUs registers one hwmon device per package, whose `Package id N`
+//! sensor is the temperature of socket N. The `k10temp` and `zenpower` drivers of AMD CPUs register
+//! one device per package without naming it; their `Tctl` or `Tdie` sensor is assigned to the
+//! sockets in the order of the devices. Other hwmon devices, e.g. of disks or GPUs, are ignored.
+//!
+//! While the hottest package is above the limit, the CPU manager offlines cores and onlines none,
+//! a soft thermal governor alongside the load policy. It keeps doing so until the package has cooled
+//! `THERMAL_MARGIN` degrees below the limit, so the CPUs do not flap around it.
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::{info, warn};
+
+/// The hwmon class directory, relative to the sysfs root.
+pub static HWMON_DIR: &str = "class/hwmon";
+
+/// Degrees Celsius below the limit the hottest package must cool to before CPUs are onlined again.
+pub const THERMAL_MARGIN: f64 = 3.0;
+
+/// The temperature of a CPU package.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PackageTemp {
+    /// The socket of the package, `None` if the driver does not tell.
+    pub socket: Option<usize>,
+    pub celsius: f64,
+}
+
+/// Reads the temperature of every CPU package found below `sysfs_root`.
+///
+/// This function performs the following steps:
+/// 1. Lists the hwmon devices in the order of their index and reads the `name` of each.
+/// 2. For `coretemp`, reads the `temp*_input` of every `Package id N` sensor as socket N.
+/// 3. For `k10temp` and `zenpower`, reads the `Tctl` sensor, or `Tdie` without one, as the next
+///    socket.
+///
+/// # Returns
+/// * `io::Result<Vec<PackageTemp>>` - The temperatures, or an error if the hwmon class is missing.
+pub async fn package_temperatures(sysfs_root: &Path) -> io::Result<Vec<PackageTemp>> {
+    let mut devices = Vec::new();
+    let mut entries = fs::read_dir(sysfs_root.join(HWMON_DIR)).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(index) = name
+            .strip_prefix("hwmon")
+            .and_then(|index| index.parse::<usize>().ok())
+        {
+            devices.push((index, entry.path()));
+        }
+    }
+    devices.sort();
+    let mut temps = Vec::new();
+    let mut amd_packages = 0;
+    for (_, dir) in devices {
+        let Ok(driver) = fs::read_to_string(dir.join("name")).await else {
+            continue;
+        };
+        let sensors = sensors(&dir).await;
+        match driver.trim() {
+            "coretemp" => {
+                for (label, input) in &sensors {
+                    if let Some(socket) = label
+                        .strip_prefix("Package id ")
+                        .and_then(|id| id.parse().ok())
+                    {
+                        if let Some(celsius) = read_celsius(input).await {
+                            temps.push(PackageTemp {
+                                socket: Some(socket),
+                                celsius,
+                            });
+                        }
+                    }
+                }
+            }
+            "k10temp" | "zenpower" => {
+                let sensor = ["Tctl", "Tdie"]
+                    .iter()
+                    .find_map(|name| sensors.iter().find(|(label, _)| label == name));
+                if let Some((_, input)) = sensor {
+                    if let Some(celsius) = read_celsius(input).await {
+                        temps.push(PackageTemp {
+                            socket: Some(amd_packages),
+                            celsius,
+                        });
+                    }
+                }
+                amd_packages += 1;
+            }
+            _ => {}
+        }
+    }
+    Ok(temps)
+}
+
+/// Returns the label and input file of every temperature sensor of hwmon device `dir`.
+async fn sensors(dir: &Path) -> Vec<(String, PathBuf)> {
+    let mut sensors = Vec::new();
+    let Ok(mut entries) = fs::read_dir(dir).await else {
+        return sensors;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(sensor) = name.strip_suffix("_label") else {
+            continue;
+        };
+        if let Ok(label) = fs::read_to_string(entry.path()).await {
+            sensors.push((
+                label.trim().to_string(),
+                dir.join(format!("{}_input", sensor)),
+            ));
+        }
+    }
+    sensors.sort();
+    sensors
+}
+
+/// Reads a temperature in millidegrees Celsius and returns it in degrees.
+async fn read_celsius(path: &Path) -> Option<f64> {
+    let millidegrees: i64 = fs::read_to_string(path).await.ok()?.trim().parse().ok()?;
+    Some(millidegrees as f64 / 1000.0)
+}
+
+/// Tracks whether the hottest package is above `--thermal-limit`.
+pub struct ThermalGuard {
+    sysfs_root: PathBuf,
+    limit: f64,
+    hottest: Option<PackageTemp>,
+    hot: bool,
+    /// Whether a failed read was already logged.
+    warned: bool,
+}
+
+impl ThermalGuard {
+    /// Creates a guard for the hwmon devices below `sysfs_root` with a limit of `limit` degrees.
+    pub fn new(sysfs_root: impl Into<PathBuf>, limit: f64) -> Self {
+        ThermalGuard {
+            sysfs_root: sysfs_root.into(),
+            limit,
+            hottest: None,
+            hot: false,
+            warned: false,
+        }
+    }
+
+    /// Returns the hottest package as of the last poll, if any was read.
+    pub fn hottest(&self) -> Option<PackageTemp> {
+        self.hottest
+    }
+
+    /// Returns whether the hottest package was above the limit, and has not cooled
+    /// `THERMAL_MARGIN` degrees below it since, as of the last poll.
+    pub fn is_hot(&self) -> bool {
+        self.hot
+    }
+
+    /// Reads the package temperatures and updates whether they are too hot. Packages that cannot
+    /// be read count as cool; a failure to read any is logged once.
+    pub async fn poll(&mut self) -> bool {
+        let temps = match package_temperatures(&self.sysfs_root).await {
+            Ok(temps) if !temps.is_empty() => {
+                self.warned = false;
+                temps
+            }
+            result => {
+                if !self.warned {
+                    let reason = result.map_or_else(
+                        |e| e.to_string(),
+                        |_| "no coretemp, k10temp, or zenpower sensors".to_string(),
+                    );
+                    warn!("Cannot read the package temperatures: {}", reason);
+                    self.warned = true;
+                }
+                Vec::new()
+            }
+        };
+        self.hottest = temps
+            .into_iter()
+            .max_by(|a, b| a.celsius.total_cmp(&b.celsius));
+        let celsius = self.hottest.map(|temp| temp.celsius);
+        let hot = match celsius {
+            Some(celsius) if self.hot => celsius > self.limit - THERMAL_MARGIN,
+            Some(celsius) => celsius > self.limit,
+            None => false,
+        };
+        if hot != self.hot {
+            match celsius {
+                Some(celsius) if hot => warn!(
+                    "Package at {:.1}°C, above the thermal limit of {:.1}°C",
+                    celsius, self.limit
+                ),
+                _ => info!("Packages cooled below {:.1}°C", self.limit - THERMAL_MARGIN),
+            }
+            self.hot = hot;
+        }
+        hot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn write(path: PathBuf, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+        fs::write(path, content).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reads_the_package_sensors_and_holds_until_cooled() {
+        let root = std::env::temp_dir().join(format!("cpu-on-off-hwmon-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root).await;
+        let hwmon = root.join(HWMON_DIR);
+        write(hwmon.join("hwmon0/name"), "nvme\n").await;
+        write(hwmon.join("hwmon0/temp1_label"), "Composite\n").await;
+        write(hwmon.join("hwmon0/temp1_input"), "99000\n").await;
+        write(hwmon.join("hwmon1/name"), "coretemp\n").await;
+        write(hwmon.join("hwmon1/temp1_label"), "Package id 0\n").await;
+        write(hwmon.join("hwmon1/temp1_input"), "70000\n").await;
+        write(hwmon.join("hwmon1/temp2_label"), "Core 0\n").await;
+        write(hwmon.join("hwmon1/temp2_input"), "95000\n").await;
+        write(hwmon.join("hwmon2/name"), "coretemp\n").await;
+        write(hwmon.join("hwmon2/temp1_label"), "Package id 1\n").await;
+        write(hwmon.join("hwmon2/temp1_input"), "81500\n").await;
+
+        let temps = package_temperatures(&root).await.unwrap();
+        assert_eq!(
+            temps,
+            [
+                PackageTemp {
+                    socket: Some(0),
+                    celsius: 70.0
+                },
+                PackageTemp {
+                    socket: Some(1),
+                    celsius: 81.5
+                }
+            ]
+        );
+
+        let mut guard = ThermalGuard::new(&root, 80.0);
+        assert!(guard.poll().await);
+        assert_eq!(guard.hottest().unwrap().socket, Some(1));
+        write(hwmon.join("hwmon2/temp1_input"), "78000\n").await;
+        assert!(guard.poll().await);
+        write(hwmon.join("hwmon2/temp1_input"), "76000\n").await;
+        assert!(!guard.poll().await);
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+}
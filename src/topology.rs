@@ -0,0 +1,2526 @@
+//! CPU topology discovery and hotplug operations through sysfs.
+use crate::actuation::{self, ActuationBackend, BackendName, Hotplug};
+use crate::cpu_list::CpuList;
+use crate::journal::{HotplugJournal, Intent};
+use crate::load::{CpuidleMetric, LoadMetric, LoadSample, ProcStatMetric};
+use crate::metrics::{Direction, SysfsAccess, METRICS};
+use crate::status_line::SocketLoad;
+use crate::strategy::{HighestId, SelectionStrategy};
+use crate::sysfs::{Sysfs, SysfsBackend};
+use crate::vulnerabilities;
+use clap::ValueEnum;
+use futures_util::stream::{self, Stream};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info, warn};
+
+pub static SYSFS_ROOT: &str = "/sys";
+pub static CPU_DIR: &str = "/sys/devices/system/cpu";
+
+/// `cpu_capacity` of the fastest CPUs, assumed for CPUs whose kernel does not report one.
+const SCHED_CAPACITY_SCALE: u32 = 1024;
+
+/// Default time a write to a CPU's `online` file may take before it is considered stuck.
+pub const DEFAULT_HOTPLUG_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many times a write to a CPU's `online` file is retried while the kernel reports the CPU
+/// busy, as it may for the last CPU of a package that handles the timers.
+const HOTPLUG_RETRIES: u32 = 3;
+
+/// Time waited before the first retry of a busy hotplug write, doubled for every further one.
+const HOTPLUG_BACKOFF: Duration = Duration::from_millis(20);
+
+/// Default number of failed hotplug writes in a row after which a CPU is blacklisted.
+pub const DEFAULT_BLACKLIST_AFTER: u32 = 3;
+
+/// Default time a CPU stays blacklisted.
+pub const DEFAULT_BLACKLIST_FOR: Duration = Duration::from_secs(300);
+
+/// A hotplug write that timed out and may still complete in the background.
+struct InDoubtWrite {
+    id: usize,
+    direction: Direction,
+    write: JoinHandle<io::Result<()>>,
+}
+
+/// Information about a single logical CPU.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CpuInfo {
+    pub id: usize,
+    pub core_id: Option<usize>,
+    pub socket_id: Option<usize>,
+    pub thread_siblings: Vec<usize>,
+    /// Cluster of CPUs sharing a cache, such as the big or the LITTLE cluster on ARM, from
+    /// `topology/cluster_id` or else the lowest CPU of `topology/cluster_cpus_list`.
+    #[serde(default)]
+    pub cluster_id: Option<usize>,
+    /// Compute capacity relative to the fastest CPUs at 1024, from `cpu_capacity`; `None` where the
+    /// kernel does not report it, as on most x86 machines.
+    #[serde(default)]
+    pub capacity: Option<u32>,
+    pub c0_percentage: f64,
+    pub online: bool,
+    /// Whether the CPU is configured, from `configure`; `false` for a standby CPU, and `None` where
+    /// there is no such file, i.e. on anything but s390x.
+    #[serde(default)]
+    pub configured: Option<bool>,
+    pub idle_states: Vec<String>,
+    /// Whether the daemon may change the state of this CPU and count its load. CPUs excluded with
+    /// `--exclude-cpus` are unmanaged.
+    pub managed: bool,
+}
+
+/// State of a single CPU in a `TopologySnapshot`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CpuSnapshot {
+    pub id: usize,
+    pub core_id: Option<usize>,
+    pub socket_id: Option<usize>,
+    #[serde(default)]
+    pub cluster_id: Option<usize>,
+    #[serde(default)]
+    pub capacity: Option<u32>,
+    pub online: bool,
+    /// Whether the CPU is configured, see `CpuInfo::configured`.
+    #[serde(default)]
+    pub configured: Option<bool>,
+    pub managed: bool,
+    pub c0_percentage: f64,
+    /// Consecutive failed hotplug writes, see `SystemTopology::hotplug_failures`.
+    #[serde(default)]
+    pub hotplug_failures: u32,
+    /// Whether the selection leaves the CPU alone after too many failures, see
+    /// `SystemTopology::blacklist`.
+    #[serde(default)]
+    pub blacklisted: bool,
+    /// Whether the selection keeps the CPU online, see `SystemTopology::held`.
+    #[serde(default)]
+    pub held: bool,
+}
+
+/// Point-in-time view of the topology, as yielded by `SystemTopology::watch`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TopologySnapshot {
+    pub time: SystemTime,
+    /// All CPUs, ordered by ID.
+    pub cpus: Vec<CpuSnapshot>,
+    pub sockets: Vec<SocketLoad>,
+    /// Average C0 percentage of the managed online CPUs.
+    pub average_c0: f64,
+    pub online_count: usize,
+    /// Whether SMT siblings are never onlined, see `SystemTopology::no_smt`.
+    #[serde(default)]
+    pub no_smt: bool,
+    /// The CPU the kernel booted on, see `SystemTopology::boot_cpu`.
+    #[serde(default)]
+    pub boot_cpu: usize,
+    /// The CPU that always stays online.
+    #[serde(default)]
+    pub housekeeping_cpu: usize,
+}
+
+/// The CPUs of the system grouped by socket, along with their latest load samples.
+pub struct SystemTopology {
+    pub cpus: HashMap<usize, CpuInfo>,
+    pub sockets: HashMap<usize, Vec<usize>>,
+    pub cpu0_socket: Option<usize>,
+    /// Forbids half-core states: group operations always cover all thread siblings of a core,
+    /// and cores that cannot be offlined as a whole are left alone.
+    pub full_cores_only: bool,
+    /// The CPU attributes, read from `/sys/devices/system/cpu` unless set by the builder.
+    pub sysfs: Arc<dyn SysfsBackend>,
+    /// Takes the CPUs away and gives them back, through hotplug unless set by the builder.
+    pub actuation: Arc<dyn ActuationBackend>,
+    /// The CPUs that were online when the topology was scanned, restored on exit.
+    pub initial_online: BTreeSet<usize>,
+    /// Time a hotplug write may take before it is considered stuck.
+    pub hotplug_timeout: Duration,
+    /// The CPU the kernel booted on: the lowest CPU it cannot offline, see `boot_cpu`.
+    pub boot_cpu: usize,
+    /// The CPU that always stays online, the boot CPU unless set by the builder.
+    pub housekeeping_cpu: usize,
+    /// Which socket's CPUs are offlined first.
+    pub offline_order: OfflineOrder,
+    /// How many CPUs the selected groups cover.
+    pub granularity: Granularity,
+    /// Offlines the secondary threads of all cores, one at a time, before any whole core, and
+    /// onlines them only after every core has a thread online again.
+    pub prefer_no_smt: bool,
+    /// Never onlines a thread of a core that already has one online, and offlines the secondary
+    /// threads first; set by the builder's `no_smt_if_vulnerable` on CPUs that report SMT as
+    /// vulnerable.
+    pub no_smt: bool,
+    /// Which class of CPUs is vacated first on heterogeneous systems; `None` ignores the capacity.
+    pub prefer_offline: Option<CapacityClass>,
+    /// Picks the CPU to hotplug among the candidates left by the other preferences.
+    pub strategy: Arc<dyn SelectionStrategy>,
+    /// Number of recently active tasks per CPU they last ran on. Offlining prefers the core with
+    /// the fewest; empty unless sampled with `--stickiness`.
+    pub stickiness: HashMap<usize, usize>,
+    /// Where each hotplug write is recorded until it has been verified; not kept unless set by the
+    /// builder.
+    pub journal: Option<HotplugJournal>,
+    /// Consecutive failed hotplug writes per CPU, counted once the retries are exhausted and reset
+    /// by a successful write.
+    pub hotplug_failures: HashMap<usize, u32>,
+    /// Failed hotplug writes in a row after which a CPU is blacklisted; 0 never blacklists.
+    pub blacklist_after: u32,
+    /// Time a CPU stays blacklisted.
+    pub blacklist_for: Duration,
+    /// The CPUs the selection leaves alone after `blacklist_after` failures in a row, with the time
+    /// their blacklisting lapses. A lapsed CPU is tried again by the selection, and blacklisted
+    /// again by its next failure unless a write succeeds first.
+    pub blacklist: HashMap<usize, Instant>,
+    /// The CPUs the selection never offlines, along with their thread siblings, e.g. the CPUs
+    /// assigned exclusively to Kubernetes pods. Replaced by the CPU manager every cycle.
+    pub held: CpuList,
+    /// The CPUs whose hotplug write failed since `begin_cycle`, passed over by the selection so
+    /// that another group is picked in their place.
+    passed_over: BTreeSet<usize>,
+    in_doubt: Option<InDoubtWrite>,
+}
+
+/// What to do with the CPUs when the program exits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExitPolicy {
+    /// Return every managed CPU to the state it had when the program started.
+    #[default]
+    Restore,
+    /// Online every CPU that is not excluded.
+    OnlineAll,
+    /// Leave the CPUs as they are.
+    Leave,
+}
+
+/// Which CPUs the control loop starts from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StartFrom {
+    /// Online every CPU that is not excluded before the first cycle.
+    #[default]
+    All,
+    /// Keep the CPUs online or offline as they are and let the policy evolve from there.
+    Current,
+}
+
+/// How many CPUs a single online or offline decision covers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Granularity {
+    /// A single hardware thread, for finer capacity steps on machines with many threads per core.
+    Thread,
+    /// All thread siblings of a core.
+    #[default]
+    Core,
+    /// All CPUs of a socket, apart from the housekeeping CPU.
+    Socket,
+}
+
+/// A class of CPUs on heterogeneous systems such as ARM big.LITTLE or DynamIQ, told apart by their
+/// `cpu_capacity`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CapacityClass {
+    /// The high-performance CPUs with the largest capacity.
+    Big,
+    /// The efficiency CPUs with a smaller capacity.
+    Little,
+}
+
+/// Which CPUs are offlined first. The socket-aware orders drain one socket at a time and offline
+/// the socket of the housekeeping CPU last, so memory stays local to the CPUs left online.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OfflineOrder {
+    /// The CPU with the highest ID, whichever its socket.
+    #[default]
+    HighestId,
+    /// The sockets other than the housekeeping CPU's, the most drained one first.
+    RemoteSocket,
+    /// The sockets other than the housekeeping CPU's, the least loaded one first.
+    LeastLoadedSocket,
+}
+
+/// Configures a `SystemTopology` before the sysfs scan, e.g. to point it at a fake sysfs tree in
+/// tests or to exclude CPUs from management.
+pub struct SystemTopologyBuilder {
+    cpu_dir: PathBuf,
+    sysfs: Option<Arc<dyn SysfsBackend>>,
+    excluded: CpuList,
+    full_cores_only: bool,
+    hotplug_timeout: Duration,
+    blacklist_after: u32,
+    blacklist_for: Duration,
+    housekeeping_cpu: Option<usize>,
+    offline_order: OfflineOrder,
+    granularity: Granularity,
+    prefer_no_smt: bool,
+    no_smt_if_vulnerable: bool,
+    prefer_offline: Option<CapacityClass>,
+    strategy: Arc<dyn SelectionStrategy>,
+    manage_isolated: bool,
+    cpuset: Option<CpuList>,
+    actuation: BackendName,
+    actuation_cgroup: PathBuf,
+    deconfigure: bool,
+    journal: Option<HotplugJournal>,
+}
+
+impl Default for SystemTopologyBuilder {
+    fn default() -> Self {
+        SystemTopologyBuilder {
+            cpu_dir: PathBuf::from(CPU_DIR),
+            sysfs: None,
+            excluded: CpuList::default(),
+            full_cores_only: false,
+            hotplug_timeout: DEFAULT_HOTPLUG_TIMEOUT,
+            blacklist_after: DEFAULT_BLACKLIST_AFTER,
+            blacklist_for: DEFAULT_BLACKLIST_FOR,
+            housekeeping_cpu: None,
+            offline_order: OfflineOrder::default(),
+            granularity: Granularity::default(),
+            prefer_no_smt: false,
+            no_smt_if_vulnerable: false,
+            prefer_offline: None,
+            strategy: Arc::new(HighestId),
+            manage_isolated: false,
+            cpuset: None,
+            actuation: BackendName::default(),
+            actuation_cgroup: PathBuf::new(),
+            deconfigure: false,
+            journal: None,
+        }
+    }
+}
+
+impl SystemTopologyBuilder {
+    /// Reads the CPUs from `devices/system/cpu` below `sysfs_root`, e.g. a host sysfs mounted
+    /// elsewhere in a container, instead of `/sys`.
+    pub fn sysfs_root(self, sysfs_root: impl AsRef<Path>) -> Self {
+        self.cpu_dir(sysfs_root.as_ref().join("devices/system/cpu"))
+    }
+
+    /// Reads the CPUs from `cpu_dir` instead of `/sys/devices/system/cpu`.
+    pub fn cpu_dir(mut self, cpu_dir: impl Into<PathBuf>) -> Self {
+        self.cpu_dir = cpu_dir.into();
+        self
+    }
+
+    /// Reads and writes the CPU attributes through `sysfs`, e.g. a `MockSysfs` in tests, instead
+    /// of the CPU directory.
+    pub fn backend(mut self, sysfs: Arc<dyn SysfsBackend>) -> Self {
+        self.sysfs = Some(sysfs);
+        self
+    }
+
+    /// Marks `excluded` as unmanaged, so they are never onlined, offlined, or averaged.
+    pub fn exclude_cpus(mut self, excluded: CpuList) -> Self {
+        self.excluded = excluded;
+        self
+    }
+
+    /// Forbids half-core states, see `SystemTopology::full_cores_only`.
+    pub fn full_cores_only(mut self, full_cores_only: bool) -> Self {
+        self.full_cores_only = full_cores_only;
+        self
+    }
+
+    /// Sets the time a hotplug write may take before it is considered stuck.
+    pub fn hotplug_timeout(mut self, hotplug_timeout: Duration) -> Self {
+        self.hotplug_timeout = hotplug_timeout;
+        self
+    }
+
+    /// Blacklists a CPU for `duration` after `after` failed hotplug writes in a row, see
+    /// `SystemTopology::blacklist`. An `after` of 0 never blacklists.
+    pub fn blacklist(mut self, after: u32, duration: Duration) -> Self {
+        self.blacklist_after = after;
+        self.blacklist_for = duration;
+        self
+    }
+
+    /// Sets the CPU that always stays online instead of the boot CPU, usually CPU0. The boot CPU is
+    /// then managed like any other CPU, which requires a kernel that can offline it
+    /// (`cpu0_hotplug`). `None` keeps the boot CPU.
+    pub fn housekeeping_cpu(mut self, housekeeping_cpu: Option<usize>) -> Self {
+        self.housekeeping_cpu = housekeeping_cpu;
+        self
+    }
+
+    /// Sets which CPUs are offlined first, see `OfflineOrder`.
+    pub fn offline_order(mut self, offline_order: OfflineOrder) -> Self {
+        self.offline_order = offline_order;
+        self
+    }
+
+    /// Sets how many CPUs the selected groups cover, see `Granularity`.
+    pub fn granularity(mut self, granularity: Granularity) -> Self {
+        self.granularity = granularity;
+        self
+    }
+
+    /// Sheds the secondary threads of all cores before whole cores, see
+    /// `SystemTopology::prefer_no_smt`.
+    pub fn prefer_no_smt(mut self, prefer_no_smt: bool) -> Self {
+        self.prefer_no_smt = prefer_no_smt;
+        self
+    }
+
+    /// Keeps the SMT siblings offline if the CPU reports SMT as vulnerable, see
+    /// `SystemTopology::no_smt`.
+    pub fn no_smt_if_vulnerable(mut self, no_smt_if_vulnerable: bool) -> Self {
+        self.no_smt_if_vulnerable = no_smt_if_vulnerable;
+        self
+    }
+
+    /// Sets which class of CPUs is vacated first, see `SystemTopology::prefer_offline`.
+    pub fn prefer_offline(mut self, prefer_offline: Option<CapacityClass>) -> Self {
+        self.prefer_offline = prefer_offline;
+        self
+    }
+
+    /// Sets the strategy that picks the CPU to hotplug, see `SelectionStrategy`.
+    pub fn strategy(mut self, strategy: Arc<dyn SelectionStrategy>) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Manages the CPUs isolated with `isolcpus` or `nohz_full` like any other, instead of leaving
+    /// them out of management and load averaging.
+    pub fn manage_isolated(mut self, manage_isolated: bool) -> Self {
+        self.manage_isolated = manage_isolated;
+        self
+    }
+
+    /// Marks the CPUs outside `cpuset`, the CPUs a cgroup cpuset allows, as unmanaged, see
+    /// `cpuset::allowed_cpus`.
+    pub fn cpuset(mut self, cpuset: Option<CpuList>) -> Self {
+        self.cpuset = cpuset;
+        self
+    }
+
+    /// Takes the CPUs away and gives them back through `backend`, see `ActuationBackend`. The
+    /// cpuset backend narrows and widens the `cpuset.cpus` of the cgroup directory `cgroup_dir`.
+    pub fn actuation(mut self, backend: BackendName, cgroup_dir: impl Into<PathBuf>) -> Self {
+        self.actuation = backend;
+        self.actuation_cgroup = cgroup_dir.into();
+        self
+    }
+
+    /// Deconfigures the CPUs it offlines where they have a `configure` file, as on s390x, see
+    /// `Hotplug::deconfigure`.
+    pub fn deconfigure(mut self, deconfigure: bool) -> Self {
+        self.deconfigure = deconfigure;
+        self
+    }
+
+    /// Records every hotplug write in `journal` until it has been verified, see `HotplugJournal`.
+    pub fn journal(mut self, journal: HotplugJournal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Scans the CPU directory, or the backend if one is set, and builds the topology.
+    ///
+    /// This function performs the following steps:
+    /// 1. Creates the actuation backend and reads every CPU listed by the sysfs backend with
+    ///    `process_cpu`.
+    /// 2. Marks the excluded CPUs as unmanaged, warning about the ones that do not exist. Unless
+    ///    `manage_isolated` is set, also marks the CPUs listed in the `isolated` and `nohz_full`
+    ///    attributes as unmanaged, see `read_isolated`. If a cpuset is set, marks the CPUs outside
+    ///    it as unmanaged as well.
+    /// 3. Finds the boot CPU with `boot_cpu`. If the housekeeping CPU is another one, checks that it
+    ///    exists and that the boot CPU can be hotplugged.
+    /// 4. Applies the constraints and returns the topology.
+    ///
+    /// # Returns
+    /// * `io::Result<SystemTopology>` - The topology, or an error if the CPU directory cannot be read,
+    ///   the actuation backend cannot be created, or the housekeeping CPU cannot be used.
+    pub async fn build(self) -> io::Result<SystemTopology> {
+        let mut cpus = HashMap::new();
+        let mut sockets = HashMap::new();
+        let mut cpu0_socket = None;
+
+        let cpu_dir = self.cpu_dir;
+        let sysfs = self.sysfs.unwrap_or_else(|| {
+            debug!("Reading CPU information from: {:?}", cpu_dir);
+            Arc::new(Sysfs::new(cpu_dir))
+        });
+        let actuation =
+            self.actuation
+                .build(Arc::clone(&sysfs), self.actuation_cgroup, self.deconfigure)?;
+
+        let ids = sysfs.cpu_ids().await?;
+        for &id in &ids {
+            SystemTopology::process_cpu(
+                &*sysfs,
+                &*actuation,
+                id,
+                &mut cpu0_socket,
+                &mut cpus,
+                &mut sockets,
+            )
+            .await;
+        }
+        for id in &self.excluded.0 {
+            match cpus.get_mut(id) {
+                Some(cpu) => cpu.managed = false,
+                None => warn!("Excluded CPU {} does not exist", id),
+            }
+        }
+        if !self.manage_isolated {
+            let isolated = SystemTopology::read_isolated(&*sysfs).await;
+            if !isolated.is_empty() {
+                info!(
+                    "Leaving the isolated and nohz_full CPUs {} out of management",
+                    isolated
+                );
+            }
+            for id in &isolated.0 {
+                if let Some(cpu) = cpus.get_mut(id) {
+                    cpu.managed = false;
+                }
+            }
+        }
+        if let Some(cpuset) = &self.cpuset {
+            let outside = CpuList(
+                cpus.keys()
+                    .filter(|id| !cpuset.0.contains(id))
+                    .copied()
+                    .collect(),
+            );
+            if !outside.is_empty() {
+                info!(
+                    "Leaving the CPUs {} outside the cgroup cpuset {} out of management",
+                    outside, cpuset
+                );
+            }
+            for id in &outside.0 {
+                if let Some(cpu) = cpus.get_mut(id) {
+                    cpu.managed = false;
+                }
+            }
+        }
+
+        let boot_cpu = boot_cpu(&*sysfs, &ids);
+        if boot_cpu != 0 {
+            info!("CPU{} is the boot CPU and cannot be offlined", boot_cpu);
+        }
+        let housekeeping_cpu = self.housekeeping_cpu.unwrap_or(boot_cpu);
+        if housekeeping_cpu != boot_cpu && !cpus.contains_key(&housekeeping_cpu) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("housekeeping CPU {} does not exist", housekeeping_cpu),
+            ));
+        }
+        if housekeeping_cpu != boot_cpu && !actuation.controls(boot_cpu) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "boot CPU {} cannot be offlined on this kernel, so it must remain the housekeeping CPU",
+                    boot_cpu
+                ),
+            ));
+        }
+
+        debug!("Finished reading CPU information");
+        info!("Found {} CPUs across {} sockets", cpus.len(), sockets.len());
+        let capacities: BTreeSet<u32> = cpus.values().filter_map(|cpu| cpu.capacity).collect();
+        if capacities.len() > 1 {
+            let clusters: BTreeSet<usize> =
+                cpus.values().filter_map(|cpu| cpu.cluster_id).collect();
+            info!(
+                "Found CPUs of capacities {:?} in {} clusters",
+                capacities,
+                clusters.len()
+            );
+        } else if self.prefer_offline.is_some() {
+            warn!("All CPUs have the same capacity, so the offline preference has no effect");
+        }
+
+        let mut no_smt = false;
+        if self.no_smt_if_vulnerable {
+            let vulnerable = vulnerabilities::smt_vulnerabilities(&*sysfs).await;
+            if vulnerable.is_empty() {
+                info!("SMT is not reported as vulnerable, onlining SMT siblings as usual");
+            } else {
+                warn!(
+                    "SMT is vulnerable to {}, never onlining a second thread of a core",
+                    vulnerable.join(", ")
+                );
+                no_smt = true;
+            }
+        }
+
+        let initial_online = online_ids(&cpus);
+        Ok(SystemTopology {
+            cpus,
+            sockets,
+            cpu0_socket,
+            full_cores_only: self.full_cores_only,
+            sysfs,
+            actuation,
+            initial_online,
+            hotplug_timeout: self.hotplug_timeout,
+            boot_cpu,
+            housekeeping_cpu,
+            offline_order: self.offline_order,
+            granularity: self.granularity,
+            prefer_no_smt: self.prefer_no_smt,
+            no_smt,
+            prefer_offline: self.prefer_offline,
+            strategy: self.strategy,
+            stickiness: HashMap::new(),
+            journal: self.journal,
+            hotplug_failures: HashMap::new(),
+            blacklist_after: self.blacklist_after,
+            blacklist_for: self.blacklist_for,
+            blacklist: HashMap::new(),
+            held: CpuList::default(),
+            passed_over: BTreeSet::new(),
+            in_doubt: None,
+        })
+    }
+}
+
+impl SystemTopology {
+    /// Scans sysfs with the default settings apart from the excluded CPUs. Use `builder()` to
+    /// configure anything else.
+    pub async fn new(excluded: &CpuList) -> io::Result<Self> {
+        Self::builder().exclude_cpus(excluded.clone()).build().await
+    }
+
+    pub fn builder() -> SystemTopologyBuilder {
+        SystemTopologyBuilder::default()
+    }
+
+    /// Returns whether the kernel can offline CPU0, i.e. whether it has an `online` file.
+    pub fn cpu0_hotpluggable(&self) -> bool {
+        self.sysfs.exists(0, "online")
+    }
+
+    /// Builds a topology from already known CPUs, e.g. for tests or for callers that discover
+    /// CPUs by other means than sysfs.
+    pub fn from_cpus(cpus: impl IntoIterator<Item = CpuInfo>) -> Self {
+        let mut by_id = HashMap::new();
+        let mut sockets: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut cpu0_socket = None;
+
+        for cpu in cpus {
+            if let Some(socket_id) = cpu.socket_id {
+                sockets.entry(socket_id).or_default().push(cpu.id);
+                if cpu.id == 0 {
+                    cpu0_socket = Some(socket_id);
+                }
+            }
+            by_id.insert(cpu.id, cpu);
+        }
+
+        let initial_online = online_ids(&by_id);
+        let sysfs: Arc<dyn SysfsBackend> = Arc::new(Sysfs::new(CPU_DIR));
+        SystemTopology {
+            cpus: by_id,
+            sockets,
+            cpu0_socket,
+            full_cores_only: false,
+            actuation: Arc::new(Hotplug::new(Arc::clone(&sysfs))),
+            sysfs,
+            initial_online,
+            hotplug_timeout: DEFAULT_HOTPLUG_TIMEOUT,
+            boot_cpu: 0,
+            housekeeping_cpu: 0,
+            offline_order: OfflineOrder::default(),
+            granularity: Granularity::default(),
+            prefer_no_smt: false,
+            no_smt: false,
+            prefer_offline: None,
+            strategy: Arc::new(HighestId),
+            stickiness: HashMap::new(),
+            journal: None,
+            hotplug_failures: HashMap::new(),
+            blacklist_after: DEFAULT_BLACKLIST_AFTER,
+            blacklist_for: DEFAULT_BLACKLIST_FOR,
+            blacklist: HashMap::new(),
+            held: CpuList::default(),
+            passed_over: BTreeSet::new(),
+            in_doubt: None,
+        }
+    }
+
+    /// Builds a single socket of one-thread cores, CPU `n` being core `n`, with the given online
+    /// state, no load, and every CPU managed.
+    #[cfg(test)]
+    pub(crate) fn single_thread_cores(online: &[bool]) -> Self {
+        Self::from_cpus(online.iter().enumerate().map(|(id, &online)| CpuInfo {
+            id,
+            core_id: Some(id),
+            socket_id: Some(0),
+            thread_siblings: vec![id],
+            cluster_id: None,
+            capacity: None,
+            c0_percentage: 0.0,
+            online,
+            configured: None,
+            idle_states: Vec::new(),
+            managed: true,
+        }))
+    }
+
+    /// Asynchronously processes a CPU, extracting relevant information and updating the provided data structures.
+    ///
+    /// This function performs the following steps:
+    /// 1. Reads the core ID and socket ID from the respective files in the CPU's topology directory.
+    /// 2. Reads the thread siblings, online status, and idle states of the CPU.
+    /// 3. If the CPU ID is 0, updates the `cpu0_socket` with the socket ID.
+    /// 4. Creates a `CpuInfo` struct with the extracted information and inserts it into the `cpus` HashMap.
+    /// 5. Updates the `sockets` HashMap with the CPU ID if the socket ID is present.
+    ///
+    /// # Arguments
+    /// * `sysfs` - The backend the CPU attributes are read from.
+    /// * `actuation` - The backend the online status is read from.
+    /// * `id` - The ID of the CPU.
+    /// * `cpu0_socket` - A mutable reference to an Option containing the socket ID of CPU0.
+    /// * `cpus` - A mutable reference to a HashMap storing information about all CPUs.
+    /// * `sockets` - A mutable reference to a HashMap storing the CPUs associated with each socket.
+    async fn process_cpu(
+        sysfs: &dyn SysfsBackend,
+        actuation: &dyn ActuationBackend,
+        id: usize,
+        cpu0_socket: &mut Option<usize>,
+        cpus: &mut HashMap<usize, CpuInfo>,
+        sockets: &mut HashMap<usize, Vec<usize>>,
+    ) {
+        debug!("Processing CPU {}", id);
+
+        let core_id = Self::read_topology_id(sysfs, id, "core_id").await;
+
+        let socket_id = Self::read_topology_id(sysfs, id, "physical_package_id").await;
+
+        let thread_siblings = Self::read_thread_siblings(sysfs, id).await;
+
+        let cluster_id = Self::read_cluster_id(sysfs, id).await;
+
+        let capacity = Self::read_capacity(sysfs, id).await;
+
+        let online = Self::is_cpu_online(actuation, id).await;
+
+        let configured = Self::read_configured(sysfs, id).await;
+
+        let idle_states = Self::get_idle_states(sysfs, id).await;
+
+        if id == 0 {
+            *cpu0_socket = socket_id;
+        }
+
+        let cpu_info = CpuInfo {
+            id,
+            core_id,
+            socket_id,
+            thread_siblings,
+            cluster_id,
+            capacity,
+            c0_percentage: 0.0,
+            online,
+            configured,
+            idle_states,
+            managed: true,
+        };
+        cpus.insert(id, cpu_info);
+
+        if let Some(socket_id) = socket_id {
+            sockets.entry(socket_id).or_default().push(id);
+        }
+    }
+
+    async fn read_topology_id(sysfs: &dyn SysfsBackend, id: usize, name: &str) -> Option<usize> {
+        match sysfs.read(id, &format!("topology/{}", name)).await {
+            Ok(s) => s.trim().parse().ok(),
+            Err(e) => {
+                METRICS.sysfs_error(SysfsAccess::TopologyRead, &e);
+                None
+            }
+        }
+    }
+
+    async fn read_thread_siblings(sysfs: &dyn SysfsBackend, id: usize) -> Vec<usize> {
+        Self::read_cpu_list(sysfs, id, "thread_siblings_list")
+            .await
+            .map(|list| list.0.into_iter().collect())
+            .unwrap_or_default()
+    }
+
+    /// Reads the CPU's cluster from `topology/cluster_id`, or from the lowest CPU of
+    /// `topology/cluster_cpus_list` where the former is missing or -1, as on some ARM kernels.
+    async fn read_cluster_id(sysfs: &dyn SysfsBackend, id: usize) -> Option<usize> {
+        if sysfs.exists(id, "topology/cluster_id") {
+            if let Some(cluster_id) = Self::read_topology_id(sysfs, id, "cluster_id").await {
+                return Some(cluster_id);
+            }
+        }
+        if !sysfs.exists(id, "topology/cluster_cpus_list") {
+            return None;
+        }
+        let cluster_cpus = Self::read_cpu_list(sysfs, id, "cluster_cpus_list").await?;
+        cluster_cpus.0.first().copied()
+    }
+
+    /// Reads the CPU's `cpu_capacity`, which only heterogeneous systems report.
+    async fn read_capacity(sysfs: &dyn SysfsBackend, id: usize) -> Option<u32> {
+        if !sysfs.exists(id, "cpu_capacity") {
+            return None;
+        }
+        match sysfs.read(id, "cpu_capacity").await {
+            Ok(s) => s.trim().parse().ok(),
+            Err(e) => {
+                METRICS.sysfs_error(SysfsAccess::TopologyRead, &e);
+                None
+            }
+        }
+    }
+
+    /// Returns the CPUs left out of management, excluded, isolated, or outside the cgroup cpuset.
+    pub fn unmanaged(&self) -> CpuList {
+        CpuList(
+            self.cpus
+                .values()
+                .filter(|cpu| !cpu.managed)
+                .map(|cpu| cpu.id)
+                .collect(),
+        )
+    }
+
+    /// Returns the CPUs `online_all_cpus` must leave alone: the unmanaged ones and, with `no_smt`,
+    /// every thread of a core but the lowest-numbered one, so onlining all CPUs brings no SMT
+    /// sibling back.
+    pub fn online_all_exclusions(&self) -> CpuList {
+        CpuList(
+            self.cpus
+                .values()
+                .filter(|cpu| {
+                    !cpu.managed
+                        || (self.no_smt && cpu.thread_siblings.iter().any(|&id| id < cpu.id))
+                })
+                .map(|cpu| cpu.id)
+                .collect(),
+        )
+    }
+
+    /// Reads the CPUs reserved for latency-critical work: the CPUs isolated from the scheduler with
+    /// `isolcpus`, listed in `isolated`, and the CPUs running without the scheduler tick, listed in
+    /// `nohz_full`. Missing attributes count as empty, as does the `(null)` that `nohz_full` reads
+    /// on some kernels when unset.
+    pub async fn read_isolated(sysfs: &dyn SysfsBackend) -> CpuList {
+        let mut isolated = CpuList::default();
+        for attr in ["isolated", "nohz_full"] {
+            if let Some(list) = sysfs
+                .read_global(attr)
+                .await
+                .ok()
+                .and_then(|list| list.trim().parse::<CpuList>().ok())
+            {
+                isolated.0.extend(list.0);
+            }
+        }
+        isolated
+    }
+
+    /// Reads a CPU list attribute of the CPU's topology directory, such as `thread_siblings_list`
+    /// or `package_cpus_list`, in the kernel's `cpulist` format, e.g. `0-1` or `0,64`.
+    ///
+    /// # Returns
+    /// * `Option<CpuList>` - The CPUs listed, or `None` if the attribute cannot be read or parsed.
+    pub async fn read_cpu_list(sysfs: &dyn SysfsBackend, id: usize, name: &str) -> Option<CpuList> {
+        let attr = format!("topology/{}", name);
+        let content = sysfs
+            .read(id, &attr)
+            .await
+            .inspect_err(|e| METRICS.sysfs_error(SysfsAccess::TopologyRead, e))
+            .ok()?;
+        content
+            .trim()
+            .parse::<CpuList>()
+            .inspect_err(|e| {
+                METRICS.sysfs_error(
+                    SysfsAccess::TopologyRead,
+                    &io::Error::new(io::ErrorKind::InvalidData, e.as_str()),
+                );
+                warn!("Cannot parse cpu{}/{}: {}", id, attr, e);
+            })
+            .ok()
+    }
+
+    async fn is_cpu_online(actuation: &dyn ActuationBackend, id: usize) -> bool {
+        actuation
+            .is_online(id)
+            .await
+            .inspect_err(|e| METRICS.sysfs_error(SysfsAccess::OnlineRead, e))
+            .unwrap_or(false)
+    }
+
+    /// Reads whether the CPU is configured, see `CpuInfo::configured`. An unreadable `configure`
+    /// file counts as configured, like a missing `online` file counts as online.
+    async fn read_configured(sysfs: &dyn SysfsBackend, id: usize) -> Option<bool> {
+        actuation::is_configured(sysfs, id)
+            .await
+            .inspect_err(|e| METRICS.sysfs_error(SysfsAccess::OnlineRead, e))
+            .unwrap_or(Some(true))
+    }
+
+    async fn get_idle_states(sysfs: &dyn SysfsBackend, id: usize) -> Vec<String> {
+        // CPUs without cpuidle, e.g. with `cpuidle.off=1` or in most VMs, have no directory.
+        let mut states: Vec<String> = sysfs
+            .list(id, "cpuidle")
+            .await
+            .inspect_err(|e| {
+                if e.kind() != io::ErrorKind::NotFound {
+                    METRICS.sysfs_error(SysfsAccess::CpuidleRead, e);
+                }
+            })
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|name| name.starts_with("state"))
+            .collect();
+        states.sort();
+        states
+    }
+
+    /// Stores the per-CPU loads of `sample` as the CPUs' C0 percentages.
+    pub fn apply_sample(&mut self, sample: &LoadSample) {
+        for (id, &load) in &sample.per_cpu {
+            if let Some(cpu) = self.cpus.get_mut(id) {
+                cpu.c0_percentage = load;
+            }
+        }
+    }
+
+    /// Selects a group of CPUs to be offlined based on their current state and topology.
+    ///
+    /// This function performs the following steps:
+    /// 1. Returns `None` if the housekeeping CPU (CPU0 by default) is offline, so the machine is never
+    ///    left without it.
+    /// 2. Filters the CPUs to get a list of managed online CPUs excluding the housekeeping CPU, and in
+    ///    full-cores-only mode also excluding its siblings.
+    /// 3. If there is only one or no online CPU (excluding the housekeeping CPU), returns `None` to avoid offlining.
+    ///    Otherwise drops the CPUs passed over after a failed write this cycle, the blacklisted ones,
+    ///    in full-cores-only mode also the ones with such a thread sibling, and the `held` ones along
+    ///    with their thread siblings.
+    /// 4. In prefer-no-SMT mode and with `no_smt`, narrows the candidates to the secondary threads,
+    ///    i.e. the CPUs with a lower-numbered thread sibling online, as long as there are any.
+    /// 5. Narrows the candidates to the class `prefer_offline` vacates first, if set, and then to the
+    ///    first socket in the `offline_order` drain order, and lets the `strategy` pick one of them.
+    ///    The default `HighestId` strategy picks the core with the lowest stickiness, i.e. the fewest
+    ///    recently active tasks, and then the highest ID. By default and without stickiness
+    ///    samples, this is the CPU with the highest ID.
+    /// 6. Collects the CPUs of the selected CPU's group under `granularity` that are also online,
+    ///    managed, and neither passed over nor blacklisted: the CPU alone, its thread siblings, or
+    ///    the candidate CPUs of its socket. In prefer-no-SMT mode and with `no_smt`, the group is
+    ///    the selected CPU alone.
+    /// 7. Returns the list of online CPUs to be offlined.
+    ///
+    /// # Returns
+    /// * `Option<Vec<usize>>` - A vector of CPU IDs to be offlined, or `None` if no CPUs can be offlined.
+    pub fn select_cpu_to_offline(&self) -> Option<Vec<usize>> {
+        self.select_offline(None)
+    }
+
+    /// Selects a group of CPUs of socket `socket_id` to be offlined, like `select_cpu_to_offline`
+    /// but ignoring the CPUs of the other sockets.
+    pub fn select_cpu_to_offline_on(&self, socket_id: usize) -> Option<Vec<usize>> {
+        self.select_offline(Some(socket_id))
+    }
+
+    fn select_offline(&self, socket_id: Option<usize>) -> Option<Vec<usize>> {
+        let housekeeping = self.housekeeping_cpu;
+        if !self.cpus.get(&housekeeping).is_some_and(|cpu| cpu.online) {
+            return None;
+        }
+        let mut online_cpus: Vec<_> = self
+            .cpus
+            .values()
+            .filter(|cpu| cpu.online && cpu.managed && cpu.id != housekeeping)
+            .filter(|cpu| !(self.full_cores_only && cpu.thread_siblings.contains(&housekeeping)))
+            .filter(|cpu| socket_id.is_none() || cpu.socket_id == socket_id)
+            .collect();
+
+        if online_cpus.len() <= 1 {
+            return None; // Don't offline if only the housekeeping CPU or one other CPU is online
+        }
+        online_cpus.retain(|cpu| !self.is_passed_over(cpu.id));
+        if self.full_cores_only {
+            online_cpus.retain(|cpu| !self.has_passed_over_sibling(cpu));
+        }
+        online_cpus.retain(|cpu| !self.is_held(cpu));
+        let single_threads = self.prefer_no_smt || self.no_smt;
+        if single_threads && online_cpus.iter().any(|cpu| self.is_secondary_thread(cpu)) {
+            online_cpus.retain(|cpu| self.is_secondary_thread(cpu));
+        }
+
+        let drain_order = self.socket_drain_order();
+        let socket_rank = |cpu: &CpuInfo| {
+            cpu.socket_id
+                .and_then(|socket| drain_order.iter().position(|&s| s == socket))
+                .unwrap_or(0)
+        };
+        let tier = |cpu: &CpuInfo| (self.capacity_rank(cpu), socket_rank(cpu));
+        let first = online_cpus.iter().map(|cpu| tier(cpu)).min()?;
+        let candidates: Vec<&CpuInfo> = online_cpus
+            .iter()
+            .copied()
+            .filter(|cpu| tier(cpu) == first)
+            .collect();
+        let selected = self.strategy.pick_offline(self, &candidates)?;
+        if single_threads {
+            return Some(vec![selected.id]);
+        }
+        let mut group: Vec<usize> = match (self.granularity, selected.socket_id) {
+            (Granularity::Thread, _) => vec![selected.id],
+            (Granularity::Socket, Some(socket_id)) => online_cpus
+                .iter()
+                .filter(|cpu| cpu.socket_id == Some(socket_id))
+                .map(|cpu| cpu.id)
+                .collect(),
+            _ => selected
+                .thread_siblings
+                .iter()
+                .filter(|&&sibling_id| {
+                    self.cpus
+                        .get(&sibling_id)
+                        .is_some_and(|sibling| sibling.online && sibling.managed)
+                        && !self.is_passed_over(sibling_id)
+                })
+                .copied()
+                .collect(),
+        };
+        group.sort_unstable();
+        Some(group)
+    }
+
+    /// Returns the sockets in the order `offline_order` drains them: the sockets other than the
+    /// housekeeping CPU's first, ranked by online CPUs or load, the highest ID among equals, and the
+    /// housekeeping CPU's socket last. Empty for `OfflineOrder::HighestId`.
+    fn socket_drain_order(&self) -> Vec<usize> {
+        let home = self
+            .cpus
+            .get(&self.housekeeping_cpu)
+            .and_then(|cpu| cpu.socket_id);
+        let mut sockets = self.socket_loads();
+        match self.offline_order {
+            OfflineOrder::HighestId => return Vec::new(),
+            OfflineOrder::RemoteSocket => sockets.sort_by(|a, b| {
+                (Some(a.socket_id) == home, a.online, Reverse(a.socket_id)).cmp(&(
+                    Some(b.socket_id) == home,
+                    b.online,
+                    Reverse(b.socket_id),
+                ))
+            }),
+            OfflineOrder::LeastLoadedSocket => sockets.sort_by(|a, b| {
+                (Some(a.socket_id) == home)
+                    .cmp(&(Some(b.socket_id) == home))
+                    .then(a.avg_c0.total_cmp(&b.avg_c0))
+                    .then(b.socket_id.cmp(&a.socket_id))
+            }),
+        }
+        sockets.into_iter().map(|socket| socket.socket_id).collect()
+    }
+
+    /// Returns the combined C0 percentage of the online CPUs that go with `cpu` when it is offlined:
+    /// the CPU alone with `Granularity::Thread`, in prefer-no-SMT mode, or with `no_smt`, the online CPUs of its
+    /// socket with `Granularity::Socket`, and its online thread siblings otherwise.
+    pub fn group_c0(&self, cpu: &CpuInfo) -> f64 {
+        let group: Vec<usize> = match (self.granularity, cpu.socket_id) {
+            _ if self.prefer_no_smt || self.no_smt => vec![cpu.id],
+            (Granularity::Thread, _) => vec![cpu.id],
+            (Granularity::Socket, Some(socket_id)) => {
+                self.sockets.get(&socket_id).cloned().unwrap_or_default()
+            }
+            _ => cpu.thread_siblings.clone(),
+        };
+        group
+            .iter()
+            .filter_map(|id| self.cpus.get(id))
+            .filter(|cpu| cpu.online)
+            .map(|cpu| cpu.c0_percentage)
+            .sum()
+    }
+
+    /// Returns the number of recently active tasks on the core of `cpu`.
+    pub(crate) fn core_stickiness(&self, cpu: &CpuInfo) -> usize {
+        std::iter::once(&cpu.id)
+            .chain(cpu.thread_siblings.iter().filter(|&&id| id != cpu.id))
+            .filter_map(|id| self.stickiness.get(id))
+            .sum()
+    }
+
+    /// Selects a group of CPUs to be onlined based on their current state and topology.
+    ///
+    /// This function performs the following steps:
+    /// 1. Filters the CPUs to get a list of managed offline CPUs, including the housekeeping CPU if it
+    ///    was found offline, other than those passed over after a failed write this cycle and the
+    ///    blacklisted ones, and in full-cores-only mode the ones with such a thread sibling.
+    /// 2. If all CPUs are already online, returns `None` to avoid onlining.
+    /// 3. Narrows the candidates to the class `prefer_offline` vacates last and then to the last
+    ///    socket in the `offline_order` drain order, so that sockets and classes are refilled in the
+    ///    reverse order they were drained, and lets the `strategy` pick one of them. The default
+    ///    `HighestId` strategy picks the lowest ID.
+    /// 4. In prefer-no-SMT mode, considers only the CPUs of cores without any thread online in step 3,
+    ///    as long as there are any, so every core gets a thread back before a second one. With
+    ///    `no_smt`, never considers the others, so cores of any socket without a thread online are
+    ///    onlined and no second thread ever is.
+    /// 5. Collects the CPUs of the selected CPU's group under `granularity` that are also offline,
+    ///    managed, and neither passed over nor blacklisted: the CPU alone, its thread siblings, or the
+    ///    candidate CPUs of its socket. In prefer-no-SMT mode and with `no_smt`, the group is the
+    ///    selected CPU alone.
+    /// 6. Returns the list of offline CPUs to be onlined.
+    ///
+    /// # Returns
+    /// * `Option<Vec<usize>>` - A vector of CPU IDs to be onlined, or `None` if no CPUs can be onlined.
+    pub fn select_cpu_to_online(&self) -> Option<Vec<usize>> {
+        self.select_online(None)
+    }
+
+    /// Selects a group of CPUs of socket `socket_id` to be onlined, like `select_cpu_to_online` but
+    /// ignoring the CPUs of the other sockets.
+    pub fn select_cpu_to_online_on(&self, socket_id: usize) -> Option<Vec<usize>> {
+        self.select_online(Some(socket_id))
+    }
+
+    fn select_online(&self, socket_id: Option<usize>) -> Option<Vec<usize>> {
+        let mut offline_cpus: Vec<_> = self
+            .cpus
+            .values()
+            .filter(|cpu| !cpu.online && cpu.managed && !self.is_passed_over(cpu.id))
+            .filter(|cpu| !(self.full_cores_only && self.has_passed_over_sibling(cpu)))
+            .filter(|cpu| socket_id.is_none() || cpu.socket_id == socket_id)
+            .collect();
+
+        if self.no_smt {
+            offline_cpus.retain(|cpu| !self.has_online_sibling(cpu));
+        }
+        if offline_cpus.is_empty() {
+            return None; // Don't online if all CPUs are already online
+        }
+        if self.prefer_no_smt && offline_cpus.iter().any(|cpu| !self.has_online_sibling(cpu)) {
+            offline_cpus.retain(|cpu| !self.has_online_sibling(cpu));
+        }
+
+        let drain_order = self.socket_drain_order();
+        let socket_rank = |cpu: &CpuInfo| {
+            cpu.socket_id
+                .and_then(|socket| drain_order.iter().position(|&s| s == socket))
+                .unwrap_or(0)
+        };
+        let tier = |cpu: &CpuInfo| (self.capacity_rank(cpu), socket_rank(cpu));
+        let last = offline_cpus.iter().map(|cpu| tier(cpu)).max()?;
+        let candidates: Vec<&CpuInfo> = offline_cpus
+            .iter()
+            .copied()
+            .filter(|cpu| tier(cpu) == last)
+            .collect();
+        let selected = self.strategy.pick_online(self, &candidates)?;
+        if self.prefer_no_smt || self.no_smt {
+            return Some(vec![selected.id]);
+        }
+        let mut group: Vec<usize> = match (self.granularity, selected.socket_id) {
+            (Granularity::Thread, _) => vec![selected.id],
+            (Granularity::Socket, Some(socket_id)) => offline_cpus
+                .iter()
+                .filter(|cpu| cpu.socket_id == Some(socket_id))
+                .map(|cpu| cpu.id)
+                .collect(),
+            _ => selected
+                .thread_siblings
+                .iter()
+                .filter(|&&sibling_id| {
+                    self.cpus
+                        .get(&sibling_id)
+                        .is_some_and(|sibling| !sibling.online && sibling.managed)
+                        && !self.is_passed_over(sibling_id)
+                })
+                .copied()
+                .collect(),
+        };
+        group.sort_unstable();
+        Some(group)
+    }
+
+    /// Ranks `cpu` by how early `prefer_offline` vacates it, the lowest first: by capacity for
+    /// `Little`, by the inverse capacity for `Big`, and all the same without a preference. CPUs
+    /// without a reported capacity count as big.
+    fn capacity_rank(&self, cpu: &CpuInfo) -> i64 {
+        let capacity = i64::from(cpu.capacity.unwrap_or(SCHED_CAPACITY_SCALE));
+        match self.prefer_offline {
+            Some(CapacityClass::Little) => capacity,
+            Some(CapacityClass::Big) => -capacity,
+            None => 0,
+        }
+    }
+
+    /// Whether a thread sibling of `cpu` with a lower ID is online, making `cpu` a secondary thread.
+    fn is_secondary_thread(&self, cpu: &CpuInfo) -> bool {
+        cpu.thread_siblings
+            .iter()
+            .any(|&id| id < cpu.id && self.cpus.get(&id).is_some_and(|sibling| sibling.online))
+    }
+
+    /// Whether another thread sibling of `cpu` is online.
+    fn has_online_sibling(&self, cpu: &CpuInfo) -> bool {
+        cpu.thread_siblings
+            .iter()
+            .any(|&id| id != cpu.id && self.cpus.get(&id).is_some_and(|sibling| sibling.online))
+    }
+
+    /// Extends `cpu_ids` with all thread siblings of the listed CPUs.
+    pub fn expand_to_cores(&self, cpu_ids: &[usize]) -> Vec<usize> {
+        let mut expanded: Vec<usize> = cpu_ids
+            .iter()
+            .flat_map(|id| {
+                let siblings = self
+                    .cpus
+                    .get(id)
+                    .map(|cpu| cpu.thread_siblings.clone())
+                    .unwrap_or_default();
+                std::iter::once(*id).chain(siblings)
+            })
+            .collect();
+        expanded.sort_unstable();
+        expanded.dedup();
+        expanded
+    }
+
+    /// Returns the managed offline CPUs that have an online thread sibling.
+    pub fn half_core_cpus(&self) -> Vec<usize> {
+        let mut ids: Vec<usize> = self
+            .cpus
+            .values()
+            .filter(|cpu| !cpu.online && cpu.managed)
+            .filter(|cpu| {
+                cpu.thread_siblings
+                    .iter()
+                    .any(|id| self.cpus.get(id).is_some_and(|sibling| sibling.online))
+            })
+            .map(|cpu| cpu.id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Returns whether the daemon may change the state of CPU `id`, reporting it if not.
+    fn is_managed(&self, id: usize) -> bool {
+        let managed = self.cpus.get(&id).is_none_or(|cpu| cpu.managed);
+        if !managed {
+            debug!("Leaving CPU {} alone: it is excluded", id);
+        }
+        managed
+    }
+
+    /// Offlines a group of CPUs, skipping excluded CPUs.
+    ///
+    /// In full-cores-only mode the group is extended to whole cores, and the core of the
+    /// housekeeping CPU is skipped since the housekeeping CPU itself is never offlined.
+    pub async fn offline_cpu_group(&mut self, cpu_ids: &[usize]) -> io::Result<()> {
+        let cpu_ids = if self.full_cores_only {
+            let expanded = self.expand_to_cores(cpu_ids);
+            let housekeeping_core = self.expand_to_cores(&[self.housekeeping_cpu]);
+            let (kept, allowed): (Vec<usize>, Vec<usize>) = expanded
+                .into_iter()
+                .partition(|id| housekeeping_core.contains(id));
+            if !kept.is_empty() && cpu_ids.iter().any(|id| kept.contains(id)) {
+                info!(
+                    "Keeping CPUs {:?} online: they share a core with housekeeping CPU {}",
+                    kept, self.housekeeping_cpu
+                );
+            }
+            allowed
+        } else {
+            cpu_ids.to_vec()
+        };
+        for id in cpu_ids {
+            if id == self.housekeeping_cpu || !self.is_managed(id) {
+                continue;
+            } // Never offline the housekeeping CPU
+            if self.actuation.controls(id) {
+                self.write_online(id, Direction::Offline).await?;
+                info!("Offlined CPU {}", id);
+            } else {
+                warn!("Cannot offline CPU {}: 'online' file does not exist", id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Onlines a group of CPUs, extended to whole cores in full-cores-only mode, skipping excluded CPUs.
+    pub async fn online_cpu_group(&mut self, cpu_ids: &[usize]) -> io::Result<()> {
+        let cpu_ids = if self.full_cores_only {
+            self.expand_to_cores(cpu_ids)
+        } else {
+            cpu_ids.to_vec()
+        };
+        for id in cpu_ids {
+            if !self.is_managed(id) {
+                continue;
+            }
+            if self.actuation.controls(id) {
+                self.write_online(id, Direction::Online).await?;
+                info!("Onlined CPU {}", id);
+            } else if id != self.boot_cpu {
+                warn!("Cannot online CPU {}: 'online' file does not exist", id);
+            } // Without cpu0_hotplug, the boot CPU is always online
+        }
+        Ok(())
+    }
+
+    /// Onlines or offlines CPU `id` through the actuation backend, e.g. by writing its `online`
+    /// file, and records the new state.
+    ///
+    /// This function performs the following steps:
+    /// 1. Settles a previous write that timed out. While it is still in progress, fails right away
+    ///    instead of queueing another write behind it.
+    /// 2. Records the intent in the journal, if any.
+    /// 3. Writes in a separate task and waits up to `hotplug_timeout` for it. While the kernel
+    ///    reports the CPU busy (`EBUSY`), retries up to `HOTPLUG_RETRIES` times, waiting
+    ///    `HOTPLUG_BACKOFF` before the first retry and twice as long before every further one.
+    /// 4. Once the write succeeds, reads the state back and fails if the CPU did not change state.
+    ///    Clears the journal once the write has failed or been verified.
+    /// 5. On timeout, keeps the write as in doubt and fails; the outcome is verified by
+    ///    `settle_in_doubt` once the write completes.
+    ///
+    /// A failed write is counted in `hotplug_failures` and the CPU passed over by the selection
+    /// until the next `begin_cycle`; a verified one resets its count.
+    ///
+    /// # Returns
+    /// * `io::Result<()>` - An error if the write failed, timed out, or is blocked by an earlier one.
+    async fn write_online(&mut self, id: usize, direction: Direction) -> io::Result<()> {
+        if !self.settle_in_doubt().await {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "an earlier hotplug write is still in progress",
+            ));
+        }
+        let online = direction == Direction::Online;
+        self.record_intent(id, direction);
+        let mut backoff = HOTPLUG_BACKOFF;
+        let mut retries = 0;
+        loop {
+            let actuation = Arc::clone(&self.actuation);
+            let mut write = tokio::spawn(async move { actuation.set_online(id, online).await });
+            let result = match tokio::time::timeout(self.hotplug_timeout, &mut write).await {
+                Ok(result) => result.map_err(io::Error::other).and_then(|result| result),
+                Err(_) => {
+                    let error = io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("hotplug write to CPU {} timed out", id),
+                    );
+                    METRICS.hotplug_timeout();
+                    METRICS.sysfs_error(SysfsAccess::OnlineWrite, &error);
+                    warn!(
+                        "Writing the online file of CPU {} did not complete within {:?}, holding further hotplug operations",
+                        id, self.hotplug_timeout
+                    );
+                    self.passed_over.insert(id);
+                    self.in_doubt = Some(InDoubtWrite {
+                        id,
+                        direction,
+                        write,
+                    });
+                    return Err(error);
+                }
+            };
+            match result {
+                Ok(()) => break,
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) && retries < HOTPLUG_RETRIES => {
+                    retries += 1;
+                    METRICS.hotplug_retry();
+                    debug!(
+                        "CPU {} is busy, retrying the {:?} write in {:?}",
+                        id, direction, backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => {
+                    self.clear_intent();
+                    METRICS.hotplug_failure(direction);
+                    METRICS.sysfs_error(SysfsAccess::OnlineWrite, &e);
+                    self.record_failure(id, direction, &e);
+                    return Err(e);
+                }
+            }
+        }
+        let online = Self::is_cpu_online(&*self.actuation, id).await;
+        let configured = Self::read_configured(&*self.sysfs, id).await;
+        self.clear_intent();
+        if let Some(cpu) = self.cpus.get_mut(&id) {
+            cpu.online = online;
+            cpu.configured = configured;
+        }
+        if online != (direction == Direction::Online) {
+            METRICS.hotplug_failure(direction);
+            let error = io::Error::other(format!(
+                "CPU {} is still {} after the write",
+                id,
+                if online { "online" } else { "offline" }
+            ));
+            self.record_failure(id, direction, &error);
+            return Err(error);
+        }
+        self.hotplug_failures.remove(&id);
+        self.blacklist.remove(&id);
+        METRICS.hotplug_transition(direction);
+        self.strategy.hotplugged(id, direction);
+        Ok(())
+    }
+
+    /// Counts a failed hotplug write of CPU `id` and passes the CPU over for the rest of the cycle.
+    /// After `blacklist_after` failures in a row, blacklists it for `blacklist_for`.
+    fn record_failure(&mut self, id: usize, direction: Direction, error: &io::Error) {
+        let failures = self.hotplug_failures.entry(id).or_default();
+        *failures += 1;
+        let failures = *failures;
+        warn!(
+            "Cannot {} CPU {} ({} failures in a row): {}",
+            direction.label(),
+            id,
+            failures,
+            error
+        );
+        self.passed_over.insert(id);
+        if self.blacklist_after > 0 && failures >= self.blacklist_after {
+            warn!(
+                "Blacklisting CPU {} for {:?} after {} failed hotplug writes in a row",
+                id, self.blacklist_for, failures
+            );
+            METRICS.cpu_blacklisted();
+            self.blacklist
+                .insert(id, Instant::now() + self.blacklist_for);
+        }
+    }
+
+    /// Returns whether the selection leaves CPU `id` alone, because its write failed this cycle or
+    /// it is blacklisted.
+    fn is_passed_over(&self, id: usize) -> bool {
+        self.passed_over.contains(&id) || self.blacklist.contains_key(&id)
+    }
+
+    /// Returns whether a thread sibling of `cpu` is passed over, see `is_passed_over`. In
+    /// full-cores-only mode, the hotplug of `cpu` would take such a sibling along.
+    fn has_passed_over_sibling(&self, cpu: &CpuInfo) -> bool {
+        cpu.thread_siblings
+            .iter()
+            .any(|&id| self.is_passed_over(id))
+    }
+
+    /// Returns whether `cpu` or one of its thread siblings is `held`.
+    fn is_held(&self, cpu: &CpuInfo) -> bool {
+        self.held.contains(cpu.id) || cpu.thread_siblings.iter().any(|&id| self.held.contains(id))
+    }
+
+    /// Forgets the CPUs passed over after a failed hotplug write and lifts the blacklisting that
+    /// has lapsed, so the selection considers those CPUs again. Called at the start of every cycle.
+    pub fn begin_cycle(&mut self) {
+        self.passed_over.clear();
+        let now = Instant::now();
+        let mut lapsed: Vec<usize> = self
+            .blacklist
+            .iter()
+            .filter(|&(_, &until)| until <= now)
+            .map(|(&id, _)| id)
+            .collect();
+        lapsed.sort_unstable();
+        for id in lapsed {
+            self.blacklist.remove(&id);
+            info!("CPU {} is no longer blacklisted, trying it again", id);
+        }
+    }
+
+    /// Checks on a hotplug write that timed out. Once it has completed, re-reads the online state of
+    /// the CPU from sysfs, since the write may or may not have taken effect.
+    ///
+    /// # Returns
+    /// * `bool` - Whether no write is in doubt any more, i.e. whether hotplug operations may proceed.
+    pub async fn settle_in_doubt(&mut self) -> bool {
+        match &self.in_doubt {
+            None => return true,
+            Some(in_doubt) if !in_doubt.write.is_finished() => return false,
+            Some(_) => {}
+        }
+        let in_doubt = self.in_doubt.take().unwrap();
+        let online = Self::is_cpu_online(&*self.actuation, in_doubt.id).await;
+        self.clear_intent();
+        let result = in_doubt
+            .write
+            .await
+            .map_err(io::Error::other)
+            .and_then(|result| result);
+        info!(
+            "Late {:?} write to CPU {} completed ({}), CPU {} is {}",
+            in_doubt.direction,
+            in_doubt.id,
+            match &result {
+                Ok(()) => "ok".to_string(),
+                Err(e) => e.to_string(),
+            },
+            in_doubt.id,
+            if online { "online" } else { "offline" }
+        );
+        if let Some(cpu) = self.cpus.get_mut(&in_doubt.id) {
+            if cpu.online != online {
+                METRICS.hotplug_transition(in_doubt.direction);
+            }
+            cpu.online = online;
+        }
+        true
+    }
+
+    /// Records in the journal that CPU `id` is about to be onlined or offlined. A journal that
+    /// cannot be written does not hold up the hotplug.
+    fn record_intent(&self, id: usize, direction: Direction) {
+        if let Some(journal) = &self.journal {
+            if let Err(e) = journal.begin(&Intent::now(id, direction)) {
+                warn!(
+                    "Cannot write the hotplug journal {:?}: {}",
+                    journal.path(),
+                    e
+                );
+            }
+        }
+    }
+
+    fn clear_intent(&self) {
+        if let Some(journal) = &self.journal {
+            if let Err(e) = journal.clear() {
+                warn!(
+                    "Cannot clear the hotplug journal {:?}: {}",
+                    journal.path(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Reconciles a hotplug write that a previous run started but never verified, e.g. because it
+    /// was killed during the write.
+    ///
+    /// This function performs the following steps:
+    /// 1. Reads the intent left in the journal, if any.
+    /// 2. Re-reads the online state of the CPU it names from sysfs, so the state the previous run
+    ///    expected is never assumed, and reports whether the interrupted write took effect.
+    /// 3. Clears the journal.
+    ///
+    /// # Returns
+    /// * `io::Result<Option<(Intent, bool)>>` - The interrupted write and whether the CPU reached the
+    ///   state it asked for, or `None` if no write was interrupted.
+    pub async fn reconcile_journal(&mut self) -> io::Result<Option<(Intent, bool)>> {
+        let Some(journal) = &self.journal else {
+            return Ok(None);
+        };
+        let Some(intent) = journal.pending()? else {
+            return Ok(None);
+        };
+        METRICS.interrupted_hotplug();
+        let online = Self::is_cpu_online(&*self.actuation, intent.cpu).await;
+        let completed = online == (intent.direction == Direction::Online);
+        warn!(
+            "Process {} was interrupted while {} CPU {}; the write {}, CPU {} is {}",
+            intent.pid,
+            if intent.direction == Direction::Online {
+                "onlining"
+            } else {
+                "offlining"
+            },
+            intent.cpu,
+            if completed {
+                "took effect"
+            } else {
+                "did not take effect"
+            },
+            intent.cpu,
+            if online { "online" } else { "offline" }
+        );
+        if let Some(cpu) = self.cpus.get_mut(&intent.cpu) {
+            cpu.online = online;
+        }
+        journal.clear()?;
+        Ok(Some((intent, completed)))
+    }
+
+    /// Returns whether a hotplug write that timed out is still in progress.
+    pub fn hotplug_in_doubt(&self) -> bool {
+        self.in_doubt.is_some()
+    }
+
+    /// Re-reads the online state of every CPU from sysfs, picking up changes made by others.
+    pub async fn refresh_online(&mut self) {
+        for cpu in self.cpus.values_mut() {
+            cpu.online = Self::is_cpu_online(&*self.actuation, cpu.id).await;
+            cpu.configured = Self::read_configured(&*self.sysfs, cpu.id).await;
+        }
+    }
+
+    /// Re-reads the online state of every CPU and updates the ones changed outside the daemon, e.g.
+    /// by an administrator writing an `online` file, so decisions are not taken on stale state. The
+    /// CPU of a write still in doubt is left to `settle_in_doubt`.
+    ///
+    /// # Returns
+    /// * `Vec<(usize, Direction)>` - The CPUs found changed and the way they went, by ID.
+    pub async fn reconcile_online(&mut self) -> Vec<(usize, Direction)> {
+        let in_doubt = self.in_doubt.as_ref().map(|in_doubt| in_doubt.id);
+        let mut changes = Vec::new();
+        for cpu in self.cpus.values_mut() {
+            if Some(cpu.id) == in_doubt {
+                continue;
+            }
+            let online = Self::is_cpu_online(&*self.actuation, cpu.id).await;
+            cpu.configured = Self::read_configured(&*self.sysfs, cpu.id).await;
+            if online != cpu.online {
+                cpu.online = online;
+                changes.push((
+                    cpu.id,
+                    if online {
+                        Direction::Online
+                    } else {
+                        Direction::Offline
+                    },
+                ));
+            }
+        }
+        changes.sort_unstable_by_key(|&(id, _)| id);
+        for &(id, direction) in &changes {
+            METRICS.external_hotplug(direction);
+            warn!(
+                "CPU {} was {} outside the daemon",
+                id,
+                match direction {
+                    Direction::Online => "onlined",
+                    Direction::Offline => "offlined",
+                }
+            );
+        }
+        changes
+    }
+
+    /// Returns every managed CPU to the online state it had when the topology was scanned.
+    ///
+    /// This function performs the following steps:
+    /// 1. Re-reads the online state of the CPUs, so that changes made while paused are seen.
+    /// 2. Onlines the managed CPUs that were online initially but are offline now.
+    /// 3. Offlines the managed CPUs that were offline initially but are online now.
+    ///
+    /// The initial state is restored exactly, even if it had half-online cores in full-cores-only mode.
+    pub async fn restore_initial(&mut self) -> io::Result<()> {
+        self.refresh_online().await;
+        self.full_cores_only = false;
+        let (to_online, to_offline): (Vec<usize>, Vec<usize>) = self
+            .cpus
+            .values()
+            .filter(|cpu| cpu.managed && cpu.online != self.initial_online.contains(&cpu.id))
+            .map(|cpu| cpu.id)
+            .partition(|id| self.initial_online.contains(id));
+        self.online_cpu_group(&to_online).await?;
+        self.offline_cpu_group(&to_offline).await
+    }
+
+    /// Captures the current state of all CPUs.
+    pub fn snapshot(&self) -> TopologySnapshot {
+        let mut cpus: Vec<CpuSnapshot> = self
+            .cpus
+            .values()
+            .map(|cpu| CpuSnapshot {
+                id: cpu.id,
+                core_id: cpu.core_id,
+                socket_id: cpu.socket_id,
+                cluster_id: cpu.cluster_id,
+                capacity: cpu.capacity,
+                online: cpu.online,
+                configured: cpu.configured,
+                managed: cpu.managed,
+                c0_percentage: cpu.c0_percentage,
+                hotplug_failures: self.hotplug_failures.get(&cpu.id).copied().unwrap_or(0),
+                blacklisted: self.blacklist.contains_key(&cpu.id),
+                held: self.held.contains(cpu.id),
+            })
+            .collect();
+        cpus.sort_by_key(|cpu| cpu.id);
+        TopologySnapshot {
+            time: SystemTime::now(),
+            cpus,
+            sockets: self.socket_loads(),
+            average_c0: self.average_c0(),
+            online_count: self.online_count(),
+            no_smt: self.no_smt,
+            boot_cpu: self.boot_cpu,
+            housekeeping_cpu: self.housekeeping_cpu,
+        }
+    }
+
+    /// Turns the topology into a stream of snapshots taken every `interval`, for applications
+    /// that want to observe the CPUs without running the control loop.
+    ///
+    /// This function performs the following steps:
+    /// 1. Selects the cpuidle load metric, or `/proc/stat` if cpuidle is not available.
+    /// 2. Takes a first sample so that every snapshot covers a whole interval.
+    /// 3. On every tick, re-reads the online state of the CPUs, samples their load, and yields a
+    ///    snapshot. A failed sample is reported and the previous loads are kept.
+    ///
+    /// Hotplug operations are never performed. Ticks missed by a slow consumer are delayed
+    /// rather than bunched up.
+    ///
+    /// # Arguments
+    /// * `interval` - The time between two snapshots.
+    ///
+    /// # Returns
+    /// * `impl Stream<Item = TopologySnapshot>` - An endless stream of snapshots.
+    pub fn watch(self, interval: Duration) -> impl Stream<Item = TopologySnapshot> {
+        let metric: Box<dyn LoadMetric> = if CpuidleMetric::is_available(&self) {
+            Box::new(CpuidleMetric::new())
+        } else {
+            Box::new(ProcStatMetric::new())
+        };
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        stream::unfold(
+            (self, metric, ticker, false),
+            |(mut topology, mut metric, mut ticker, primed)| async move {
+                if !primed {
+                    let _ = metric.sample(&topology).await;
+                    ticker.tick().await; // The first tick completes immediately.
+                }
+                ticker.tick().await;
+                topology.refresh_online().await;
+                match metric.sample(&topology).await {
+                    Ok(sample) => topology.apply_sample(&sample),
+                    Err(e) => warn!("Failed to sample the {} load: {}", metric.name(), e),
+                }
+                let snapshot = topology.snapshot();
+                Some((snapshot, (topology, metric, ticker, true)))
+            },
+        )
+    }
+
+    pub fn online_count(&self) -> usize {
+        self.cpus.values().filter(|cpu| cpu.online).count()
+    }
+
+    /// Counts the CPUs that are not excluded.
+    pub fn managed_count(&self) -> usize {
+        self.cpus.values().filter(|cpu| cpu.managed).count()
+    }
+
+    /// Counts the online CPUs that are not excluded.
+    pub fn managed_online_count(&self) -> usize {
+        self.cpus
+            .values()
+            .filter(|cpu| cpu.online && cpu.managed)
+            .count()
+    }
+
+    /// Returns the average C0 percentage across all managed online CPUs, weighted by their capacity.
+    /// On a machine whose CPUs all have the same capacity, this is the plain average.
+    pub fn average_c0(&self) -> f64 {
+        weighted_c0(self.cpus.values().filter(|cpu| cpu.online && cpu.managed))
+    }
+
+    /// Returns the summed capacity of the managed online CPUs, the capacity `average_c0` is spread
+    /// over. CPUs without a reported capacity count as the fastest ones.
+    pub fn online_capacity(&self) -> f64 {
+        self.cpus
+            .values()
+            .filter(|cpu| cpu.online && cpu.managed)
+            .map(|cpu| f64::from(cpu.capacity.unwrap_or(SCHED_CAPACITY_SCALE)))
+            .sum()
+    }
+
+    /// Returns the summed capacity of the managed CPUs, online or not, in the units of `online_capacity`.
+    pub fn managed_capacity(&self) -> f64 {
+        self.cpus
+            .values()
+            .filter(|cpu| cpu.managed)
+            .map(|cpu| f64::from(cpu.capacity.unwrap_or(SCHED_CAPACITY_SCALE)))
+            .sum()
+    }
+
+    /// Returns the summed capacity of `cpus`, in the units of `online_capacity`.
+    pub fn group_capacity(&self, cpus: &[usize]) -> f64 {
+        cpus.iter()
+            .filter_map(|id| self.cpus.get(id))
+            .map(|cpu| f64::from(cpu.capacity.unwrap_or(SCHED_CAPACITY_SCALE)))
+            .sum()
+    }
+
+    pub fn socket_loads(&self) -> Vec<SocketLoad> {
+        let mut loads: Vec<SocketLoad> = self
+            .sockets
+            .iter()
+            .map(|(&socket_id, cpu_ids)| {
+                let online: Vec<&CpuInfo> = cpu_ids
+                    .iter()
+                    .map(|id| &self.cpus[id])
+                    .filter(|cpu| cpu.online)
+                    .collect();
+                let avg_c0 = weighted_c0(online.iter().copied());
+                SocketLoad {
+                    socket_id,
+                    avg_c0,
+                    online: online.len(),
+                    total: cpu_ids.len(),
+                }
+            })
+            .collect();
+        loads.sort_by_key(|load| load.socket_id);
+        loads
+    }
+
+    pub fn print_summary(&self) {
+        info!("System Topology Summary:");
+        info!("Total CPUs: {}", self.cpus.len());
+        info!("Total Sockets: {}", self.sockets.len());
+        info!("CPU0 Socket: {:?}", self.cpu0_socket);
+        info!(
+            "CPU0 hotplug: {}",
+            if self.cpu0_hotpluggable() {
+                "supported"
+            } else {
+                "not supported"
+            }
+        );
+        info!("Boot CPU: {}", self.boot_cpu);
+        info!("Housekeeping CPU: {}", self.housekeeping_cpu);
+        let mut excluded: Vec<usize> = self
+            .cpus
+            .values()
+            .filter(|cpu| !cpu.managed)
+            .map(|cpu| cpu.id)
+            .collect();
+        if !excluded.is_empty() {
+            excluded.sort_unstable();
+            info!("Excluded CPUs: {:?}", excluded);
+        }
+        let mut standby: Vec<usize> = self
+            .cpus
+            .values()
+            .filter(|cpu| cpu.configured == Some(false))
+            .map(|cpu| cpu.id)
+            .collect();
+        if !standby.is_empty() {
+            standby.sort_unstable();
+            info!("Standby CPUs: {:?}", standby);
+        }
+
+        for (&socket_id, cpus) in &self.sockets {
+            info!("Socket {}: {} CPUs", socket_id, cpus.len());
+            let online_cpus = cpus
+                .iter()
+                .filter(|&&cpu_id| self.cpus[&cpu_id].online)
+                .count();
+            info!("  Online CPUs: {}", online_cpus);
+        }
+
+        // Print idle states for CPU0 as an example
+        if let Some(cpu0) = self.cpus.get(&0) {
+            info!("Idle states for CPU0: {:?}", cpu0.idle_states);
+        }
+    }
+}
+
+/// Returns the IDs of the online CPUs.
+fn online_ids(cpus: &HashMap<usize, CpuInfo>) -> BTreeSet<usize> {
+    cpus.values()
+        .filter(|cpu| cpu.online)
+        .map(|cpu| cpu.id)
+        .collect()
+}
+
+/// Returns the average C0 percentage of `cpus`, each weighted by its capacity, or 0 without CPUs.
+/// A busy LITTLE CPU then adds less to the load than a busy big one, as it adds less headroom when
+/// onlined. CPUs without a reported capacity weigh as much as the fastest ones.
+fn weighted_c0<'a>(cpus: impl IntoIterator<Item = &'a CpuInfo>) -> f64 {
+    let (load, capacity) = cpus.into_iter().fold((0.0, 0.0), |(load, capacity), cpu| {
+        let weight = f64::from(cpu.capacity.unwrap_or(SCHED_CAPACITY_SCALE));
+        (load + cpu.c0_percentage * weight, capacity + weight)
+    });
+    if capacity == 0.0 {
+        return 0.0;
+    }
+    load / capacity
+}
+
+/// Returns the boot CPU among the CPUs `ids` of `sysfs`: the lowest CPU without an `online` file,
+/// which the kernel cannot offline. That is CPU0 on most kernels, but some architectures boot on
+/// and pin another CPU. If every CPU can be offlined, e.g. with `cpu0_hotplug`, returns CPU0.
+pub fn boot_cpu(sysfs: &dyn SysfsBackend, ids: &[usize]) -> usize {
+    ids.iter()
+        .copied()
+        .filter(|&id| !sysfs.exists(id, "online"))
+        .min()
+        .unwrap_or(0)
+}
+
+/// Onlines all CPUs of `sysfs` except the `excluded` CPUs through `actuation`. The boot CPU is
+/// skipped when the backend cannot control it, e.g. when the kernel cannot offline it.
+pub async fn online_all_cpus(
+    sysfs: &dyn SysfsBackend,
+    actuation: &dyn ActuationBackend,
+    excluded: &CpuList,
+) -> io::Result<()> {
+    let ids = sysfs.cpu_ids().await?;
+    let boot_cpu = boot_cpu(sysfs, &ids);
+    for id in ids {
+        if excluded.contains(id) {
+            continue;
+        } // Skip CPUs the daemon must not touch
+        if actuation.controls(id) {
+            actuation
+                .set_online(id, true)
+                .await
+                .inspect_err(|e| METRICS.sysfs_error(SysfsAccess::OnlineWrite, e))?;
+            info!("Onlined CPU {}", id);
+        } else if id != boot_cpu {
+            warn!("Cannot online CPU {}: 'online' file does not exist", id);
+        } // Without cpu0_hotplug, the boot CPU is always online
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sysfs::MockSysfs;
+
+    /// Builds a single-socket machine with `cores` cores of two SMT siblings each, numbered the
+    /// way Linux does on x86 (CPU `n` and `n + cores` share a core).
+    fn smt_topology(cores: usize) -> SystemTopology {
+        SystemTopology::from_cpus((0..cores * 2).map(|id| {
+            let core = id % cores;
+            CpuInfo {
+                id,
+                core_id: Some(core),
+                socket_id: Some(0),
+                thread_siblings: vec![core, core + cores],
+                cluster_id: None,
+                capacity: None,
+                c0_percentage: 0.0,
+                online: true,
+                configured: None,
+                idle_states: Vec::new(),
+                managed: true,
+            }
+        }))
+    }
+
+    fn set_online(topology: &mut SystemTopology, ids: &[usize], online: bool) {
+        for id in ids {
+            topology.cpus.get_mut(id).unwrap().online = online;
+        }
+    }
+
+    #[test]
+    fn offline_selects_sibling_group_of_highest_cpu() {
+        let topology = smt_topology(4);
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![3, 7]));
+    }
+
+    #[test]
+    fn offline_prefers_the_core_with_the_fewest_active_tasks() {
+        let mut topology = smt_topology(4);
+        topology.stickiness = HashMap::from([(0, 3), (1, 1), (3, 2), (7, 1)]);
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![2, 6]));
+    }
+
+    #[test]
+    fn offline_skips_siblings_that_are_already_offline() {
+        let mut topology = smt_topology(4);
+        set_online(&mut topology, &[7], false);
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![2, 6]));
+    }
+
+    #[test]
+    fn offline_keeps_cpu0_and_one_other_cpu() {
+        let mut topology = smt_topology(2);
+        set_online(&mut topology, &[1, 3], false);
+        assert_eq!(topology.select_cpu_to_offline(), None);
+    }
+
+    /// Builds a machine of `cpus` CPUs without SMT, numbered round-robin across `sockets` sockets.
+    fn round_robin_topology(cpus: usize, sockets: usize) -> SystemTopology {
+        SystemTopology::from_cpus((0..cpus).map(|id| CpuInfo {
+            id,
+            core_id: Some(id / sockets),
+            socket_id: Some(id % sockets),
+            thread_siblings: vec![id],
+            cluster_id: None,
+            capacity: None,
+            c0_percentage: 0.0,
+            online: true,
+            configured: None,
+            idle_states: Vec::new(),
+            managed: true,
+        }))
+    }
+
+    #[test]
+    fn remote_socket_order_drains_one_socket_at_a_time() {
+        let mut topology = round_robin_topology(8, 2);
+        topology.offline_order = OfflineOrder::RemoteSocket;
+        let mut offlined = Vec::new();
+        while let Some(group) = topology.select_cpu_to_offline() {
+            set_online(&mut topology, &group, false);
+            offlined.extend(group);
+        }
+        assert_eq!(offlined, [7, 5, 3, 1, 6, 4]);
+
+        let mut onlined = Vec::new();
+        while let Some(group) = topology.select_cpu_to_online() {
+            set_online(&mut topology, &group, true);
+            onlined.extend(group);
+        }
+        assert_eq!(onlined, [4, 6, 1, 3, 5, 7]);
+    }
+
+    #[test]
+    fn least_loaded_socket_order_follows_the_load() {
+        let mut topology = round_robin_topology(6, 3);
+        topology.offline_order = OfflineOrder::LeastLoadedSocket;
+        for (id, load) in [(1, 10.0), (4, 10.0), (2, 50.0), (5, 50.0)] {
+            topology.cpus.get_mut(&id).unwrap().c0_percentage = load;
+        }
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![4]));
+        topology.offline_order = OfflineOrder::HighestId;
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![5]));
+    }
+
+    #[test]
+    fn granularity_sets_the_size_of_the_groups() {
+        let mut topology = smt_topology(4);
+        topology.granularity = Granularity::Thread;
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![7]));
+        set_online(&mut topology, &[3, 7], false);
+        assert_eq!(topology.select_cpu_to_online(), Some(vec![3]));
+
+        let mut topology = round_robin_topology(8, 2);
+        topology.granularity = Granularity::Socket;
+        topology.offline_order = OfflineOrder::RemoteSocket;
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![1, 3, 5, 7]));
+        set_online(&mut topology, &[1, 3, 5, 7], false);
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![2, 4, 6]));
+        assert_eq!(topology.select_cpu_to_online(), Some(vec![1, 3, 5, 7]));
+    }
+
+    #[test]
+    fn prefer_no_smt_sheds_second_threads_before_cores() {
+        let mut topology = smt_topology(3);
+        topology.prefer_no_smt = true;
+        let mut offlined = Vec::new();
+        while let Some(group) = topology.select_cpu_to_offline() {
+            set_online(&mut topology, &group, false);
+            offlined.extend(group);
+        }
+        assert_eq!(offlined, [5, 4, 3, 2]);
+
+        let mut onlined = Vec::new();
+        while let Some(group) = topology.select_cpu_to_online() {
+            set_online(&mut topology, &group, true);
+            onlined.extend(group);
+        }
+        assert_eq!(onlined, [2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn no_smt_never_onlines_a_second_thread() {
+        let mut topology = smt_topology(3);
+        topology.no_smt = true;
+        assert_eq!(
+            topology.online_all_exclusions().0,
+            BTreeSet::from([3, 4, 5])
+        );
+        let mut offlined = Vec::new();
+        while let Some(group) = topology.select_cpu_to_offline() {
+            set_online(&mut topology, &group, false);
+            offlined.extend(group);
+        }
+        assert_eq!(offlined, [5, 4, 3, 2]);
+
+        let mut onlined = Vec::new();
+        while let Some(group) = topology.select_cpu_to_online() {
+            set_online(&mut topology, &group, true);
+            onlined.extend(group);
+        }
+        assert_eq!(onlined, [2]);
+    }
+
+    #[test]
+    fn online_selects_sibling_group_of_lowest_offline_cpu() {
+        let mut topology = smt_topology(4);
+        set_online(&mut topology, &[2, 3, 6, 7], false);
+        assert_eq!(topology.select_cpu_to_online(), Some(vec![2, 6]));
+    }
+
+    #[test]
+    fn online_returns_none_when_everything_is_online() {
+        let topology = smt_topology(4);
+        assert_eq!(topology.select_cpu_to_online(), None);
+    }
+
+    #[test]
+    fn average_c0_weights_cpus_by_capacity() {
+        let mut topology = round_robin_topology(4, 1);
+        for (id, capacity, c0) in [
+            (0, 1024, 20.0),
+            (1, 1024, 20.0),
+            (2, 512, 80.0),
+            (3, 512, 80.0),
+        ] {
+            let cpu = topology.cpus.get_mut(&id).unwrap();
+            cpu.capacity = Some(capacity);
+            cpu.c0_percentage = c0;
+        }
+        assert!((topology.average_c0() - 40.0).abs() < 1e-9);
+        assert!((topology.socket_loads()[0].avg_c0 - 40.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn average_c0_only_counts_online_cpus() {
+        let mut topology = smt_topology(2);
+        for (id, c0) in [(0, 80.0), (1, 40.0), (2, 60.0), (3, 100.0)] {
+            topology.cpus.get_mut(&id).unwrap().c0_percentage = c0;
+        }
+        set_online(&mut topology, &[3], false);
+        assert_eq!(topology.online_count(), 3);
+        assert!((topology.average_c0() - 60.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn full_cores_only_never_selects_the_core_of_cpu0() {
+        let mut topology = smt_topology(2);
+        topology.full_cores_only = true;
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![1, 3]));
+        set_online(&mut topology, &[1, 3], false);
+        assert_eq!(topology.select_cpu_to_offline(), None);
+    }
+
+    #[test]
+    fn another_housekeeping_cpu_lets_cpu0_be_offlined() {
+        let mut topology = smt_topology(2);
+        topology.housekeeping_cpu = 1;
+        set_online(&mut topology, &[2, 3], false);
+        assert_eq!(topology.select_cpu_to_offline(), None);
+        set_online(&mut topology, &[2], true);
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![0, 2]));
+
+        topology.full_cores_only = true;
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![0, 2]));
+        set_online(&mut topology, &[1], false);
+        assert_eq!(topology.select_cpu_to_offline(), None);
+        assert_eq!(topology.select_cpu_to_online(), Some(vec![1, 3]));
+    }
+
+    #[test]
+    fn finds_and_expands_half_online_cores() {
+        let mut topology = smt_topology(4);
+        set_online(&mut topology, &[3, 6, 7], false);
+        assert_eq!(topology.half_core_cpus(), vec![6]);
+        assert_eq!(topology.expand_to_cores(&[6, 7]), vec![2, 3, 6, 7]);
+    }
+
+    #[test]
+    fn excluded_cpus_are_never_selected_or_averaged() {
+        let mut topology = smt_topology(4);
+        for id in [3, 7] {
+            topology.cpus.get_mut(&id).unwrap().managed = false;
+        }
+        topology.cpus.get_mut(&7).unwrap().c0_percentage = 100.0;
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![2, 6]));
+        assert_eq!(topology.average_c0(), 0.0);
+
+        set_online(&mut topology, &[3, 7], false);
+        assert_eq!(topology.select_cpu_to_online(), None);
+    }
+    #[test]
+    fn snapshot_orders_cpus_and_summarizes_the_load() {
+        let mut topology = smt_topology(2);
+        set_online(&mut topology, &[3], false);
+        topology.cpus.get_mut(&1).unwrap().c0_percentage = 60.0;
+        let snapshot = topology.snapshot();
+        let ids: Vec<usize> = snapshot.cpus.iter().map(|cpu| cpu.id).collect();
+        assert_eq!(ids, [0, 1, 2, 3]);
+        assert!(!snapshot.cpus[3].online);
+        assert_eq!(snapshot.online_count, 3);
+        assert_eq!(snapshot.average_c0, 20.0);
+        assert_eq!(snapshot.sockets[0].online, 3);
+    }
+
+    #[tokio::test]
+    async fn builder_scans_a_fake_sysfs_tree() {
+        let root = std::env::temp_dir().join(format!("cpu-on-off-sysfs-{}", std::process::id()));
+        for (id, online) in [(0, None), (1, Some("1")), (2, Some("0"))] {
+            let topology_dir = root.join(format!("cpu{}", id)).join("topology");
+            std::fs::create_dir_all(&topology_dir).unwrap();
+            std::fs::write(topology_dir.join("core_id"), format!("{}\n", id)).unwrap();
+            std::fs::write(topology_dir.join("physical_package_id"), "0\n").unwrap();
+            std::fs::write(
+                topology_dir.join("thread_siblings_list"),
+                format!("{}\n", id),
+            )
+            .unwrap();
+            if let Some(online) = online {
+                std::fs::write(root.join(format!("cpu{}", id)).join("online"), online).unwrap();
+            }
+        }
+        std::fs::create_dir_all(root.join("cpufreq")).unwrap();
+
+        let mut topology = SystemTopology::builder()
+            .cpu_dir(&root)
+            .exclude_cpus("2".parse().unwrap())
+            .full_cores_only(true)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(topology.cpus.len(), 3);
+        assert_eq!(topology.online_count(), 2);
+        assert!(!topology.cpus[&2].managed);
+        assert!(topology.full_cores_only);
+
+        topology.offline_cpu_group(&[1]).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("cpu1/online")).unwrap(),
+            "0"
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restores_the_initial_online_state() {
+        let root = std::env::temp_dir().join(format!("cpu-on-off-restore-{}", std::process::id()));
+        for (id, online) in [(1, "1"), (2, "0"), (3, "1")] {
+            let cpu_dir = root.join(format!("cpu{}", id));
+            std::fs::create_dir_all(&cpu_dir).unwrap();
+            std::fs::write(cpu_dir.join("online"), online).unwrap();
+        }
+        let mut topology = SystemTopology::builder()
+            .cpu_dir(&root)
+            .exclude_cpus("3".parse().unwrap())
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(topology.initial_online, BTreeSet::from([1, 3]));
+
+        for (id, online) in [(1, "0"), (2, "1"), (3, "0")] {
+            std::fs::write(root.join(format!("cpu{}/online", id)), online).unwrap();
+        }
+        topology.restore_initial().await.unwrap();
+        let state = |id| std::fs::read_to_string(root.join(format!("cpu{}/online", id))).unwrap();
+        assert_eq!([state(1), state(2), state(3)], ["1", "0", "0"]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn onlines_all_cpus_below_a_sysfs_root() {
+        let root = std::env::temp_dir().join(format!("cpu-on-off-root-{}", std::process::id()));
+        let cpu_dir = root.join("devices/system/cpu");
+        for (id, online) in [(1, "0"), (2, "0"), (3, "0")] {
+            std::fs::create_dir_all(cpu_dir.join(format!("cpu{}", id))).unwrap();
+            std::fs::write(cpu_dir.join(format!("cpu{}/online", id)), online).unwrap();
+        }
+        let topology = SystemTopology::builder()
+            .sysfs_root(&root)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(topology.online_count(), 0);
+
+        online_all_cpus(
+            &*topology.sysfs,
+            &*topology.actuation,
+            &"3".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        let state =
+            |id| std::fs::read_to_string(cpu_dir.join(format!("cpu{}/online", id))).unwrap();
+        assert_eq!([state(1), state(2), state(3)], ["1", "1", "0"]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn selects_and_hotplugs_through_a_mock_backend() {
+        let sysfs = Arc::new(
+            MockSysfs::new()
+                .with_cpu(0, 0, 0, "0,2")
+                .with_cpu(1, 1, 0, "1,3")
+                .with_cpu(2, 0, 0, "0,2")
+                .with_cpu(3, 1, 0, "1,3"),
+        );
+        let mut topology = SystemTopology::builder()
+            .backend(sysfs.clone())
+            .build()
+            .await
+            .unwrap();
+        assert!(!topology.cpu0_hotpluggable());
+        assert_eq!(topology.cpus[&3].thread_siblings, [1, 3]);
+
+        let group = topology.select_cpu_to_offline().unwrap();
+        assert_eq!(group, [1, 3]);
+        topology.offline_cpu_group(&group).await.unwrap();
+        assert_eq!(sysfs.get(3, "online").as_deref(), Some("0"));
+        assert_eq!(topology.online_count(), 2);
+
+        sysfs.set(1, "online", "1");
+        topology.refresh_online().await;
+        assert_eq!(topology.half_core_cpus(), [3]);
+    }
+
+    #[tokio::test]
+    async fn retries_busy_writes_and_passes_over_failing_cpus() {
+        let sysfs = Arc::new(
+            MockSysfs::new()
+                .with_cpu(0, 0, 0, "0")
+                .with_cpu(1, 1, 0, "1")
+                .with_cpu(2, 2, 0, "2")
+                .with_cpu(3, 3, 0, "3"),
+        );
+        let mut topology = SystemTopology::builder()
+            .backend(sysfs.clone())
+            .build()
+            .await
+            .unwrap();
+
+        // A CPU that is busy for fewer writes than there are retries is offlined all the same.
+        sysfs.fail_writes(2, "online", libc::EBUSY, HOTPLUG_RETRIES);
+        topology.offline_cpu_group(&[2]).await.unwrap();
+        assert_eq!(sysfs.get(2, "online").as_deref(), Some("0"));
+        assert!(topology.hotplug_failures.is_empty());
+
+        // A CPU that keeps failing is counted and passed over for the rest of the cycle.
+        sysfs.fail_writes(2, "online", libc::EBUSY, HOTPLUG_RETRIES + 1);
+        assert!(topology.online_cpu_group(&[2]).await.is_err());
+        assert_eq!(topology.hotplug_failures[&2], 1);
+        assert_eq!(topology.snapshot().cpus[2].hotplug_failures, 1);
+        assert_eq!(topology.select_cpu_to_online(), None);
+        sysfs.fail_writes(3, "online", libc::EIO, 1);
+        assert!(topology.offline_cpu_group(&[3]).await.is_err());
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![1]));
+
+        topology.begin_cycle();
+        assert_eq!(topology.select_cpu_to_online(), Some(vec![2]));
+        topology.online_cpu_group(&[2]).await.unwrap();
+        assert!(!topology.hotplug_failures.contains_key(&2));
+    }
+
+    #[tokio::test]
+    async fn blacklists_a_cpu_that_keeps_failing_until_it_lapses() {
+        let sysfs = Arc::new(
+            MockSysfs::new()
+                .with_cpu(0, 0, 0, "0")
+                .with_cpu(1, 1, 0, "1")
+                .with_cpu(2, 2, 0, "2")
+                .with_cpu(3, 3, 0, "3"),
+        );
+        let mut topology = SystemTopology::builder()
+            .backend(sysfs.clone())
+            .blacklist(2, Duration::from_millis(50))
+            .build()
+            .await
+            .unwrap();
+
+        sysfs.fail_writes(3, "online", libc::EIO, 3);
+        assert!(topology.offline_cpu_group(&[3]).await.is_err());
+        topology.begin_cycle();
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![3]));
+        assert!(topology.offline_cpu_group(&[3]).await.is_err());
+        topology.begin_cycle();
+        assert!(topology.snapshot().cpus[3].blacklisted);
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![2]));
+
+        // Once lapsed, a single further failure blacklists the CPU again.
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        topology.begin_cycle();
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![3]));
+        assert!(topology.offline_cpu_group(&[3]).await.is_err());
+        topology.begin_cycle();
+        assert!(topology.blacklist.contains_key(&3));
+
+        // A successful write clears the record.
+        topology.offline_cpu_group(&[3]).await.unwrap();
+        assert!(topology.blacklist.is_empty());
+        assert!(topology.hotplug_failures.is_empty());
+    }
+
+    #[tokio::test]
+    async fn leaves_a_blacklisted_thread_out_of_its_core() {
+        let sysfs = Arc::new(
+            MockSysfs::new()
+                .with_cpu(0, 0, 0, "0,2")
+                .with_cpu(1, 1, 0, "1,3")
+                .with_cpu(2, 0, 0, "0,2")
+                .with_cpu(3, 1, 0, "1,3"),
+        );
+        let mut topology = SystemTopology::builder()
+            .backend(sysfs.clone())
+            .blacklist(1, Duration::from_secs(60))
+            .build()
+            .await
+            .unwrap();
+
+        sysfs.fail_writes(1, "online", libc::EIO, 1);
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![1, 3]));
+        assert!(topology.offline_cpu_group(&[1, 3]).await.is_err());
+        topology.begin_cycle();
+        assert!(topology.snapshot().cpus[1].blacklisted);
+        // Whole cores would take the blacklisted thread along.
+        topology.full_cores_only = true;
+        assert_eq!(topology.select_cpu_to_offline(), None);
+        topology.full_cores_only = false;
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![3]));
+        topology.offline_cpu_group(&[3]).await.unwrap();
+        assert_eq!(sysfs.get(1, "online").as_deref(), Some("1"));
+    }
+
+    #[tokio::test]
+    async fn keeps_a_boot_cpu_other_than_cpu0_online() {
+        let sysfs = Arc::new(
+            MockSysfs::new()
+                .with_cpu(0, 0, 0, "0")
+                .with_cpu(1, 1, 0, "1")
+                .with_cpu(2, 2, 0, "2")
+                .with_cpu(3, 3, 0, "3"),
+        );
+        sysfs.set(0, "online", "1");
+        sysfs.remove(2, "online");
+        let mut topology = SystemTopology::builder()
+            .backend(sysfs.clone())
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(topology.boot_cpu, 2);
+        assert_eq!(topology.housekeeping_cpu, 2);
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![3]));
+        topology.offline_cpu_group(&[3, 2, 0]).await.unwrap();
+        assert_eq!(sysfs.get(0, "online").as_deref(), Some("0"));
+        assert_eq!(topology.select_cpu_to_offline(), None);
+
+        // Another housekeeping CPU needs a boot CPU the kernel can offline.
+        let error = SystemTopology::builder()
+            .backend(sysfs.clone())
+            .housekeeping_cpu(Some(1))
+            .build()
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn leaves_isolated_and_nohz_full_cpus_unmanaged() {
+        let sysfs = Arc::new(
+            MockSysfs::new()
+                .with_cpu(0, 0, 0, "0")
+                .with_cpu(1, 1, 0, "1")
+                .with_cpu(2, 2, 0, "2")
+                .with_cpu(3, 3, 0, "3"),
+        );
+        sysfs.set_global("isolated", "3");
+        sysfs.set_global("nohz_full", "(null)");
+        let topology = SystemTopology::builder()
+            .backend(sysfs.clone())
+            .build()
+            .await
+            .unwrap();
+        assert!(!topology.cpus[&3].managed);
+        assert_eq!(topology.select_cpu_to_offline(), Some(vec![2]));
+
+        sysfs.set_global("nohz_full", "2-3");
+        assert_eq!(
+            SystemTopology::read_isolated(&*sysfs).await.to_string(),
+            "2,3"
+        );
+        let topology = SystemTopology::builder()
+            .backend(sysfs)
+            .manage_isolated(true)
+            .build()
+            .await
+            .unwrap();
+        assert!(topology.cpus.values().all(|cpu| cpu.managed));
+    }
+
+    #[tokio::test]
+    async fn reconciles_a_write_interrupted_by_a_crash() {
+        let path =
+            std::env::temp_dir().join(format!("cpu-on-off-reconcile-{}.json", std::process::id()));
+        let journal = HotplugJournal::new(&path);
+        let sysfs = Arc::new(
+            MockSysfs::new()
+                .with_cpu(0, 0, 0, "0")
+                .with_cpu(1, 1, 0, "1")
+                .with_cpu(2, 2, 0, "2"),
+        );
+        let mut topology = SystemTopology::builder()
+            .backend(sysfs.clone())
+            .journal(journal.clone())
+            .build()
+            .await
+            .unwrap();
+        topology.offline_cpu_group(&[2]).await.unwrap();
+        assert_eq!(journal.pending().unwrap(), None);
+
+        // The previous run died after asking for CPU 1 to go offline; the kernel never did it.
+        journal.begin(&Intent::now(1, Direction::Offline)).unwrap();
+        let (intent, completed) = topology.reconcile_journal().await.unwrap().unwrap();
+        assert_eq!(intent.cpu, 1);
+        assert!(!completed);
+        assert!(topology.cpus[&1].online);
+        assert_eq!(journal.pending().unwrap(), None);
+        assert!(topology.reconcile_journal().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn holds_hotplug_behind_a_stuck_write() {
+        let root = std::env::temp_dir().join(format!("cpu-on-off-stuck-{}", std::process::id()));
+        let online_path = root.join("cpu1/online");
+        std::fs::create_dir_all(root.join("cpu1")).unwrap();
+        std::fs::write(&online_path, "1").unwrap();
+        let mut topology = SystemTopology::builder()
+            .cpu_dir(&root)
+            .hotplug_timeout(Duration::from_millis(50))
+            .build()
+            .await
+            .unwrap();
+
+        // Opening a FIFO for writing blocks until a reader shows up, like a stuck hotplug.
+        std::fs::remove_file(&online_path).unwrap();
+        let status = std::process::Command::new("mkfifo")
+            .arg(&online_path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let err = topology.offline_cpu_group(&[1]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let err = topology.offline_cpu_group(&[1]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(topology.hotplug_in_doubt());
+
+        assert_eq!(std::fs::read_to_string(&online_path).unwrap(), "0");
+        std::fs::remove_file(&online_path).unwrap();
+        std::fs::write(&online_path, "0").unwrap();
+        while !topology.settle_in_doubt().await {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!topology.cpus[&1].online);
+        topology.online_cpu_group(&[1]).await.unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn snapshot_round_trips_through_json() {
+        let snapshot = smt_topology(2).snapshot();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let decoded: TopologySnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.cpus, snapshot.cpus);
+        assert_eq!(decoded.time, snapshot.time);
+    }
+}
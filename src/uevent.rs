@@ -0,0 +1,196 @@
+//! Kernel uevents of the `cpu` subsystem, for `--uevents`.
+//!
+//! The kernel broadcasts a uevent on the `NETLINK_KOBJECT_UEVENT` socket whenever a device is
+//! added, removed, onlined, or offlined, including the CPUs below `/devices/system/cpu`. Each
+//! message is an `ACTION@DEVPATH` header followed by `KEY=VALUE` pairs, all NUL-terminated:
+//!
+//! ```text
+//! offline@/devices/system/cpu/cpu3\0ACTION=offline\0DEVPATH=/devices/system/cpu/cpu3\0SUBSYSTEM=cpu\0SEQNUM=4242\0
+//! ```
+//!
+//! With `--uevents`, the CPU manager picks up a CPU hotplugged by another agent, or a CPU hot-added
+//! to or removed from the system, as soon as the kernel reports it instead of at its next cycle or
+//! rescan.
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+/// The multicast group the kernel sends its uevents to, as opposed to the ones relayed by udev.
+const KERNEL_GROUP: u32 = 1;
+
+/// Largest uevent the kernel sends, `UEVENT_BUFFER_SIZE`.
+const UEVENT_BUFFER_SIZE: usize = 2048;
+
+/// What happened to a CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UeventAction {
+    /// The CPU was hot-added to the system.
+    Add,
+    /// The CPU was removed from the system.
+    Remove,
+    /// The CPU was onlined.
+    Online,
+    /// The CPU was offlined.
+    Offline,
+}
+
+/// A uevent of a single CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuUevent {
+    pub action: UeventAction,
+    pub cpu: usize,
+}
+
+/// Parses a kernel uevent message.
+///
+/// # Returns
+/// * `Option<CpuUevent>` - The event, or `None` for devices other than CPUs and for actions other
+///   than `add`, `remove`, `online`, and `offline`, e.g. `change`.
+pub fn parse(message: &[u8]) -> Option<CpuUevent> {
+    let mut action = None;
+    let mut devpath = None;
+    let mut subsystem = None;
+    for field in message.split(|&byte| byte == 0) {
+        let field = std::str::from_utf8(field).ok()?;
+        if let Some((key, value)) = field.split_once('=') {
+            match key {
+                "ACTION" => action = Some(value),
+                "DEVPATH" => devpath = Some(value),
+                "SUBSYSTEM" => subsystem = Some(value),
+                _ => {}
+            }
+        }
+    }
+    if subsystem != Some("cpu") {
+        return None;
+    }
+    let action = match action? {
+        "add" => UeventAction::Add,
+        "remove" => UeventAction::Remove,
+        "online" => UeventAction::Online,
+        "offline" => UeventAction::Offline,
+        _ => return None,
+    };
+    let cpu = devpath?
+        .strip_prefix("/devices/system/cpu/cpu")?
+        .parse()
+        .ok()?;
+    Some(CpuUevent { action, cpu })
+}
+
+/// Opens a netlink socket subscribed to the kernel's uevents.
+fn open_socket() -> io::Result<OwnedFd> {
+    // Safety: plain socket creation, the descriptor is owned right away.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+            libc::NETLINK_KOBJECT_UEVENT,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safety: `fd` is a new descriptor that nothing else owns.
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    // Safety: `sockaddr_nl` is a plain struct, for which all zeroes is a valid value.
+    let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    addr.nl_groups = KERNEL_GROUP;
+    // Safety: `addr` is a valid `sockaddr_nl` of the size passed along with it.
+    let result = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+/// Subscribes to the kernel's uevents and forwards those of CPUs until the receiver is dropped.
+/// A failure to read is logged and ends the subscription.
+///
+/// # Returns
+/// * `io::Result<mpsc::UnboundedReceiver<CpuUevent>>` - The CPU events, or an error if the
+///   netlink socket cannot be opened, e.g. in a network namespace without uevents.
+pub fn watch_cpu_uevents() -> io::Result<mpsc::UnboundedReceiver<CpuUevent>> {
+    let socket = AsyncFd::new(open_socket()?)?;
+    let (sender, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut buffer = [0u8; UEVENT_BUFFER_SIZE];
+        loop {
+            let read = tokio::select! {
+                _ = sender.closed() => return,
+                guard = socket.readable() => match guard {
+                    Ok(mut guard) => guard.try_io(|socket| {
+                        // Safety: `buffer` is valid for writes of its length.
+                        let read = unsafe {
+                            libc::recv(
+                                socket.as_raw_fd(),
+                                buffer.as_mut_ptr() as *mut libc::c_void,
+                                buffer.len(),
+                                0,
+                            )
+                        };
+                        if read < 0 {
+                            Err(io::Error::last_os_error())
+                        } else {
+                            Ok(read as usize)
+                        }
+                    }),
+                    Err(e) => Ok(Err(e)),
+                },
+            };
+            let length = match read {
+                Ok(Ok(length)) => length,
+                Ok(Err(e)) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                    warn!("Missed uevents: the receive buffer overflowed");
+                    continue;
+                }
+                Ok(Err(e)) => {
+                    warn!("Cannot read uevents, relying on polling: {}", e);
+                    return;
+                }
+                Err(_would_block) => continue,
+            };
+            if let Some(event) = parse(&buffer[..length]) {
+                debug!("uevent: CPU {} {:?}", event.cpu, event.action);
+                if sender.send(event).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cpu_events_and_skips_others() {
+        let offline = b"offline@/devices/system/cpu/cpu3\0ACTION=offline\0DEVPATH=/devices/system/cpu/cpu3\0SUBSYSTEM=cpu\0SEQNUM=4242\0";
+        assert_eq!(
+            parse(offline),
+            Some(CpuUevent {
+                action: UeventAction::Offline,
+                cpu: 3
+            })
+        );
+        let add = b"add@/devices/system/cpu/cpu16\0ACTION=add\0DEVPATH=/devices/system/cpu/cpu16\0SUBSYSTEM=cpu\0";
+        assert_eq!(parse(add).unwrap().action, UeventAction::Add);
+        let change = b"change@/devices/system/cpu/cpu3\0ACTION=change\0DEVPATH=/devices/system/cpu/cpu3\0SUBSYSTEM=cpu\0";
+        assert_eq!(parse(change), None);
+        let disk = b"add@/devices/virtual/block/loop0\0ACTION=add\0DEVPATH=/devices/virtual/block/loop0\0SUBSYSTEM=block\0";
+        assert_eq!(parse(disk), None);
+    }
+}
@@ -0,0 +1,226 @@
+//! Requests to a host-side companion to hot-unplug and hot-plug the vCPUs of a KVM guest, for
+//! `--vcpu-companion`.
+//!
+//! Offlining a vCPU inside a guest only parks its thread on the host: the vCPU stays assigned to
+//! the guest and counts against the host's capacity. With `--vcpu-companion`, the CPU manager
+//! tells a companion on the host, e.g. a small service calling `virsh setvcpu --live` or QEMU's
+//! `device_del`, which vCPUs it has offlined and how many it wants, whenever that changes:
+//!
+//! ```text
+//! POST /vcpus HTTP/1.1
+//! Content-Type: application/json
+//!
+//! {"guest":"web-1","online":[0,1,2,3],"offline":[4,5,6,7],"wanted_vcpus":4}
+//! ```
+//!
+//! The companion unplugs the offline vCPUs, releasing them to the host. When the load calls for
+//! more CPUs than the guest has left, `wanted_vcpus` exceeds the online ones, and the companion
+//! plugs vCPUs back in; the CPU manager picks them up on its next rescan, see `--rescan-secs` and
+//! `--uevents`.
+use crate::http;
+use crate::queue::{self, QueueSender};
+use crate::topology::SystemTopology;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Number of requests buffered before the oldest ones are dropped; only the latest one matters.
+const QUEUE_LEN: usize = 4;
+
+/// Time between two attempts to deliver a request the companion did not accept.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Longest time spent delivering the last request on shutdown.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The vCPUs of the guest as sent to the companion.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct VcpuRequest {
+    /// The guest's host name, so one companion can serve several guests.
+    pub guest: String,
+    /// The online CPUs, by ID.
+    pub online: Vec<usize>,
+    /// The offline CPUs the companion may unplug, by ID. CPUs excluded from management are left
+    /// out, since the daemon did not offline them.
+    pub offline: Vec<usize>,
+    /// The number of vCPUs the guest wants online.
+    pub wanted_vcpus: usize,
+}
+
+impl VcpuRequest {
+    /// Describes the vCPUs of `topology`. With `wants_more`, the load calls for more CPUs than are
+    /// left to online, and one more vCPU than is online is wanted.
+    pub fn new(guest: &str, topology: &SystemTopology, wants_more: bool) -> Self {
+        let mut online = Vec::new();
+        let mut offline = Vec::new();
+        for cpu in topology.cpus.values() {
+            if cpu.online {
+                online.push(cpu.id);
+            } else if cpu.managed {
+                offline.push(cpu.id);
+            }
+        }
+        online.sort_unstable();
+        offline.sort_unstable();
+        VcpuRequest {
+            guest: guest.to_string(),
+            wanted_vcpus: online.len() + usize::from(wants_more),
+            online,
+            offline,
+        }
+    }
+}
+
+/// Handle used by the control loop to queue requests for the companion.
+pub struct VcpuCompanion {
+    tx: QueueSender<VcpuRequest>,
+    guest: String,
+    /// The last request queued, so an unchanged one is not sent again.
+    last: Option<VcpuRequest>,
+    writer: JoinHandle<()>,
+}
+
+impl VcpuCompanion {
+    /// Starts the background task delivering requests to the companion at `url`, an
+    /// `http://host:port/path` URL, and returns a handle to it. A request the companion does not
+    /// accept is tried again every `RETRY_INTERVAL` until a newer one replaces it.
+    pub fn spawn(url: &str) -> Result<Self, String> {
+        let (host, path) = http::split_url(url)?;
+        let (tx, mut rx) = queue::channel::<VcpuRequest>("vcpu", QUEUE_LEN);
+        let guest = crate::influx::hostname();
+        info!("Requesting vCPU hotplug from the companion at {}", url);
+        let writer = tokio::spawn(async move {
+            let mut pending = rx.recv().await;
+            while let Some(request) = pending.take() {
+                let body = match serde_json::to_vec(&request) {
+                    Ok(body) => body,
+                    Err(e) => {
+                        warn!("Cannot encode the vCPU request: {}", e);
+                        pending = rx.recv().await;
+                        continue;
+                    }
+                };
+                match http::send("POST", &host, &path, "application/json", &body).await {
+                    Ok(()) => {
+                        debug!(
+                            "The companion accepted {} wanted vCPUs, {} to release",
+                            request.wanted_vcpus,
+                            request.offline.len()
+                        );
+                        pending = rx.recv().await;
+                    }
+                    Err(e) => {
+                        warn!("The vCPU companion did not accept the request: {}", e);
+                        pending = tokio::select! {
+                            newer = rx.recv() => newer,
+                            _ = tokio::time::sleep(RETRY_INTERVAL) => Some(request),
+                        };
+                    }
+                }
+            }
+        });
+        Ok(VcpuCompanion {
+            tx,
+            guest,
+            last: None,
+            writer,
+        })
+    }
+
+    /// Queues a request describing `topology` if it differs from the last one, without waiting
+    /// for the companion. See `VcpuRequest::new` for `wants_more`.
+    pub fn update(&mut self, topology: &SystemTopology, wants_more: bool) {
+        let request = VcpuRequest::new(&self.guest, topology, wants_more);
+        if self.last.as_ref() == Some(&request) {
+            return;
+        }
+        self.last = Some(request.clone());
+        self.tx.push(request);
+    }
+
+    /// Stops accepting requests and waits up to `FLUSH_TIMEOUT` for the last one to be delivered.
+    pub async fn close(self) {
+        drop(self.tx);
+        if tokio::time::timeout(FLUSH_TIMEOUT, self.writer)
+            .await
+            .is_err()
+        {
+            warn!(
+                "Gave up delivering the vCPU request after {:?}",
+                FLUSH_TIMEOUT
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn wants_one_more_vcpu_when_none_is_left() {
+        let topology = SystemTopology::single_thread_cores(&[true, true, false, false]);
+        let request = VcpuRequest::new("guest", &topology, false);
+        assert_eq!(request.online, [0, 1]);
+        assert_eq!(request.offline, [2, 3]);
+        assert_eq!(request.wanted_vcpus, 2);
+        assert_eq!(VcpuRequest::new("guest", &topology, true).wanted_vcpus, 3);
+    }
+
+    #[tokio::test]
+    async fn posts_only_changed_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/vcpus", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut bodies = Vec::new();
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 1024];
+                loop {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                    let text = String::from_utf8_lossy(&request);
+                    if text.ends_with('}') || read == 0 {
+                        break;
+                    }
+                }
+                stream
+                    .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                    .await
+                    .unwrap();
+                let text = String::from_utf8(request).unwrap();
+                bodies.push(text.split("\r\n\r\n").nth(1).unwrap().to_string());
+                if bodies.len() == 2 {
+                    return bodies;
+                }
+            }
+            bodies
+        });
+        let mut companion = VcpuCompanion::spawn(&url).unwrap();
+        companion.update(&SystemTopology::single_thread_cores(&[true, false]), false);
+        // Unchanged, so not sent again.
+        companion.update(&SystemTopology::single_thread_cores(&[true, false]), false);
+        companion.close().await;
+        let mut companion = VcpuCompanion::spawn(&url).unwrap();
+        companion.update(&SystemTopology::single_thread_cores(&[true, false]), true);
+        companion.close().await;
+        let bodies = server.await.unwrap();
+        let guest = crate::influx::hostname();
+        assert_eq!(
+            bodies,
+            [
+                format!(
+                    r#"{{"guest":"{}","online":[0],"offline":[1],"wanted_vcpus":1}}"#,
+                    guest
+                ),
+                format!(
+                    r#"{{"guest":"{}","online":[0],"offline":[1],"wanted_vcpus":2}}"#,
+                    guest
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,53 @@
+//! CPU vulnerability status, for `--no-smt-if-vulnerable`.
+//!
+//! The kernel reports the status of every known hardware vulnerability in
+//! `/sys/devices/system/cpu/vulnerabilities`. For L1TF, MDS, and their relatives TAA and MMIO stale
+//! data, a thread can leak data to its sibling on the same core however the kernel mitigates it,
+//! so the status ends in `SMT vulnerable` while SMT is on, and the kernel recommends turning it off.
+//! With `--no-smt-if-vulnerable`, the topology then never onlines a thread of a core that already has
+//! one online, see `SystemTopology::no_smt`.
+use crate::sysfs::SysfsBackend;
+
+/// The vulnerability files, relative to the CPU directory, whose status tells whether SMT is safe.
+pub const SMT_VULNERABILITIES: [&str; 4] = [
+    "vulnerabilities/l1tf",
+    "vulnerabilities/mds",
+    "vulnerabilities/tsx_async_abort",
+    "vulnerabilities/mmio_stale_data",
+];
+
+/// Returns the names of the vulnerabilities whose status reports SMT as vulnerable, e.g. `l1tf`
+/// for `Mitigation: PTE Inversion; VMX: conditional cache flushes, SMT vulnerable`. Missing files,
+/// as on CPUs or kernels that predate a vulnerability, count as not vulnerable.
+pub async fn smt_vulnerabilities(sysfs: &dyn SysfsBackend) -> Vec<&'static str> {
+    let mut vulnerable = Vec::new();
+    for attr in SMT_VULNERABILITIES {
+        if let Ok(status) = sysfs.read_global(attr).await {
+            if status.contains("SMT vulnerable") {
+                vulnerable.push(attr.trim_start_matches("vulnerabilities/"));
+            }
+        }
+    }
+    vulnerable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sysfs::MockSysfs;
+
+    #[tokio::test]
+    async fn reports_only_the_statuses_that_call_smt_vulnerable() {
+        let sysfs = MockSysfs::new();
+        sysfs.set_global(
+            "vulnerabilities/l1tf",
+            "Mitigation: PTE Inversion; VMX: conditional cache flushes, SMT vulnerable",
+        );
+        sysfs.set_global(
+            "vulnerabilities/mds",
+            "Mitigation: Clear CPU buffers; SMT Host state unknown",
+        );
+        sysfs.set_global("vulnerabilities/tsx_async_abort", "Not affected");
+        assert_eq!(smt_vulnerabilities(&sysfs).await, ["l1tf"]);
+    }
+}
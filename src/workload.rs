@@ -0,0 +1,188 @@
+//! Classification of the workload from the load history, used to pick a profile automatically.
+//!
+//! - `steady`: the load barely moves, as on a server with constant traffic. Uses `balanced`.
+//! - `batch`: the load alternates with a regular period, as with cron-driven batch jobs. Uses
+//!   `power-saver`, whose hysteresis and cooldown ride out the short gaps between runs instead of
+//!   offlining and onlining cores on every cycle.
+//! - `bursty`: the load jumps irregularly, as on an interactive machine. Uses `performance`,
+//!   which onlines cores quickly and in larger steps.
+use crate::profile::ProfileName;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Number of samples the classification is based on.
+pub const HISTORY_LEN: usize = 120;
+
+/// Number of samples between two classifications.
+pub const CLASSIFY_EVERY: usize = 30;
+
+/// Standard deviation of the load, in percentage points, below which the workload is steady.
+const STEADY_MAX_STDDEV: f64 = 10.0;
+
+/// Autocorrelation at the best lag above which the workload is periodic.
+const PERIODIC_MIN_AUTOCORRELATION: f64 = 0.5;
+
+/// Shortest period, in samples, considered for periodic workloads.
+const MIN_PERIOD: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WorkloadClass {
+    Steady,
+    Bursty,
+    Batch,
+}
+
+impl WorkloadClass {
+    /// Returns the profile suited to this kind of workload.
+    pub fn profile_name(self) -> ProfileName {
+        match self {
+            WorkloadClass::Steady => ProfileName::Balanced,
+            WorkloadClass::Bursty => ProfileName::Performance,
+            WorkloadClass::Batch => ProfileName::PowerSaver,
+        }
+    }
+}
+
+/// How the workload class is determined: classified automatically or pinned by the user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WorkloadMode {
+    Auto,
+    Steady,
+    Bursty,
+    Batch,
+}
+
+impl WorkloadMode {
+    /// Returns the pinned class, or `None` in automatic mode.
+    pub fn pinned(self) -> Option<WorkloadClass> {
+        match self {
+            WorkloadMode::Auto => None,
+            WorkloadMode::Steady => Some(WorkloadClass::Steady),
+            WorkloadMode::Bursty => Some(WorkloadClass::Bursty),
+            WorkloadMode::Batch => Some(WorkloadClass::Batch),
+        }
+    }
+}
+
+/// Returns the profiles that may be in effect: every class's profile in automatic mode, and
+/// `profile` otherwise.
+pub fn candidate_profiles(mode: Option<WorkloadMode>, profile: ProfileName) -> Vec<ProfileName> {
+    match mode {
+        Some(WorkloadMode::Auto) => vec![
+            WorkloadClass::Steady.profile_name(),
+            WorkloadClass::Bursty.profile_name(),
+            WorkloadClass::Batch.profile_name(),
+        ],
+        _ => vec![profile],
+    }
+}
+
+/// The workload class and the profile in effect, as reported by the `status` command.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct WorkloadStatus {
+    pub mode: WorkloadMode,
+    /// `None` until enough samples have been collected in automatic mode.
+    pub class: Option<WorkloadClass>,
+    pub profile: ProfileName,
+}
+
+/// Collects the load history and periodically classifies it.
+#[derive(Default)]
+pub struct Classifier {
+    history: VecDeque<f64>,
+    since_last: usize,
+}
+
+impl Classifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a load sample. Returns the classification every `CLASSIFY_EVERY` samples once the
+    /// history is full, and `None` otherwise.
+    pub fn push(&mut self, load: f64) -> Option<WorkloadClass> {
+        if self.history.len() >= HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(load);
+        self.since_last += 1;
+        if self.history.len() < HISTORY_LEN || self.since_last < CLASSIFY_EVERY {
+            return None;
+        }
+        self.since_last = 0;
+        Some(classify(self.history.make_contiguous()))
+    }
+}
+
+/// Classifies a load history.
+///
+/// This function performs the following steps:
+/// 1. Computes the mean and standard deviation of the history; a small deviation means `Steady`.
+/// 2. Computes the autocorrelation for every lag from `MIN_PERIOD` to half the history; a strong
+///    correlation at any lag means the load repeats with that period, i.e. `Batch`.
+/// 3. Otherwise the load varies without a pattern, i.e. `Bursty`.
+pub fn classify(history: &[f64]) -> WorkloadClass {
+    let n = history.len() as f64;
+    let mean = history.iter().sum::<f64>() / n;
+    let variance = history.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n;
+    if variance.sqrt() < STEADY_MAX_STDDEV {
+        return WorkloadClass::Steady;
+    }
+
+    let total = variance * n;
+    let periodic = (MIN_PERIOD..=history.len() / 2).any(|lag| {
+        let covariance: f64 = history
+            .iter()
+            .zip(&history[lag..])
+            .map(|(a, b)| (a - mean) * (b - mean))
+            .sum();
+        covariance / total > PERIODIC_MIN_AUTOCORRELATION
+    });
+    if periodic {
+        WorkloadClass::Batch
+    } else {
+        WorkloadClass::Bursty
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_steady_periodic_and_bursty_loads() {
+        let steady: Vec<f64> = (0..HISTORY_LEN).map(|i| 60.0 + (i % 3) as f64).collect();
+        assert_eq!(classify(&steady), WorkloadClass::Steady);
+
+        let batch: Vec<f64> = (0..HISTORY_LEN)
+            .map(|i| if i % 20 < 10 { 95.0 } else { 5.0 })
+            .collect();
+        assert_eq!(classify(&batch), WorkloadClass::Batch);
+
+        // Irregular spikes from a linear congruential generator.
+        let mut state: u32 = 12345;
+        let bursty: Vec<f64> = (0..HISTORY_LEN)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                if (state >> 16) & 7 == 0 {
+                    90.0
+                } else {
+                    10.0
+                }
+            })
+            .collect();
+        assert_eq!(classify(&bursty), WorkloadClass::Bursty);
+    }
+
+    #[test]
+    fn classifies_every_few_samples_once_the_history_is_full() {
+        let mut classifier = Classifier::new();
+        let classifications = (0..HISTORY_LEN + CLASSIFY_EVERY)
+            .filter(|_| classifier.push(50.0).is_some())
+            .count();
+        assert_eq!(classifications, 2);
+    }
+}
@@ -0,0 +1,179 @@
+//! End-to-end test of the real binary against a fake `/sys`.
+//!
+//! The test builds a fake CPU tree in a temporary directory and starts the daemon through
+//! `unshare --user --map-root-user --mount`, which bind-mounts the tree over `/sys` in a private
+//! mount namespace, so the binary runs with its default paths and without root. It then sends
+//! SIGHUP and SIGTERM and checks the `online` files the daemon leaves behind.
+//!
+//! The test needs util-linux `unshare` and unprivileged user namespaces, which many CI runners and
+//! containers disable, so it is ignored by default. Run it with `cargo test -- --ignored`.
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A daemon started in its own user and mount namespace, with its log lines.
+struct Daemon {
+    child: Child,
+    lines: Receiver<String>,
+}
+
+impl Daemon {
+    /// Starts the binary with `/sys` replaced by `sys` and the other paths below `dir`.
+    fn start(sys: &Path, dir: &Path, extra_args: &[&str]) -> Self {
+        let mut child = Command::new("unshare")
+            .args(["--user", "--map-root-user", "--mount", "sh", "-c"])
+            .arg(r#"mount --bind "$0" /sys && exec "$@""#)
+            .arg(sys)
+            .arg(env!("CARGO_BIN_EXE_cpu-on-off-rust"))
+            .arg("--control-socket")
+            .arg(dir.join("control.sock"))
+            .arg("--hotplug-journal")
+            .arg(dir.join("hotplug.json"))
+            .arg("--crash-dump")
+            .arg(dir.join("decisions.jsonl"))
+            .args(extra_args)
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("cannot run unshare");
+        let stdout = child.stdout.take().unwrap();
+        let (tx, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Daemon { child, lines }
+    }
+
+    fn signal(&self, signal: libc::c_int) {
+        // unshare and sh exec the binary, so the child is the daemon itself.
+        assert_eq!(
+            unsafe { libc::kill(self.child.id() as libc::pid_t, signal) },
+            0
+        );
+    }
+
+    /// Waits for a log line containing `text`.
+    fn wait_for_line(&self, text: &str) {
+        let deadline = Instant::now() + TIMEOUT;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match self.lines.recv_timeout(left) {
+                Ok(line) if line.contains(text) => return,
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        panic!("no log line containing {:?}", text);
+    }
+
+    /// Waits for the daemon to exit and returns whether it succeeded.
+    fn wait(&mut self) -> bool {
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return status.success();
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("the daemon did not exit");
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Builds a tree of four CPUs without SMT below `sys`, with `offline` offline.
+fn fake_sys(sys: &Path, offline: &[usize]) {
+    for id in 0..4 {
+        let dir = cpu_dir(sys, id);
+        fs::create_dir_all(dir.join("topology")).unwrap();
+        fs::write(dir.join("topology/core_id"), format!("{}\n", id)).unwrap();
+        fs::write(dir.join("topology/physical_package_id"), "0\n").unwrap();
+        fs::write(
+            dir.join("topology/thread_siblings_list"),
+            format!("{}\n", id),
+        )
+        .unwrap();
+        for state in 0..2 {
+            let state_dir = dir.join(format!("cpuidle/state{}", state));
+            fs::create_dir_all(&state_dir).unwrap();
+            fs::write(state_dir.join("time"), "0\n").unwrap();
+        }
+        if id != 0 {
+            let online = if offline.contains(&id) { "0" } else { "1" };
+            fs::write(dir.join("online"), online).unwrap();
+        }
+    }
+}
+
+fn cpu_dir(sys: &Path, id: usize) -> PathBuf {
+    sys.join(format!("devices/system/cpu/cpu{}", id))
+}
+
+/// Whether CPU `id`'s `online` file reads 1 or it has none, like CPU0.
+fn is_online(sys: &Path, id: usize) -> bool {
+    match fs::read_to_string(cpu_dir(sys, id).join("online")) {
+        Ok(state) => state.trim() == "1",
+        Err(_) => true,
+    }
+}
+
+/// Waits for CPU `id` to be online, as the log line announcing the write precedes it.
+fn wait_online(sys: &Path, id: usize) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !is_online(sys, id) {
+        assert!(Instant::now() < deadline, "CPU{} was not onlined", id);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+#[ignore = "needs util-linux unshare and unprivileged user namespaces"]
+fn binary_follows_sighup_and_applies_the_exit_policy_on_sigterm() {
+    let dir = std::env::temp_dir().join(format!("cpu-on-off-e2e-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let sys = dir.join("sys");
+    fake_sys(&sys, &[3]);
+    let config = dir.join("config.toml");
+    fs::write(&config, "on_exit = \"restore\"\n").unwrap();
+
+    // The idle times never advance, so every CPU reads as fully busy and CPU3 is onlined.
+    let config_arg = config.to_str().unwrap();
+    let mut daemon = Daemon::start(
+        &sys,
+        &dir,
+        &[
+            "--config",
+            config_arg,
+            "--start-from",
+            "current",
+            "-i",
+            "0.1",
+        ],
+    );
+    daemon.wait_for_line("onlining core [3]");
+    wait_online(&sys, 3);
+
+    // Without the reload, the restore policy would offline CPU3 again on exit.
+    fs::write(&config, "on_exit = \"leave\"\n").unwrap();
+    daemon.signal(libc::SIGHUP);
+    daemon.wait_for_line("Reloaded the configuration");
+    daemon.signal(libc::SIGTERM);
+    assert!(daemon.wait());
+
+    assert!((0..4).all(|id| is_online(&sys, id)));
+    assert!(!dir.join("hotplug.json").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,1411 @@
+//! End-to-end runs of the control loop against fake sysfs trees of real machine shapes.
+//!
+//! Each fixture writes the `online`, `topology`, and `cpuidle` files of a machine below a
+//! temporary sysfs root, and a scripted load metric feeds the CPU manager one load per cycle.
+//! After every cycle, the `online` files show which CPUs the daemon selected.
+use async_trait::async_trait;
+use clap::Parser;
+use cpu_on_off_rust::actuation::BackendName;
+use cpu_on_off_rust::control::{self, ControlState};
+use cpu_on_off_rust::cpu_list::CpuList;
+use cpu_on_off_rust::cpuset;
+use cpu_on_off_rust::load::{LoadMetric, LoadSample, ProcStatMetric, SchedstatMetric, StealReader};
+use cpu_on_off_rust::pm_qos::LatencyGuard;
+use cpu_on_off_rust::uevent;
+use cpu_on_off_rust::{Args, CpuManager, MockSysfs, Settings, SystemTopology};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio_util::sync::CancellationToken;
+
+/// Numbers the fixtures, so that tests running in parallel get their own trees.
+static NEXT_FIXTURE: AtomicUsize = AtomicUsize::new(0);
+
+/// A fake sysfs tree, removed when dropped.
+struct Fixture {
+    root: PathBuf,
+}
+
+impl Fixture {
+    fn new(name: &str) -> Self {
+        let root = std::env::temp_dir().join(format!(
+            "cpu-on-off-fixture-{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT_FIXTURE.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&root);
+        Fixture { root }
+    }
+
+    /// Two sockets of four cores with two threads each, numbered like Intel Xeons: the second
+    /// threads follow the first threads of all cores, and core IDs restart on every socket.
+    fn dual_socket_xeon() -> Self {
+        let fixture = Self::new("xeon");
+        for id in 0..16 {
+            let core = id % 8;
+            let siblings = format!("{},{}", core, core + 8);
+            fixture.cpu(id, core / 4, core % 4, &siblings, 3);
+        }
+        fixture
+    }
+
+    /// One socket of eight cores with two adjacent threads each, as on AMD Ryzen.
+    fn ryzen_8_core() -> Self {
+        let fixture = Self::new("ryzen");
+        for id in 0..16 {
+            let siblings = format!("{}-{}", id & !1, id | 1);
+            fixture.cpu(id, 0, id / 2, &siblings, 2);
+        }
+        fixture
+    }
+
+    /// Four cores without SMT or cpuidle, as on many ARM boards, where CPU0 has an `online`
+    /// file too.
+    fn arm_4_core() -> Self {
+        let fixture = Self::new("arm");
+        for id in 0..4 {
+            fixture.cpu(id, 0, id, &id.to_string(), 0);
+        }
+        fs::write(fixture.cpu_dir(0).join("online"), "1").unwrap();
+        fixture
+    }
+
+    /// A big.LITTLE phone or server SoC with four LITTLE CPUs in cluster 0 and four big CPUs in
+    /// cluster 1, without SMT.
+    fn big_little_4_4() -> Self {
+        let fixture = Self::new("big-little");
+        for id in 0..8 {
+            fixture.cpu(id, 0, id, &id.to_string(), 2);
+            let dir = fixture.cpu_dir(id);
+            let (cluster, capacity) = if id < 4 { (0, 446) } else { (1, 1024) };
+            fs::write(dir.join("topology/cluster_id"), format!("{}\n", cluster)).unwrap();
+            fs::write(dir.join("cpu_capacity"), format!("{}\n", capacity)).unwrap();
+        }
+        fixture
+    }
+
+    fn cpu_dir(&self, id: usize) -> PathBuf {
+        self.root.join(format!("devices/system/cpu/cpu{}", id))
+    }
+
+    /// Writes the attributes of an online CPU with `idle_states` cpuidle states. CPU0 gets no
+    /// `online` file, as on most kernels.
+    fn cpu(&self, id: usize, socket: usize, core: usize, siblings: &str, idle_states: usize) {
+        let dir = self.cpu_dir(id);
+        fs::create_dir_all(dir.join("topology")).unwrap();
+        fs::write(dir.join("topology/core_id"), format!("{}\n", core)).unwrap();
+        fs::write(
+            dir.join("topology/physical_package_id"),
+            format!("{}\n", socket),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("topology/thread_siblings_list"),
+            format!("{}\n", siblings),
+        )
+        .unwrap();
+        for state in 0..idle_states {
+            let state_dir = dir.join(format!("cpuidle/state{}", state));
+            fs::create_dir_all(&state_dir).unwrap();
+            fs::write(state_dir.join("time"), "0\n").unwrap();
+            fs::write(state_dir.join("disable"), "0\n").unwrap();
+        }
+        if id != 0 {
+            fs::write(dir.join("online"), "1").unwrap();
+        }
+    }
+
+    /// Returns the CPUs whose `online` file reads 1 or that have none, skipping attributes of the
+    /// CPU directory itself such as `isolated`.
+    fn online(&self) -> BTreeSet<usize> {
+        let mut online = BTreeSet::new();
+        for entry in fs::read_dir(self.root.join("devices/system/cpu")).unwrap() {
+            let entry = entry.unwrap();
+            let name = entry.file_name().into_string().unwrap();
+            let Some(id) = name.strip_prefix("cpu").and_then(|id| id.parse().ok()) else {
+                continue;
+            };
+            match fs::read_to_string(entry.path().join("online")) {
+                Ok(state) if state.trim() != "1" => {}
+                _ => {
+                    online.insert(id);
+                }
+            }
+        }
+        online
+    }
+
+    /// Builds a CPU manager over this tree with the balanced profile, `extra_args`, and a scripted
+    /// system load.
+    async fn manager(&self, extra_args: &[&str], loads: &[f64]) -> CpuManager {
+        let mut manager = self.build_manager(extra_args).await;
+        manager.set_metric(Box::new(ScriptedSamples::loads(loads)));
+        manager
+    }
+
+    async fn build_manager(&self, extra_args: &[&str]) -> CpuManager {
+        self.build_manager_with(extra_args, Arc::new(ControlState::new()))
+            .await
+    }
+
+    /// Builds a CPU manager like `build_manager`, sharing `control` with a control socket.
+    async fn build_manager_with(
+        &self,
+        extra_args: &[&str],
+        control: Arc<ControlState>,
+    ) -> CpuManager {
+        let root = self.root.to_str().unwrap();
+        let mut args = vec!["cpu-on-off-rust", "--sysfs-root", root];
+        if !extra_args.contains(&"-m") {
+            args.extend_from_slice(&["-m", "proc-stat"]);
+        }
+        args.extend_from_slice(extra_args);
+        let settings = Settings::resolve(&Args::parse_from(args)).unwrap();
+        let backend_cgroup = cpuset::cgroup_dir(&settings.sysfs_root, &settings.backend_cgroup);
+        let cpuset = cpuset::allowed_cpus(
+            &settings.sysfs_root,
+            !settings.ignore_cpuset,
+            settings.restrict_to_cgroup.as_deref(),
+            (settings.backend == BackendName::Cpuset).then_some(backend_cgroup.as_path()),
+        )
+        .await
+        .unwrap();
+        let topology = SystemTopology::builder()
+            .sysfs_root(&settings.sysfs_root)
+            .actuation(settings.backend, backend_cgroup)
+            .exclude_cpus(settings.exclude_cpus.clone())
+            .manage_isolated(settings.manage_isolated)
+            .cpuset(cpuset)
+            .full_cores_only(settings.full_cores_only)
+            .offline_order(settings.offline_order)
+            .strategy(settings.strategy().build())
+            .granularity(settings.granularity)
+            .prefer_no_smt(settings.prefer_no_smt)
+            .no_smt_if_vulnerable(settings.no_smt_if_vulnerable)
+            .deconfigure(settings.deconfigure)
+            .prefer_offline(settings.prefer_offline)
+            .blacklist(settings.blacklist_after, settings.blacklist_for)
+            .build()
+            .await
+            .unwrap();
+        CpuManager::new(settings, topology, control).unwrap()
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Reports the next of a scripted series of samples on every sample.
+struct ScriptedSamples(VecDeque<LoadSample>);
+
+impl ScriptedSamples {
+    /// Scripts a series of system loads alone.
+    fn loads(loads: &[f64]) -> Self {
+        Self(loads.iter().map(|&load| system_load(load)).collect())
+    }
+}
+
+#[async_trait]
+impl LoadMetric for ScriptedSamples {
+    fn name(&self) -> &'static str {
+        "scripted"
+    }
+
+    async fn sample(&mut self, _topology: &SystemTopology) -> io::Result<LoadSample> {
+        self.0
+            .pop_front()
+            .ok_or_else(|| io::Error::other("the load script is exhausted"))
+    }
+}
+
+/// A sample of the system-wide `load` alone.
+fn system_load(load: f64) -> LoadSample {
+    LoadSample {
+        system: Some(load),
+        ..LoadSample::default()
+    }
+}
+
+/// Reports a scripted load per CPU on every sample, taken from the next entry of a series of
+/// loads by socket.
+struct ScriptedSocketLoads {
+    sockets: HashMap<usize, usize>,
+    loads: VecDeque<Vec<f64>>,
+}
+
+#[async_trait]
+impl LoadMetric for ScriptedSocketLoads {
+    fn name(&self) -> &'static str {
+        "scripted-sockets"
+    }
+
+    async fn sample(&mut self, _topology: &SystemTopology) -> io::Result<LoadSample> {
+        let loads = self
+            .loads
+            .pop_front()
+            .ok_or_else(|| io::Error::other("the load script is exhausted"))?;
+        Ok(LoadSample {
+            per_cpu: self
+                .sockets
+                .iter()
+                .map(|(&cpu, &socket)| (cpu, loads[socket]))
+                .collect(),
+            ..LoadSample::default()
+        })
+    }
+}
+
+/// Builds a CPU manager over the in-memory `sysfs` with the balanced profile, `extra_args`, and a
+/// scripted system load, e.g. to make hotplug writes fail.
+async fn mock_manager(sysfs: Arc<MockSysfs>, extra_args: &[&str], loads: &[f64]) -> CpuManager {
+    let mut args = vec!["cpu-on-off-rust", "-m", "proc-stat"];
+    args.extend_from_slice(extra_args);
+    let settings = Settings::resolve(&Args::parse_from(args)).unwrap();
+    let topology = SystemTopology::builder()
+        .backend(sysfs)
+        .blacklist(settings.blacklist_after, settings.blacklist_for)
+        .build()
+        .await
+        .unwrap();
+    let mut manager = CpuManager::new(settings, topology, Arc::new(ControlState::new())).unwrap();
+    manager.set_metric(Box::new(ScriptedSamples::loads(loads)));
+    manager
+}
+
+/// Two cores of two threads each, CPU0 and CPU2 sharing the first.
+fn smt_2_core() -> Arc<MockSysfs> {
+    Arc::new(
+        MockSysfs::new()
+            .with_cpu(0, 0, 0, "0,2")
+            .with_cpu(1, 1, 0, "1,3")
+            .with_cpu(2, 0, 0, "0,2")
+            .with_cpu(3, 1, 0, "1,3"),
+    )
+}
+
+/// Runs one cycle per load and checks the CPUs offline after each cycle.
+async fn run_script(fixture: &Fixture, extra_args: &[&str], script: &[(f64, &[usize])]) {
+    let loads: Vec<f64> = script.iter().map(|(load, _)| *load).collect();
+    let mut manager = fixture.manager(extra_args, &loads).await;
+    let offline: Vec<&[usize]> = script.iter().map(|(_, offline)| *offline).collect();
+    check_cycles(fixture, &mut manager, &offline).await;
+}
+
+/// Runs one cycle per entry of `offline` and checks the CPUs offline after each cycle.
+async fn check_cycles(fixture: &Fixture, manager: &mut CpuManager, offline: &[&[usize]]) {
+    let all: BTreeSet<usize> = manager.topology().cpus.keys().copied().collect();
+    for (cycle, offline) in offline.iter().enumerate() {
+        manager.step().await.unwrap();
+        let expected: BTreeSet<usize> = all
+            .difference(&offline.iter().copied().collect())
+            .copied()
+            .collect();
+        assert_eq!(fixture.online(), expected, "cycle {}", cycle);
+    }
+}
+
+#[tokio::test]
+async fn dual_socket_xeon_parks_whole_cores_from_the_top() {
+    let fixture = Fixture::dual_socket_xeon();
+    let topology = SystemTopology::builder()
+        .sysfs_root(&fixture.root)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(topology.sockets.len(), 2);
+    assert_eq!(topology.cpus[&12].socket_id, Some(1));
+    assert_eq!(topology.cpus[&12].thread_siblings, [4, 12]);
+    assert_eq!(topology.cpus[&3].idle_states.len(), 3);
+    assert!(!topology.cpu0_hotpluggable());
+
+    run_script(
+        &fixture,
+        &[],
+        &[
+            (10.0, &[7, 15]),
+            (10.0, &[6, 7, 14, 15]),
+            (95.0, &[7, 15]),
+            (60.0, &[7, 15]),
+            (95.0, &[]),
+            (95.0, &[]),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn dual_socket_xeon_leaves_excluded_cpus_alone() {
+    let fixture = Fixture::dual_socket_xeon();
+    run_script(
+        &fixture,
+        &["--exclude-cpus", "15"],
+        &[(10.0, &[6, 14]), (10.0, &[5, 6, 13, 14]), (95.0, &[6, 14])],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn dual_socket_xeon_scales_each_socket_on_its_own() {
+    let fixture = Fixture::dual_socket_xeon();
+    let mut manager = fixture.build_manager(&["--per-socket"]).await;
+    let sockets = manager
+        .topology()
+        .cpus
+        .values()
+        .map(|cpu| (cpu.id, cpu.socket_id.unwrap()))
+        .collect();
+    // Socket 0 is saturated and socket 1 idle, an average of 50% that alone would change nothing.
+    let loads = [[95.0, 5.0]; 4].into_iter().chain([[95.0, 95.0]]);
+    manager.set_metric(Box::new(ScriptedSocketLoads {
+        sockets,
+        loads: loads.map(Vec::from).collect(),
+    }));
+    check_cycles(
+        &fixture,
+        &mut manager,
+        &[
+            &[7, 15],
+            &[6, 7, 14, 15],
+            &[5, 6, 7, 13, 14, 15],
+            &[5, 6, 7, 13, 14, 15],
+            &[6, 7, 14, 15],
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn dual_socket_xeon_steal_limit_holds_the_saturated_socket() {
+    let fixture = Fixture::dual_socket_xeon();
+    for id in [3, 11] {
+        fs::write(fixture.cpu_dir(id).join("online"), "0").unwrap();
+    }
+    let proc_stat = fixture.root.join("proc_stat");
+    let write = |user: u64, steal: u64| {
+        let stat: String = (0..16)
+            .map(|id| format!("cpu{} {} 0 0 15 0 0 0 {} 0 0\n", id, user, steal))
+            .collect();
+        fs::write(&proc_stat, stat).unwrap();
+    };
+    let mut manager = fixture
+        .build_manager(&["--per-socket", "--steal-limit", "20"])
+        .await;
+    manager.set_steal_reader(StealReader::with_path(&proc_stat));
+    let sockets = manager
+        .topology()
+        .cpus
+        .values()
+        .map(|cpu| (cpu.id, cpu.socket_id.unwrap()))
+        .collect();
+    manager.set_metric(Box::new(ScriptedSocketLoads {
+        sockets,
+        loads: [[95.0, 50.0]; 2].into_iter().map(Vec::from).collect(),
+    }));
+    // 35% and then 5% of the time stolen.
+    write(50, 35);
+    check_cycles(&fixture, &mut manager, &[&[3, 11]]).await;
+    write(145, 40);
+    check_cycles(&fixture, &mut manager, &[&[]]).await;
+}
+
+#[tokio::test]
+async fn dual_socket_xeon_moves_cores_to_the_saturated_socket() {
+    let fixture = Fixture::dual_socket_xeon();
+    for id in [3, 11] {
+        fs::write(fixture.cpu_dir(id).join("online"), "0").unwrap();
+    }
+    let mut manager = fixture.build_manager(&["--rebalance-sockets"]).await;
+    let sockets = manager
+        .topology()
+        .cpus
+        .values()
+        .map(|cpu| (cpu.id, cpu.socket_id.unwrap()))
+        .collect();
+    // An average of about 52% and then 63%, within the thresholds of the balanced profile.
+    manager.set_metric(Box::new(ScriptedSocketLoads {
+        sockets,
+        loads: [[95.0, 20.0]; 2].into_iter().map(Vec::from).collect(),
+    }));
+    check_cycles(&fixture, &mut manager, &[&[7, 15], &[7, 15]]).await;
+}
+
+#[tokio::test]
+async fn dual_socket_xeon_least_loaded_strategy_parks_the_idle_socket() {
+    let fixture = Fixture::dual_socket_xeon();
+    let mut manager = fixture.build_manager(&["--strategy", "least-loaded"]).await;
+    let sockets = manager
+        .topology()
+        .cpus
+        .values()
+        .map(|cpu| (cpu.id, cpu.socket_id.unwrap()))
+        .collect();
+    // Socket 1 does more work, so the cores of socket 0 go first, from the highest ID down.
+    manager.set_metric(Box::new(ScriptedSocketLoads {
+        sockets,
+        loads: [[5.0, 25.0]; 2].into_iter().map(Vec::from).collect(),
+    }));
+    check_cycles(&fixture, &mut manager, &[&[3, 11], &[2, 3, 10, 11]]).await;
+}
+
+#[tokio::test]
+async fn dual_socket_xeon_performance_profile_onlines_on_the_busiest_socket() {
+    for (args, offline) in [
+        (&["--profile", "performance"][..], [3, 11]),
+        (
+            &["--profile", "performance", "--strategy", "highest-id"][..],
+            [7, 15],
+        ),
+    ] {
+        let fixture = Fixture::dual_socket_xeon();
+        for id in [2, 3, 7, 10, 11, 15] {
+            fs::write(fixture.cpu_dir(id).join("online"), "0").unwrap();
+        }
+        let mut manager = fixture.build_manager(args).await;
+        let sockets = manager
+            .topology()
+            .cpus
+            .values()
+            .map(|cpu| (cpu.id, cpu.socket_id.unwrap()))
+            .collect();
+        manager.set_metric(Box::new(ScriptedSocketLoads {
+            sockets,
+            loads: [[60.0, 95.0]; 2].into_iter().map(Vec::from).collect(),
+        }));
+        check_cycles(&fixture, &mut manager, &[&[2, 3, 7, 10, 11, 15], &offline]).await;
+    }
+}
+
+#[tokio::test]
+async fn big_little_vacates_the_preferred_cluster_first() {
+    let fixture = Fixture::big_little_4_4();
+    let topology = SystemTopology::builder()
+        .sysfs_root(&fixture.root)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(topology.cpus[&2].cluster_id, Some(0));
+    assert_eq!(topology.cpus[&2].capacity, Some(446));
+    assert_eq!(topology.cpus[&6].cluster_id, Some(1));
+    assert_eq!(topology.cpus[&6].capacity, Some(1024));
+
+    run_script(
+        &fixture,
+        &["--prefer-offline", "little"],
+        &[(10.0, &[3]), (10.0, &[2, 3]), (95.0, &[3])],
+    )
+    .await;
+    let fixture = Fixture::big_little_4_4();
+    run_script(
+        &fixture,
+        &["--prefer-offline", "big"],
+        &[(10.0, &[7]), (10.0, &[6, 7]), (95.0, &[7])],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn ready_file_appears_after_calibration() {
+    let fixture = Fixture::arm_4_core();
+    let ready_file = fixture.root.join("ready");
+    let path = ready_file.to_str().unwrap();
+    let mut manager = fixture
+        .manager(
+            &["--ready-file", path, "--calibration-samples", "2"],
+            &[50.0, 50.0, 50.0],
+        )
+        .await;
+    for _ in 0..2 {
+        manager.step().await.unwrap();
+        assert!(!ready_file.exists());
+    }
+    manager.step().await.unwrap();
+    let pid = fs::read_to_string(&ready_file).unwrap();
+    assert_eq!(pid.trim(), std::process::id().to_string());
+    manager.close().await;
+    assert!(!ready_file.exists());
+}
+
+#[tokio::test]
+async fn ryzen_consolidates_down_to_the_housekeeping_core() {
+    let fixture = Fixture::ryzen_8_core();
+    let script: Vec<(f64, Vec<usize>)> = (1..=8)
+        .map(|cycle: usize| (5.0, (16 - 2 * cycle.min(7)..16).collect()))
+        .chain([(95.0, (4..16).collect())])
+        .collect();
+    let script: Vec<(f64, &[usize])> = script
+        .iter()
+        .map(|(load, offline)| (*load, offline.as_slice()))
+        .collect();
+    run_script(&fixture, &[], &script).await;
+}
+
+#[tokio::test]
+async fn ryzen_holds_the_cpus_while_an_inhibitor_connection_is_open() {
+    let fixture = Fixture::ryzen_8_core();
+    let state = Arc::new(ControlState::new());
+    let socket = fixture.root.join("control.sock");
+    let (path, server_state) = (socket.clone(), Arc::clone(&state));
+    tokio::spawn(async move { control::serve(&path, server_state).await });
+    let mut manager = fixture.build_manager_with(&[], Arc::clone(&state)).await;
+    manager.set_metric(Box::new(ScriptedSamples::loads(&[5.0; 3])));
+
+    let mut client = loop {
+        match UnixStream::connect(&socket).await {
+            Ok(client) => break client,
+            Err(_) => tokio::task::yield_now().await,
+        }
+    };
+    client
+        .write_all(b"{\"command\":\"inhibit\",\"who\":\"bench\",\"why\":\"benchmark run\"}\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    BufReader::new(&mut client)
+        .read_line(&mut response)
+        .await
+        .unwrap();
+    assert!(response.contains("\"ok\":true"), "{}", response);
+    check_cycles(&fixture, &mut manager, &[&[], &[]]).await;
+
+    drop(client);
+    while state.inhibited_by().is_some() {
+        tokio::task::yield_now().await;
+    }
+    check_cycles(&fixture, &mut manager, &[&[14, 15]]).await;
+}
+
+#[tokio::test]
+async fn ryzen_full_cores_only_keeps_the_floor() {
+    let fixture = Fixture::ryzen_8_core();
+    run_script(
+        &fixture,
+        &["--full-cores-only", "--min-cpus", "12"],
+        &[
+            (5.0, &[14, 15]),
+            (5.0, &[12, 13, 14, 15]),
+            (5.0, &[12, 13, 14, 15]),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn arm_spike_filter_ignores_short_excursions() {
+    let fixture = Fixture::arm_4_core();
+    run_script(
+        &fixture,
+        &["--interval", "1", "--spike-filter-secs", "3"],
+        &[
+            (5.0, &[3]),
+            (5.0, &[2, 3]),
+            // A two-second spike, e.g. a cron job, does not wake CPUs.
+            (90.0, &[2, 3]),
+            (90.0, &[2, 3]),
+            (60.0, &[2, 3]),
+            // Three seconds above the upper threshold do.
+            (90.0, &[2, 3]),
+            (90.0, &[2, 3]),
+            (90.0, &[3]),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn ryzen_leaves_isolated_cpus_alone() {
+    let isolated_ryzen = || {
+        let fixture = Fixture::ryzen_8_core();
+        let cpu_dir = fixture.root.join("devices/system/cpu");
+        fs::write(cpu_dir.join("isolated"), "14-15\n").unwrap();
+        fs::write(cpu_dir.join("nohz_full"), "(null)\n").unwrap();
+        fixture
+    };
+    run_script(
+        &isolated_ryzen(),
+        &[],
+        &[
+            (10.0, &[12, 13]),
+            (10.0, &[10, 11, 12, 13]),
+            (95.0, &[12, 13]),
+        ],
+    )
+    .await;
+    run_script(
+        &isolated_ryzen(),
+        &["--manage-isolated"],
+        &[(10.0, &[14, 15])],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn ryzen_restricts_management_to_the_target_cgroup_cpuset() {
+    let fixture = Fixture::ryzen_8_core();
+    let cgroup_fs = fixture.root.join("fs/cgroup");
+    fs::create_dir_all(cgroup_fs.join("system.slice")).unwrap();
+    fs::write(
+        cgroup_fs.join("cgroup.controllers"),
+        "cpuset cpu io memory\n",
+    )
+    .unwrap();
+    fs::write(cgroup_fs.join("system.slice/cpuset.cpus"), "0-11\n").unwrap();
+    run_script(
+        &fixture,
+        &["--restrict-to-cgroup", "system.slice"],
+        &[
+            (10.0, &[10, 11]),
+            (10.0, &[8, 9, 10, 11]),
+            (95.0, &[10, 11]),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn ryzen_cpuset_backend_narrows_the_slice_instead_of_hotplugging() {
+    let fixture = Fixture::ryzen_8_core();
+    let cgroup_fs = fixture.root.join("fs/cgroup");
+    let slice = cgroup_fs.join("system.slice");
+    fs::create_dir_all(&slice).unwrap();
+    fs::write(
+        cgroup_fs.join("cgroup.controllers"),
+        "cpuset cpu io memory\n",
+    )
+    .unwrap();
+    fs::write(slice.join("cpuset.cpus"), "\n").unwrap();
+    fs::write(slice.join("cpuset.cpus.effective"), "0-15\n").unwrap();
+    let mut manager = fixture
+        .manager(&["--backend", "cpuset"], &[10.0, 10.0, 95.0])
+        .await;
+    let all = fixture.online();
+    for expected in ["0-13", "0-11", "0-13"] {
+        manager.step().await.unwrap();
+        let cpus: CpuList = fs::read_to_string(slice.join("cpuset.cpus"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(cpus, expected.parse().unwrap());
+        assert_eq!(fixture.online(), all);
+    }
+}
+
+#[tokio::test]
+async fn ryzen_tiered_backend_caps_the_frequency_before_offlining() {
+    let fixture = Fixture::ryzen_8_core();
+    for id in 0..16 {
+        let dir = fixture.cpu_dir(id).join("cpufreq");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("cpuinfo_min_freq"), "400000\n").unwrap();
+        fs::write(dir.join("cpuinfo_max_freq"), "4000000\n").unwrap();
+        fs::write(dir.join("scaling_max_freq"), "4000000\n").unwrap();
+    }
+    let max_freq = |id: usize| {
+        fs::read_to_string(fixture.cpu_dir(id).join("cpufreq/scaling_max_freq")).unwrap()
+    };
+    let mut manager = fixture
+        .manager(
+            &["--backend", "tiered"],
+            &[10.0, 10.0, 10.0, 10.0, 10.0, 95.0, 95.0],
+        )
+        .await;
+    // The cap comes down in four steps before a single core goes offline.
+    check_cycles(&fixture, &mut manager, &[&[], &[], &[], &[]]).await;
+    assert_eq!(max_freq(0), "400000");
+    assert_eq!(max_freq(15), "400000");
+    // On the way up, every core comes back before the cap is raised.
+    check_cycles(&fixture, &mut manager, &[&[14, 15], &[], &[]]).await;
+    assert_eq!(max_freq(0), "1300000");
+    assert_eq!(max_freq(15), "1300000");
+}
+
+#[tokio::test]
+async fn xeon_tiered_backend_allows_deeper_idle_states_before_offlining() {
+    let fixture = Fixture::dual_socket_xeon();
+    for id in 0..16 {
+        let dir = fixture.cpu_dir(id);
+        fs::create_dir_all(dir.join("cpufreq")).unwrap();
+        fs::write(dir.join("cpufreq/cpuinfo_min_freq"), "800000\n").unwrap();
+        fs::write(dir.join("cpufreq/cpuinfo_max_freq"), "3200000\n").unwrap();
+        fs::write(dir.join("cpufreq/scaling_max_freq"), "3200000\n").unwrap();
+        fs::write(dir.join("cpuidle/state2/disable"), "1\n").unwrap();
+    }
+    let state2_disabled =
+        |id: usize| fs::read_to_string(fixture.cpu_dir(id).join("cpuidle/state2/disable")).unwrap();
+    let mut manager = fixture
+        .manager(
+            &["--backend", "tiered"],
+            &[10.0, 10.0, 10.0, 10.0, 10.0, 10.0, 95.0, 95.0, 95.0],
+        )
+        .await;
+    // Four frequency steps, then the deepest idle state, then the first core.
+    check_cycles(&fixture, &mut manager, &[&[], &[], &[], &[], &[]]).await;
+    assert_eq!(state2_disabled(3), "0");
+    check_cycles(&fixture, &mut manager, &[&[7, 15]]).await;
+    // The stages are left in the reverse order.
+    check_cycles(&fixture, &mut manager, &[&[]]).await;
+    assert_eq!(state2_disabled(3), "0");
+    check_cycles(&fixture, &mut manager, &[&[]]).await;
+    assert_eq!(state2_disabled(3), "1");
+    assert_eq!(
+        fs::read_to_string(fixture.cpu_dir(3).join("cpufreq/scaling_max_freq")).unwrap(),
+        "800000"
+    );
+    check_cycles(&fixture, &mut manager, &[&[]]).await;
+    assert_eq!(
+        fs::read_to_string(fixture.cpu_dir(3).join("cpufreq/scaling_max_freq")).unwrap(),
+        "1400000"
+    );
+}
+
+#[tokio::test]
+async fn xeon_idle_policy_allows_deeper_idle_states_before_hotplugging() {
+    let fixture = Fixture::dual_socket_xeon();
+    for id in 0..16 {
+        fs::write(fixture.cpu_dir(id).join("cpuidle/state2/disable"), "1\n").unwrap();
+    }
+    let state2_disabled =
+        |id: usize| fs::read_to_string(fixture.cpu_dir(id).join("cpuidle/state2/disable")).unwrap();
+    let mut manager = fixture
+        .manager(&["--idle-policy", "load"], &[10.0, 10.0, 95.0, 95.0])
+        .await;
+    check_cycles(&fixture, &mut manager, &[&[]]).await;
+    assert_eq!(state2_disabled(15), "0");
+    check_cycles(&fixture, &mut manager, &[&[7, 15], &[]]).await;
+    assert_eq!(state2_disabled(15), "0");
+    check_cycles(&fixture, &mut manager, &[&[]]).await;
+    assert_eq!(state2_disabled(15), "1");
+}
+
+#[tokio::test]
+async fn xeon_max_cstate_disables_the_deeper_idle_states() {
+    let fixture = Fixture::dual_socket_xeon();
+    let mut manager = fixture.manager(&["--max-cstate", "1"], &[10.0, 95.0]).await;
+    check_cycles(&fixture, &mut manager, &[&[7, 15]]).await;
+    for id in [0, 8, 15] {
+        let state = |n: usize| {
+            fs::read_to_string(
+                fixture
+                    .cpu_dir(id)
+                    .join(format!("cpuidle/state{}/disable", n)),
+            )
+            .unwrap()
+        };
+        assert_eq!((state(1).as_str(), state(2).as_str()), ("0", "1"));
+    }
+    // A CPU onlined again gets the limit too.
+    fs::write(fixture.cpu_dir(7).join("cpuidle/state2/disable"), "0\n").unwrap();
+    check_cycles(&fixture, &mut manager, &[&[]]).await;
+    assert_eq!(
+        fs::read_to_string(fixture.cpu_dir(7).join("cpuidle/state2/disable")).unwrap(),
+        "1"
+    );
+}
+
+#[tokio::test]
+async fn xeon_latency_sensitive_mode_holds_offlining_and_deep_idle_states() {
+    let fixture = Fixture::dual_socket_xeon();
+    for id in 0..16 {
+        let dir = fixture.cpu_dir(id).join("cpuidle");
+        for (state, latency) in [(0, "0"), (1, "2"), (2, "80")] {
+            fs::write(dir.join(format!("state{}/latency", state)), latency).unwrap();
+        }
+    }
+    let device = fixture.root.join("cpu_dma_latency");
+    fs::write(&device, 20i32.to_ne_bytes()).unwrap();
+    let mut manager = fixture
+        .manager(&["--latency-us", "50"], &[10.0, 10.0, 10.0])
+        .await;
+    manager.set_latency_guard(LatencyGuard::with_path(50, &device));
+    let state2_disabled =
+        |id: usize| fs::read_to_string(fixture.cpu_dir(id).join("cpuidle/state2/disable")).unwrap();
+    check_cycles(&fixture, &mut manager, &[&[], &[]]).await;
+    assert_eq!(state2_disabled(0), "1");
+    assert!(manager.topology().cpus[&15].online);
+
+    // The audio server closes the device.
+    fs::write(&device, 2_000_000_000i32.to_ne_bytes()).unwrap();
+    check_cycles(&fixture, &mut manager, &[&[7, 15]]).await;
+    assert_eq!(state2_disabled(0), "0");
+}
+
+#[tokio::test]
+async fn ryzen_panic_threshold_onlines_everything_at_once() {
+    let fixture = Fixture::ryzen_8_core();
+    run_script(
+        &fixture,
+        &["--panic-threshold", "95"],
+        &[
+            (5.0, &[14, 15]),
+            (5.0, &[12, 13, 14, 15]),
+            (5.0, &[10, 11, 12, 13, 14, 15]),
+            // Above the upper threshold but not the panic threshold: one core per cycle.
+            (90.0, &[12, 13, 14, 15]),
+            // A burst brings back every core in the same cycle.
+            (97.0, &[]),
+            (60.0, &[]),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn ryzen_round_robin_strategy_rotates_the_parked_cores() {
+    let fixture = Fixture::ryzen_8_core();
+    run_script(
+        &fixture,
+        &["--strategy", "round-robin"],
+        &[
+            (10.0, &[14, 15]),
+            (95.0, &[]),
+            // The core just onlined again stays, and the next one takes its turn.
+            (10.0, &[12, 13]),
+            (95.0, &[]),
+            (10.0, &[10, 11]),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn ryzen_transition_limit_holds_hotplug_within_the_minute() {
+    let fixture = Fixture::ryzen_8_core();
+    run_script(
+        &fixture,
+        &[
+            "--mode",
+            "target",
+            "--target-util",
+            "70",
+            "--max-transitions-per-minute",
+            "5",
+        ],
+        &[
+            // Six cores could go, but only two fit into five transitions.
+            (10.0, &[12, 13, 14, 15]),
+            // The fifth transition alone would split the next core.
+            (10.0, &[12, 13, 14, 15]),
+            (100.0, &[12, 13, 14, 15]),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn ryzen_target_mode_converges_in_one_step() {
+    let fixture = Fixture::ryzen_8_core();
+    run_script(
+        &fixture,
+        &["--mode", "target", "--target-util", "70"],
+        &[
+            // 10% of 16 CPUs needs 2.3 CPUs at 70%, so six cores go at once.
+            (10.0, &[4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]),
+            // 4 saturated CPUs need 5.7.
+            (100.0, &[6, 7, 8, 9, 10, 11, 12, 13, 14, 15]),
+            // 50% of 6 CPUs needs 4.3, more than the 4 left without another core.
+            (50.0, &[6, 7, 8, 9, 10, 11, 12, 13, 14, 15]),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn arm_pid_mode_approaches_the_target_gradually() {
+    let fixture = Fixture::arm_4_core();
+    run_script(
+        &fixture,
+        &["--mode", "pid", "--kp", "0.5", "--ki", "0"],
+        &[
+            // 10% of 4 CPUs needs 0.6 CPUs at 70%; half of the 3.4 CPUs too many is 1.7.
+            (10.0, &[3]),
+            // Half of the 2.6 too many of the remaining 3 is 1.3.
+            (10.0, &[2, 3]),
+            // Without an integral term, half of the 1.7 too many is not a whole CPU.
+            (10.0, &[2, 3]),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn arm_offlines_single_cpus_but_keeps_cpu0() {
+    let fixture = Fixture::arm_4_core();
+    let topology = SystemTopology::builder()
+        .sysfs_root(&fixture.root)
+        .build()
+        .await
+        .unwrap();
+    assert!(topology.cpu0_hotpluggable());
+    assert!(topology.cpus[&1].idle_states.is_empty());
+
+    run_script(
+        &fixture,
+        &[],
+        &[(5.0, &[3]), (5.0, &[2, 3]), (5.0, &[2, 3]), (90.0, &[3])],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn xeon_thermal_limit_offlines_the_hot_package_until_it_cools() {
+    let fixture = Fixture::dual_socket_xeon();
+    let hwmon = fixture.root.join("class/hwmon");
+    for (socket, celsius) in [(0, "90000"), (1, "60000")] {
+        let dir = hwmon.join(format!("hwmon{}", socket));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("name"), "coretemp\n").unwrap();
+        fs::write(dir.join("temp1_label"), format!("Package id {}\n", socket)).unwrap();
+        fs::write(dir.join("temp1_input"), celsius).unwrap();
+    }
+    let package_0 = hwmon.join("hwmon0/temp1_input");
+    let mut manager = fixture
+        .manager(&["--thermal-limit", "80"], &[90.0; 5])
+        .await;
+    // High load, yet the hot package loses a core every cycle.
+    check_cycles(&fixture, &mut manager, &[&[3, 11], &[2, 3, 10, 11]]).await;
+    // Within the margin below the limit, nothing is onlined.
+    fs::write(&package_0, "78000").unwrap();
+    check_cycles(&fixture, &mut manager, &[&[1, 2, 3, 9, 10, 11]]).await;
+    // Cooled down, the load policy onlines again.
+    fs::write(&package_0, "70000").unwrap();
+    check_cycles(&fixture, &mut manager, &[&[2, 3, 10, 11], &[3, 11]]).await;
+}
+
+#[tokio::test]
+async fn ryzen_power_budget_offlines_until_the_package_power_drops() {
+    let fixture = Fixture::ryzen_8_core();
+    let zone = fixture.root.join("class/powercap/intel-rapl:0");
+    fs::create_dir_all(&zone).unwrap();
+    fs::write(zone.join("name"), "package-0\n").unwrap();
+    fs::write(zone.join("max_energy_range_uj"), "65532610987\n").unwrap();
+    let energy = zone.join("energy_uj");
+    fs::write(&energy, "0\n").unwrap();
+    let mut manager = fixture
+        .manager(&["--power-budget-watts", "50"], &[90.0; 5])
+        .await;
+    // The first cycle has no power reading yet.
+    check_cycles(&fixture, &mut manager, &[&[]]).await;
+    // 100 J within the few milliseconds of a test cycle is far above the budget, even at high load.
+    fs::write(&energy, "100000000\n").unwrap();
+    check_cycles(&fixture, &mut manager, &[&[14, 15]]).await;
+    fs::write(&energy, "200000000\n").unwrap();
+    check_cycles(&fixture, &mut manager, &[&[12, 13, 14, 15]]).await;
+    // No energy used leaves headroom, so the high load onlines again.
+    check_cycles(&fixture, &mut manager, &[&[14, 15], &[]]).await;
+}
+
+#[tokio::test]
+async fn ryzen_steal_limit_holds_scale_up_while_the_host_is_oversubscribed() {
+    let fixture = Fixture::ryzen_8_core();
+    let mut manager = fixture.build_manager(&["--steal-limit", "20"]).await;
+    manager.set_metric(Box::new(ScriptedSamples(
+        [(10.0, 0.0), (90.0, 35.0), (90.0, 35.0), (90.0, 5.0)]
+            .map(|(load, steal)| LoadSample {
+                steal: Some(steal),
+                ..system_load(load)
+            })
+            .into(),
+    )));
+    check_cycles(
+        &fixture,
+        &mut manager,
+        &[&[14, 15], &[14, 15], &[14, 15], &[]],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn ryzen_steal_limit_applies_to_the_cpuidle_metric() {
+    let fixture = Fixture::ryzen_8_core();
+    for id in [14, 15] {
+        fs::write(fixture.cpu_dir(id).join("online"), "0").unwrap();
+    }
+    let proc_stat = fixture.root.join("proc_stat");
+    let write = |user: u64, steal: u64| {
+        let stat: String = (0..16)
+            .map(|id| format!("cpu{} {} 0 0 15 0 0 0 {} 0 0\n", id, user, steal))
+            .collect();
+        fs::write(&proc_stat, stat).unwrap();
+    };
+    // The cpuidle residency never advances, so every CPU is fully loaded.
+    let mut manager = fixture
+        .build_manager(&["-m", "cpuidle", "--steal-limit", "20"])
+        .await;
+    manager.set_steal_reader(StealReader::with_path(&proc_stat));
+    // 35% of the time since boot was stolen.
+    write(50, 35);
+    check_cycles(&fixture, &mut manager, &[&[14, 15]]).await;
+    // 5% of the time since was stolen.
+    write(145, 40);
+    check_cycles(&fixture, &mut manager, &[&[]]).await;
+}
+
+#[tokio::test]
+async fn ryzen_wakeup_latency_onlines_below_the_upper_threshold() {
+    let fixture = Fixture::ryzen_8_core();
+    let mut manager = fixture.build_manager(&["--wakeup-latency-us", "200"]).await;
+    manager.set_metric(Box::new(ScriptedSamples(
+        [(10.0, 20.0), (50.0, 20.0), (50.0, 450.0), (50.0, 20.0)]
+            .map(|(load, latency)| LoadSample {
+                wakeup_latency_us: Some(latency),
+                ..system_load(load)
+            })
+            .into(),
+    )));
+    check_cycles(&fixture, &mut manager, &[&[14, 15], &[14, 15], &[], &[]]).await;
+}
+
+#[tokio::test]
+async fn ryzen_keeps_the_cores_of_exclusive_kubernetes_cpus_online() {
+    let fixture = Fixture::ryzen_8_core();
+    let state = fixture.root.join("cpu_manager_state");
+    fs::write(
+        &state,
+        r#"{"policyName":"static","defaultCpuSet":"0-11,13-14","entries":{"6b1f":{"app":"12"},"9c4e":{"db":"15"}},"checksum":1}"#,
+    )
+    .unwrap();
+    let mut manager = fixture
+        .manager(&["--kubelet-state", state.to_str().unwrap()], &[5.0; 3])
+        .await;
+    check_cycles(&fixture, &mut manager, &[&[10, 11], &[8, 9, 10, 11]]).await;
+    // Once the pods are gone, their cores are offlined like any other.
+    fs::write(
+        &state,
+        r#"{"policyName":"static","defaultCpuSet":"0-15","entries":{},"checksum":2}"#,
+    )
+    .unwrap();
+    check_cycles(&fixture, &mut manager, &[&[8, 9, 10, 11, 14, 15]]).await;
+}
+
+#[tokio::test]
+async fn ryzen_follows_the_cpu_usage_of_the_watched_cgroup() {
+    let fixture = Fixture::ryzen_8_core();
+    let cgroup = fixture.root.join("fs/cgroup/batch.slice");
+    fs::create_dir_all(&cgroup).unwrap();
+    let stat = cgroup.join("cpu.stat");
+    fs::write(
+        &stat,
+        "usage_usec 5000000
+user_usec 5000000
+system_usec 0
+",
+    )
+    .unwrap();
+    let mut manager = fixture
+        .build_manager(&["-m", "cgroup", "--watch-cgroup", "/batch.slice"])
+        .await;
+    // The rest of the system may be busy, but the slice is idle.
+    check_cycles(&fixture, &mut manager, &[&[14, 15]]).await;
+    // Far more CPU time than the online CPUs had since, so the slice is saturated.
+    fs::write(
+        &stat,
+        "usage_usec 9000000000
+",
+    )
+    .unwrap();
+    check_cycles(&fixture, &mut manager, &[&[]]).await;
+}
+
+#[tokio::test]
+async fn ryzen_exclude_nice_parks_cores_busy_with_niced_tasks() {
+    let fixture = Fixture::ryzen_8_core();
+    let proc_stat = fixture.root.join("proc_stat");
+    // Every CPU is busy, 90% of the time with niced tasks.
+    let stat: String = (0..16)
+        .map(|id| format!("cpu{} 100 900 0 0 0 0 0 0 0 0\n", id))
+        .collect();
+    fs::write(&proc_stat, &stat).unwrap();
+    let mut manager = fixture.build_manager(&["--exclude-nice"]).await;
+    manager.set_metric(Box::new(ProcStatMetric::with_path(&proc_stat)));
+    check_cycles(&fixture, &mut manager, &[&[14, 15]]).await;
+
+    let mut manager = fixture.build_manager(&[]).await;
+    manager.set_metric(Box::new(ProcStatMetric::with_path(&proc_stat)));
+    check_cycles(&fixture, &mut manager, &[&[]]).await;
+}
+
+#[tokio::test]
+async fn ryzen_schedstat_onlines_for_queued_tasks() {
+    let fixture = Fixture::ryzen_8_core();
+    let schedstat = fixture.root.join("schedstat");
+    let write = |running: u64, waiting: u64| {
+        let stat: String = (0..16)
+            .map(|id| format!("cpu{} 0 0 0 0 0 0 {} {} 0\n", id, running, waiting))
+            .collect();
+        fs::write(&schedstat, format!("version 15\ntimestamp 1\n{}", stat)).unwrap();
+    };
+    write(0, 0);
+    let mut manager = fixture.build_manager(&[]).await;
+    manager.set_metric(Box::new(SchedstatMetric::with_path(&schedstat).unwrap()));
+    check_cycles(&fixture, &mut manager, &[&[14, 15]]).await;
+    // Far more waiting than running time since: the run queues are long.
+    write(1_000_000, 1_000_000_000_000);
+    check_cycles(&fixture, &mut manager, &[&[]]).await;
+    assert!(manager.topology().average_c0() > 100.0);
+}
+
+#[tokio::test]
+async fn ryzen_cores_first_boost_policy_runs_turbo_only_with_every_core_online() {
+    let fixture = Fixture::ryzen_8_core();
+    let no_turbo = fixture
+        .root
+        .join("devices/system/cpu/intel_pstate/no_turbo");
+    fs::create_dir_all(no_turbo.parent().unwrap()).unwrap();
+    fs::write(&no_turbo, "0\n").unwrap();
+    let mut manager = fixture
+        .manager(
+            &["--boost-policy", "cores-first"],
+            &[10.0, 10.0, 90.0, 90.0],
+        )
+        .await;
+    // Turbo goes first as the load drops, then the cores.
+    check_cycles(&fixture, &mut manager, &[&[]]).await;
+    assert_eq!(fs::read_to_string(&no_turbo).unwrap(), "1");
+    check_cycles(&fixture, &mut manager, &[&[14, 15]]).await;
+    // Every core comes back before turbo as the load climbs.
+    check_cycles(&fixture, &mut manager, &[&[]]).await;
+    assert_eq!(fs::read_to_string(&no_turbo).unwrap(), "1");
+    check_cycles(&fixture, &mut manager, &[&[]]).await;
+    assert_eq!(fs::read_to_string(&no_turbo).unwrap(), "0");
+}
+
+#[tokio::test]
+async fn ryzen_tune_pstate_biases_the_online_cpus_with_the_load() {
+    let fixture = Fixture::ryzen_8_core();
+    let cpu_dir = fixture.root.join("devices/system/cpu");
+    for id in 0..16 {
+        let cpufreq = cpu_dir.join(format!("cpu{}/cpufreq", id));
+        fs::create_dir_all(&cpufreq).unwrap();
+        fs::write(
+            cpufreq.join("energy_performance_preference"),
+            "balance_performance\n",
+        )
+        .unwrap();
+    }
+    let max_perf = cpu_dir.join("intel_pstate/max_perf_pct");
+    fs::create_dir_all(max_perf.parent().unwrap()).unwrap();
+    fs::write(&max_perf, "100\n").unwrap();
+    let epp = |id: usize| {
+        fs::read_to_string(cpu_dir.join(format!("cpu{}/cpufreq/energy_performance_preference", id)))
+            .unwrap()
+    };
+    let mut manager = fixture.manager(&["--tune-pstate"], &[10.0, 90.0]).await;
+    check_cycles(&fixture, &mut manager, &[&[14, 15]]).await;
+    assert_eq!(epp(0), "power");
+    assert_eq!(fs::read_to_string(&max_perf).unwrap(), "60");
+    // The cores onlined on high load are tuned for performance along with the rest.
+    check_cycles(&fixture, &mut manager, &[&[]]).await;
+    assert_eq!(epp(0), "balance_performance");
+    assert_eq!(epp(14), "balance_performance");
+    assert_eq!(fs::read_to_string(&max_perf).unwrap(), "100");
+}
+
+#[tokio::test]
+async fn dual_socket_xeon_onlines_no_smt_sibling_when_smt_is_vulnerable() {
+    let fixture = Fixture::dual_socket_xeon();
+    let vulnerabilities = fixture.root.join("devices/system/cpu/vulnerabilities");
+    fs::create_dir_all(&vulnerabilities).unwrap();
+    fs::write(
+        vulnerabilities.join("l1tf"),
+        "Mitigation: PTE Inversion; VMX: conditional cache flushes, SMT vulnerable\n",
+    )
+    .unwrap();
+    for id in [7, 14, 15] {
+        fs::write(fixture.cpu_dir(id).join("online"), "0").unwrap();
+    }
+    let mut manager = fixture
+        .manager(&["--no-smt-if-vulnerable"], &[95.0, 95.0, 10.0])
+        .await;
+    // Core 7 comes back without its sibling, and the siblings of online cores stay offline even on
+    // high load, so low load sheds the next secondary thread.
+    check_cycles(
+        &fixture,
+        &mut manager,
+        &[&[14, 15], &[14, 15], &[13, 14, 15]],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn ryzen_picks_up_cpus_hotplugged_by_hand() {
+    let fixture = Fixture::ryzen_8_core();
+    let control = Arc::new(ControlState::new());
+    let (_, mut events) = control.events.subscribe();
+    let mut manager = fixture.build_manager_with(&[], Arc::clone(&control)).await;
+    manager.set_metric(Box::new(ScriptedSamples::loads(&[50.0, 95.0])));
+    // An administrator offlines core 1 behind the daemon's back, which then onlines it again on
+    // high load instead of finding every CPU online.
+    for id in [2, 3] {
+        fs::write(fixture.cpu_dir(id).join("online"), "0").unwrap();
+    }
+    check_cycles(&fixture, &mut manager, &[&[2, 3]]).await;
+    assert!(!manager.topology().cpus[&2].online);
+    let event = events.try_recv().unwrap();
+    assert_eq!(
+        serde_json::to_value(&event.kind).unwrap(),
+        serde_json::json!({"event": "external_hotplug", "direction": "offline", "cpus": [2, 3]})
+    );
+    check_cycles(&fixture, &mut manager, &[&[]]).await;
+}
+
+#[tokio::test]
+async fn ryzen_manages_hot_added_cpus() {
+    let fixture = Fixture::ryzen_8_core();
+    let control = Arc::new(ControlState::new());
+    let (_, mut events) = control.events.subscribe();
+    let mut manager = fixture
+        .build_manager_with(&["--rescan-secs", "0.001"], Arc::clone(&control))
+        .await;
+    manager.set_metric(Box::new(ScriptedSamples::loads(&[50.0, 95.0])));
+    // A ninth core is hot-added, e.g. to a VM, and comes up offline.
+    for id in [16, 17] {
+        fixture.cpu(id, 0, 8, "16-17", 2);
+        fs::write(fixture.cpu_dir(id).join("online"), "0").unwrap();
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    manager.step().await.unwrap();
+    assert_eq!(manager.topology().cpus.len(), 18);
+    assert!(manager.topology().cpus[&16].managed);
+    assert!(!manager.topology().cpus[&16].online);
+    let event = events.try_recv().unwrap();
+    assert_eq!(
+        serde_json::to_value(&event.kind).unwrap(),
+        serde_json::json!({"event": "topology_changed", "added": [16, 17], "removed": []})
+    );
+    check_cycles(&fixture, &mut manager, &[&[]]).await;
+}
+
+/// With `--uevents`, hot-added CPUs and CPUs hotplugged by another agent are picked up between
+/// cycles, long before the next rescan or cycle.
+#[tokio::test]
+async fn ryzen_reacts_to_uevents_between_cycles() {
+    let fixture = Fixture::ryzen_8_core();
+    let control = Arc::new(ControlState::new());
+    let (_, mut events) = control.events.subscribe();
+    let mut manager = fixture
+        .build_manager_with(
+            &["--uevents", "--interval", "3600", "--rescan-secs", "0"],
+            Arc::clone(&control),
+        )
+        .await;
+    manager.set_metric(Box::new(ScriptedSamples::loads(&[50.0])));
+    let (uevents, receiver) = tokio::sync::mpsc::unbounded_channel();
+    manager.set_uevents(receiver);
+
+    let shutdown = CancellationToken::new();
+    let hotplug_elsewhere = async {
+        // Waits for the first cycle, after which the next one is an hour away.
+        while control
+            .manager
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_none_or(|status| status.governor.load.is_none())
+        {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        for id in [16, 17] {
+            fixture.cpu(id, 0, 8, "16-17", 2);
+        }
+        fs::write(fixture.cpu_dir(3).join("online"), "0").unwrap();
+        for message in [
+            "offline@/devices/system/cpu/cpu3\0ACTION=offline\0DEVPATH=/devices/system/cpu/cpu3\0SUBSYSTEM=cpu\0",
+            "add@/devices/system/cpu/cpu16\0ACTION=add\0DEVPATH=/devices/system/cpu/cpu16\0SUBSYSTEM=cpu\0",
+        ] {
+            uevents.send(uevent::parse(message.as_bytes()).unwrap()).unwrap();
+        }
+        let mut seen = Vec::new();
+        while seen.len() < 2 {
+            let event = serde_json::to_value(events.recv().await.unwrap().kind).unwrap();
+            if ["topology_changed", "external_hotplug"].contains(&event["event"].as_str().unwrap())
+            {
+                seen.push(event);
+            }
+        }
+        shutdown.cancel();
+        seen
+    };
+    let (result, seen) = tokio::time::timeout(std::time::Duration::from_secs(10), async {
+        tokio::join!(
+            manager.run(control.paused.subscribe(), shutdown.clone()),
+            hotplug_elsewhere
+        )
+    })
+    .await
+    .unwrap();
+    result.unwrap();
+    assert_eq!(
+        seen,
+        [
+            serde_json::json!({"event": "external_hotplug", "direction": "offline", "cpus": [3]}),
+            serde_json::json!({"event": "topology_changed", "added": [16, 17], "removed": []}),
+        ]
+    );
+    assert_eq!(manager.topology().cpus.len(), 18);
+    assert!(!manager.topology().cpus[&3].online);
+}
+
+#[tokio::test]
+async fn smt_core_with_a_failing_thread_costs_one_write_per_cycle() {
+    let sysfs = smt_2_core();
+    sysfs.fail_writes(1, "online", libc::EIO, u32::MAX);
+    let mut manager = mock_manager(sysfs.clone(), &["--blacklist-after", "0"], &[5.0; 3]).await;
+    for _ in 0..3 {
+        tokio::time::timeout(Duration::from_secs(5), manager.step())
+            .await
+            .expect("the cycle ends")
+            .unwrap();
+    }
+    assert_eq!(manager.topology().snapshot().cpus[1].hotplug_failures, 3);
+    assert_eq!(sysfs.get(3, "online").as_deref(), Some("1"));
+}
+
+#[tokio::test]
+async fn smt_core_offlines_without_its_blacklisted_thread() {
+    let sysfs = smt_2_core();
+    sysfs.fail_writes(1, "online", libc::EIO, u32::MAX);
+    let mut manager = mock_manager(sysfs.clone(), &["--blacklist-after", "2"], &[5.0; 3]).await;
+    for _ in 0..3 {
+        manager.step().await.unwrap();
+    }
+    let snapshot = manager.topology().snapshot();
+    assert!(snapshot.cpus[1].blacklisted);
+    assert_eq!(snapshot.cpus[1].hotplug_failures, 2);
+    assert_eq!(sysfs.get(3, "online").as_deref(), Some("0"));
+}
+
+#[tokio::test]
+async fn failed_writes_leave_the_transition_limit_alone() {
+    let sysfs = Arc::new(
+        MockSysfs::new()
+            .with_cpu(0, 0, 0, "0")
+            .with_cpu(1, 1, 0, "1")
+            .with_cpu(2, 2, 0, "2")
+            .with_cpu(3, 3, 0, "3"),
+    );
+    sysfs.fail_writes(3, "online", libc::EIO, u32::MAX);
+    let mut manager = mock_manager(
+        sysfs.clone(),
+        &[
+            "--max-transitions-per-minute",
+            "1",
+            "--blacklist-after",
+            "1",
+        ],
+        &[5.0; 2],
+    )
+    .await;
+    manager.step().await.unwrap();
+    assert_eq!(sysfs.get(2, "online").as_deref(), Some("1"));
+    manager.step().await.unwrap();
+    assert_eq!(sysfs.get(2, "online").as_deref(), Some("0"));
+}